@@ -0,0 +1,121 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Time taken to deliver single big messages between two peers on localhost. Useful for tuning
+//! `Config::stream_receive_window` and `Config::connection_receive_window`.
+//!
+//! With the `testing` feature there's also a run with latency simulated by delaying every send.
+//!
+//! ```text
+//! cargo bench --bench large_transfers --features testing
+//! ```
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Criterion, ParameterizedBenchmark, Throughput};
+use quic_p2p::{Builder, Config, Event, NodeInfo, QuicP2p};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{self, Receiver};
+use unwrap::unwrap;
+
+const MB: usize = 1024 * 1024;
+const SIZES: [usize; 3] = [MB, 10 * MB, 100 * MB];
+
+struct Link {
+    sender: QuicP2p,
+    receiver_info: NodeInfo,
+    receiver_ev_rx: Receiver<Event>,
+    // Kept alive for the duration of the benchmark
+    _receiver: QuicP2p,
+}
+
+impl Link {
+    fn new() -> Self {
+        let (receiver, receiver_ev_rx) = new_peer();
+        let receiver_info = unwrap!(receiver.our_connection_info());
+        let (sender, _) = new_peer();
+
+        // Connection setup is not what we are measuring
+        sender.connect_to(receiver_info.clone());
+        for event in receiver_ev_rx.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        Self {
+            sender,
+            receiver_info,
+            receiver_ev_rx,
+            _receiver: receiver,
+        }
+    }
+
+    fn transfer(&self, msg: &bytes::Bytes) {
+        self.sender
+            .send(self.receiver_info.clone().into(), msg.clone());
+        for event in self.receiver_ev_rx.iter() {
+            if let Event::NewMessage { msg: received, .. } = event {
+                assert_eq!(received.len(), msg.len());
+                return;
+            }
+        }
+        panic!("Receiver is gone");
+    }
+}
+
+fn new_peer() -> (QuicP2p, Receiver<Event>) {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let quic_p2p = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    (quic_p2p, ev_rx)
+}
+
+fn transfers(c: &mut Criterion, name: &str, link: Link) {
+    c.bench(
+        name,
+        ParameterizedBenchmark::new(
+            "transfer",
+            move |b, &size| {
+                let msg = bytes::Bytes::from(vec![0; size]);
+                b.iter(|| link.transfer(&msg))
+            },
+            SIZES.to_vec(),
+        )
+        .throughput(|&size| Throughput::Bytes(size as u32))
+        .sample_size(10),
+    );
+}
+
+fn localhost(c: &mut Criterion) {
+    transfers(c, "localhost", Link::new());
+}
+
+#[cfg(feature = "testing")]
+fn simulated_latency(c: &mut Criterion) {
+    use quic_p2p::FaultSpec;
+
+    let link = Link::new();
+    link.sender
+        .inject_fault(FaultSpec::DelayOutbound { delay_msec: 50 });
+    transfers(c, "simulated_latency_50ms", link);
+}
+
+#[cfg(not(feature = "testing"))]
+criterion_group!(benches, localhost);
+#[cfg(feature = "testing")]
+criterion_group!(benches, localhost, simulated_latency);
+criterion_main!(benches);
@@ -77,7 +77,7 @@ fn main() -> Result<(), io::Error> {
     // Initialise QuicP2p
     let (ev_tx, ev_rx) = channel();
 
-    let (mut qp2p, our_cert_der) = {
+    let (qp2p, our_cert_der) = {
         let our_complete_cert = SerialisableCertificate::default();
         let cert_der = our_complete_cert.cert_der.clone();
         (
@@ -85,7 +85,7 @@ fn main() -> Result<(), io::Error> {
                 .with_config(Config {
                     our_complete_cert: Some(our_complete_cert),
                     port: Some(bootstrap_node_config.port),
-                    ip: Some(IpAddr::V4(bootstrap_node_config.ip)),
+                    bind_addr: Some(IpAddr::V4(bootstrap_node_config.ip)),
                     ..Default::default()
                 },)
                 .build()),
@@ -112,7 +112,7 @@ fn main() -> Result<(), io::Error> {
 
     for event in ev_rx.iter() {
         match event {
-            Event::ConnectedTo { peer } => {
+            Event::ConnectedTo { peer, .. } => {
                 let peer_addr = match &peer {
                     Peer::Node { node_info } => node_info.peer_addr,
                     Peer::Client { .. } => panic!("In this example only Node peers are expected"),
@@ -77,10 +77,10 @@ fn main() {
     let CliArgs { port, our_ip } = parse_cli_args();
     let (ev_tx, ev_rx) = channel();
 
-    let mut qp2p = unwrap!(Builder::new(ev_tx)
+    let qp2p = unwrap!(Builder::new(ev_tx)
         .with_config(Config {
             port,
-            ip: our_ip,
+            bind_addr: our_ip,
             ..Default::default()
         },)
         .build());
@@ -104,7 +104,7 @@ fn main() {
                 };
                 let mut peerlist = peerlist.lock().unwrap();
                 let result = match cmd {
-                    "ourinfo" => Ok(print_ourinfo(&mut qp2p)),
+                    "ourinfo" => Ok(print_ourinfo(&qp2p)),
                     "addpeer" => peerlist
                         .insert_from_json(&args.collect::<Vec<_>>().join(" "))
                         .and(Ok(())),
@@ -115,11 +115,12 @@ fn main() {
                         .and_then(|idx| idx.parse().or(Err("Invalid index argument")))
                         .and_then(|idx| peerlist.remove(idx))
                         .and(Ok(())),
-                    "send" => on_cmd_send(&mut args, &peerlist, &mut qp2p),
-                    "sendrand" => on_cmd_send_rand(&mut args, &peerlist, &mut qp2p),
+                    "send" => on_cmd_send(&mut args, &peerlist, &qp2p),
+                    "sendrand" => on_cmd_send_rand(&mut args, &peerlist, &qp2p),
+                    "dump" => Ok(print_dump(&qp2p)),
                     "quit" | "exit" => break 'outer,
                     "help" => Ok(println!(
-                        "Commands: ourinfo, addpeer, listpeers, delpeer, send, quit, exit, help"
+                        "Commands: ourinfo, addpeer, listpeers, delpeer, send, dump, quit, exit, help"
                     )),
                     _ => Err("Unknown command"),
                 };
@@ -141,7 +142,7 @@ fn main() {
 fn on_cmd_send<'a>(
     mut args: impl Iterator<Item = &'a str>,
     peer_list: &PeerList,
-    qp2p: &mut QuicP2p,
+    qp2p: &QuicP2p,
 ) -> Result<(), &'static str> {
     args.next()
         .ok_or("Missing index argument")
@@ -158,7 +159,7 @@ fn on_cmd_send<'a>(
 fn on_cmd_send_rand<'a>(
     mut args: impl Iterator<Item = &'a str>,
     peer_list: &PeerList,
-    qp2p: &mut QuicP2p,
+    qp2p: &QuicP2p,
 ) -> Result<(), &'static str> {
     args.next()
         .ok_or("Missing index argument")
@@ -183,8 +184,9 @@ fn handle_qp2p_events(
     thread::spawn(move || {
         for event in event_rx.iter() {
             match event {
-                Event::ConnectedTo { peer } => unwrap!(peer_list.lock()).insert(peer),
-                Event::NewMessage { peer_addr, msg } => {
+                Event::ConnectedTo { peer, .. } => unwrap!(peer_list.lock()).insert(peer),
+                Event::NewMessage { peer, msg, .. } => {
+                    let peer_addr = peer.peer_addr();
                     if msg.len() > 512 {
                         println!("[{}] received bytes: {}", peer_addr, msg.len());
                     } else {
@@ -201,7 +203,7 @@ fn handle_qp2p_events(
     })
 }
 
-fn print_ourinfo(qp2p: &mut QuicP2p) {
+fn print_ourinfo(qp2p: &QuicP2p) {
     let ourinfo: Peer = match qp2p.our_connection_info() {
         Ok(ourinfo) => ourinfo.into(),
         Err(e) => {
@@ -216,6 +218,14 @@ fn print_ourinfo(qp2p: &mut QuicP2p) {
     );
 }
 
+/// Prints our internal state, e.g. to see why a connection is stuck.
+fn print_dump(qp2p: &QuicP2p) {
+    match qp2p.debug_dump() {
+        Ok(dump) => println!("{}", unwrap!(serde_json::to_string_pretty(&dump))),
+        Err(e) => println!("Error getting the dump: {}", e),
+    }
+}
+
 fn parse_cli_args() -> CliArgs {
     let matches = App::new("Simple chat app built on Crust")
         .about(
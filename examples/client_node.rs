@@ -72,12 +72,12 @@ fn main() {
 impl ClientNode {
     fn new(bootstrap_node_info: NodeInfo) -> Self {
         let (event_tx, event_rx) = channel();
-        let mut qp2p = unwrap!(Builder::new(event_tx)
+        let qp2p = unwrap!(Builder::new(event_tx)
             .with_config(Config {
                 port: Some(0),
                 hard_coded_contacts: {
                     let mut hcc: HashSet<_> = Default::default();
-                    assert!(hcc.insert(bootstrap_node_info.clone()));
+                    assert!(hcc.insert(bootstrap_node_info.clone().into()));
                     hcc
                 },
                 idle_timeout_msec: Some(0),
@@ -124,8 +124,8 @@ impl ClientNode {
         let event_rx = unwrap!(self.event_rx.take());
         for event in event_rx.iter() {
             match event {
-                Event::ConnectedTo { peer } => self.on_connect(peer),
-                Event::NewMessage { peer_addr, msg } => self.on_msg_receive(peer_addr, msg),
+                Event::ConnectedTo { peer, .. } => self.on_connect(peer),
+                Event::NewMessage { peer, msg, .. } => self.on_msg_receive(peer.peer_addr(), msg),
                 event => warn!("Unexpected event: {:?}", event),
             }
         }
@@ -0,0 +1,27 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Feeds arbitrary frames to the wire message decoder. Besides not panicking, every frame that is
+//! accepted must be in its canonical form, i.e. encode back to exactly the same bytes.
+//!
+//! Run with `cargo fuzz run wire_msg` from the crate root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use quic_p2p::WireMsg;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(wire_msg) = WireMsg::from_bytes_safe(data.to_vec()) {
+        let frame = wire_msg
+            .into_frame()
+            .expect("a parsed message frames again");
+        assert_eq!(&frame[..], data);
+    }
+});
@@ -0,0 +1,76 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Keeps the number of peers in our connection table within `Config::max_total_connections`. New
+//! connections, the ones we make and the ones peers make to us alike, are admitted as per
+//! `Config::admission_policy` once we are at the limit.
+
+use crate::config::AdmissionPolicy;
+use crate::context::Context;
+use crate::event::Event;
+use crate::wire_msg::CloseReason;
+use crate::PeerKind;
+use std::net::SocketAddr;
+
+/// Whether a new connection to or from the peer may be added to our connection table. At the
+/// limit this closes the connection of the idlest client if the policy allows it and fires
+/// `Event::ConnectionLimitReached`. An entry the peer already has doesn't count towards the limit.
+pub fn admit(c: &mut Context, peer_addr: SocketAddr) -> bool {
+    let max = match c.max_total_connections {
+        Some(max) => max,
+        None => return true,
+    };
+    let others = c
+        .connections
+        .keys()
+        .filter(|addr| **addr != peer_addr)
+        .count();
+    if others < max {
+        return true;
+    }
+
+    let evicted = match c.admission_policy {
+        AdmissionPolicy::RejectNew => None,
+        AdmissionPolicy::EvictIdleClient => idlest_client(c, peer_addr),
+    };
+    match evicted {
+        Some(evicted) => {
+            debug!(
+                "At the connection limit of {} - closing the connection to client {} to admit {}",
+                max, evicted, peer_addr
+            );
+            let _ = c.close_connection(&evicted, CloseReason::ConnectionLimit);
+        }
+        None => debug!(
+            "At the connection limit of {} - not admitting {}",
+            max, peer_addr
+        ),
+    }
+
+    let event = Event::ConnectionLimitReached { peer_addr, evicted };
+    if let Err(e) = c.event_tx.send(event) {
+        info!("Could not fire event: {:?}", e);
+    }
+
+    evicted.is_some()
+}
+
+/// The client we heard from least recently, leaving out the ones we are reading messages from and
+/// the peer being admitted, which can have an entry of its own already.
+fn idlest_client(c: &Context, admitted: SocketAddr) -> Option<SocketAddr> {
+    c.connections
+        .iter()
+        .filter(|(peer_addr, conn)| {
+            **peer_addr != admitted
+                && conn.peer_kind == Some(PeerKind::Client)
+                && conn.stream_reads.is_empty()
+        })
+        .min_by_key(|(_, conn)| conn.last_heard_at)
+        .map(|(peer_addr, _)| *peer_addr)
+}
@@ -0,0 +1,216 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Backpressure from an application not keeping up, see `Config::backpressure`. The backlog is
+//! made of the events it has yet to take, if we can tell, and the messages we have read from the
+//! peers but not handed over yet. Its level is checked periodically and changes reported via
+//! `Event::Backpressure`. While it's critical, the data lanes of the peers which sent us the most
+//! can be paused, so that they are held back by QUIC flow control rather than by our memory.
+
+use crate::clock;
+use crate::config::BackpressureConfig;
+use crate::connection::Connection;
+use crate::context::{Context, Ctx};
+use crate::event::Event;
+use std::cmp;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::{task, Async, Poll, Stream};
+
+/// How backed up the application is, see `Config::backpressure`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum BackpressureLevel {
+    /// Less than half of either maximum
+    Normal,
+    /// Half of either maximum or more
+    Elevated,
+    /// Either maximum or more
+    Critical,
+}
+
+impl BackpressureLevel {
+    fn of(backlog: usize, max: usize) -> Self {
+        if backlog >= max {
+            BackpressureLevel::Critical
+        } else if backlog >= max / 2 {
+            BackpressureLevel::Elevated
+        } else {
+            BackpressureLevel::Normal
+        }
+    }
+}
+
+impl Default for BackpressureLevel {
+    fn default() -> Self {
+        BackpressureLevel::Normal
+    }
+}
+
+/// Backlog of the messages from the peer, obtained via `QuicP2p::read_backlogs`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReadBacklog {
+    pub peer_addr: SocketAddr,
+    /// Messages from the peer we have started reading but not finished yet
+    pub incomplete_reads: usize,
+    /// Bytes of the messages from the peer we have read but not handed over yet
+    pub unread_bytes: usize,
+    /// Whether new streams from the peer are left unaccepted for the backlog to drain
+    pub is_paused: bool,
+}
+
+impl ReadBacklog {
+    pub(crate) fn new(peer_addr: SocketAddr, conn: &Connection) -> Self {
+        Self {
+            peer_addr,
+            incomplete_reads: conn.stream_reads.len(),
+            unread_bytes: conn.unread_bytes(),
+            is_paused: conn.is_read_paused,
+        }
+    }
+}
+
+/// Check the backlog every `check_interval_msec` for as long as the event loop runs.
+pub fn start(ctx: &Ctx, cfg: BackpressureConfig) {
+    let interval = Duration::from_millis(cfg.check_interval_msec);
+    let ctx_clone = ctx.clone();
+    let leaf = clock::interval(&ctx.with(|c| c.clock.clone()), interval).for_each(move |_| {
+        ctx_clone.with_mut(|c| check(c, &cfg));
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+/// Note the bytes of a message read from the peer, towards finding the noisiest peers.
+pub fn record_read(ctx: &Ctx, peer_addr: SocketAddr, len: usize) {
+    ctx.with_mut(|c| {
+        if let Some(conn) = c.connections.get_mut(&peer_addr) {
+            conn.recent_read_bytes = conn.recent_read_bytes.saturating_add(len);
+        }
+    })
+}
+
+/// Stream of the streams the peer opens, yielding none while reading from the peer is paused.
+pub fn pausable<S: Stream>(ctx: &Ctx, peer_addr: SocketAddr, streams: S) -> Pausable<S> {
+    Pausable {
+        inner: streams,
+        peer_addr,
+        ctx: ctx.clone(),
+    }
+}
+
+pub struct Pausable<S> {
+    inner: S,
+    peer_addr: SocketAddr,
+    ctx: Ctx,
+}
+
+impl<S: Stream> Stream for Pausable<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let peer_addr = self.peer_addr;
+        let is_paused = self
+            .ctx
+            .with_mut(|c| match c.connections.get_mut(&peer_addr) {
+                Some(conn) if conn.is_read_paused => {
+                    conn.paused_reader = Some(task::current());
+                    true
+                }
+                _ => false,
+            });
+        if is_paused {
+            return Ok(Async::NotReady);
+        }
+
+        self.inner.poll()
+    }
+}
+
+fn check(c: &mut Context, cfg: &BackpressureConfig) {
+    let event_depth = c.event_tx.depth().unwrap_or(0);
+    let unread_bytes = c.connections.values().map(Connection::unread_bytes).sum();
+    let level = cmp::max(
+        BackpressureLevel::of(event_depth, cfg.max_event_depth),
+        BackpressureLevel::of(unread_bytes, cfg.max_unread_bytes),
+    );
+
+    if level != c.backpressure_level {
+        debug!(
+            "Backpressure {:?}: {} events not taken, {} bytes read not handed over",
+            level, event_depth, unread_bytes
+        );
+        c.backpressure_level = level;
+        if let Err(e) = c.event_tx.send(Event::Backpressure { level }) {
+            info!("Could not fire event: {:?}", e);
+        }
+    }
+
+    if cfg.pause_noisiest_peers {
+        match level {
+            BackpressureLevel::Critical => pause_noisiest(c),
+            BackpressureLevel::Normal => resume_all(c),
+            // Leave the paused ones paused until the backlog has drained
+            BackpressureLevel::Elevated => (),
+        }
+    }
+
+    for conn in c.connections.values_mut() {
+        conn.recent_read_bytes = 0;
+    }
+}
+
+fn pause_noisiest(c: &mut Context) {
+    let noisiest = c
+        .connections
+        .iter_mut()
+        .filter(|(_, conn)| !conn.is_read_paused)
+        .map(|(peer_addr, conn)| {
+            let noise = conn.unread_bytes() + conn.recent_read_bytes;
+            (noise, *peer_addr, conn)
+        })
+        .filter(|(noise, _, _)| *noise > 0)
+        .max_by_key(|(noise, _, _)| *noise);
+
+    if let Some((noise, peer_addr, conn)) = noisiest {
+        info!(
+            "Pausing reads from peer {} which sent {} bytes lately",
+            peer_addr, noise
+        );
+        conn.is_read_paused = true;
+    }
+}
+
+fn resume_all(c: &mut Context) {
+    for (peer_addr, conn) in c.connections.iter_mut() {
+        if conn.is_read_paused {
+            info!("Resuming reads from peer {}", peer_addr);
+            conn.is_read_paused = false;
+            if let Some(task) = conn.paused_reader.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backpressure_levels_follow_the_backlog() {
+        assert_eq!(BackpressureLevel::of(0, 100), BackpressureLevel::Normal);
+        assert_eq!(BackpressureLevel::of(49, 100), BackpressureLevel::Normal);
+        assert_eq!(BackpressureLevel::of(50, 100), BackpressureLevel::Elevated);
+        assert_eq!(BackpressureLevel::of(99, 100), BackpressureLevel::Elevated);
+        assert_eq!(BackpressureLevel::of(100, 100), BackpressureLevel::Critical);
+        assert!(BackpressureLevel::Critical > BackpressureLevel::Elevated);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Connects to many nodes at once, e.g. a whole section, summarised in a single
+//! `Event::BatchConnectComplete` once every one of them has succeeded or failed.
+
+use crate::connect;
+use crate::context::Ctx;
+use crate::error::Error;
+use crate::event::{Event, EventTx};
+use crate::NodeInfo;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Batches of connects still waiting for some of their outcomes.
+#[derive(Default)]
+pub struct BatchConnects {
+    batches: Vec<Batch>,
+}
+
+struct Batch {
+    pending: HashMap<SocketAddr, NodeInfo>,
+    connected: Vec<NodeInfo>,
+    failed: Vec<NodeInfo>,
+}
+
+impl BatchConnects {
+    /// The connect to the peer succeeded or failed. Batches waiting for it are updated and the
+    /// ones now complete are reported.
+    pub fn resolve(&mut self, event_tx: &EventTx, peer_addr: SocketAddr, is_connected: bool) {
+        for batch in &mut self.batches {
+            if let Some(node_info) = batch.pending.remove(&peer_addr) {
+                if is_connected {
+                    batch.connected.push(node_info);
+                } else {
+                    batch.failed.push(node_info);
+                }
+            }
+        }
+
+        let (complete, incomplete) = self
+            .batches
+            .drain(..)
+            .partition(|batch| batch.pending.is_empty());
+        self.batches = incomplete;
+
+        for batch in complete {
+            let event = Event::BatchConnectComplete {
+                connected: batch.connected,
+                failed: batch.failed,
+            };
+            if let Err(e) = event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Connect to all the given nodes. Ones we are connected to already count as connected straight
+/// away and the ones being connected to already are waited for rather than connected to again.
+/// This must not be called while the `Context` is already borrowed.
+pub fn start(ctx: &Ctx, peers: Vec<NodeInfo>) {
+    let mut pending = HashMap::with_capacity(peers.len());
+    for node_info in peers {
+        let _ = pending.entry(node_info.peer_addr).or_insert(node_info);
+    }
+    let to_connect: Vec<_> = pending.values().cloned().collect();
+
+    ctx.with_mut(|c| {
+        if pending.is_empty() {
+            let event = Event::BatchConnectComplete {
+                connected: Vec::new(),
+                failed: Vec::new(),
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+            return;
+        }
+
+        c.batch_connects.batches.push(Batch {
+            pending,
+            connected: Vec::new(),
+            failed: Vec::new(),
+        })
+    });
+
+    for node_info in to_connect {
+        let peer_addr = node_info.peer_addr;
+        match connect::connect_to(ctx, node_info, None, None) {
+            Ok(()) => set_we_contacted_peer(ctx, peer_addr),
+            Err(Error::DuplicateConnectionToPeer(_)) => {
+                // Either connected already or its outcome is yet to come
+                let is_initiated = ctx.with(|c| {
+                    c.connections
+                        .get(&peer_addr)
+                        .map_or(false, |conn| conn.to_peer.is_initiated())
+                });
+                if !is_initiated {
+                    ctx.with_mut(|c| c.batch_connects.resolve(&c.event_tx, peer_addr, true));
+                }
+            }
+            Err(_) => ctx.with_mut(|c| c.batch_connects.resolve(&c.event_tx, peer_addr, false)),
+        }
+    }
+}
+
+fn set_we_contacted_peer(ctx: &Ctx, peer_addr: SocketAddr) {
+    ctx.with_mut(|c| {
+        if let Some(conn) = c.connections.get_mut(&peer_addr) {
+            conn.we_contacted_peer = true;
+        }
+    })
+}
@@ -0,0 +1,179 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Sends a burst of messages to a peer in as few frames as will fit, each written back-to-back on
+//! a stream of its own. The outcome of the whole batch is reported in a single
+//! `Event::SentUserMessageBatch` or `Event::UnsentUserMessageBatch`.
+
+use crate::communicate;
+use crate::context::Ctx;
+use crate::event::{Event, EventTx, UnsentReason};
+use crate::wire_msg::{self, WireMsg};
+use crate::{Peer, DEFAULT_CHANNEL, R};
+use std::collections::HashMap;
+
+/// Batches still waiting for some of their writes.
+#[derive(Default)]
+pub struct BatchSends {
+    next_id: u64,
+    pending: HashMap<u64, BatchSend>,
+}
+
+struct BatchSend {
+    peer: Peer,
+    token: u64,
+    /// Messages of every frame in the batch, `None` once the frame is written.
+    frames: Vec<Option<Vec<bytes::Bytes>>>,
+    remaining: usize,
+}
+
+impl BatchSends {
+    fn insert(&mut self, peer: Peer, token: u64, frames: Vec<Vec<bytes::Bytes>>) -> u64 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let _ = self.pending.insert(
+            id,
+            BatchSend {
+                peer,
+                token,
+                remaining: frames.len(),
+                frames: frames.into_iter().map(Some).collect(),
+            },
+        );
+        id
+    }
+
+    /// The write of the frame finished. The batch is reported once it was the last one outstanding.
+    pub fn resolve(&mut self, event_tx: &EventTx, id: u64, frame_idx: usize, is_sent: bool) {
+        let is_complete = match self.pending.get_mut(&id) {
+            Some(batch) => {
+                if is_sent {
+                    batch.frames[frame_idx] = None;
+                }
+                batch.remaining -= 1;
+                batch.remaining == 0
+            }
+            None => return debug!("Write outcome for an unknown batch: {}", id),
+        };
+
+        if is_complete {
+            if let Some(batch) = self.pending.remove(&id) {
+                let unsent = batch.frames.into_iter().flatten().flatten().collect();
+                fire_outcome(
+                    event_tx,
+                    batch.peer,
+                    batch.token,
+                    unsent,
+                    UnsentReason::WriteFailed,
+                );
+            }
+        }
+    }
+}
+
+/// Send the messages to the peer in order. Messages on the same stream are delivered in order,
+/// but with more than one frame needed the streams can overtake each other. Like
+/// `QuicP2p::send_to_many` this doesn't connect to the peer. This must not be called while the
+/// `Context` is already borrowed.
+pub fn start(ctx: &Ctx, peer: Peer, msgs: Vec<bytes::Bytes>, token: u64) {
+    let peer_addr = peer.peer_addr();
+    let max_frame_len = ctx.with(|c| c.max_msg_size_allowed + wire_msg::MAX_FRAME_OVERHEAD);
+    let frames = wire_msg::split_into_batches(msgs, max_frame_len);
+    let framed: R<Vec<_>> = frames
+        .iter()
+        .map(|msgs| WireMsg::UserMsgBatch(msgs.clone()).into_frame())
+        .collect();
+    let framed = match framed {
+        Ok(framed) => framed,
+        Err(e) => {
+            debug!("Could not frame the batch for peer {}: {}", peer_addr, e);
+            let unsent = frames.into_iter().flatten().collect();
+            return ctx.with(|c| {
+                fire_outcome(&c.event_tx, peer, token, unsent, UnsentReason::WriteFailed)
+            });
+        }
+    };
+    if frames.is_empty() {
+        return ctx.with(|c| {
+            fire_outcome(
+                &c.event_tx,
+                peer,
+                token,
+                Vec::new(),
+                UnsentReason::WriteFailed,
+            )
+        });
+    }
+
+    let is_connected = ctx.with(|c| {
+        c.connections
+            .get(&peer_addr)
+            .and_then(|conn| communicate::writable_q_conn(c, conn))
+            .is_some()
+    });
+    if !is_connected {
+        trace!("Not connected to {} to send the batch over", peer_addr);
+        let unsent = frames.into_iter().flatten().collect();
+        return ctx
+            .with(|c| fire_outcome(&c.event_tx, peer, token, unsent, UnsentReason::NotConnected));
+    }
+
+    let id = ctx.with_mut(|c| c.batch_sends.insert(peer, token, frames.clone()));
+
+    ctx.with(|c| {
+        // Checked just above and nothing could have changed since
+        let q_conn = match c
+            .connections
+            .get(&peer_addr)
+            .and_then(|conn| communicate::writable_q_conn(c, conn))
+        {
+            Some(q_conn) => q_conn,
+            None => return,
+        };
+
+        for (frame_idx, (msgs, frame)) in frames.into_iter().zip(framed).enumerate() {
+            let wire_msg = WireMsg::UserMsgBatch(msgs);
+            let ctx_on_written = ctx.clone();
+            communicate::write_frame_to_peer_connection(
+                ctx,
+                peer_addr,
+                q_conn,
+                Some(wire_msg),
+                DEFAULT_CHANNEL,
+                frame,
+                Some(Box::new(move |is_sent| {
+                    ctx_on_written
+                        .with_mut(|c| c.batch_sends.resolve(&c.event_tx, id, frame_idx, is_sent))
+                })),
+            );
+        }
+    });
+}
+
+fn fire_outcome(
+    event_tx: &EventTx,
+    peer: Peer,
+    token: u64,
+    unsent: Vec<bytes::Bytes>,
+    reason: UnsentReason,
+) {
+    let event = if unsent.is_empty() {
+        Event::SentUserMessageBatch { peer, token }
+    } else {
+        Event::UnsentUserMessageBatch {
+            peer,
+            msgs: unsent,
+            token,
+            reason,
+        }
+    };
+    if let Err(e) = event_tx.send(event) {
+        info!("Could not fire event: {:?}", e);
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Network diagnostics for operators validating their firewall/NAT setups. Every subcommand
+//! prints its results as JSON on stdout, one object per line, so it can be scripted.
+//!
+//! ```text
+//! quic-p2p-cli echo-server
+//! quic-p2p-cli ping '<nodeinfo json>'
+//! quic-p2p-cli bootstrap-test <cache-file>
+//! quic-p2p-cli throughput '<nodeinfo json>' <MB>
+//! ```
+//!
+//! Node infos are given in the JSON format `echo-server` prints them in.
+
+#[macro_use]
+extern crate unwrap;
+
+use bytes::Bytes;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use quic_p2p::{Builder, Client, Config, Event, NodeInfo};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs;
+use std::net::IpAddr;
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Size of each message sent during the throughput test.
+const THROUGHPUT_CHUNK_SIZE: usize = 1024 * 1024;
+
+fn main() {
+    let matches = App::new("quic-p2p-cli")
+        .about("Network diagnostics for quic-p2p deployments. Results are printed as JSON.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .short("p")
+                .help("Port we listen on")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("ip")
+                .long("ip")
+                .help("IP address we listen on")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .short("t")
+                .help("Seconds to wait for each step before giving up")
+                .takes_value(true)
+                .default_value("10")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("ping")
+                .about("Connect to a node and report how long it took")
+                .arg(Arg::with_name("nodeinfo").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("echo-server")
+                .about("Run a node which sends every message it receives back to its sender"),
+        )
+        .subcommand(
+            SubCommand::with_name("bootstrap-test")
+                .about("Bootstrap off the nodes in a cache file (a JSON list of node infos)")
+                .arg(Arg::with_name("cache-file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("throughput")
+                .about("Send data to an echo server and measure how fast it comes back")
+                .arg(Arg::with_name("nodeinfo").required(true))
+                .arg(Arg::with_name("MB").required(true)),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("ping", Some(args)) => ping(args),
+        ("echo-server", Some(args)) => echo_server(args),
+        ("bootstrap-test", Some(args)) => bootstrap_test(args),
+        ("throughput", Some(args)) => throughput(args),
+        _ => unreachable!("Subcommand is required"),
+    };
+
+    if let Err(error) = result {
+        println!("{}", json!({ "error": error }));
+        process::exit(1);
+    }
+}
+
+fn ping(args: &ArgMatches) -> Result<(), String> {
+    let node_info = parse_node_info(args)?;
+    let timeout = parse_timeout(args)?;
+    let (client, ev_rx) = build_client(args, Default::default())?;
+
+    let started_at = Instant::now();
+    client.connect_to(node_info.clone());
+    let is_connected = wait_for(&ev_rx, timeout, |event| match event {
+        Event::ConnectedTo { peer, .. } if peer.peer_addr() == node_info.peer_addr => Some(()),
+        _ => None,
+    })
+    .is_some();
+
+    println!(
+        "{}",
+        json!({
+            "command": "ping",
+            "peer_addr": node_info.peer_addr.to_string(),
+            "success": is_connected,
+            "connect_ms": if is_connected { Some(millis(started_at.elapsed())) } else { None },
+        })
+    );
+
+    Ok(())
+}
+
+fn echo_server(args: &ArgMatches) -> Result<(), String> {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let node = Builder::new(ev_tx)
+        .with_config(config(args)?)
+        .with_proxies(Default::default(), true)
+        .build_node()
+        .map_err(|e| e.to_string())?;
+    let our_info = node.our_connection_info().map_err(|e| e.to_string())?;
+
+    println!(
+        "{}",
+        json!({
+            "command": "echo-server",
+            "our_connection_info": our_info,
+        })
+    );
+
+    for event in ev_rx.iter() {
+        match event {
+            Event::ConnectedTo { peer, .. } => {
+                println!(
+                    "{}",
+                    json!({ "event": "connected", "peer_addr": peer.peer_addr().to_string() })
+                );
+            }
+            Event::ConnectionFailure { peer_addr, reason } => {
+                println!(
+                    "{}",
+                    json!({
+                        "event": "disconnected",
+                        "peer_addr": peer_addr.to_string(),
+                        "reason": format!("{:?}", reason),
+                    })
+                );
+            }
+            Event::NewMessage { peer, msg, .. } => node.send(peer, msg),
+            Event::Finish => break,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn bootstrap_test(args: &ArgMatches) -> Result<(), String> {
+    let cache_file = unwrap!(args.value_of("cache-file"));
+    let contents = fs::read_to_string(cache_file).map_err(|e| e.to_string())?;
+    let nodes: VecDeque<NodeInfo> = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+    let node_count = nodes.len();
+    let timeout = parse_timeout(args)?;
+    let (client, ev_rx) = build_client(args, nodes)?;
+
+    let started_at = Instant::now();
+    client.bootstrap();
+    let outcome = wait_for(&ev_rx, timeout, |event| match event {
+        Event::BootstrappedTo { node, .. } => Some(Some(node)),
+        Event::BootstrapFailure => Some(None),
+        _ => None,
+    });
+
+    let bootstrapped_to = match outcome {
+        Some(Some(node)) => Some(node.peer_addr.to_string()),
+        Some(None) | None => None,
+    };
+    println!(
+        "{}",
+        json!({
+            "command": "bootstrap-test",
+            "nodes_tried": node_count,
+            "success": bootstrapped_to.is_some(),
+            "bootstrapped_to": bootstrapped_to,
+            "timed_out": outcome.is_none(),
+            "elapsed_ms": millis(started_at.elapsed()),
+        })
+    );
+
+    Ok(())
+}
+
+fn throughput(args: &ArgMatches) -> Result<(), String> {
+    let node_info = parse_node_info(args)?;
+    let megabytes: usize = unwrap!(args.value_of("MB"))
+        .parse()
+        .map_err(|e| format!("Invalid MB: {}", e))?;
+    let timeout = parse_timeout(args)?;
+    let (client, ev_rx) = build_client(args, Default::default())?;
+
+    client.connect_to(node_info.clone());
+    if wait_for(&ev_rx, timeout, |event| match event {
+        Event::ConnectedTo { .. } => Some(()),
+        _ => None,
+    })
+    .is_none()
+    {
+        return Err(format!("Could not connect to {}", node_info.peer_addr));
+    }
+
+    let total_bytes = megabytes * THROUGHPUT_CHUNK_SIZE;
+    let chunk = Bytes::from(vec![0; THROUGHPUT_CHUNK_SIZE]);
+    let started_at = Instant::now();
+    for _ in 0..megabytes {
+        client.send(node_info.clone().into(), chunk.clone());
+    }
+
+    let mut echoed_bytes = 0;
+    while echoed_bytes < total_bytes {
+        let received = wait_for(&ev_rx, timeout, |event| match event {
+            Event::NewMessage { msg, .. } => Some(msg.len()),
+            _ => None,
+        });
+        match received {
+            Some(len) => echoed_bytes += len,
+            None => break,
+        }
+    }
+
+    let elapsed = started_at.elapsed();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    println!(
+        "{}",
+        json!({
+            "command": "throughput",
+            "peer_addr": node_info.peer_addr.to_string(),
+            "bytes_sent": total_bytes,
+            "bytes_echoed": echoed_bytes,
+            "elapsed_ms": millis(elapsed),
+            // Data crosses the link twice, there and back again
+            "mbps": (2 * echoed_bytes) as f64 * 8.0 / 1e6 / secs,
+        })
+    );
+
+    Ok(())
+}
+
+fn build_client(
+    args: &ArgMatches,
+    proxies: VecDeque<NodeInfo>,
+) -> Result<(Client, Receiver<Event>), String> {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let client = Builder::new(ev_tx)
+        .with_config(config(args)?)
+        .with_proxies(proxies, true)
+        .build_client()
+        .map_err(|e| e.to_string())?;
+    Ok((client, ev_rx))
+}
+
+fn config(args: &ArgMatches) -> Result<Config, String> {
+    let port = match args.value_of("port") {
+        Some(port) => Some(port.parse().map_err(|e| format!("Invalid port: {}", e))?),
+        None => None,
+    };
+    let ip = match args.value_of("ip") {
+        Some(ip) => Some(
+            ip.parse::<IpAddr>()
+                .map_err(|e| format!("Invalid IP: {}", e))?,
+        ),
+        None => None,
+    };
+
+    Ok(Config {
+        port,
+        ip,
+        ..Config::with_default_cert()
+    })
+}
+
+fn parse_node_info(args: &ArgMatches) -> Result<NodeInfo, String> {
+    serde_json::from_str(unwrap!(args.value_of("nodeinfo")))
+        .map_err(|e| format!("Invalid node info: {}", e))
+}
+
+fn parse_timeout(args: &ArgMatches) -> Result<Duration, String> {
+    unwrap!(args.value_of("timeout"))
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|e| format!("Invalid timeout: {}", e))
+}
+
+/// Wait for the first event `f` picks something out of, giving up after `timeout`.
+fn wait_for<T, F>(ev_rx: &Receiver<Event>, timeout: Duration, mut f: F) -> Option<T>
+where
+    F: FnMut(Event) -> Option<T>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        match ev_rx.recv_timeout(deadline - now) {
+            Ok(event) => {
+                if let Some(picked) = f(event) {
+                    return Some(picked);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
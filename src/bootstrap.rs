@@ -9,24 +9,32 @@
 
 use crate::connect;
 use crate::connection::BootstrapGroupMaker;
-use crate::context::ctx;
+use crate::context::Ctx;
 
-pub fn start() {
-    let (proxies, event_tx): (Vec<_>, _) = ctx(|c| {
-        (
-            c.bootstrap_cache
-                .peers()
-                .iter()
-                .rev()
-                .chain(c.bootstrap_cache.hard_coded_contacts().iter())
-                .cloned()
-                .collect(),
-            c.event_tx.clone(),
-        )
-    });
+pub fn start(ctx: &Ctx) {
+    let (proxies, hosts, event_tx, clock, member_budget, grace): (Vec<_>, _, _, _, _, _) = ctx
+        .with(|c| {
+            (
+                c.bootstrap_cache
+                    .ranked()
+                    .into_iter()
+                    .map(|ranked_peer| ranked_peer.node_info)
+                    .chain(c.bootstrap_cache.hard_coded_contacts().iter().cloned())
+                    .collect(),
+                c.host_contacts.contacts().to_vec(),
+                c.event_tx.clone(),
+                c.clock.clone(),
+                c.bootstrap_member_budget,
+                c.bootstrap_grace,
+            )
+        });
 
-    let maker = BootstrapGroupMaker::new(event_tx);
+    let maker = BootstrapGroupMaker::new(event_tx, clock, member_budget, grace);
     for proxy in proxies {
-        let _ = connect::connect_to(proxy, None, Some(&maker));
+        let _ = connect::connect_to(ctx, proxy, None, Some(&maker));
+    }
+    // Dialling the addresses already among the proxies again is a no-op
+    for host in hosts {
+        connect::connect_to_host(ctx, host, Some(maker.clone()));
     }
 }
@@ -7,15 +7,88 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::context::Ctx;
 use crate::dirs::Dirs;
+use crate::event::Event;
 use crate::utils;
 use crate::{Error, NodeInfo, R};
-use std::collections::{HashSet, VecDeque};
-use std::path::PathBuf;
-use std::{fs, io};
-
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, io, mem};
+use tokio::prelude::{Future, Stream};
+
+/// Name of the cache file, unless namespaced.
+const CACHE_FILE_NAME: &str = "bootstrap_cache";
 /// Maximum peers in the cache.
 const MAX_CACHE_SIZE: usize = 200;
+/// The latest RTT sample is weighted by 1/8 in the smoothed RTT, as in TCP (RFC 6298).
+const RTT_SMOOTHING_DIVISOR: u32 = 8;
+
+/// Outcomes of our connects to a peer so far.
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectRecord {
+    attempts: u32,
+    successes: u32,
+    consecutive_failures: u32,
+    srtt: Option<Duration>,
+    last_health_check: Option<Instant>,
+}
+
+impl ConnectRecord {
+    /// Orders the more promising record first: the one with the higher success rate and then the
+    /// lower RTT. Peers never tried are assumed to succeed half the time.
+    fn rank(&self, other: &Self) -> Ordering {
+        // Compare (successes + 1) / (attempts + 2) without floating point
+        let ours = u64::from(self.successes + 1) * u64::from(other.attempts + 2);
+        let theirs = u64::from(other.successes + 1) * u64::from(self.attempts + 2);
+        theirs
+            .cmp(&ours)
+            .then_with(|| match (self.srtt, other.srtt) {
+                (Some(ours), Some(theirs)) => ours.cmp(&theirs),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+    }
+}
+
+/// Cached peer along with how connecting to it went so far, as returned by
+/// `QuicP2p::bootstrap_cache_ranked`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RankedPeer {
+    pub node_info: NodeInfo,
+    /// Connects attempted since we started
+    pub connect_attempts: u32,
+    /// Connects that succeeded since we started
+    pub connect_successes: u32,
+    /// Smoothed time taken to connect, which approximates the round trip time to the peer
+    pub srtt: Option<Duration>,
+}
+
+/// How peers imported via `QuicP2p::import_bootstrap_cache` are merged into the cache. Peers
+/// cached already with the same certificate are never duplicated.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MergeStrategy {
+    /// Add the imported peers, replacing the certificate cached for an address with the imported
+    /// one as the newer of the two
+    PreferImported,
+    /// Add the imported peers, keeping the certificate cached for an address
+    KeepExisting,
+    /// Forget the cached peers and cache the imported ones instead
+    Replace,
+}
+
+impl Default for MergeStrategy {
+    fn default() -> Self {
+        MergeStrategy::PreferImported
+    }
+}
+
+/// Woken whenever the cache changes, see `start_update_events`.
+pub type UpdateNotifier = tokio::sync::mpsc::Sender<()>;
 
 /// A very simple LRU like struct that writes itself to disk every 10 entries added.
 pub struct BootstrapCache {
@@ -23,6 +96,12 @@ pub struct BootstrapCache {
     cache_path: PathBuf,
     add_count: u8,
     hard_coded_contacts: HashSet<NodeInfo>,
+    connect_records: HashMap<SocketAddr, ConnectRecord>,
+    /// Peers added since the update was last taken
+    added: Vec<NodeInfo>,
+    /// Peers removed since the update was last taken
+    removed: Vec<NodeInfo>,
+    update_notifier: Option<UpdateNotifier>,
 }
 
 impl BootstrapCache {
@@ -37,9 +116,23 @@ impl BootstrapCache {
         hard_coded_contacts: HashSet<NodeInfo>,
         user_override: Option<&Dirs>,
     ) -> R<BootstrapCache> {
+        Self::with_namespace(hard_coded_contacts, user_override, None)
+    }
+
+    /// Like `new`, but the cache file is named after the given namespace, so several instances
+    /// can keep their caches in the same directory.
+    pub fn with_namespace(
+        hard_coded_contacts: HashSet<NodeInfo>,
+        user_override: Option<&Dirs>,
+        namespace: Option<&str>,
+    ) -> R<BootstrapCache> {
+        let file_name = match namespace {
+            Some(namespace) => format!("{}_{}", CACHE_FILE_NAME, namespace),
+            None => CACHE_FILE_NAME.to_string(),
+        };
         let path = |dir: &Dirs| {
             let path = dir.cache_dir();
-            path.join("bootstrap_cache")
+            path.join(&file_name)
         };
 
         let cache_path = user_override.map_or_else(
@@ -63,9 +156,18 @@ impl BootstrapCache {
             cache_path,
             add_count: 0u8,
             hard_coded_contacts,
+            connect_records: Default::default(),
+            added: Default::default(),
+            removed: Default::default(),
+            update_notifier: None,
         })
     }
 
+    /// File the cache is kept in.
+    pub fn path(&self) -> &Path {
+        &self.cache_path
+    }
+
     pub fn peers_mut(&mut self) -> &mut VecDeque<NodeInfo> {
         &mut self.peers
     }
@@ -78,6 +180,33 @@ impl BootstrapCache {
         &self.hard_coded_contacts
     }
 
+    /// Hard code a contact given by hostname at an address it resolved to.
+    pub fn add_hard_coded_contact(&mut self, peer: NodeInfo) {
+        let _ = self.hard_coded_contacts.insert(peer);
+    }
+
+    /// A contact given by hostname no longer resolves to the address.
+    pub fn remove_hard_coded_contact(&mut self, peer: &NodeInfo) {
+        let _ = self.hard_coded_contacts.remove(peer);
+    }
+
+    /// Wake the given notifier whenever peers are added or removed.
+    pub fn set_update_notifier(&mut self, update_notifier: UpdateNotifier) {
+        self.update_notifier = Some(update_notifier);
+    }
+
+    /// Peers added and removed since this was last called, if any. A peer added and removed again
+    /// in the meantime, or the other way round, is left out.
+    pub fn take_update(&mut self) -> Option<(Vec<NodeInfo>, Vec<NodeInfo>)> {
+        if self.added.is_empty() && self.removed.is_empty() {
+            return None;
+        }
+        Some((
+            mem::replace(&mut self.added, Vec::new()),
+            mem::replace(&mut self.removed, Vec::new()),
+        ))
+    }
+
     /// Caches given peer if it's not in hard coded contacts.
     pub fn add_peer(&mut self, peer: NodeInfo) {
         if self.hard_coded_contacts.contains(&peer) {
@@ -91,15 +220,305 @@ impl BootstrapCache {
         }
     }
 
+    /// Note a successful connect to the peer which took the given time.
+    pub fn record_connect_success(&mut self, peer_addr: SocketAddr, rtt: Duration) {
+        let record = self.connect_record_mut(peer_addr);
+        record.attempts += 1;
+        record.successes += 1;
+        record.consecutive_failures = 0;
+        record.srtt = Some(match record.srtt {
+            Some(srtt) => (srtt * (RTT_SMOOTHING_DIVISOR - 1) + rtt) / RTT_SMOOTHING_DIVISOR,
+            None => rtt,
+        });
+    }
+
+    /// Note a failed connect to the peer.
+    pub fn record_connect_failure(&mut self, peer_addr: SocketAddr) {
+        let record = self.connect_record_mut(peer_addr);
+        record.attempts += 1;
+        record.consecutive_failures += 1;
+    }
+
+    /// Up to `count` cached peers, least recently health checked first. Peers never checked come
+    /// first of all.
+    pub fn peers_to_health_check(
+        &self,
+        count: usize,
+        exclude: impl Fn(&SocketAddr) -> bool,
+    ) -> Vec<NodeInfo> {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|node_info| !exclude(&node_info.peer_addr))
+            .map(|node_info| {
+                let last_health_check = self
+                    .connect_records
+                    .get(&node_info.peer_addr)
+                    .and_then(|record| record.last_health_check);
+                (node_info, last_health_check)
+            })
+            .collect();
+        // `None` orders before any `Some`
+        peers.sort_by_key(|(_, last_health_check)| *last_health_check);
+
+        peers
+            .into_iter()
+            .take(count)
+            .map(|(node_info, _)| node_info.clone())
+            .collect()
+    }
+
+    /// Note the outcome of a health check of the peer, evicting it if it has now failed to connect
+    /// `max_consecutive_failures` times in a row. Returns whether it was evicted.
+    pub fn record_health_check(
+        &mut self,
+        peer_addr: SocketAddr,
+        rtt: Option<Duration>,
+        max_consecutive_failures: u32,
+        now: Instant,
+    ) -> bool {
+        match rtt {
+            Some(rtt) => self.record_connect_success(peer_addr, rtt),
+            None => self.record_connect_failure(peer_addr),
+        }
+
+        let record = self.connect_record_mut(peer_addr);
+        record.last_health_check = Some(now);
+        if record.consecutive_failures < max_consecutive_failures {
+            return false;
+        }
+
+        let _ = self.connect_records.remove(&peer_addr);
+        self.remove_peer(&peer_addr);
+        true
+    }
+
+    /// Cached peers, most promising first. Peers ranking equally are ordered most recently cached
+    /// first.
+    pub fn ranked(&self) -> Vec<RankedPeer> {
+        let mut ranked: Vec<_> = self
+            .peers
+            .iter()
+            .rev()
+            .map(|node_info| {
+                (
+                    node_info,
+                    self.connect_records
+                        .get(&node_info.peer_addr)
+                        .cloned()
+                        .unwrap_or_default(),
+                )
+            })
+            .collect();
+        ranked.sort_by(|(_, lhs), (_, rhs)| lhs.rank(rhs));
+
+        ranked
+            .into_iter()
+            .map(|(node_info, record)| RankedPeer {
+                node_info: node_info.clone(),
+                connect_attempts: record.attempts,
+                connect_successes: record.successes,
+                srtt: record.srtt,
+            })
+            .collect()
+    }
+
+    /// Merge the given peers, e.g. contacts harvested elsewhere, into the cache as per the strategy,
+    /// syncing the change to disk straight away. Hard coded contacts are skipped. Returns how many
+    /// peers were added or had their certificate replaced.
+    pub fn import(&mut self, peers: Vec<NodeInfo>, strategy: MergeStrategy) -> usize {
+        if strategy == MergeStrategy::Replace {
+            for peer in mem::replace(&mut self.peers, VecDeque::new()) {
+                self.note_removed(peer);
+            }
+        }
+
+        let mut imported = 0;
+        for peer in peers {
+            if self.hard_coded_contacts.contains(&peer) || self.peers.contains(&peer) {
+                continue;
+            }
+            match self
+                .peers
+                .iter_mut()
+                .find(|cached| cached.peer_addr == peer.peer_addr)
+            {
+                Some(_) if strategy == MergeStrategy::KeepExisting => continue,
+                Some(cached) => {
+                    let old = mem::replace(cached, peer.clone());
+                    self.note_removed(old);
+                    self.note_added(peer);
+                }
+                None => {
+                    self.peers.push_back(peer.clone());
+                    self.note_added(peer);
+                    if let Some(evicted) = self.pop_excess() {
+                        self.note_removed(evicted);
+                    }
+                }
+            }
+            imported += 1;
+        }
+
+        if let Err(e) = utils::write_to_disk(&self.cache_path, &self.peers) {
+            info!("Failed to write bootstrap cache to disk: {}", e);
+        }
+        imported
+    }
+
+    /// Removes every cached entry for the given address, syncing the change to disk straight away.
+    pub fn remove_peer(&mut self, peer_addr: &SocketAddr) {
+        let (removed, kept) = mem::replace(&mut self.peers, VecDeque::new())
+            .into_iter()
+            .partition::<Vec<_>, _>(|p| p.peer_addr == *peer_addr);
+        self.peers = kept.into_iter().collect();
+        if !removed.is_empty() {
+            for peer in removed {
+                self.note_removed(peer);
+            }
+            if let Err(e) = utils::write_to_disk(&self.cache_path, &self.peers) {
+                info!("Failed to write bootstrap cache to disk: {}", e);
+            }
+        }
+    }
+
+    /// The peer rotated its certificate: swap the old one for the new one wherever we hold it.
+    /// Hard coded contacts are left alone, with the peer cached under its new certificate instead.
+    pub fn replace_cert(&mut self, old: &NodeInfo, new_cert_der: Vec<u8>) {
+        let new = NodeInfo {
+            peer_addr: old.peer_addr,
+            peer_cert_der: new_cert_der,
+        };
+
+        let mut is_replaced = false;
+        for peer in self.peers.iter_mut() {
+            if peer == old {
+                *peer = new.clone();
+                is_replaced = true;
+            }
+        }
+
+        if is_replaced {
+            self.note_removed(old.clone());
+            self.note_added(new);
+            if let Err(e) = utils::write_to_disk(&self.cache_path, &self.peers) {
+                info!("Failed to write bootstrap cache to disk: {}", e);
+            }
+        } else if self.hard_coded_contacts.contains(old) {
+            self.add_peer(new);
+        }
+    }
+
+    /// The node with the given certificate showed up at `new_addr`: move it there from the other
+    /// address we hold it at, taking its connect record along. Hard coded contacts are left alone,
+    /// with the node cached at its new address instead. Returns what the node was known as, if it
+    /// has moved.
+    pub fn rebind(&mut self, cert_der: &[u8], new_addr: SocketAddr) -> Option<NodeInfo> {
+        let has_moved =
+            |peer: &NodeInfo| peer.peer_cert_der[..] == cert_der[..] && peer.peer_addr != new_addr;
+        let new = NodeInfo {
+            peer_addr: new_addr,
+            peer_cert_der: cert_der.to_vec(),
+        };
+
+        let pos = match self.peers.iter().position(|peer| has_moved(peer)) {
+            Some(pos) => pos,
+            None => {
+                if self.peers.contains(&new) {
+                    return None;
+                }
+                let old = self
+                    .hard_coded_contacts
+                    .iter()
+                    .find(|peer| has_moved(peer))
+                    .cloned()?;
+                self.add_peer(new);
+                return Some(old);
+            }
+        };
+
+        let old = mem::replace(&mut self.peers[pos], new.clone());
+        self.note_removed(old.clone());
+        // It might have been cached at its new address already
+        if self.peers.iter().filter(|peer| **peer == new).count() > 1 {
+            let _ = self.peers.remove(pos);
+        } else {
+            self.note_added(new);
+        }
+        if let Some(record) = self.connect_records.remove(&old.peer_addr) {
+            let _ = self.connect_records.insert(new_addr, record);
+        }
+        if let Err(e) = utils::write_to_disk(&self.cache_path, &self.peers) {
+            info!("Failed to write bootstrap cache to disk: {}", e);
+        }
+
+        Some(old)
+    }
+
+    fn connect_record_mut(&mut self, peer_addr: SocketAddr) -> &mut ConnectRecord {
+        // Forget the peers which are no longer of interest every now and then
+        if self.connect_records.len() >= 2 * MAX_CACHE_SIZE {
+            let peers = &self.peers;
+            let hard_coded_contacts = &self.hard_coded_contacts;
+            self.connect_records.retain(|peer_addr, _| {
+                peers
+                    .iter()
+                    .chain(hard_coded_contacts.iter())
+                    .any(|node_info| node_info.peer_addr == *peer_addr)
+            });
+        }
+
+        self.connect_records
+            .entry(peer_addr)
+            .or_insert_with(Default::default)
+    }
+
     fn insert_new(&mut self, peer: NodeInfo) {
-        self.peers.push_back(peer);
+        self.peers.push_back(peer.clone());
+        self.note_added(peer);
         self.add_count += 1;
-        if self.peers.len() > MAX_CACHE_SIZE {
-            let _ = self.peers.pop_front();
+        if let Some(evicted) = self.pop_excess() {
+            self.note_removed(evicted);
         }
         self.try_sync_to_disk();
     }
 
+    /// Evict the least recently cached peer if we are over the limit.
+    fn pop_excess(&mut self) -> Option<NodeInfo> {
+        if self.peers.len() > MAX_CACHE_SIZE {
+            self.peers.pop_front()
+        } else {
+            None
+        }
+    }
+
+    fn note_added(&mut self, peer: NodeInfo) {
+        match self.removed.iter().position(|removed| *removed == peer) {
+            Some(pos) => {
+                let _ = self.removed.remove(pos);
+            }
+            None => self.added.push(peer),
+        }
+        self.notify_update();
+    }
+
+    fn note_removed(&mut self, peer: NodeInfo) {
+        match self.added.iter().position(|added| *added == peer) {
+            Some(pos) => {
+                let _ = self.added.remove(pos);
+            }
+            None => self.removed.push(peer),
+        }
+        self.notify_update();
+    }
+
+    fn notify_update(&mut self) {
+        if let Some(update_notifier) = self.update_notifier.as_mut() {
+            // Full means a wake up is pending already
+            let _ = update_notifier.try_send(());
+        }
+    }
+
     fn move_to_cache_top(&mut self, peer: NodeInfo) {
         if let Some(pos) = self.peers.iter().position(|p| *p == peer) {
             let _ = self.peers.remove(pos);
@@ -118,6 +537,36 @@ impl BootstrapCache {
     }
 }
 
+/// Fire `Event::BootstrapCacheUpdated` for the changes to the cache, at most once per `debounce`
+/// so that e.g. a bootstrap caching many peers at once is reported in one go.
+pub fn start_update_events(ctx: &Ctx, debounce: Duration) {
+    let (update_notifier, updates) = tokio::sync::mpsc::channel(1);
+    let clock = ctx.with_mut(|c| {
+        c.bootstrap_cache.set_update_notifier(update_notifier);
+        c.clock.clone()
+    });
+
+    let ctx_clone = ctx.clone();
+    let leaf = updates
+        .map_err(|e| warn!("Error in bootstrap cache updates: {:?}", e))
+        .for_each(move |()| {
+            let ctx = ctx_clone.clone();
+            clock.delay(clock.now() + debounce).then(move |_| {
+                ctx.with_mut(|c| {
+                    if let Some((added, removed)) = c.bootstrap_cache.take_update() {
+                        let event = Event::BootstrapCacheUpdated { added, removed };
+                        if let Err(e) = c.event_tx.send(event) {
+                            info!("Could not fire event: {:?}", e);
+                        }
+                    }
+                });
+                Ok(())
+            })
+        });
+
+    ctx.spawn(leaf);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,6 +590,33 @@ mod tests {
             assert_eq!(cache.peers.len(), 10);
         }
 
+        #[test]
+        fn caches_in_different_namespaces_do_not_share_peers() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::with_namespace(
+                Default::default(),
+                Some(&dirs),
+                Some("a")
+            ));
+            for _ in 0..10 {
+                cache.add_peer(rand_node_info());
+            }
+
+            let other = unwrap!(BootstrapCache::with_namespace(
+                Default::default(),
+                Some(&dirs),
+                Some("b")
+            ));
+            assert!(other.peers.is_empty());
+
+            let same = unwrap!(BootstrapCache::with_namespace(
+                Default::default(),
+                Some(&dirs),
+                Some("a")
+            ));
+            assert_eq!(same.peers.len(), 10);
+        }
+
         #[test]
         fn when_given_peer_is_in_hard_coded_contacts_it_is_not_cached() {
             let peer1 = rand_node_info();
@@ -173,6 +649,217 @@ mod tests {
         }
     }
 
+    mod remove_peer {
+        use super::*;
+
+        #[test]
+        fn it_removes_the_peer_and_syncs_to_disk() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            cache.add_peer(peer1.clone());
+            cache.add_peer(peer2.clone());
+
+            cache.remove_peer(&peer1.peer_addr);
+
+            let peers: Vec<NodeInfo> = cache.peers.iter().cloned().collect();
+            assert_eq!(peers, vec![peer2.clone()]);
+
+            let cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peers: Vec<NodeInfo> = cache.peers.iter().cloned().collect();
+            assert_eq!(peers, vec![peer2]);
+        }
+    }
+
+    mod take_update {
+        use super::*;
+
+        #[test]
+        fn it_reports_the_net_changes_since_last_taken() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            let peer3 = rand_node_info();
+            assert_eq!(cache.take_update(), None);
+
+            cache.add_peer(peer1.clone());
+            cache.add_peer(peer2.clone());
+            // Not a change
+            cache.add_peer(peer1.clone());
+            assert_eq!(
+                cache.take_update(),
+                Some((vec![peer1.clone(), peer2.clone()], vec![]))
+            );
+            assert_eq!(cache.take_update(), None);
+
+            // Added and removed again in the meantime
+            cache.add_peer(peer3.clone());
+            cache.remove_peer(&peer3.peer_addr);
+            cache.remove_peer(&peer1.peer_addr);
+            assert_eq!(cache.take_update(), Some((vec![], vec![peer1])));
+        }
+
+        #[test]
+        fn it_reports_the_peers_evicted_to_cap_the_cache_size() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let oldest = rand_node_info();
+            cache.add_peer(oldest.clone());
+            for _ in 1..MAX_CACHE_SIZE {
+                cache.add_peer(rand_node_info());
+            }
+            let _ = cache.take_update();
+
+            let newest = rand_node_info();
+            cache.add_peer(newest.clone());
+            assert_eq!(cache.take_update(), Some((vec![newest], vec![oldest])));
+        }
+    }
+
+    mod replace_cert {
+        use super::*;
+
+        #[test]
+        fn it_replaces_the_cert_and_syncs_to_disk() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            cache.add_peer(peer1.clone());
+            cache.add_peer(peer2.clone());
+
+            let new_cert_der = rand_node_info().peer_cert_der;
+            cache.replace_cert(&peer1, new_cert_der.clone());
+
+            let rotated = NodeInfo {
+                peer_addr: peer1.peer_addr,
+                peer_cert_der: new_cert_der,
+            };
+            let cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peers: Vec<NodeInfo> = cache.peers.iter().cloned().collect();
+            assert_eq!(peers, vec![rotated, peer2]);
+        }
+
+        #[test]
+        fn hard_coded_contacts_are_cached_under_the_new_cert() {
+            let peer = rand_node_info();
+            let mut hard_coded: HashSet<_> = Default::default();
+            assert!(hard_coded.insert(peer.clone()));
+
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(hard_coded, Some(&dirs)));
+
+            let new_cert_der = rand_node_info().peer_cert_der;
+            cache.replace_cert(&peer, new_cert_der.clone());
+
+            assert!(cache.hard_coded_contacts().contains(&peer));
+            let peers: Vec<NodeInfo> = cache.peers.iter().cloned().collect();
+            assert_eq!(
+                peers,
+                vec![NodeInfo {
+                    peer_addr: peer.peer_addr,
+                    peer_cert_der: new_cert_der,
+                }]
+            );
+        }
+    }
+
+    mod ranked {
+        use super::*;
+
+        #[test]
+        fn it_puts_reliable_and_fast_peers_first() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let flaky = rand_node_info();
+            let slow = rand_node_info();
+            let fast = rand_node_info();
+            let untried = rand_node_info();
+            cache.add_peer(flaky.clone());
+            cache.add_peer(slow.clone());
+            cache.add_peer(fast.clone());
+            cache.add_peer(untried.clone());
+
+            cache.record_connect_failure(flaky.peer_addr);
+            cache.record_connect_failure(flaky.peer_addr);
+            cache.record_connect_success(slow.peer_addr, Duration::from_millis(300));
+            cache.record_connect_success(fast.peer_addr, Duration::from_millis(800));
+            cache.record_connect_success(fast.peer_addr, Duration::from_millis(10));
+
+            let ranked: Vec<_> = cache.ranked().into_iter().map(|p| p.node_info).collect();
+            assert_eq!(ranked, vec![fast, slow, untried, flaky]);
+        }
+
+        #[test]
+        fn it_smooths_rtt() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer = rand_node_info();
+            cache.add_peer(peer.clone());
+
+            cache.record_connect_success(peer.peer_addr, Duration::from_millis(800));
+            cache.record_connect_success(peer.peer_addr, Duration::from_millis(0));
+
+            let ranked = cache.ranked();
+            assert_eq!(ranked[0].srtt, Some(Duration::from_millis(700)));
+            assert_eq!(ranked[0].connect_attempts, 2);
+            assert_eq!(ranked[0].connect_successes, 2);
+        }
+    }
+
+    mod health_check {
+        use super::*;
+
+        #[test]
+        fn least_recently_checked_peers_are_checked_first() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            let peer3 = rand_node_info();
+            cache.add_peer(peer1.clone());
+            cache.add_peer(peer2.clone());
+            cache.add_peer(peer3.clone());
+
+            let now = Instant::now();
+            let rtt = Some(Duration::from_millis(10));
+            assert!(!cache.record_health_check(peer1.peer_addr, rtt, 1, now));
+            assert!(!cache.record_health_check(
+                peer2.peer_addr,
+                rtt,
+                1,
+                now + Duration::from_secs(1)
+            ));
+
+            let to_check = cache.peers_to_health_check(2, |_| false);
+            assert_eq!(to_check, vec![peer3.clone(), peer1]);
+
+            let to_check =
+                cache.peers_to_health_check(2, |peer_addr| *peer_addr == peer3.peer_addr);
+            assert_eq!(to_check.len(), 2);
+            assert!(!to_check.contains(&peer3));
+        }
+
+        #[test]
+        fn persistently_unreachable_peers_are_evicted() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer = rand_node_info();
+            cache.add_peer(peer.clone());
+            let now = Instant::now();
+
+            assert!(!cache.record_health_check(peer.peer_addr, None, 2, now));
+            // A success in between resets the count
+            cache.record_connect_success(peer.peer_addr, Duration::from_millis(10));
+            assert!(!cache.record_health_check(peer.peer_addr, None, 2, now));
+            assert!(cache.record_health_check(peer.peer_addr, None, 2, now));
+
+            assert!(cache.peers().is_empty());
+        }
+    }
+
     mod move_to_cache_top {
         use super::*;
 
@@ -193,4 +880,111 @@ mod tests {
             assert_eq!(peers, vec![peer1, peer3, peer2]);
         }
     }
+
+    mod import {
+        use super::*;
+
+        fn with_cert(peer: &NodeInfo, cert_byte: u8) -> NodeInfo {
+            NodeInfo {
+                peer_addr: peer.peer_addr,
+                peer_cert_der: vec![cert_byte],
+            }
+        }
+
+        #[test]
+        fn duplicates_are_skipped_and_conflicts_resolved_as_per_strategy() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            cache.add_peer(peer1.clone());
+
+            let imported = vec![
+                peer1.clone(),
+                with_cert(&peer1, 1),
+                peer2.clone(),
+                peer2.clone(),
+            ];
+            assert_eq!(cache.import(imported, MergeStrategy::KeepExisting), 1);
+            assert_eq!(cache.peers, vec![peer1.clone(), peer2.clone()]);
+
+            assert_eq!(
+                cache.import(vec![with_cert(&peer1, 1)], MergeStrategy::PreferImported),
+                1
+            );
+            assert_eq!(cache.peers, vec![with_cert(&peer1, 1), peer2.clone()]);
+
+            // Synced to disk straight away
+            let cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            assert_eq!(cache.peers, vec![with_cert(&peer1, 1), peer2]);
+        }
+
+        #[test]
+        fn replacing_forgets_the_cached_peers() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            cache.add_peer(peer1);
+
+            assert_eq!(cache.import(vec![peer2.clone()], MergeStrategy::Replace), 1);
+            assert_eq!(cache.peers, vec![peer2]);
+        }
+    }
+
+    mod rebind {
+        use super::*;
+
+        #[test]
+        fn moved_peers_are_cached_at_their_new_address_with_their_record() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            cache.add_peer(peer1.clone());
+            cache.add_peer(peer2.clone());
+            cache.record_connect_success(peer1.peer_addr, Duration::from_millis(10));
+
+            let new_addr: SocketAddr = unwrap!("127.0.0.2:5000".parse());
+            assert_eq!(
+                cache.rebind(&peer1.peer_cert_der, new_addr),
+                Some(peer1.clone())
+            );
+            let moved = NodeInfo {
+                peer_addr: new_addr,
+                peer_cert_der: peer1.peer_cert_der.clone(),
+            };
+            assert_eq!(cache.peers, vec![moved.clone(), peer2]);
+            assert_eq!(cache.ranked()[0].node_info, moved);
+            assert_eq!(cache.ranked()[0].connect_successes, 1);
+
+            // Nothing to do once it's there
+            assert_eq!(cache.rebind(&peer1.peer_cert_der, new_addr), None);
+            assert_eq!(cache.rebind(&[1, 2, 3], new_addr), None);
+        }
+
+        #[test]
+        fn moved_hard_coded_contacts_are_cached_at_their_new_address() {
+            let dirs = test_dirs();
+            let peer = rand_node_info();
+            let mut hard_coded_contacts = HashSet::new();
+            let _ = hard_coded_contacts.insert(peer.clone());
+            let mut cache = unwrap!(BootstrapCache::new(hard_coded_contacts, Some(&dirs)));
+
+            let new_addr: SocketAddr = unwrap!("127.0.0.2:5000".parse());
+            assert_eq!(
+                cache.rebind(&peer.peer_cert_der, new_addr),
+                Some(peer.clone())
+            );
+            assert_eq!(cache.hard_coded_contacts().len(), 1);
+            assert_eq!(
+                cache.peers,
+                vec![NodeInfo {
+                    peer_addr: new_addr,
+                    peer_cert_der: peer.peer_cert_der.clone(),
+                }]
+            );
+            assert_eq!(cache.rebind(&peer.peer_cert_der, new_addr), None);
+        }
+    }
 }
@@ -0,0 +1,114 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Background reachability checks of the peers in our bootstrap cache, so that the cache stays
+//! useful for bootstrapping after a restart.
+
+use crate::clock;
+use crate::config::CacheHealthCheckConfig;
+use crate::connection::QConn;
+use crate::context::Ctx;
+use crate::peer_config;
+use crate::wire_msg::CloseReason;
+use crate::{NodeInfo, R};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::{Future, Stream};
+
+/// Check a few of the cached peers every `interval_sec` for as long as the event loop runs.
+pub fn start(ctx: &Ctx, cfg: CacheHealthCheckConfig) {
+    let interval = Duration::from_secs(cfg.interval_sec);
+    let ctx_clone = ctx.clone();
+    let leaf = clock::interval(&ctx.with(|c| c.clock.clone()), interval).for_each(move |_| {
+        check(&ctx_clone, cfg);
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+fn check(ctx: &Ctx, cfg: CacheHealthCheckConfig) {
+    // Peers we are connected to are evidently reachable and get ranked as we connect to them
+    let peers = ctx.with(|c| {
+        c.bootstrap_cache
+            .peers_to_health_check(cfg.peers_per_check as usize, |peer_addr| {
+                c.connections.contains_key(peer_addr)
+            })
+    });
+
+    for node_info in peers {
+        ping(ctx, node_info, cfg.max_consecutive_failures);
+    }
+}
+
+/// Complete a QUIC handshake with the peer to see whether it's reachable, closing the connection
+/// straight after.
+fn ping(ctx: &Ctx, node_info: NodeInfo, max_consecutive_failures: u32) {
+    let peer_addr = node_info.peer_addr;
+    let clock = ctx.with(|c| c.clock.clone());
+    let started_at = clock.now();
+
+    let connecting =
+        peer_config::new_client_cfg(ctx, &node_info.peer_cert_der).and_then(|peer_cfg| {
+            ctx.with(|c| -> R<_> {
+                let wire_addr = c.transport.wire_addr(peer_addr)?;
+                Ok(c.quic_ep()
+                    .connect_with(peer_cfg, &wire_addr, &c.server_name)?)
+            })
+        });
+    let connecting = match connecting {
+        Ok(connecting) => connecting,
+        Err(e) => {
+            debug!("Could not health check peer {}: {}", peer_addr, e);
+            return on_checked(ctx, peer_addr, None, max_consecutive_failures);
+        }
+    };
+
+    let ctx_clone = ctx.clone();
+    let leaf = connecting.then(move |res| {
+        let ctx = &ctx_clone;
+        let rtt = match res {
+            Ok((conn_driver, q_conn, _incoming_streams)) => {
+                ctx.spawn_driver(conn_driver, |_, _| ());
+                QConn::from(q_conn).set_close_reason(CloseReason::Shutdown);
+                Some(clock.now().duration_since(started_at))
+            }
+            Err(e) => {
+                debug!("Health check of peer {} failed: {}", peer_addr, e);
+                None
+            }
+        };
+        on_checked(ctx, peer_addr, rtt, max_consecutive_failures);
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+fn on_checked(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    rtt: Option<Duration>,
+    max_consecutive_failures: u32,
+) {
+    let is_evicted = ctx.with_mut(|c| {
+        c.bootstrap_cache.record_health_check(
+            peer_addr,
+            rtt,
+            max_consecutive_failures,
+            c.clock.now(),
+        )
+    });
+    if is_evicted {
+        info!(
+            "Evicted persistently unreachable peer {} from bootstrap cache",
+            peer_addr
+        );
+    }
+}
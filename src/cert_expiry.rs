@@ -0,0 +1,195 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Expiry of certificates. Ours is watched so the user hears about it in time, see
+//! `Config::cert_expiry_warning_sec`, and optionally rotated. Peers fail TLS with an expired one,
+//! which is otherwise indistinguishable from any other failed connect, so we tell those apart by
+//! reading when the certificate of the peer expires ourselves.
+
+use crate::cert_rotation;
+use crate::context::Ctx;
+use crate::event::Event;
+use crate::utils;
+use chrono::NaiveDateTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::prelude::Future;
+
+/// Longest we go without checking our certificate, so that it's picked up when the user rotates it
+/// to one expiring sooner.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+
+/// Time the DER encoded X.509 certificate expires at, `None` if it can't be read.
+pub fn not_after(cert_der: &[u8]) -> Option<SystemTime> {
+    let (cert, _) = read_tlv(cert_der, TAG_SEQUENCE)?;
+    let (tbs, _) = read_tlv(cert, TAG_SEQUENCE)?;
+    let tbs = match read_any_tlv(tbs)? {
+        (TAG_VERSION, _, rest) => rest,
+        _ => tbs,
+    };
+    // Serial number, signature algorithm and issuer come before the validity
+    let (_, _, rest) = read_any_tlv(tbs)?;
+    let (_, _, rest) = read_any_tlv(rest)?;
+    let (_, _, rest) = read_any_tlv(rest)?;
+    let (validity, _) = read_tlv(rest, TAG_SEQUENCE)?;
+    let (_, _, rest) = read_any_tlv(validity)?;
+    let (tag, not_after, _) = read_any_tlv(rest)?;
+
+    read_time(tag, not_after)
+}
+
+/// Whether the certificate has expired by `now`. Certificates we can't read are taken not to.
+pub fn is_expired(cert_der: &[u8], now: SystemTime) -> bool {
+    not_after(cert_der).map_or(false, |not_after| not_after <= now)
+}
+
+/// Watch our certificate for as long as the event loop runs.
+pub fn start(ctx: &Ctx, warning: Duration, auto_rotate: bool) {
+    check(ctx, warning, auto_rotate, None);
+}
+
+/// Warn about our certificate if it's about to expire and we haven't already, `handled` being the
+/// last one we did, and check again when the next one is due.
+fn check(ctx: &Ctx, warning: Duration, auto_rotate: bool, mut handled: Option<Vec<u8>>) {
+    let (our_cert_der, now) =
+        ctx.with(|c| (c.our_complete_cert.cert_der.clone(), c.clock.system_time()));
+    let expires_in = match not_after(&our_cert_der) {
+        Some(not_after) => not_after.duration_since(now).unwrap_or_default(),
+        None => return warn!("Can't tell when our certificate expires"),
+    };
+
+    if expires_in <= warning && handled.as_ref() != Some(&our_cert_der) {
+        warn!(
+            "Our certificate {} expires in {:?}",
+            utils::cert_fingerprint(&our_cert_der),
+            expires_in
+        );
+        ctx.with(|c| {
+            if let Err(e) = c.event_tx.send(Event::CertificateExpiring { expires_in }) {
+                info!("Could not fire event: {:?}", e);
+            }
+        });
+
+        handled = Some(our_cert_der);
+        if auto_rotate {
+            match cert_rotation::rotate(ctx) {
+                // Should the new one be no better, it's up to the user to make it so
+                Ok(new_cert) => handled = Some(new_cert.cert_der),
+                Err(e) => warn!("Could not rotate our expiring certificate: {}", e),
+            }
+        }
+        return check(ctx, warning, auto_rotate, handled);
+    }
+
+    let wait = expires_in
+        .checked_sub(warning)
+        .unwrap_or(MAX_CHECK_INTERVAL)
+        .min(MAX_CHECK_INTERVAL);
+    let clock = ctx.with(|c| c.clock.clone());
+    let ctx_clone = ctx.clone();
+    let leaf = clock
+        .delay(clock.now() + wait)
+        .map(move |()| check(&ctx_clone, warning, auto_rotate, handled));
+
+    ctx.spawn(leaf);
+}
+
+/// Contents of the DER element with the given tag at the start of `der`, along with what follows.
+fn read_tlv(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match read_any_tlv(der)? {
+        (t, content, rest) if t == tag => Some((content, rest)),
+        _ => None,
+    }
+}
+
+/// Tag and contents of the DER element at the start of `der`, along with what follows.
+fn read_any_tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *der.get(0)?;
+    let first_len_byte = *der.get(1)?;
+    let (len, header_len) = if first_len_byte < 0x80 {
+        (usize::from(first_len_byte), 2)
+    } else {
+        let len_bytes = usize::from(first_len_byte & 0x7f);
+        if len_bytes == 0 || len_bytes > 4 {
+            return None;
+        }
+        let len = der
+            .get(2..2 + len_bytes)?
+            .iter()
+            .fold(0, |len, byte| (len << 8) | usize::from(*byte));
+        (len, 2 + len_bytes)
+    };
+    let end = header_len.checked_add(len)?;
+    let content = der.get(header_len..end)?;
+
+    Some((tag, content, &der[end..]))
+}
+
+fn read_time(tag: u8, time: &[u8]) -> Option<SystemTime> {
+    let time = String::from_utf8(time.to_vec()).ok()?;
+    let time = match tag {
+        // Two digit years stand for 1950 to 2049
+        TAG_UTC_TIME => {
+            let year: u32 = time.get(..2)?.parse().ok()?;
+            let century = if year < 50 { "20" } else { "19" };
+            format!("{}{}", century, time)
+        }
+        TAG_GENERALIZED_TIME => time,
+        _ => return None,
+    };
+    let secs = NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ")
+        .ok()?
+        .timestamp();
+    if secs < 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CertParams, SerialisableCertificate};
+
+    #[test]
+    fn expiry_is_read_off_the_certificate() {
+        let now = SystemTime::now();
+        let cert = SerialisableCertificate::generate(&CertParams {
+            validity_days: Some(2),
+            ..Default::default()
+        });
+        let expiry = unwrap!(not_after(&cert.cert_der));
+        let validity = unwrap!(expiry.duration_since(now));
+        let two_days = Duration::from_secs(2 * 24 * 3600);
+        assert!(validity > two_days - Duration::from_secs(60));
+        assert!(validity < two_days + Duration::from_secs(60));
+
+        assert!(!is_expired(&cert.cert_der, now));
+        assert!(is_expired(&cert.cert_der, now + 2 * two_days));
+
+        // The default certificates never practically expire
+        let cert = SerialisableCertificate::default();
+        assert!(!is_expired(
+            &cert.cert_der,
+            now + Duration::from_secs(100 * 365 * 24 * 3600)
+        ));
+    }
+
+    #[test]
+    fn garbage_has_no_expiry() {
+        assert!(not_after(&[]).is_none());
+        assert!(not_after(&[0x30, 0x82, 0xff]).is_none());
+        assert!(!is_expired(&[1, 2, 3], SystemTime::now()));
+    }
+}
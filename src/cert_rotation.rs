@@ -0,0 +1,196 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Rotation of our certificate without losing our identity. The new certificate is announced to
+//! the connected peers signed with the key of the old one, so they can tell it's still us and
+//! update the contact they hold for us. Existing connections are kept as they are.
+
+use crate::bootstrap_cache::BootstrapCache;
+use crate::communicate;
+use crate::config::{OurType, SerialisableCertificate};
+use crate::connection::ToPeer;
+use crate::context::Ctx;
+use crate::error::Error;
+use crate::event::{Event, EventTx};
+use crate::handshake_auth;
+use crate::reputation::{self, Violation};
+use crate::utils;
+use crate::wire_msg::WireMsg;
+use crate::{NodeInfo, Peer, R};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ClientHello, ResolvesServerCert};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::prelude::future;
+
+/// Certificate our endpoint presents to the peers connecting to us. It can be replaced at any
+/// time, with only the connections made afterwards seeing the new one. Peers asking for a server
+/// name other than ours (see `Config::server_name`) are presented none, which fails their TLS
+/// handshake.
+#[derive(Clone)]
+pub struct ServerCert {
+    current: Arc<Mutex<CertifiedKey>>,
+    server_name: String,
+}
+
+impl ServerCert {
+    pub fn new(cert: &SerialisableCertificate, server_name: String) -> R<Self> {
+        Ok(ServerCert {
+            current: Arc::new(Mutex::new(certified_key(cert)?)),
+            server_name,
+        })
+    }
+
+    /// Present the given certificate from now on.
+    pub fn replace(&self, cert: &SerialisableCertificate) -> R<()> {
+        let certified_key = certified_key(cert)?;
+        match self.current.lock() {
+            Ok(mut current) => *current = certified_key,
+            Err(poisoned) => *poisoned.into_inner() = certified_key,
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ServerCert {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let requested: Option<&str> = client_hello.server_name().map(Into::into);
+        // DNS names are case insensitive
+        if !requested.map_or(false, |name| name.eq_ignore_ascii_case(&self.server_name)) {
+            debug!(
+                "Refusing TLS handshake for server name {:?} instead of {}",
+                requested, self.server_name
+            );
+            return None;
+        }
+
+        self.current.lock().ok().map(|current| current.clone())
+    }
+}
+
+fn certified_key(cert: &SerialisableCertificate) -> R<CertifiedKey> {
+    let key = sign::any_supported_type(&rustls::PrivateKey(cert.key_der.clone()))
+        .map_err(|()| Error::CertRotation("unsupported private key"))?;
+    Ok(CertifiedKey::new(
+        vec![rustls::Certificate(cert.cert_der.clone())],
+        Arc::new(key),
+    ))
+}
+
+/// Replace our certificate with a freshly generated one and announce it to the connected peers.
+/// This must not be called while the `Context` is already borrowed.
+pub fn rotate(ctx: &Ctx) -> R<SerialisableCertificate> {
+    let new_cert = ctx.with(|c| match c.rng {
+        Some(ref rng) => rng.gen_cert(&c.server_name, &c.cert_params),
+        None => SerialisableCertificate::generate_for(&c.server_name, &c.cert_params),
+    });
+
+    let (signature, peers) = ctx.with_mut(|c| -> R<(Vec<u8>, Vec<SocketAddr>)> {
+        let signature =
+            handshake_auth::sign_cert_rotation(&c.our_complete_cert, &new_cert.cert_der)?;
+        c.server_cert.replace(&new_cert)?;
+        c.our_complete_cert = new_cert.clone();
+        if let Some(ref mut us) = c.our_connection_info {
+            us.peer_cert_der = new_cert.cert_der.clone();
+        }
+
+        // Only nodes are connected to by their certificate, so there's no one to tell otherwise
+        let peers = if c.our_type == OurType::Node {
+            c.connections
+                .iter()
+                .filter(|(_, conn)| conn.is_connected())
+                .map(|(peer_addr, _)| *peer_addr)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok((signature, peers))
+    })?;
+
+    for peer_addr in peers {
+        communicate::write_to_peer(
+            ctx,
+            peer_addr,
+            WireMsg::CertRotation {
+                new_cert: new_cert.cert_der.clone(),
+                signature_by_old_key: signature.clone(),
+            },
+        );
+    }
+
+    Ok(new_cert)
+}
+
+/// The peer announced it rotated its certificate. We are called with the `Context` already
+/// borrowed.
+pub fn handle_announcement(
+    ctx: &Ctx,
+    peer: Peer,
+    new_cert_der: Vec<u8>,
+    signature: &[u8],
+    event_tx: &EventTx,
+    bootstrap_cache: &mut BootstrapCache,
+) {
+    let old = match peer {
+        Peer::Node { node_info } => node_info,
+        Peer::Client { peer_addr, .. } => {
+            return debug!(
+                "Ignoring certificate rotation announced by client {}",
+                peer_addr
+            );
+        }
+    };
+    let peer_addr = old.peer_addr;
+
+    if let Err(e) =
+        handshake_auth::verify_cert_rotation(&old.peer_cert_der, &new_cert_der, signature)
+    {
+        debug!("Node {} announced an invalid certificate: {}", old, e);
+        let ctx_clone = ctx.clone();
+        ctx.spawn(future::lazy(move || {
+            reputation::penalise(&ctx_clone, peer_addr, Violation::ProtocolViolation);
+            Ok(())
+        }));
+        return;
+    }
+
+    info!(
+        "Node {} rotated its certificate to {}",
+        old,
+        utils::cert_fingerprint(&new_cert_der)
+    );
+    bootstrap_cache.replace_cert(&old, new_cert_der.clone());
+
+    // Reconnects must expect the new certificate
+    let peer_cert_der = new_cert_der.clone();
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        ctx_clone.with_mut(|c| {
+            if let Some(conn) = c.connections.get_mut(&peer_addr) {
+                if let ToPeer::Established {
+                    peer_cert_der: ref mut current,
+                    ..
+                } = conn.to_peer
+                {
+                    *current = peer_cert_der;
+                }
+            }
+        });
+        Ok(())
+    }));
+
+    let new = NodeInfo {
+        peer_addr,
+        peer_cert_der: new_cert_der,
+    };
+    if let Err(e) = event_tx.send(Event::PeerCertificateRotated { old, new }) {
+        info!("Could not fire event: {:?}", e);
+    }
+}
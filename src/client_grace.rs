@@ -0,0 +1,214 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! User messages to clients which aren't connected just now. We can't connect to clients, so
+//! these are held for a grace period in case the client comes back, recognised by its
+//! certificate, and given up on with `Event::UnsentUserMessage` otherwise.
+
+use crate::communicate;
+use crate::connection::{FromPeer, QConn};
+use crate::context::{Context, Ctx};
+use crate::event::{Event, UnsentReason};
+use crate::wire_msg::WireMsg;
+use crate::{ClientInfo, Peer};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::prelude::Future;
+
+struct Held {
+    peer: Peer,
+    msgs: Vec<WireMsg>,
+    expires_at: Instant,
+}
+
+/// Messages held for the clients, keyed by the certificates of the clients.
+#[derive(Default)]
+pub struct HeldClientSends {
+    clients: HashMap<Vec<u8>, Held>,
+}
+
+impl HeldClientSends {
+    /// Hold the message for the client until `expires_at`. If messages are already held for it
+    /// they all expire together with the first one. Returns whether this is the first message held
+    /// for the client.
+    pub fn hold(&mut self, peer: Peer, msg: WireMsg, expires_at: Instant) -> bool {
+        let cert_der = peer.peer_cert_der().unwrap_or_default().to_vec();
+        let mut is_first = false;
+        self.clients
+            .entry(cert_der)
+            .or_insert_with(|| {
+                is_first = true;
+                Held {
+                    peer,
+                    msgs: Vec::new(),
+                    expires_at,
+                }
+            })
+            .msgs
+            .push(msg);
+        is_first
+    }
+
+    /// Take out the messages held for the client, oldest first.
+    pub fn take(&mut self, cert_der: &[u8]) -> Vec<WireMsg> {
+        self.clients
+            .remove(cert_der)
+            .map_or_else(Vec::new, |held| held.msgs)
+    }
+
+    /// Take out the messages held for the client if they have expired by `now`, along with the
+    /// peer they were meant for.
+    pub fn expire(&mut self, cert_der: &[u8], now: Instant) -> Option<(Peer, Vec<WireMsg>)> {
+        if self.clients.get(cert_der)?.expires_at > now {
+            return None;
+        }
+        self.clients
+            .remove(cert_der)
+            .map(|held| (held.peer, held.msgs))
+    }
+}
+
+/// Send the message to the client over its current connection, which might be from an address
+/// other than the one it was addressed to, or hold it for the grace period if the client isn't
+/// connected. This must not be called while the `Context` is already borrowed.
+pub fn send_or_hold(ctx: &Ctx, peer_addr: SocketAddr, client_info: ClientInfo, msg: WireMsg) {
+    let grace_delay = ctx.with_mut(|c| {
+        if let Some((addr, q_conn)) = connection_of(c, &client_info.peer_cert_der) {
+            communicate::write_to_peer_connection(ctx, addr, q_conn, msg);
+            return None;
+        }
+
+        let peer = Peer::Client {
+            peer_addr,
+            client_info: client_info.clone(),
+        };
+        let grace = match c.client_send_grace {
+            Some(grace) => grace,
+            None => {
+                fire_unsent(c, peer, vec![msg]);
+                return None;
+            }
+        };
+
+        let expires_at = c.clock.now() + grace;
+        if c.held_client_sends.hold(peer, msg, expires_at) {
+            Some(c.clock.delay(expires_at))
+        } else {
+            None
+        }
+    });
+
+    if let Some(grace_delay) = grace_delay {
+        let cert_der = client_info.peer_cert_der;
+        let ctx_clone = ctx.clone();
+        let leaf = grace_delay.then(move |r| {
+            if let Err(e) = r {
+                info!("Error in client send grace delay: {:?}", e);
+            }
+            ctx_clone.with_mut(|c| {
+                if let Some((peer, msgs)) = c.held_client_sends.expire(&cert_der, c.clock.now()) {
+                    fire_unsent(c, peer, msgs);
+                }
+            });
+            Ok(())
+        });
+        ctx.spawn(leaf);
+    }
+}
+
+/// Deliver the messages held for the client which has just (re)connected to us from `peer_addr`.
+pub fn deliver_held(ctx: &Ctx, c: &mut Context, peer_addr: SocketAddr, cert_der: &[u8]) {
+    let msgs = c.held_client_sends.take(cert_der);
+    if msgs.is_empty() {
+        return;
+    }
+
+    match c.connections.get(&peer_addr).map(|conn| &conn.from_peer) {
+        Some(FromPeer::Established { ref q_conn, .. }) => {
+            debug!(
+                "Delivering {} held messages to the client {}",
+                msgs.len(),
+                peer_addr
+            );
+            for msg in msgs {
+                communicate::write_to_peer_connection(ctx, peer_addr, q_conn, msg);
+            }
+        }
+        _ => warn!(
+            "Dropping messages held for the client {} as it's not connected anymore",
+            peer_addr
+        ),
+    }
+}
+
+fn connection_of<'a>(c: &'a Context, cert_der: &[u8]) -> Option<(SocketAddr, &'a QConn)> {
+    c.connections.iter().find_map(|(peer_addr, conn)| {
+        let is_the_client = conn.client_info.as_ref().map_or(false, |client_info| {
+            client_info.peer_cert_der[..] == cert_der[..]
+        });
+        match conn.from_peer {
+            FromPeer::Established { ref q_conn, .. } if is_the_client => Some((*peer_addr, q_conn)),
+            _ => None,
+        }
+    })
+}
+
+fn fire_unsent(c: &Context, peer: Peer, msgs: Vec<WireMsg>) {
+    for msg in msgs.into_iter().filter_map(WireMsg::into_user_msg) {
+        let event = Event::UnsentUserMessage {
+            peer: peer.clone(),
+            msg,
+            reason: UnsentReason::ClientDisconnected,
+        };
+        if let Err(e) = c.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn client(cert_der: Vec<u8>) -> Peer {
+        Peer::Client {
+            peer_addr: unwrap!("127.0.0.1:1000".parse()),
+            client_info: ClientInfo {
+                peer_cert_der: cert_der,
+            },
+        }
+    }
+
+    fn user_msg(byte: u8) -> WireMsg {
+        WireMsg::UserMsg(bytes::Bytes::from(vec![byte]))
+    }
+
+    #[test]
+    fn held_messages_expire_together_unless_taken() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(10);
+        let mut held: HeldClientSends = Default::default();
+
+        assert!(held.hold(client(vec![1]), user_msg(1), later));
+        assert!(!held.hold(client(vec![1]), user_msg(2), later + Duration::from_secs(5)));
+        assert!(held.hold(client(vec![2]), user_msg(3), later));
+
+        assert!(held.expire(&[1], now).is_none());
+        let (peer, msgs) = unwrap!(held.expire(&[1], later));
+        assert_eq!(peer, client(vec![1]));
+        assert_eq!(msgs.len(), 2);
+        assert!(held.expire(&[1], later).is_none());
+
+        assert_eq!(held.take(&[2]).len(), 1);
+        assert!(held.take(&[2]).is_empty());
+        assert!(held.expire(&[2], later).is_none());
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Source of the time for our timeouts, backoffs and other timers. It's the system clock unless
+//! tests swap in a `ManualClock` via `Builder::with_clock` to move time on instantly.
+//!
+//! The idle timeout and keep-alives of the QUIC transport are run by quinn itself and so always
+//! follow the system clock.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::prelude::future::Either;
+use tokio::prelude::{stream, Future, Stream};
+use tokio::timer::Delay;
+
+/// Tells the time and fires timers.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Future resolving once the time is `deadline` or later. It's polled on the event loop only.
+    fn delay(&self, deadline: Instant) -> Box<dyn Future<Item = (), Error = ()>>;
+
+    /// Wall-clock time, for the timestamps we exchange with peers. Unlike `now` it isn't moved on
+    /// by a `ManualClock`.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock shared by the builder and the event loop.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The system clock, timers included.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(Delay::new(deadline).map_err(|e| warn!("Error in timer: {:?}", e)))
+    }
+}
+
+/// Wall-clock time in milliseconds since the UNIX epoch, as exchanged with peers.
+pub fn unix_time_msec(clock: &SharedClock) -> u64 {
+    match clock.system_time().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis()),
+        Err(_) => 0,
+    }
+}
+
+/// Stream ticking every `period` starting a `period` from now, for as long as it's polled.
+pub fn interval(clock: &SharedClock, period: Duration) -> impl Stream<Item = (), Error = ()> {
+    let clock = clock.clone();
+    stream::unfold(clock.now() + period, move |next| {
+        Some(clock.delay(next).map(move |()| ((), next + period)))
+    })
+}
+
+/// Run `f` for no longer than `timeout` from now. Fails with `None` once the time is up, which
+/// drops `f`, or with the error of `f` otherwise.
+pub fn timeout<F>(
+    clock: &SharedClock,
+    f: F,
+    timeout: Duration,
+) -> impl Future<Item = F::Item, Error = Option<F::Error>>
+where
+    F: Future,
+{
+    timeout_at(clock, f, clock.now() + timeout)
+}
+
+/// Run `f` until `deadline` at the latest, failing like `timeout` otherwise.
+pub fn timeout_at<F>(
+    clock: &SharedClock,
+    f: F,
+    deadline: Instant,
+) -> impl Future<Item = F::Item, Error = Option<F::Error>>
+where
+    F: Future,
+{
+    f.select2(clock.delay(deadline)).then(|r| match r {
+        Ok(Either::A((item, _))) => Ok(item),
+        Err(Either::A((e, _))) => Err(Some(e)),
+        Ok(Either::B(_)) | Err(Either::B(_)) => Err(None),
+    })
+}
+
+#[cfg(feature = "testing")]
+pub use self::manual::ManualClock;
+
+#[cfg(feature = "testing")]
+mod manual {
+    use super::Clock;
+    use std::mem;
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::time::{Duration, Instant};
+    use tokio::prelude::{future, Future};
+    use tokio::sync::oneshot;
+
+    /// Clock standing still until moved on by `advance`, firing the timers that fall due as it
+    /// goes. Clones share the same time. Only available with the `testing` feature.
+    #[derive(Clone)]
+    pub struct ManualClock(Arc<Mutex<ManualTime>>);
+
+    struct ManualTime {
+        now: Instant,
+        timers: Vec<(Instant, oneshot::Sender<()>)>,
+    }
+
+    impl ManualClock {
+        /// Clock starting at the current system time.
+        pub fn new() -> Self {
+            ManualClock(Arc::new(Mutex::new(ManualTime {
+                now: Instant::now(),
+                timers: Vec::new(),
+            })))
+        }
+
+        /// Move the time on by `by`, firing every timer due by then.
+        pub fn advance(&self, by: Duration) {
+            let due = {
+                let mut time = self.time();
+                time.now += by;
+                let now = time.now;
+                let (due, pending) = mem::replace(&mut time.timers, Vec::new())
+                    .into_iter()
+                    .partition(|(deadline, _)| *deadline <= now);
+                time.timers = pending;
+                due
+            };
+
+            for (_, tx) in due {
+                let _ = tx.send(());
+            }
+        }
+
+        fn time(&self) -> MutexGuard<ManualTime> {
+            match self.0.lock() {
+                Ok(time) => time,
+                Err(poisoned) => poisoned.into_inner(),
+            }
+        }
+    }
+
+    impl Default for ManualClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.time().now
+        }
+
+        fn delay(&self, deadline: Instant) -> Box<dyn Future<Item = (), Error = ()>> {
+            let mut time = self.time();
+            if deadline <= time.now {
+                return Box::new(future::ok(()));
+            }
+            let (tx, rx) = oneshot::channel();
+            time.timers.push((deadline, tx));
+            Box::new(rx.map_err(|e| info!("Manual clock gone: {:?}", e)))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use tokio::prelude::{future, Async, Poll};
+    use tokio::runtime::current_thread;
+
+    fn poll_once<F: Future>(f: &mut F) -> Poll<F::Item, F::Error> {
+        unwrap!(current_thread::block_on_all(future::lazy(|| {
+            Ok::<_, ()>(f.poll())
+        })))
+    }
+
+    #[test]
+    fn manual_clock_fires_timers_as_it_is_advanced() {
+        let manual = ManualClock::new();
+        let clock: SharedClock = Arc::new(manual.clone());
+        let started_at = clock.now();
+
+        let mut short = clock.delay(started_at + Duration::from_secs(60));
+        let mut long = clock.delay(started_at + Duration::from_secs(3600));
+        let mut timed_out = timeout(&clock, future::empty::<(), ()>(), Duration::from_secs(60));
+
+        assert_eq!(poll_once(&mut short), Ok(Async::NotReady));
+        assert_eq!(poll_once(&mut long), Ok(Async::NotReady));
+        assert_eq!(poll_once(&mut timed_out), Ok(Async::NotReady));
+
+        manual.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), started_at + Duration::from_secs(60));
+
+        assert_eq!(poll_once(&mut short), Ok(Async::Ready(())));
+        assert_eq!(poll_once(&mut long), Ok(Async::NotReady));
+        assert_eq!(poll_once(&mut timed_out), Err(None));
+    }
+}
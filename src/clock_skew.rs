@@ -0,0 +1,58 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Estimates of how far the clock of each peer is ahead of ours, e.g. to line up logs across
+//! peers. A first estimate is taken from the timestamp in the peer's handshake and refined with
+//! every health check round trip, see `QuicP2p::peer_clock_skew`.
+
+use crate::clock;
+use crate::context::Ctx;
+use std::net::SocketAddr;
+
+/// Skew from the time the peer sent a message at and the time we received it at, both in
+/// milliseconds since the UNIX epoch. It's off by the time the message took to get here.
+pub fn from_one_way(sent_at_msec: u64, rxd_at_msec: u64) -> i64 {
+    sent_at_msec as i64 - rxd_at_msec as i64
+}
+
+/// Skew from a health check round trip: the time we sent the request at, the time the peer sent
+/// the response at and the time we received the response at. Assuming the way there took as long
+/// as the way back, the peer responded half way through the round trip.
+pub fn from_round_trip(req_sent_at_msec: u64, resp_sent_at_msec: u64, rxd_at_msec: u64) -> i64 {
+    let half_way = (req_sent_at_msec as i64 + rxd_at_msec as i64) / 2;
+    resp_sent_at_msec as i64 - half_way
+}
+
+/// Record the first estimate for the peer from the time it sent its handshake at. This must not
+/// be called while the `Context` is already borrowed.
+pub fn record_handshake(ctx: &Ctx, peer_addr: SocketAddr, sent_at_msec: u64) {
+    ctx.with_mut(|c| {
+        let rxd_at_msec = clock::unix_time_msec(&c.clock);
+        if let Some(conn) = c.connections.get_mut(&peer_addr) {
+            conn.clock_skew_msec = Some(from_one_way(sent_at_msec, rxd_at_msec));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_cancel_out_the_latency() {
+        // Peer 500 ms ahead, 40 ms each way
+        let req_sent_at = 10_000;
+        let resp_sent_at = req_sent_at + 40 + 500;
+        let rxd_at = req_sent_at + 80;
+
+        assert_eq!(from_round_trip(req_sent_at, resp_sent_at, rxd_at), 500);
+        assert_eq!(from_one_way(resp_sent_at, rxd_at), 460);
+        assert_eq!(from_one_way(rxd_at, resp_sent_at), -460);
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Typed user messages. The application registers a `Codec` for its message type and then sends
+//! and receives values of that type instead of serialising them to `Bytes` itself. Only compiled
+//! in with the `codec` feature.
+
+use crate::error::Error;
+use crate::R;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// Turns the application messages into bytes and back. Registered via `Builder::with_codec`.
+pub trait Codec: Send + Sync + 'static {
+    /// Type of the messages, typically an enum of everything the application sends
+    type Msg: Send + 'static;
+
+    fn encode(&self, msg: &Self::Msg) -> R<bytes::Bytes>;
+
+    fn decode(&self, bytes: &[u8]) -> R<Self::Msg>;
+}
+
+/// Codec serialising any serde type with bincode.
+pub struct BincodeCodec<T>(PhantomData<fn() -> T>);
+
+impl<T> BincodeCodec<T> {
+    pub fn new() -> Self {
+        BincodeCodec(PhantomData)
+    }
+}
+
+impl<T> Default for BincodeCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Send + 'static> Codec for BincodeCodec<T> {
+    type Msg = T;
+
+    fn encode(&self, msg: &T) -> R<bytes::Bytes> {
+        Ok(From::from(bincode::serialize(msg)?))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> R<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// `Codec` with its message type erased, so that it can be held without making `QuicP2p` and the
+/// events generic.
+pub trait ErasedCodec: Send + Sync {
+    fn encode_any(&self, msg: &dyn Any) -> R<bytes::Bytes>;
+
+    fn decode_any(&self, bytes: &[u8]) -> R<Box<dyn Any + Send>>;
+}
+
+impl<C: Codec> ErasedCodec for C {
+    fn encode_any(&self, msg: &dyn Any) -> R<bytes::Bytes> {
+        let msg = msg
+            .downcast_ref::<C::Msg>()
+            .ok_or(Error::CodecTypeMismatch)?;
+        self.encode(msg)
+    }
+
+    fn decode_any(&self, bytes: &[u8]) -> R<Box<dyn Any + Send>> {
+        Ok(Box::new(self.decode(bytes)?))
+    }
+}
+
+/// Codec shared by the user facing handles and the event loop.
+pub type SharedCodec = Arc<dyn ErasedCodec>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Msg {
+        Ping(u32),
+        Text(String),
+    }
+
+    #[test]
+    fn messages_survive_the_round_trip_through_the_erased_codec() {
+        let codec: SharedCodec = Arc::new(BincodeCodec::<Msg>::new());
+
+        for msg in vec![Msg::Ping(7), Msg::Text("hello".to_string())] {
+            let encoded = unwrap!(codec.encode_any(&msg));
+            let decoded = unwrap!(unwrap!(codec.decode_any(&encoded)).downcast::<Msg>());
+            assert_eq!(*decoded, msg);
+        }
+    }
+
+    #[test]
+    fn messages_of_other_types_are_refused() {
+        let codec: SharedCodec = Arc::new(BincodeCodec::<Msg>::new());
+
+        match codec.encode_any(&7u32) {
+            Err(Error::CodecTypeMismatch) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+}
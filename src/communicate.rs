@@ -7,38 +7,99 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::backpressure;
 use crate::bootstrap_cache::BootstrapCache;
-use crate::connection::{Connection, FromPeer, QConn, ToPeer};
-use crate::context::{ctx, ctx_mut};
+use crate::cert_rotation;
+use crate::client_grace;
+use crate::clock;
+use crate::clock_skew;
+use crate::connection::{Connection, FromPeer, PendingSend, QConn, ToPeer};
+use crate::context::{Context, Ctx};
+use crate::dedup;
 use crate::error::Error;
-use crate::event::Event;
+use crate::event::{Event, EventTx, UnsentReason};
+#[cfg(feature = "testing")]
+use crate::fault_injection;
+use crate::handshake_auth::{self, Nonce};
+use crate::lanes;
+use crate::liveness;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::ordering;
+use crate::reputation::{self, Violation};
+use crate::send_scheduler;
+use crate::stats;
+use crate::transfer;
 use crate::utils;
-use crate::wire_msg::{Handshake, WireMsg};
+use crate::wire_msg::{self, CloseReason, Handshake, WireMsg};
+#[cfg(feature = "wire-tap")]
+use crate::wire_tap::{self, Direction};
 use crate::{connect, NodeInfo};
-use crate::{Peer, R};
+use crate::{ClientInfo, Peer, PeerKind, DEFAULT_CHANNEL, ECHO_MARKER, R};
+use std::collections::HashMap;
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::mpsc::Sender;
-use tokio::prelude::{Future, Stream};
-use tokio::runtime::current_thread;
+use std::time::{Duration, Instant};
+use tokio::prelude::{future, Future, Stream};
 
 /// Send message to peer. If the peer is a node and is not connected, it will attempt to connect to
-/// it first and then send the message. For un-connected clients, it'll simply error out.
-pub fn try_write_to_peer(peer: Peer, msg: WireMsg) {
+/// it first and then send the message. User messages to un-connected clients are held for a while
+/// in case they reconnect.
+pub fn try_write_to_peer(ctx: &Ctx, peer: Peer, msg: WireMsg) {
+    try_write_to_peer_with_expiry(ctx, peer, msg, None)
+}
+
+/// Like `try_write_to_peer`, but if the message has to wait for the connection to the node to be
+/// established it's given up on once `expires_at` passes.
+pub fn try_write_to_peer_with_expiry(
+    ctx: &Ctx,
+    peer: Peer,
+    msg: WireMsg,
+    expires_at: Option<Instant>,
+) {
+    try_send_to_peer(ctx, peer, PendingSend::new(msg, expires_at))
+}
+
+/// Like `try_write_to_peer_with_expiry`, but for a message which may be a replay, see
+/// `PendingSend::replay`.
+pub fn try_send_to_peer(ctx: &Ctx, peer: Peer, pending_send: PendingSend) {
     let node_info = match peer {
-        Peer::Client { peer_addr } => return write_to_peer(peer_addr, msg),
+        Peer::Client {
+            peer_addr,
+            client_info,
+        } => {
+            let msg = pending_send.msg;
+            return if msg.is_user_msg() {
+                client_grace::send_or_hold(ctx, peer_addr, client_info, msg)
+            } else {
+                write_to_peer(ctx, peer_addr, msg)
+            };
+        }
         Peer::Node { node_info } => node_info,
     };
 
-    let connect_and_send = ctx_mut(|c| {
+    let (connect_and_send, is_overloaded) = ctx.with_mut(|c| {
         let peer_addr = node_info.peer_addr;
         let event_tx = c.event_tx.clone();
+        let clock = c.clock.clone();
         let conn = c
             .connections
             .entry(peer_addr)
-            .or_insert_with(|| Connection::new(peer_addr, event_tx, None));
+            .or_insert_with(|| Connection::new(ctx, peer_addr, event_tx, clock, None));
 
-        match conn.to_peer {
-            ToPeer::NoConnection => Some(msg),
+        if c.send_over_incoming_connections
+            && !conn.to_peer.is_established()
+            && conn.peer_handshake_rxd
+        {
+            if let FromPeer::Established { ref q_conn, .. } = conn.from_peer {
+                send_to_peer_connection(ctx, peer_addr, q_conn, pending_send);
+                return (None, conn.is_overloaded(c.per_peer_buffer_limit));
+            }
+        }
+
+        let connect_and_send = match conn.to_peer {
+            ToPeer::NoConnection => Some(pending_send),
             ToPeer::NotNeeded => {
                 warn!("TODO We normally can't get here - ignoring");
                 None
@@ -49,22 +110,36 @@ pub fn try_write_to_peer(peer: Peer, msg: WireMsg) {
                 ..
             } => {
                 if *peer_cert_der != node_info.peer_cert_der {
-                    info!("TODO Certificate we have for the peer already doesn't match with the \
-                    one given - we should disconnect to such peers - something fishy going on.");
+                    info!(
+                        "TODO Certificate we have for the peer ({}) already doesn't match with \
+                         the one given ({}) - we should disconnect to such peers - something \
+                         fishy going on.",
+                        utils::cert_fingerprint(peer_cert_der),
+                        node_info.fingerprint()
+                    );
                 }
-                pending_sends.push(msg);
+                pending_sends.push(pending_send);
                 None
             }
             ToPeer::Established { ref q_conn, .. } => {
-                write_to_peer_connection(node_info.peer_addr, q_conn, msg);
+                send_to_peer_connection(ctx, node_info.peer_addr, q_conn, pending_send);
                 None
             }
-        }
+        };
+
+        (
+            connect_and_send,
+            conn.is_overloaded(c.per_peer_buffer_limit),
+        )
     });
 
+    if is_overloaded {
+        return drop_overloaded_peer(ctx, node_info.peer_addr);
+    }
+
     if connect_and_send.is_some() {
         let peer_addr = node_info.peer_addr;
-        if let Err(e) = connect::connect_to(node_info, connect_and_send, None) {
+        if let Err(e) = connect::connect_to(ctx, node_info, connect_and_send, None) {
             debug!(
                 "Unable to connect to peer {} to be able to send message: {:?}",
                 peer_addr, e
@@ -75,117 +150,569 @@ pub fn try_write_to_peer(peer: Peer, msg: WireMsg) {
 
 /// This will fail if we don't have a connection to the peer or if the peer is in an invalid state
 /// to be sent a message to.
-pub fn write_to_peer(peer_addr: SocketAddr, msg: WireMsg) {
-    ctx(|c| {
+pub fn write_to_peer(ctx: &Ctx, peer_addr: SocketAddr, msg: WireMsg) {
+    ctx.with(|c| {
         let conn = match c.connections.get(&peer_addr) {
             Some(conn) => conn,
             None => return trace!("Asked to communicate with an unknown peer: {}", peer_addr),
         };
 
-        match &conn.to_peer {
-            ToPeer::NotNeeded => {
-                if let FromPeer::Established { ref q_conn, .. } = conn.from_peer {
-                    write_to_peer_connection(peer_addr, q_conn, msg);
-                } else {
-                    debug!(
-                        "TODO We cannot communicate with someone we are not needing to connect to \
-                         and they are not connected to us just now. Peer: {}",
-                        peer_addr
-                    );
-                }
-            }
-            ToPeer::Established { ref q_conn, .. } => {
-                write_to_peer_connection(peer_addr, q_conn, msg)
-            }
-            ToPeer::NoConnection | ToPeer::Initiated { .. } => {
-                return debug!(
-                    "Peer {} is in invalid state {:?} to be communicated to",
-                    peer_addr, conn.to_peer
-                );
-            }
+        match writable_q_conn(c, conn) {
+            Some(q_conn) => write_to_peer_connection(ctx, peer_addr, q_conn, msg),
+            None => debug!(
+                "Peer {} is in invalid state {:?} to be communicated to",
+                peer_addr, conn.to_peer
+            ),
         }
     })
 }
 
+/// The QUIC connection messages to the peer go over, unless it's in no state to be written to.
+pub fn writable_q_conn<'a>(c: &Context, conn: &'a Connection) -> Option<&'a QConn> {
+    match (&conn.to_peer, &conn.from_peer) {
+        (ToPeer::Established { q_conn, .. }, _) => Some(q_conn),
+        (ToPeer::NotNeeded, FromPeer::Established { q_conn, .. }) => Some(q_conn),
+        (ToPeer::NoConnection, FromPeer::Established { q_conn, .. })
+        | (ToPeer::Initiated { .. }, FromPeer::Established { q_conn, .. })
+            if c.send_over_incoming_connections && conn.peer_handshake_rxd =>
+        {
+            Some(q_conn)
+        }
+        _ => None,
+    }
+}
+
+/// Told whether the message was written to the peer in full.
+pub type OnWritten = Box<dyn FnOnce(bool)>;
+
 /// Write to the peer, given the QUIC connection to it
-pub fn write_to_peer_connection(peer_addr: SocketAddr, conn: &QConn, wire_msg: WireMsg) {
-    let leaf = conn
-        .open_uni()
-        .map_err(move |e| {
-            utils::handle_communication_err(peer_addr, &From::from(e), "Open-Unidirectional")
-        })
-        .and_then(move |o_stream| {
-            tokio::io::write_all(o_stream, wire_msg.into()).map_err(move |e| {
-                utils::handle_communication_err(peer_addr, &From::from(e), "Write-All")
-            })
-        })
-        .and_then(move |(o_stream, _): (_, bytes::Bytes)| {
-            tokio::io::shutdown(o_stream).map_err(move |e| {
-                utils::handle_communication_err(peer_addr, &From::from(e), "Shutdown-after-write")
-            })
-        })
-        .map(|_| ());
+pub fn write_to_peer_connection(ctx: &Ctx, peer_addr: SocketAddr, conn: &QConn, wire_msg: WireMsg) {
+    send_to_peer_connection(ctx, peer_addr, conn, PendingSend::new(wire_msg, None))
+}
 
-    current_thread::spawn(leaf);
+/// Like `write_to_peer_connection`, but for a message which may be a replay, see
+/// `PendingSend::replay`.
+pub fn send_to_peer_connection(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    conn: &QConn,
+    pending_send: PendingSend,
+) {
+    let wire_msg = pending_send.msg;
+    let user_msg = if wire_msg.is_user_msg() {
+        Some(wire_msg.clone())
+    } else {
+        None
+    };
+    let channel = wire_msg.channel();
+    let frame = match wire_msg.into_frame() {
+        Ok(frame) => frame,
+        Err(e) => return warn!("Could not frame a message to peer {}: {}", peer_addr, e),
+    };
+    write_tracked_frame(
+        ctx,
+        peer_addr,
+        conn,
+        user_msg,
+        channel,
+        frame,
+        None,
+        pending_send.unacked_msg_id,
+    )
 }
 
-/// Listen for incoming streams containing peer messages and read them when available
-pub fn read_from_peer(peer_addr: SocketAddr, incoming_streams: quinn::IncomingStreams) {
-    let leaf = incoming_streams
-        .map_err(move |e| {
-            utils::handle_communication_err(peer_addr, &From::from(e), "Incoming streams failed");
-        })
-        .for_each(move |quic_stream| {
-            read_peer_stream(peer_addr, quic_stream).map_err(|e| {
-                debug!(
-                    "Error in Incoming-streams while reading from peer {}: {:?} - {}.",
-                    peer_addr, e, e
-                )
-            })
+/// Write the already framed message to the peer, so that the same frame can be shared by many
+/// peers. `user_msg` is the message in the frame if it's a user one.
+pub fn write_frame_to_peer_connection(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    conn: &QConn,
+    user_msg: Option<WireMsg>,
+    channel: u8,
+    frame: bytes::Bytes,
+    on_written: Option<OnWritten>,
+) {
+    write_tracked_frame(
+        ctx, peer_addr, conn, user_msg, channel, frame, on_written, None,
+    )
+}
+
+/// Write the framed message to the peer. A user message is tracked for replay under
+/// `unacked_msg_id` if it's a replay, under a new id otherwise.
+#[allow(clippy::too_many_arguments)]
+fn write_tracked_frame(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    conn: &QConn,
+    user_msg: Option<WireMsg>,
+    channel: u8,
+    frame: bytes::Bytes,
+    on_written: Option<OnWritten>,
+    unacked_msg_id: Option<u64>,
+) {
+    let unsent_msg = user_msg.clone().and_then(WireMsg::into_user_msg);
+    let in_reply_to = user_msg.as_ref().and_then(WireMsg::in_reply_to);
+    let open_uni = conn.open_uni();
+    #[cfg(feature = "testing")]
+    let open_uni = fault_injection::delay_outbound(ctx, open_uni);
+
+    // We are usually called with the `Context` already borrowed, so sequencing, tracking and
+    // tagging the message is deferred to when the leaf is first polled.
+    let ctx_clone = ctx.clone();
+    let leaf = future::lazy(move || {
+        let ctx = &ctx_clone;
+        let seq = user_msg
+            .as_ref()
+            .and_then(|_| ordering::next_seq(ctx, peer_addr));
+        let unacked_msg_id =
+            user_msg.and_then(|msg| track_unacked_msg(ctx, peer_addr, msg, unacked_msg_id));
+        let (write_timeout, is_tagging) = ctx.with(|c| {
+            (
+                c.write_timeout.map(|timeout| (timeout, c.clock.clone())),
+                c.dedup.is_some(),
+            )
         });
+        let reply_stream = in_reply_to.and_then(|msg_id| take_reply_stream(ctx, peer_addr, msg_id));
+        let tags = (seq, unacked_msg_id.filter(|_| is_tagging));
+        Ok::<_, ()>((ctx_clone, tags, unacked_msg_id, write_timeout, reply_stream))
+    })
+    .and_then(
+        move |(ctx, tags, unacked_msg_id, write_timeout, reply_stream)| {
+            let (seq, dedup_id) = tags;
+            let frame = match seq {
+                Some(seq) => wire_msg::sequenced_frame(seq, &frame),
+                None => frame,
+            };
+            let frame = match dedup_id {
+                Some(dedup_id) => wire_msg::dedup_frame(dedup_id, &frame),
+                None => frame,
+            };
+            // Replies go back over the stream the peer sent its message on, if it's waiting on it
+            let o_stream = match reply_stream {
+                Some(o_stream) => future::Either::A(future::ok(o_stream)),
+                None => future::Either::B(open_uni),
+            };
+            let ctx_on_open_err = ctx.clone();
+            let ctx_on_written = ctx.clone();
+            o_stream
+                .map_err(move |e| {
+                    utils::handle_communication_err(
+                        &ctx_on_open_err,
+                        peer_addr,
+                        &From::from(e),
+                        "Open-Unidirectional",
+                    )
+                })
+                .and_then(move |o_stream| {
+                    #[cfg(feature = "wire-tap")]
+                    wire_tap::tap(&ctx, Direction::Outgoing, peer_addr, &frame);
+                    #[cfg(feature = "metrics")]
+                    metrics::record_outbound(&ctx, frame.len());
+                    stats::record_outbound(&ctx, frame.len());
+                    let ctx_on_write_err = ctx.clone();
+                    let ctx_on_shutdown_err = ctx.clone();
+                    let write =
+                        send_scheduler::write_all(&ctx, (peer_addr, channel), o_stream, frame)
+                            .map_err(move |e| {
+                                utils::handle_communication_err(
+                                    &ctx_on_write_err,
+                                    peer_addr,
+                                    &From::from(e),
+                                    "Write-All",
+                                )
+                            })
+                            .and_then(move |o_stream| {
+                                tokio::io::shutdown(o_stream).map_err(move |e| {
+                                    utils::handle_communication_err(
+                                        &ctx_on_shutdown_err,
+                                        peer_addr,
+                                        &From::from(e),
+                                        "Shutdown-after-write",
+                                    )
+                                })
+                            });
+                    // Dropping the stream on timeout cancels it
+                    match write_timeout {
+                        Some((write_timeout, clock)) => future::Either::A(
+                            clock::timeout(&clock, write, write_timeout).map_err(move |e| {
+                                if e.is_none() {
+                                    handle_write_timeout(
+                                        &ctx,
+                                        peer_addr,
+                                        unacked_msg_id,
+                                        unsent_msg,
+                                    );
+                                }
+                            }),
+                        ),
+                        None => future::Either::B(write),
+                    }
+                })
+                .map(move |_| {
+                    if let Some(id) = unacked_msg_id {
+                        ack_msg(&ctx_on_written, peer_addr, id);
+                    }
+                })
+        },
+    )
+    .then(move |res| {
+        if let Some(on_written) = on_written {
+            on_written(res.is_ok());
+        }
+        res
+    });
+
+    ctx.spawn(leaf);
+}
+
+/// The peer didn't take our message in time. User messages are given up on rather than replayed
+/// on reconnect.
+fn handle_write_timeout(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    unacked_msg_id: Option<u64>,
+    unsent_msg: Option<bytes::Bytes>,
+) {
+    debug!(
+        "Write to peer {} timed out - cancelling the stream",
+        peer_addr
+    );
+
+    if let Some(id) = unacked_msg_id {
+        ack_msg(ctx, peer_addr, id);
+    }
+
+    let msg = match unsent_msg {
+        Some(msg) => msg,
+        None => return,
+    };
+    ctx.with(|c| {
+        let peer = match c.connections.get(&peer_addr).and_then(|conn| conn.peer()) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let event = Event::UnsentUserMessage {
+            peer,
+            msg,
+            reason: UnsentReason::WriteTimedOut,
+        };
+        if let Err(e) = c.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+    })
+}
+
+/// Hold on to the user message until the peer acknowledges it so that it can be replayed should
+/// the connection fail in the meantime. Only done if auto-reconnect is enabled. Replays keep the
+/// id they were tracked under before.
+fn track_unacked_msg(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    msg: WireMsg,
+    replayed_id: Option<u64>,
+) -> Option<u64> {
+    let (id, is_overloaded) = ctx.with_mut(|c| {
+        let cap = c.auto_reconnect?.max_unacked_msgs;
+        let id = replayed_id.unwrap_or(c.next_unacked_msg_id);
+        let conn = c.connections.get_mut(&peer_addr)?;
+        conn.unacked_msgs.push(id, msg, cap);
+        let is_overloaded = conn.is_overloaded(c.per_peer_buffer_limit);
+        if replayed_id.is_none() {
+            c.next_unacked_msg_id = id.wrapping_add(1);
+        }
+        Some((id, is_overloaded))
+    })?;
+
+    if is_overloaded {
+        drop_overloaded_peer(ctx, peer_addr);
+        return None;
+    }
+
+    Some(id)
+}
+
+fn ack_msg(ctx: &Ctx, peer_addr: SocketAddr, id: u64) {
+    ctx.with_mut(|c| {
+        if let Some(conn) = c.connections.get_mut(&peer_addr) {
+            conn.unacked_msgs.ack(id);
+        }
+    })
+}
 
-    current_thread::spawn(leaf);
+/// Listen for incoming streams containing peer messages and read them when available. This is
+/// the data lane of the connection, see the `lanes` module. Each stream is read in a task of its
+/// own which can be cancelled via `Connection::stream_reads`. No new streams are taken while reading
+/// from the peer is paused, see `backpressure`. This must not be called while the `Context` is
+/// already borrowed.
+pub fn read_from_peer(ctx: &Ctx, peer_addr: SocketAddr, incoming_streams: quinn::IncomingStreams) {
+    let budget = ctx.with(|c| c.data_lane_budget);
+    let ctx_on_err = ctx.clone();
+    let ctx_clone = ctx.clone();
+    let leaf = lanes::data_lane(
+        backpressure::pausable(ctx, peer_addr, incoming_streams),
+        budget,
+    )
+    .map_err(move |e| {
+        utils::handle_communication_err(
+            &ctx_on_err,
+            peer_addr,
+            &From::from(e),
+            "Incoming streams failed",
+        );
+    })
+    .for_each(move |quic_stream| {
+        read_peer_stream(&ctx_clone, peer_addr, quic_stream).map_err(|e| {
+            debug!(
+                "Error in Incoming-streams while reading from peer {}: {:?} - {}.",
+                peer_addr, e, e
+            )
+        })
+    });
+
+    ctx.spawn(leaf);
 }
 
-fn read_peer_stream(peer_addr: SocketAddr, quic_stream: quinn::NewStream) -> R<()> {
-    let i_stream = match quic_stream {
-        quinn::NewStream::Bi(_, _) => {
-            let e = Error::BiDirectionalStreamAttempted(peer_addr);
-            utils::handle_communication_err(peer_addr, &e, "Receiving Stream");
-            return Err(e);
+fn read_peer_stream(ctx: &Ctx, peer_addr: SocketAddr, quic_stream: quinn::NewStream) -> R<()> {
+    // The message on a bi-directional stream is read just like one on a uni-directional stream
+    let (i_stream, o_stream) = match quic_stream {
+        quinn::NewStream::Bi(o_stream, i_stream) => (i_stream, Some(o_stream)),
+        quinn::NewStream::Uni(i_stream) => (i_stream, None),
+    };
+
+    let admitted = ctx.with_mut(|c| {
+        let conn = c.connections.get_mut(&peer_addr)?;
+        if conn.stream_reads.len() >= c.max_incomplete_reads {
+            return Some(None);
         }
-        quinn::NewStream::Uni(uni) => uni,
+        let read = conn.stream_reads.start();
+        if let Err(e) = c.event_tx.send(Event::StreamOpened { peer_addr }) {
+            info!("Could not fire event: {:?}", e);
+        }
+        Some(Some(read))
+    });
+    let (read_id, terminator_rx) = match admitted {
+        Some(Some(read)) => read,
+        Some(None) => {
+            debug!(
+                "Too many incomplete messages from peer {} - refusing its new stream",
+                peer_addr
+            );
+            reputation::penalise(ctx, peer_addr, Violation::StalledRead);
+            return Ok(());
+        }
+        None => {
+            trace!(
+                "Rxd stream from someone we don't know. Probably it was pending when we dropped \
+                 the peer connection. Ignoring the stream from peer: {}",
+                peer_addr
+            );
+            return Ok(());
+        }
+    };
+
+    let (max_len, read_timeout, clock) = ctx.with(|c| {
+        (
+            c.max_msg_size_allowed
+                + wire_msg::MAX_FRAME_OVERHEAD
+                + wire_msg::SEQUENCED_PREFIX_LEN
+                + wire_msg::DEDUP_PREFIX_LEN,
+            c.read_timeout,
+            c.clock.clone(),
+        )
+    });
+    let read = i_stream.read_to_end(max_len).map_err(Error::from);
+    // Dropping the stream on timeout cancels it
+    let read = match read_timeout {
+        Some(read_timeout) => future::Either::A(
+            clock::timeout(&clock, read, read_timeout)
+                .map_err(move |e| e.unwrap_or(Error::ReadTimedOut(peer_addr))),
+        ),
+        None => future::Either::B(read),
     };
 
-    let leaf = i_stream
-        .read_to_end(ctx(|c| c.max_msg_size_allowed))
-        .map_err(move |e| utils::handle_communication_err(peer_addr, &From::from(e), "Read-To-End"))
+    let ctx_on_read = ctx.clone();
+    let ctx_on_err = ctx.clone();
+    let ctx_clone = ctx.clone();
+    let read = read
+        .then(move |res| {
+            ctx_on_read.with_mut(|c| {
+                if let Some(conn) = c.connections.get_mut(&peer_addr) {
+                    conn.stream_reads.finish(read_id);
+                }
+            });
+            res
+        })
+        .map_err(move |e| match e {
+            Error::ReadTimedOut(_) => {
+                debug!("{} - cancelling the stream", e);
+                reputation::penalise(&ctx_on_err, peer_addr, Violation::StalledRead);
+            }
+            // A stream that is finished normally is read successfully, so this means it went on
+            // for longer than `max_len`
+            Error::Read(quinn::ReadError::Finished) => {
+                debug!(
+                    "Peer {} sent a message longer than the {} bytes allowed",
+                    peer_addr, max_len
+                );
+                reputation::penalise(&ctx_on_err, peer_addr, Violation::OversizedMessage);
+            }
+            e => utils::handle_communication_err(&ctx_on_err, peer_addr, &e, "Read-To-End"),
+        })
         .and_then(move |(_i_stream, raw)| {
-            WireMsg::from_raw(raw)
-                .map_err(|e| utils::handle_communication_err(peer_addr, &e, "Raw to WireMsg"))
-                .map(|wire_msg| handle_wire_msg(peer_addr, wire_msg))
+            let ctx = &ctx_clone;
+            #[cfg(feature = "wire-tap")]
+            wire_tap::tap(ctx, Direction::Incoming, peer_addr, &raw);
+            #[cfg(feature = "metrics")]
+            metrics::record_inbound(ctx, raw.len());
+            stats::record_inbound(ctx, raw.len());
+            backpressure::record_read(ctx, peer_addr, raw.len());
+            WireMsg::from_tagged_bytes_safe(raw)
+                .map_err(|e| {
+                    let violation = if let Error::WireMsgTooLarge(_) = e {
+                        Violation::OversizedMessage
+                    } else {
+                        Violation::ProtocolViolation
+                    };
+                    reputation::penalise(ctx, peer_addr, violation);
+                    utils::handle_communication_err(ctx, peer_addr, &e, "Raw to WireMsg")
+                })
+                .map(|(dedup_id, seq, wire_msg)| {
+                    if dedup_id.map_or(false, |id| dedup::is_duplicate(ctx, peer_addr, id)) {
+                        // Its place in the sequence is taken all the same
+                        if let Some(seq) = seq {
+                            ordering::skip(ctx, peer_addr, seq);
+                        }
+                        return;
+                    }
+                    if let Some(o_stream) = o_stream {
+                        keep_reply_stream(ctx, peer_addr, &wire_msg, o_stream);
+                    }
+                    match seq {
+                        Some(seq) => ordering::receive(ctx, peer_addr, seq, wire_msg),
+                        None => handle_wire_msg(ctx, peer_addr, wire_msg),
+                    }
+                })
         });
+    // Dropping the read once cancelled cancels the stream
+    let terminator_leaf = terminator_rx
+        .into_future()
+        .map(move |_| debug!("Cancelled reading a message from peer {}", peer_addr))
+        .map_err(|_| ());
+    let leaf = read.select(terminator_leaf).then(|_| Ok(()));
 
-    current_thread::spawn(leaf);
+    ctx.spawn(leaf);
 
     Ok(())
 }
 
+/// Hold on to the send half of the bi-directional stream the peer sent us the message on, so that
+/// our reply to it goes back over the same stream. Only messages with ids can be replied to, so
+/// the send half is finished straight away for the others, as it is once the peer has as many
+/// streams awaiting replies as it may have incomplete reads. This must not be called while the
+/// `Context` is already borrowed.
+fn keep_reply_stream(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    wire_msg: &WireMsg,
+    o_stream: quinn::SendStream,
+) {
+    let unused = match wire_msg.msg_id() {
+        Some(msg_id) => ctx.with_mut(|c| {
+            let max_reply_streams = c.max_incomplete_reads;
+            match c.connections.get_mut(&peer_addr) {
+                Some(conn) if conn.reply_streams.len() < max_reply_streams => {
+                    conn.reply_streams.insert(msg_id, o_stream)
+                }
+                _ => Some(o_stream),
+            }
+        }),
+        None => Some(o_stream),
+    };
+
+    if let Some(o_stream) = unused {
+        ctx.spawn(tokio::io::shutdown(o_stream).then(move |r| {
+            if let Err(e) = r {
+                debug!("Could not finish reply stream to peer {}: {}", peer_addr, e);
+            }
+            Ok(())
+        }));
+    }
+}
+
+/// Send half of the stream the peer is waiting on for our reply to its message, if there's one.
+/// This must not be called while the `Context` is already borrowed.
+fn take_reply_stream(ctx: &Ctx, peer_addr: SocketAddr, msg_id: u64) -> Option<quinn::SendStream> {
+    ctx.with_mut(|c| {
+        c.connections
+            .get_mut(&peer_addr)
+            .and_then(|conn| conn.reply_streams.remove(&msg_id))
+    })
+}
+
+/// Cancel reading the messages the peer is sending us just now, keeping the connection. Returns
+/// how many reads were cancelled. This must not be called while the `Context` is already borrowed.
+pub fn cancel_reads_from(ctx: &Ctx, peer_addr: SocketAddr) -> R<usize> {
+    ctx.with_mut(|c| {
+        let conn = c
+            .connections
+            .get_mut(&peer_addr)
+            .ok_or(Error::PeerNotConnected(peer_addr))?;
+        Ok(conn.stream_reads.cancel_all())
+    })
+}
+
 /// Handle wire messages from peer
-pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
+pub fn handle_wire_msg(ctx: &Ctx, peer_addr: SocketAddr, wire_msg: WireMsg) {
+    #[cfg(feature = "testing")]
+    {
+        if wire_msg.is_user_msg() && fault_injection::should_drop_inbound(ctx) {
+            return trace!(
+                "Dropping message from peer {} due to injected fault",
+                peer_addr
+            );
+        }
+    }
+
+    let channel = wire_msg.channel();
+    if channel != DEFAULT_CHANNEL && !ctx.with(|c| c.channels.contains(&channel)) {
+        return debug!(
+            "Dropping message from peer {} on channel {} we don't accept",
+            peer_addr, channel
+        );
+    }
+
     match wire_msg {
-        WireMsg::Handshake(h) => handle_rx_handshake(peer_addr, h),
+        WireMsg::Handshake(h) => handle_rx_handshake(ctx, peer_addr, h),
+        WireMsg::HealthCheckReq { sent_at_msec } => {
+            handle_health_check_req(ctx, peer_addr, sent_at_msec)
+        }
         wire_msg => {
-            ctx_mut(|c| {
+            let is_overloaded = ctx.with_mut(|c| {
                 let conn = match c.connections.get_mut(&peer_addr) {
                     Some(conn) => conn,
                     None => {
-                        trace!("Rxd wire-message from someone we don't know. Probably it was a \
+                        trace!(
+                            "Rxd wire-message from someone we don't know. Probably it was a \
                         pending stream when we dropped the peer connection. Ignoring this message \
-                        from peer: {}", peer_addr);
-                        return;
+                        from peer: {}",
+                            peer_addr
+                        );
+                        return false;
                     }
                 };
+                conn.last_heard_at = c.clock.now();
+                liveness::heard_from(&c.event_tx, peer_addr, conn);
+                if let WireMsg::HealthCheckResp {
+                    req_sent_at_msec,
+                    sent_at_msec,
+                } = wire_msg
+                {
+                    conn.clock_skew_msec = Some(clock_skew::from_round_trip(
+                        req_sent_at_msec,
+                        sent_at_msec,
+                        clock::unix_time_msec(&c.clock),
+                    ));
+                }
 
                 match conn.from_peer {
                     // TODO see if repetition can be reduced
@@ -206,6 +733,7 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                                 peer_cert_der: peer_cert_der.clone(),
                             };
                             dispatch_wire_msg(
+                                ctx,
                                 node_info.into(),
                                 q_conn,
                                 c.our_ext_addr_tx.take(),
@@ -213,6 +741,7 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                                 wire_msg,
                                 &mut c.bootstrap_cache,
                                 conn.we_contacted_peer,
+                                c.echo_service,
                             );
                         }
                     },
@@ -223,14 +752,24 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                         ToPeer::NoConnection | ToPeer::Initiated { .. } => {
                             pending_reads.push(wire_msg);
                         }
+                        // Connection event is not fired until the peer introduces itself
+                        ToPeer::Established { .. } if !conn.peer_handshake_rxd => {
+                            pending_reads.push(wire_msg);
+                        }
                         ToPeer::NotNeeded => dispatch_wire_msg(
-                            Peer::Client { peer_addr },
+                            ctx,
+                            Peer::Client {
+                                peer_addr,
+                                // Always known by the time the peer is marked as a client
+                                client_info: conn.client_info.clone().unwrap_or_default(),
+                            },
                             q_conn,
                             c.our_ext_addr_tx.take(),
                             &c.event_tx,
                             wire_msg,
                             &mut c.bootstrap_cache,
                             conn.we_contacted_peer,
+                            c.echo_service,
                         ),
                         ToPeer::Established {
                             ref q_conn,
@@ -241,6 +780,7 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                                 peer_cert_der: peer_cert_der.clone(),
                             };
                             dispatch_wire_msg(
+                                ctx,
                                 node_info.into(),
                                 q_conn,
                                 c.our_ext_addr_tx.take(),
@@ -248,86 +788,396 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                                 wire_msg,
                                 &mut c.bootstrap_cache,
                                 conn.we_contacted_peer,
+                                c.echo_service,
                             );
                         }
                     },
-                    FromPeer::NoConnection => unreachable!(
-                        "Cannot have no connection for someone \
-                         we got a message from"
-                    ),
+                    // The peer is sending over the connection we made to it
+                    FromPeer::NoConnection => match conn.to_peer {
+                        ToPeer::Established {
+                            ref q_conn,
+                            ref peer_cert_der,
+                        } => {
+                            let node_info = NodeInfo {
+                                peer_addr,
+                                peer_cert_der: peer_cert_der.clone(),
+                            };
+                            dispatch_wire_msg(
+                                ctx,
+                                node_info.into(),
+                                q_conn,
+                                c.our_ext_addr_tx.take(),
+                                &c.event_tx,
+                                wire_msg,
+                                &mut c.bootstrap_cache,
+                                conn.we_contacted_peer,
+                                c.echo_service,
+                            );
+                        }
+                        ToPeer::NoConnection | ToPeer::NotNeeded | ToPeer::Initiated { .. } => {
+                            let _ = c.sever_illegal_state(
+                                peer_addr,
+                                "cannot have no connection for someone we got a message from"
+                                    .to_string(),
+                            );
+                            return false;
+                        }
+                    },
                 }
+
+                conn.is_overloaded(c.per_peer_buffer_limit)
             });
+
+            if is_overloaded {
+                drop_overloaded_peer(ctx, peer_addr);
+            }
         }
     }
 }
 
+/// Inform the user and drop the connection to a peer on whose behalf we are buffering more than
+/// we allow.
+fn drop_overloaded_peer(ctx: &Ctx, peer_addr: SocketAddr) {
+    ctx.with_mut(|c| {
+        info!(
+            "Buffering too much data for peer {} - dropping the connection to it",
+            peer_addr
+        );
+        if let Err(e) = c.event_tx.send(Event::PeerOverloaded { peer_addr }) {
+            info!("Could not fire event: {:?}", e);
+        }
+        let _ = c.close_connection(&peer_addr, CloseReason::Overloaded);
+    });
+    reputation::penalise(ctx, peer_addr, Violation::OversizedMessage);
+}
+
 /// Dispatch wire message
 // TODO: Improve by not taking `inform_tx` which is necessary right now to prevent double borrow
+#[allow(clippy::too_many_arguments)]
 pub fn dispatch_wire_msg(
+    ctx: &Ctx,
     peer: Peer,
     q_conn: &QConn,
     inform_tx: Option<Sender<SocketAddr>>,
-    event_tx: &Sender<Event>,
+    event_tx: &EventTx,
     wire_msg: WireMsg,
     bootstrap_cache: &mut BootstrapCache,
     we_contacted_peer: bool,
+    echo_service: bool,
 ) {
+    if echo_service {
+        if let Some(echo) = echo_of(&wire_msg) {
+            write_to_peer_connection(ctx, peer.peer_addr(), q_conn, echo);
+        }
+    }
+
     match wire_msg {
-        WireMsg::UserMsg(m) => {
-            handle_user_msg(peer, event_tx, m, bootstrap_cache, we_contacted_peer)
+        WireMsg::UserMsg(msg) => handle_user_msg(
+            peer,
+            event_tx,
+            msg,
+            None,
+            None,
+            DEFAULT_CHANNEL,
+            bootstrap_cache,
+            we_contacted_peer,
+        ),
+        WireMsg::UserMsgEnvelope {
+            msg,
+            msg_id,
+            in_reply_to,
+            channel,
+        } => handle_user_msg(
+            peer,
+            event_tx,
+            msg,
+            msg_id,
+            in_reply_to,
+            channel,
+            bootstrap_cache,
+            we_contacted_peer,
+        ),
+        WireMsg::TypedUserMsg(msg) => {
+            handle_typed_user_msg(peer, event_tx, msg, bootstrap_cache, we_contacted_peer)
+        }
+        WireMsg::UserMsgBatch(msgs) => {
+            for msg in msgs {
+                handle_user_msg(
+                    peer.clone(),
+                    event_tx,
+                    msg,
+                    None,
+                    None,
+                    DEFAULT_CHANNEL,
+                    bootstrap_cache,
+                    we_contacted_peer,
+                );
+            }
+        }
+        WireMsg::EndpointEchoReq => handle_echo_req(ctx, peer.peer_addr(), q_conn),
+        WireMsg::EndpointEchoResp(our_addr) => handle_echo_resp(ctx, our_addr, inform_tx),
+        WireMsg::GetContacts => handle_get_contacts(ctx, peer.peer_addr()),
+        WireMsg::Contacts(contacts) => handle_contacts(ctx, peer.peer_addr(), contacts),
+        WireMsg::ReverseConnect { target_info } => {
+            handle_reverse_connect(ctx, peer.peer_addr(), target_info)
+        }
+        WireMsg::ReverseConnectResult {
+            target_addr,
+            success,
+        } => {
+            let event = Event::ReverseConnectResult {
+                via: peer.peer_addr(),
+                target_addr,
+                success,
+            };
+            if let Err(e) = event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+        // Its timestamps were taken in by `handle_wire_msg` already
+        WireMsg::HealthCheckResp { .. } => (),
+        WireMsg::FileOffer { hash, len } => transfer::handle_offer(ctx, peer, hash, len),
+        WireMsg::FileAccept { hash, offset } => {
+            transfer::handle_accept(ctx, peer.peer_addr(), hash, offset)
+        }
+        WireMsg::FileChunk { hash, offset, data } => {
+            transfer::handle_chunk(ctx, peer, hash, offset, data)
+        }
+        WireMsg::FileRefuse { hash } => transfer::handle_refuse(ctx, peer.peer_addr(), hash),
+        WireMsg::CertRotation {
+            new_cert,
+            signature_by_old_key,
+        } => cert_rotation::handle_announcement(
+            ctx,
+            peer,
+            new_cert,
+            &signature_by_old_key,
+            event_tx,
+            bootstrap_cache,
+        ),
+        // The connection is borrowed by our caller so it's severed once we are done
+        WireMsg::Handshake(_) | WireMsg::HealthCheckReq { .. } => {
+            let peer_addr = peer.peer_addr();
+            let ctx_clone = ctx.clone();
+            ctx.spawn(future::lazy(move || {
+                let ctx = &ctx_clone;
+                let _ = ctx.with_mut(|c| {
+                    c.sever_illegal_state(peer_addr, "should have been handled already".to_string())
+                });
+                Ok(())
+            }));
         }
-        WireMsg::EndpointEchoReq => handle_echo_req(peer.peer_addr(), q_conn),
-        WireMsg::EndpointEchoResp(our_addr) => handle_echo_resp(our_addr, inform_tx),
-        WireMsg::Handshake(_) => unreachable!("Should have been handled already"),
     }
 }
 
-fn handle_rx_handshake(peer_addr: SocketAddr, handshake: Handshake) {
-    if let Handshake::Node { cert_der } = handshake {
-        return handle_rx_cert(peer_addr, cert_der);
-    }
+fn handle_rx_handshake(ctx: &Ctx, peer_addr: SocketAddr, handshake: Handshake) {
+    // Only acted on once the peer has authenticated
+    let network_id = handshake.network_id().to_string();
+    let sent_at_msec = handshake.sent_at_msec();
+    let observed_addr = handshake.observed_addr();
+    let channels = handshake.channels().to_vec();
+    let ordered_delivery = handshake.ordered_delivery();
+    let (client_info, user_data) = match handshake {
+        Handshake::Node {
+            cert_der,
+            user_data,
+            nonce,
+            signature,
+            ..
+        } => {
+            if let Err(e) = authenticate_handshake(ctx, &cert_der, nonce, &signature)
+                .and_then(|()| verify_tls_identity(ctx, peer_addr, &cert_der))
+            {
+                return reject_handshake(ctx, peer_addr, &e);
+            }
+            if is_from_foreign_network(ctx, peer_addr, &network_id) {
+                return reputation::penalise(ctx, peer_addr, Violation::HandshakeFailure);
+            }
+            clock_skew::record_handshake(ctx, peer_addr, sent_at_msec);
+            ctx.with_mut(|c| c.observed_addrs.record(peer_addr, observed_addr));
+            handle_address_change(ctx, peer_addr, &cert_der);
+            return handle_rx_cert(
+                ctx,
+                peer_addr,
+                cert_der,
+                user_data,
+                channels,
+                ordered_delivery,
+            );
+        }
+        Handshake::Client {
+            cert_der,
+            user_data,
+            nonce,
+            signature,
+            ..
+        } => {
+            if let Err(e) = authenticate_handshake(ctx, &cert_der, nonce, &signature) {
+                return reject_handshake(ctx, peer_addr, &e);
+            }
+            if is_from_foreign_network(ctx, peer_addr, &network_id) {
+                return reputation::penalise(ctx, peer_addr, Violation::HandshakeFailure);
+            }
+            clock_skew::record_handshake(ctx, peer_addr, sent_at_msec);
+            (
+                ClientInfo {
+                    peer_cert_der: cert_der,
+                },
+                user_data,
+            )
+        }
+    };
 
     // Handshake from a client
-    ctx_mut(|c| {
+    let is_illegal = ctx.with_mut(|c| {
         let conn = match c.connections.get_mut(&peer_addr) {
             Some(conn) => conn,
             None => {
-                trace!("Rxd handshake from someone we don't know. Probably it was a pending \
+                trace!(
+                    "Rxd handshake from someone we don't know. Probably it was a pending \
                 stream when we dropped the peer connection. Ignoring this message from peer: {}",
-                peer_addr);
-                return;
+                    peer_addr
+                );
+                return false;
             }
         };
 
         match conn.to_peer {
             ToPeer::NoConnection => (),
             ToPeer::NotNeeded | ToPeer::Initiated { .. } | ToPeer::Established { .. } => {
-                // TODO consider booting this peer out
                 debug!(
                     "Illegal handshake message - we have {:?} for the peer",
                     conn.to_peer
                 );
-                return;
+                return true;
             }
         }
 
         conn.to_peer = ToPeer::NotNeeded;
+        conn.peer_handshake_rxd = true;
+        conn.peer_user_data = user_data.clone();
+        conn.peer_channels = Some(channels);
+        conn.peer_ordered_delivery = Some(ordered_delivery);
+        conn.peer_kind = Some(PeerKind::Client);
+        conn.client_info = Some(client_info.clone());
+        c.observed_addrs.record(peer_addr, observed_addr);
 
-        let peer = Peer::Client { peer_addr };
+        let cert_der = client_info.peer_cert_der.clone();
+        let peer = Peer::Client {
+            peer_addr,
+            client_info,
+        };
 
-        if let Err(e) = c.event_tx.send(Event::ConnectedTo { peer }) {
+        let event = Event::ConnectedTo {
+            peer,
+            user_data,
+            timings: conn.connect_timings(),
+        };
+        c.lifetime_stats.record_peer_connected();
+        if let Err(e) = c.event_tx.send(event) {
             info!("ERROR in informing user about a new peer: {:?} - {}", e, e);
         }
+
+        client_grace::deliver_held(ctx, c, peer_addr, &cert_der);
+
+        false
+    });
+
+    if is_illegal {
+        reputation::penalise(ctx, peer_addr, Violation::HandshakeFailure);
+    }
+}
+
+/// The node proved it owns a certificate we might know it by at another address. If so it has
+/// moved, e.g. its IP address changed, so rebind it in the bootstrap cache and tell the user. This
+/// must not be called while the `Context` is already borrowed.
+fn handle_address_change(ctx: &Ctx, peer_addr: SocketAddr, cert_der: &[u8]) {
+    ctx.with_mut(|c| {
+        let old = match c.bootstrap_cache.rebind(cert_der, peer_addr) {
+            Some(old) => old,
+            None => return,
+        };
+        let new = NodeInfo {
+            peer_addr,
+            peer_cert_der: cert_der.to_vec(),
+        };
+        info!("Node {} has moved to {}", old, peer_addr);
+        if let Err(e) = c.event_tx.send(Event::PeerAddressChanged { old, new }) {
+            info!("Could not fire event: {:?}", e);
+        }
     })
 }
 
-fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
+/// Check the peer owns the certificate it presents, that the handshake was meant for us and that
+/// it is not a replay. A handshake signed for the certificate we had before a rotation is refused,
+/// the peer has to connect again.
+fn authenticate_handshake(ctx: &Ctx, cert_der: &[u8], nonce: Nonce, signature: &[u8]) -> R<()> {
+    ctx.with_mut(|c| {
+        handshake_auth::verify(cert_der, &c.our_complete_cert.cert_der, &nonce, signature)?;
+        if !c.seen_handshake_nonces.insert(nonce) {
+            return Err(Error::HandshakeAuth("replayed handshake"));
+        }
+
+        Ok(())
+    })
+}
+
+/// With `Config::mutual_tls` the certificate a node claims in its handshake must be the one it
+/// presented in TLS when connecting to us. Otherwise anyone who got hold of the handshake of a node
+/// could pass for it until we connect back.
+fn verify_tls_identity(ctx: &Ctx, peer_addr: SocketAddr, cert_der: &[u8]) -> R<()> {
+    ctx.with(|c| {
+        if !c.mutual_tls {
+            return Ok(());
+        }
+
+        let tls_cert_der = c
+            .connections
+            .get(&peer_addr)
+            .and_then(|conn| match conn.from_peer {
+                FromPeer::Established { ref q_conn, .. } => q_conn.peer_cert_der(),
+                _ => None,
+            });
+        match tls_cert_der {
+            Some(ref tls_cert_der) if tls_cert_der[..] == cert_der[..] => Ok(()),
+            Some(_) => Err(Error::HandshakeAuth(
+                "certificate differs from the one presented in TLS",
+            )),
+            None => Err(Error::HandshakeAuth("no certificate presented in TLS")),
+        }
+    })
+}
+
+/// Inform the user about the peer presenting an invalid handshake and drop the connection to it.
+fn reject_handshake(ctx: &Ctx, peer_addr: SocketAddr, e: &Error) {
+    ctx.with_mut(|c| {
+        info!("Rejecting handshake from peer {}: {}", peer_addr, e);
+        let event = Event::ProtocolViolation {
+            peer_addr,
+            reason: e.to_string(),
+        };
+        if let Err(e) = c.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+        let _ = c.close_connection(&peer_addr, CloseReason::ProtocolViolation);
+    });
+    reputation::penalise(ctx, peer_addr, Violation::ProtocolViolation);
+}
+
+fn handle_rx_cert(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    peer_cert_der: Vec<u8>,
+    user_data: Option<bytes::Bytes>,
+    channels: Vec<u8>,
+    ordered_delivery: bool,
+) {
     let node_info = NodeInfo {
         peer_addr,
         peer_cert_der,
     };
 
-    let reverse_connect_to_peer = ctx_mut(|c| {
+    let reverse_connect_to_peer = ctx.with_mut(|c| {
         // FIXME: Dropping the connection most probably will not drop the incoming stream
         // and then if you get a message on it you might still end up here without an entry
         // for the peer in your connection map. Fix by finding out the best way to drop the
@@ -346,7 +1196,14 @@ fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
         };
 
         match conn.to_peer {
-            ToPeer::NoConnection => true,
+            ToPeer::NoConnection => {
+                conn.peer_handshake_rxd = true;
+                conn.peer_user_data = user_data;
+                conn.peer_channels = Some(channels);
+                conn.peer_ordered_delivery = Some(ordered_delivery);
+                conn.peer_kind = Some(PeerKind::Node);
+                true
+            }
             ToPeer::NotNeeded => {
                 info!(
                     "TODO received a Node handshake from someone who has introduced oneself \
@@ -356,14 +1213,94 @@ fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
             }
             ToPeer::Initiated {
                 ref peer_cert_der, ..
+            } => {
+                if *peer_cert_der != node_info.peer_cert_der {
+                    info!(
+                        "TODO Certificate we have for the peer ({}) already doesn't match with \
+                         the one given ({}) - we should disconnect to such peers - something \
+                         fishy going on.",
+                        utils::cert_fingerprint(peer_cert_der),
+                        node_info.fingerprint()
+                    );
+                }
+                conn.peer_handshake_rxd = true;
+                conn.peer_user_data = user_data;
+                conn.peer_channels = Some(channels);
+                conn.peer_ordered_delivery = Some(ordered_delivery);
+                conn.peer_kind = Some(PeerKind::Node);
+                false
             }
-            | ToPeer::Established {
-                ref peer_cert_der, ..
+            ToPeer::Established {
+                ref peer_cert_der,
+                ref q_conn,
             } => {
                 if *peer_cert_der != node_info.peer_cert_der {
-                    info!("TODO Certificate we have for the peer already doesn't match with \
-                        the one given - we should disconnect to such peers - something fishy going \
-                        on.");
+                    info!(
+                        "TODO Certificate we have for the peer ({}) already doesn't match with \
+                         the one given ({}) - we should disconnect to such peers - something \
+                         fishy going on.",
+                        utils::cert_fingerprint(peer_cert_der),
+                        node_info.fingerprint()
+                    );
+                }
+                conn.peer_handshake_rxd = true;
+                conn.peer_user_data = user_data.clone();
+                conn.peer_channels = Some(channels);
+                conn.peer_ordered_delivery = Some(ordered_delivery);
+                conn.peer_kind = Some(PeerKind::Node);
+
+                if conn.we_contacted_peer {
+                    c.bootstrap_cache.add_peer(node_info.clone());
+                }
+
+                // We had connected to the peer first and it has now connected back to us - we
+                // are now fully connected.
+                let timings = conn.connect_timings();
+                if let FromPeer::Established {
+                    ref mut pending_reads,
+                    ..
+                } = conn.from_peer
+                {
+                    let bootstrap_elapsed = conn
+                        .bootstrap_group_ref
+                        .take()
+                        .and_then(|group_ref| group_ref.succeed());
+                    let event = if let Some(elapsed) = bootstrap_elapsed {
+                        Event::BootstrappedTo {
+                            node: node_info.clone(),
+                            user_data,
+                            elapsed,
+                            timings,
+                        }
+                    } else {
+                        Event::ConnectedTo {
+                            peer: node_info.clone().into(),
+                            user_data,
+                            timings,
+                        }
+                    };
+
+                    c.lifetime_stats.record_peer_connected();
+                    if let Err(e) = c.event_tx.send(event) {
+                        info!("ERROR in informing user about a new peer: {:?} - {}", e, e);
+                    }
+
+                    let peer = Peer::Node {
+                        node_info: node_info.clone(),
+                    };
+                    for pending_read in pending_reads.drain(..) {
+                        dispatch_wire_msg(
+                            ctx,
+                            peer.clone(),
+                            q_conn,
+                            c.our_ext_addr_tx.take(),
+                            &c.event_tx,
+                            pending_read,
+                            &mut c.bootstrap_cache,
+                            conn.we_contacted_peer,
+                            c.echo_service,
+                        );
+                    }
                 }
                 false
             }
@@ -371,7 +1308,7 @@ fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
     });
 
     if reverse_connect_to_peer {
-        if let Err(e) = connect::connect_to(node_info, None, None) {
+        if let Err(e) = connect::connect_to(ctx, node_info, None, None) {
             debug!(
                 "ERROR: Could not reverse connect to peer {}: {}",
                 peer_addr, e
@@ -380,19 +1317,98 @@ fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
     }
 }
 
+/// Check the network the peer claims to belong to. Peers from other networks are disconnected and
+/// forgotten. Only called once the peer has authenticated, so nobody can have a peer purged from
+/// our bootstrap cache by claiming its address.
+fn is_from_foreign_network(ctx: &Ctx, peer_addr: SocketAddr, their_network_id: &str) -> bool {
+    ctx.with_mut(|c| {
+        if c.network_id == their_network_id {
+            return false;
+        }
+
+        info!(
+            "Peer {} belongs to a different network ({:?}, ours is {:?}) - rejecting it.",
+            peer_addr, their_network_id, c.network_id
+        );
+        let _ = c.close_connection(&peer_addr, CloseReason::Refused);
+        c.bootstrap_cache.remove_peer(&peer_addr);
+
+        true
+    })
+}
+
 fn handle_user_msg(
     peer: Peer,
-    event_tx: &Sender<Event>,
+    event_tx: &EventTx,
     msg: bytes::Bytes,
+    msg_id: Option<u64>,
+    in_reply_to: Option<u64>,
+    channel: u8,
     bootstrap_cache: &mut BootstrapCache,
     we_contacted_peer: bool,
 ) {
-    let peer_addr = peer.peer_addr();
-    let new_msg = Event::NewMessage { peer_addr, msg };
+    let new_msg = Event::NewMessage {
+        peer: peer.clone(),
+        msg,
+        msg_id,
+        in_reply_to,
+        channel,
+    };
     if let Err(e) = event_tx.send(new_msg) {
         info!("Could not dispatch incoming user message: {:?}", e);
     }
 
+    cache_node_we_contacted(peer, bootstrap_cache, we_contacted_peer);
+}
+
+/// Decode the message with the codec the user registered and hand it over as is if there's none
+/// or it can't be decoded.
+fn handle_typed_user_msg(
+    peer: Peer,
+    event_tx: &EventTx,
+    msg: bytes::Bytes,
+    bootstrap_cache: &mut BootstrapCache,
+    we_contacted_peer: bool,
+) {
+    #[cfg(feature = "codec")]
+    {
+        match event_tx.decode(&msg) {
+            Some(Ok(decoded)) => {
+                let new_msg = Event::NewTypedMessage {
+                    peer: peer.clone(),
+                    msg: decoded,
+                };
+                if let Err(e) = event_tx.send(new_msg) {
+                    info!("Could not dispatch incoming user message: {:?}", e);
+                }
+                return cache_node_we_contacted(peer, bootstrap_cache, we_contacted_peer);
+            }
+            Some(Err(e)) => info!(
+                "Could not decode typed message from peer {} - handing it over as is: {}",
+                peer.peer_addr(),
+                e
+            ),
+            None => (),
+        }
+    }
+
+    handle_user_msg(
+        peer,
+        event_tx,
+        msg,
+        None,
+        None,
+        DEFAULT_CHANNEL,
+        bootstrap_cache,
+        we_contacted_peer,
+    )
+}
+
+fn cache_node_we_contacted(
+    peer: Peer,
+    bootstrap_cache: &mut BootstrapCache,
+    we_contacted_peer: bool,
+) {
     if let Peer::Node { node_info } = peer {
         if we_contacted_peer {
             bootstrap_cache.add_peer(node_info);
@@ -400,17 +1416,344 @@ fn handle_user_msg(
     }
 }
 
-fn handle_echo_req(peer_addr: SocketAddr, q_conn: &QConn) {
+/// Respond over whichever connection the peer has made to us, even if it hasn't introduced itself
+/// yet.
+fn handle_health_check_req(ctx: &Ctx, peer_addr: SocketAddr, req_sent_at_msec: u64) {
+    ctx.with(|c| {
+        let conn = match c.connections.get(&peer_addr) {
+            Some(conn) => conn,
+            None => return trace!("Rxd health check from someone we don't know: {}", peer_addr),
+        };
+        match (&conn.from_peer, &conn.to_peer) {
+            (FromPeer::Established { q_conn, .. }, _) | (_, ToPeer::Established { q_conn, .. }) => {
+                let resp = WireMsg::HealthCheckResp {
+                    req_sent_at_msec,
+                    sent_at_msec: clock::unix_time_msec(&c.clock),
+                };
+                write_to_peer_connection(ctx, peer_addr, q_conn, resp)
+            }
+            _ => debug!(
+                "Peer {} is in invalid state {:?} to respond to its health check",
+                peer_addr, conn.to_peer
+            ),
+        }
+    })
+}
+
+/// What an echo service (see `Config::echo_service`) sends back for the message: each user message
+/// prefixed with `ECHO_MARKER`, in reply to the original if it has an id. Echoes aren't echoed
+/// again, so two echo services can't bounce a message between each other forever.
+fn echo_of(wire_msg: &WireMsg) -> Option<WireMsg> {
+    let mark = |msg: &bytes::Bytes| {
+        if msg.starts_with(ECHO_MARKER) {
+            return None;
+        }
+        let mut echo = Vec::with_capacity(ECHO_MARKER.len() + msg.len());
+        echo.extend_from_slice(ECHO_MARKER);
+        echo.extend_from_slice(msg);
+        Some(bytes::Bytes::from(echo))
+    };
+
+    match *wire_msg {
+        WireMsg::UserMsg(ref msg) | WireMsg::TypedUserMsg(ref msg) => {
+            mark(msg).map(WireMsg::UserMsg)
+        }
+        WireMsg::UserMsgEnvelope {
+            ref msg,
+            msg_id,
+            channel,
+            ..
+        } => Some(WireMsg::UserMsgEnvelope {
+            msg: mark(msg)?,
+            msg_id: None,
+            in_reply_to: msg_id,
+            channel,
+        }),
+        WireMsg::UserMsgBatch(ref msgs) => {
+            let echoes: Vec<_> = msgs.iter().filter_map(mark).collect();
+            if echoes.is_empty() {
+                None
+            } else {
+                Some(WireMsg::UserMsgBatch(echoes))
+            }
+        }
+        _ => None,
+    }
+}
+
+fn handle_echo_req(ctx: &Ctx, peer_addr: SocketAddr, q_conn: &QConn) {
     let msg = WireMsg::EndpointEchoResp(peer_addr);
-    write_to_peer_connection(peer_addr, q_conn, msg);
+    write_to_peer_connection(ctx, peer_addr, q_conn, msg);
 }
 
-fn handle_echo_resp(our_ext_addr: SocketAddr, inform_tx: Option<Sender<SocketAddr>>) {
+fn handle_echo_resp(ctx: &Ctx, our_ext_addr: SocketAddr, inform_tx: Option<Sender<SocketAddr>>) {
     if let Some(tx) = inform_tx {
         if let Err(e) = tx.send(our_ext_addr) {
             info!("Error informing endpoint echo service response: {:?}", e);
         }
     }
+
+    // We are called with the `Context` already borrowed so note our address once it's released
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        ctx.with(|c| match c.external_port {
+            Some(port) if port != our_ext_addr.port() => warn!(
+                "Echo service saw us on port {} rather than our external port {}, is the port \
+                 forwarded?",
+                our_ext_addr.port(),
+                port
+            ),
+            _ => (),
+        });
+        set_our_addr(ctx, our_ext_addr);
+        Ok(())
+    }));
+}
+
+fn handle_get_contacts(ctx: &Ctx, requester: SocketAddr) {
+    // We are called with the `Context` already borrowed so respond once it's released
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        let contacts = ctx.with_mut(|c| {
+            let now = c.clock.now();
+            let min_interval = Duration::from_secs(c.contacts_request_interval_sec);
+            if !note_request(&mut c.contacts_shared_at, requester, min_interval, now) {
+                return None;
+            }
+
+            // Most recently validated contacts first
+            Some(
+                c.bootstrap_cache
+                    .peers()
+                    .iter()
+                    .rev()
+                    .filter(|node_info| node_info.peer_addr != requester)
+                    .take(c.max_contacts_to_share)
+                    .cloned()
+                    .collect(),
+            )
+        });
+
+        match contacts {
+            Some(contacts) => write_to_peer(ctx, requester, WireMsg::Contacts(contacts)),
+            None => debug!(
+                "Ignoring too frequent request for contacts from peer {}",
+                requester
+            ),
+        }
+
+        Ok(())
+    }));
+}
+
+fn handle_contacts(ctx: &Ctx, peer_addr: SocketAddr, contacts: Vec<NodeInfo>) {
+    // We are called with the `Context` already borrowed so take them in once it's released
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        let was_requested = ctx.with_mut(|c| {
+            if !c.contacts_requested_from.remove(&peer_addr) {
+                return false;
+            }
+            let event = Event::ContactsReceived {
+                peer_addr,
+                contacts,
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+            true
+        });
+
+        if !was_requested {
+            debug!(
+                "Ignoring contacts peer {} sent without being asked for them",
+                peer_addr
+            );
+            reputation::penalise(ctx, peer_addr, Violation::ProtocolViolation);
+        }
+
+        Ok(())
+    }));
+}
+
+/// Note the request the peer makes unless it made one within `min_interval` already, in which case
+/// `false` is returned. Requests made longer ago are forgotten on the way, so `requested_at` only
+/// ever holds the peers which asked within the last `min_interval`.
+fn note_request(
+    requested_at: &mut HashMap<SocketAddr, Instant>,
+    requester: SocketAddr,
+    min_interval: Duration,
+    now: Instant,
+) -> bool {
+    requested_at.retain(|_, at| now.duration_since(*at) < min_interval);
+    if requested_at.contains_key(&requester) {
+        return false;
+    }
+    let _ = requested_at.insert(requester, now);
+    true
+}
+
+fn handle_reverse_connect(ctx: &Ctx, requester: SocketAddr, target_info: NodeInfo) {
+    // We are called with the `Context` already borrowed so connect once it's released
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        let target_addr = target_info.peer_addr;
+        trace!("Peer {} asked us to connect to {}", requester, target_addr);
+
+        let (is_too_frequent, is_known_target) = ctx.with_mut(|c| {
+            let now = c.clock.now();
+            let min_interval = Duration::from_secs(c.reverse_connect_interval_sec);
+            (
+                !note_request(&mut c.reverse_connects_at, requester, min_interval, now),
+                is_known_node(c, &target_info),
+            )
+        });
+        if is_too_frequent {
+            debug!(
+                "Ignoring too frequent request to connect to {} from peer {}",
+                target_addr, requester
+            );
+            return Ok(());
+        }
+        if !is_known_target {
+            debug!(
+                "Refusing to connect to {} on behalf of {} as we don't know it",
+                target_addr, requester
+            );
+            report_reverse_connect_result(ctx, vec![requester], target_addr, false);
+            return Ok(());
+        }
+
+        if let Err(e) = connect::connect_to(ctx, target_info, None, None) {
+            debug!(
+                "Could not connect to {} on behalf of {}: {}",
+                target_addr, requester, e
+            );
+        }
+
+        // Either the outcome is known already or we wait for the connection attempt to finish
+        let success = ctx.with_mut(|c| {
+            let conn = match c.connections.get_mut(&target_addr) {
+                Some(conn) => conn,
+                None => return Some(false),
+            };
+            match conn.to_peer {
+                ToPeer::Initiated { .. } => {
+                    conn.reverse_connect_requesters.push(requester);
+                    None
+                }
+                ToPeer::Established { .. } => Some(true),
+                ToPeer::NoConnection | ToPeer::NotNeeded => Some(false),
+            }
+        });
+        if let Some(success) = success {
+            report_reverse_connect_result(ctx, vec![requester], target_addr, success);
+        }
+
+        Ok(())
+    }));
+}
+
+/// Whether the node is one we'd connect to of our own accord: it's in our bootstrap cache, one of
+/// our hard coded contacts, or we are connected to it already. We don't dial arbitrary addresses
+/// on behalf of peers.
+fn is_known_node(c: &Context, node_info: &NodeInfo) -> bool {
+    if c.bootstrap_cache.peers().contains(node_info)
+        || c.bootstrap_cache.hard_coded_contacts().contains(node_info)
+    {
+        return true;
+    }
+
+    c.connections
+        .get(&node_info.peer_addr)
+        .map_or(false, |conn| match conn.to_peer {
+            ToPeer::Initiated {
+                ref peer_cert_der, ..
+            }
+            | ToPeer::Established {
+                ref peer_cert_der, ..
+            } => *peer_cert_der == node_info.peer_cert_der,
+            ToPeer::NoConnection | ToPeer::NotNeeded => {
+                conn.peer_kind == Some(PeerKind::Node) && conn.from_peer.is_established()
+            }
+        })
+}
+
+/// Let the peers which asked us to connect to `target_addr` know how it went. This must not be
+/// called while the `Context` is already borrowed.
+pub fn report_reverse_connect_result(
+    ctx: &Ctx,
+    requesters: Vec<SocketAddr>,
+    target_addr: SocketAddr,
+    success: bool,
+) {
+    for requester in requesters {
+        write_to_peer(
+            ctx,
+            requester,
+            WireMsg::ReverseConnectResult {
+                target_addr,
+                success,
+            },
+        );
+    }
+}
+
+/// Find out our connection info in the background. It's cached and `Event::OurConnectionInfoReady`
+/// is fired once done.
+pub fn resolve_our_connection_info(ctx: &Ctx) {
+    if let Some(our_addr) = ctx.with(|c| {
+        c.external_connection_addr()
+            .or_else(|| c.observed_addrs.consensus())
+    }) {
+        ctx.with_mut(|c| c.our_connection_info_requested = true);
+        return set_our_addr(ctx, our_addr);
+    }
+
+    let echo_server = ctx.with_mut(|c| {
+        c.our_connection_info_requested = true;
+        c.bootstrap_cache
+            .hard_coded_contacts()
+            .iter()
+            .next()
+            .cloned()
+    });
+
+    // FIXME: Just like the blocking version we ask only one peer just now
+    if let Some(node_info) = echo_server {
+        return try_write_to_peer(ctx, node_info.into(), WireMsg::EndpointEchoReq);
+    }
+
+    match ctx.with(|c| c.quic_ep().local_addr()) {
+        Ok(addr) if !addr.ip().is_unspecified() => set_our_addr(ctx, addr),
+        Ok(addr) => info!(
+            "Cannot resolve our connection info: there's no echo server to ask and we are bound \
+             to an unspecified address {}",
+            addr
+        ),
+        Err(e) => info!("Could not obtain our local address: {:?} - {}", e, e),
+    }
+}
+
+/// Cache our connection info for the given address, informing the user if they are waiting for it.
+fn set_our_addr(ctx: &Ctx, our_addr: SocketAddr) {
+    ctx.with_mut(|c| {
+        let node_info = NodeInfo {
+            peer_addr: c.with_external_port(our_addr),
+            peer_cert_der: c.our_complete_cert.cert_der.clone(),
+        };
+        c.our_connection_info = Some(node_info.clone());
+
+        if mem::replace(&mut c.our_connection_info_requested, false) {
+            if let Err(e) = c.event_tx.send(Event::OurConnectionInfoReady { node_info }) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+    })
 }
 
 #[cfg(test)]
@@ -424,7 +1767,8 @@ mod tests {
 
         #[test]
         fn when_peer_is_node_and_we_contacted_it_before_it_is_moved_to_bootstrap_cache_top() {
-            let (event_tx, _event_rx) = mpsc::channel();
+            let (tx, _event_rx) = mpsc::channel();
+            let event_tx = EventTx::new(tx, Default::default(), Default::default());
             let peer1 = rand_node_info();
             let peer2 = rand_node_info();
             let peer = Peer::Node {
@@ -439,6 +1783,9 @@ mod tests {
                 peer,
                 &event_tx,
                 bytes::Bytes::from(vec![]),
+                None,
+                None,
+                DEFAULT_CHANNEL,
                 &mut bootstrap_cache,
                 true,
             );
@@ -447,4 +1794,80 @@ mod tests {
             assert_eq!(cached_peers, vec![peer2, peer1]);
         }
     }
+
+    mod note_request {
+        use super::*;
+
+        #[test]
+        fn requests_within_the_interval_are_refused_and_older_ones_forgotten() {
+            let mut requested_at = HashMap::new();
+            let interval = Duration::from_secs(10);
+            let peer1 = rand_node_info().peer_addr;
+            let peer2 = rand_node_info().peer_addr;
+            let start = Instant::now();
+
+            assert!(note_request(&mut requested_at, peer1, interval, start));
+            assert!(!note_request(
+                &mut requested_at,
+                peer1,
+                interval,
+                start + interval / 2
+            ));
+
+            let later = start + interval;
+            assert!(note_request(&mut requested_at, peer2, interval, later));
+            assert!(!requested_at.contains_key(&peer1));
+            assert!(note_request(&mut requested_at, peer1, interval, later));
+        }
+    }
+
+    mod echo_of {
+        use super::*;
+
+        fn marked(msg: &[u8]) -> bytes::Bytes {
+            bytes::Bytes::from([ECHO_MARKER, msg].concat())
+        }
+
+        #[test]
+        fn user_messages_are_echoed_in_reply_to_them() {
+            match echo_of(&WireMsg::UserMsg(bytes::Bytes::from(vec![1, 2]))) {
+                Some(WireMsg::UserMsg(msg)) => assert_eq!(msg, marked(&[1, 2])),
+                x => panic!("Unexpected echo: {:?}", x),
+            }
+
+            let envelope = WireMsg::UserMsgEnvelope {
+                msg: bytes::Bytes::from(vec![3]),
+                msg_id: Some(7),
+                in_reply_to: None,
+                channel: 2,
+            };
+            match echo_of(&envelope) {
+                Some(WireMsg::UserMsgEnvelope {
+                    msg,
+                    msg_id,
+                    in_reply_to,
+                    channel,
+                }) => {
+                    assert_eq!(msg, marked(&[3]));
+                    assert_eq!(msg_id, None);
+                    assert_eq!(in_reply_to, Some(7));
+                    assert_eq!(channel, 2);
+                }
+                x => panic!("Unexpected echo: {:?}", x),
+            }
+
+            assert!(echo_of(&WireMsg::EndpointEchoReq).is_none());
+        }
+
+        #[test]
+        fn echoes_are_not_echoed_again() {
+            assert!(echo_of(&WireMsg::UserMsg(marked(&[1]))).is_none());
+
+            let batch = WireMsg::UserMsgBatch(vec![marked(&[1]), bytes::Bytes::from(vec![2])]);
+            match echo_of(&batch) {
+                Some(WireMsg::UserMsgBatch(msgs)) => assert_eq!(msgs, vec![marked(&[2])]),
+                x => panic!("Unexpected echo: {:?}", x),
+            }
+        }
+    }
 }
@@ -9,22 +9,53 @@
 
 use crate::dirs::Dirs;
 use crate::error::Error;
+use crate::event::EventVerbosity;
 use crate::utils;
-use crate::{NodeInfo, R};
+use crate::{Contact, NodeInfo, DEFAULT_SERVER_NAME, R};
 use std::collections::HashSet;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::{fmt, fs, io};
 
 /// QuicP2p configurations
-#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+///
+/// Fields missing when it's deserialised, e.g. from config files written before they were added,
+/// take their values from `Config::default`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default)]
 pub struct Config {
-    /// Hard Coded contacts
-    pub hard_coded_contacts: HashSet<NodeInfo>,
+    /// Hard Coded contacts, given by IP address or by hostname, see `NodeAddr`. Every address a
+    /// hostname resolves to is dialled.
+    pub hard_coded_contacts: HashSet<Contact>,
+    /// Time in seconds what the hard coded contacts given by hostname resolved to is cached for.
+    /// They are resolved again sooner if dialling one of their addresses fails. If none supplied
+    /// we'll default to the documented constant.
+    pub dns_cache_ttl_sec: Option<u64>,
+    /// Directory our bootstrap cache is kept in. If none supplied the platform's cache directory
+    /// is used. If `our_complete_cert` is supplied as well, the cache file is named after it, so
+    /// several instances on one host can share the directory without trampling each other's
+    /// caches.
+    pub bootstrap_cache_dir: Option<PathBuf>,
     /// Port we want to reserve for QUIC. If none supplied we'll use the OS given random port.
     pub port: Option<u16>,
-    /// IP address for the listener. If none supplied we'll use the default address (0.0.0.0).
-    pub ip: Option<IpAddr>,
+    /// If the port asked for is taken, e.g. by another instance on the same host, try the ports
+    /// right after it and then a random one rather than failing. The port we end up on is in
+    /// `Event::ListenerStarted` and our connection info.
+    pub port_fallback: bool,
+    /// IP address we bind to. If none supplied we'll bind to all the interfaces (0.0.0.0). This
+    /// isn't necessarily the address peers reach us at, see `external_addr`.
+    #[serde(alias = "ip")]
+    pub bind_addr: Option<IpAddr>,
+    /// IP address peers reach us at, e.g. our public IP when we are bound to all the interfaces or
+    /// behind a port-forwarding NAT. It's advertised in our connection info along with our port,
+    /// without asking echo services or the peers how they see us. If none supplied it's
+    /// discovered.
+    pub external_addr: Option<IpAddr>,
+    /// Port peers reach us at when it isn't the one we are bound to, e.g. with the router
+    /// forwarding a port of its own to `port`. It's advertised in our connection info whichever
+    /// way our address is found, and we warn if the echo service saw us on another one. If none
+    /// supplied the port we are bound to is advertised.
+    pub external_port: Option<u16>,
     /// This is the maximum message size we'll allow the peer to send to us. Any bigger message and
     /// we'll error out probably shutting down the connection to the peer. If none supplied we'll
     /// default to the documented constant.
@@ -35,14 +66,296 @@ pub struct Config {
     /// The interval is in milliseconds. A value of 0 disables this feature.
     pub idle_timeout_msec: Option<u64>,
     /// Interval to send keep-alives if we are idling so that the peer does not disconnect from us
-    /// declaring us offline. If none is supplied we'll default to the documented constant.
+    /// declaring us offline. If none is supplied we'll default to the one of `traffic_profile`.
     ///
     /// The interval is in milliseconds. A value of 0 disables this feature.
     pub keep_alive_interval_msec: Option<u32>,
-    /// Path to our TLS Certificate. This file must contain `SerialisableCertificate` as content
+    /// Number of bytes the peer may send on a single stream before we have read them. Raise it to
+    /// let big messages make full use of fast links with high latency, at the cost of memory. If
+    /// none supplied we'll default to the one of `traffic_profile`.
+    pub stream_receive_window: Option<u64>,
+    /// Number of bytes the peer may send on all the streams of a connection together before we
+    /// have read them. It's never taken to be smaller than `stream_receive_window`. If none
+    /// supplied we'll default to the one of `traffic_profile`.
+    pub connection_receive_window: Option<u64>,
+    /// Path to our TLS Certificate. This file must contain `SerialisableCertificate` as content.
+    /// The key must be an ECDSA P-256 or an Ed25519 one as it's also used to sign our handshakes.
     pub our_complete_cert: Option<SerialisableCertificate>,
     /// Specify if we are a client or a node
     pub our_type: OurType,
+    /// Whether our endpoint accepts connections at all. Clients which must never listen, e.g. on
+    /// mobile or behind strict firewalls, can turn this off to only ever connect out. Nodes have to
+    /// listen so turning it off for them is a configuration error. Defaults to `true`.
+    pub listen: bool,
+    /// Whether nodes authenticate themselves in TLS when connecting to each other, rather than
+    /// only the side being connected to. The certificate a node then claims in its handshake must
+    /// be the one it presented in TLS, or the connection is rejected. Every node of the network
+    /// should turn this on together, as nodes with it off present no certificate. Clients are
+    /// unaffected.
+    pub mutual_tls: bool,
+    /// Name the certificates we generate are issued for, which we ask for via SNI when connecting
+    /// and expect of the peers connecting to us. Connections asking for another name are refused,
+    /// so independent networks built on this crate can tell themselves apart in TLS already. A
+    /// supplied `our_complete_cert` must be issued for it too. Every node of the network should use
+    /// the same one. If none supplied we'll default to the documented constant.
+    pub server_name: Option<String>,
+    /// Name of the network we belong to. Peers presenting a different name in their handshake are
+    /// rejected and purged from our bootstrap cache. This prevents e.g. test networks from
+    /// polluting the caches of production ones.
+    pub network_id: String,
+    /// If set, failed connections to nodes are re-established automatically and the messages the
+    /// peer had not acknowledged yet are sent again. If none supplied connections are not retried.
+    pub auto_reconnect: Option<RetryPolicy>,
+    /// If set, dials to an address are refused for a while after they fail, the while doubling
+    /// with every consecutive failure. This keeps e.g. upper layers calling `connect_to` in a loop
+    /// from flooding an unreachable address. If none supplied dials are never held back.
+    pub dial_backoff: Option<DialBackoffConfig>,
+    /// Maximum number of bytes we'll buffer on behalf of a single peer (pending sends, pending
+    /// reads and unacknowledged messages). Any more and we'll drop the connection to the peer. If
+    /// none supplied there's no limit.
+    pub per_peer_buffer_limit: Option<u64>,
+    /// Relay all our traffic through this SOCKS5 proxy instead of talking to the peers directly,
+    /// for networks that only let it out that way. Can't be combined with
+    /// `Builder::with_transport`.
+    pub upstream_proxy: Option<ProxyConfig>,
+    /// Peers are always scored for misbehaviour (see `QuicP2p::peer_score`). If set, offenders are
+    /// also throttled and eventually blacklisted as per the given thresholds. If none supplied no
+    /// action is taken against them.
+    pub reputation: Option<ReputationConfig>,
+    /// Maximum number of contacts from our bootstrap cache we share with a peer asking for them.
+    /// If none supplied we'll default to the documented constant.
+    pub max_contacts_to_share: Option<u32>,
+    /// Minimum interval, in seconds, between two requests for contacts from the same peer. More
+    /// frequent requests are ignored. If none supplied we'll default to the documented constant.
+    pub contacts_request_interval_sec: Option<u64>,
+    /// Minimum interval, in seconds, between two requests from the same peer to connect to a node
+    /// on its behalf. More frequent requests are ignored. If none supplied we'll default to the
+    /// documented constant.
+    pub reverse_connect_interval_sec: Option<u64>,
+    /// Time each proxy gets to connect to us when bootstrapping. Proxies which don't are given up
+    /// on, so a few slow or unreachable ones don't hold up `Event::BootstrapFailure`. If none
+    /// supplied they get as long as any other connect.
+    ///
+    /// The budget is in milliseconds.
+    pub bootstrap_member_budget_msec: Option<u64>,
+    /// Time the other proxies still connecting get once we have bootstrapped to one, e.g. to
+    /// compare their latencies via `Event::BootstrappedTo`. If none supplied they are given up on
+    /// straight away.
+    ///
+    /// The grace period is in milliseconds.
+    pub bootstrap_grace_msec: Option<u64>,
+    /// Maximum number of outgoing connections being set up simultaneously. Any more connects are
+    /// queued and started, in order, as the ones in flight complete. If none supplied there's no
+    /// limit.
+    pub max_concurrent_connects: Option<u32>,
+    /// Maximum number of peers we have connections with or are connecting to, whichever way
+    /// round. What happens to new ones beyond it is up to `admission_policy`, and
+    /// `Event::ConnectionLimitReached` is fired either way. If none supplied there's no limit.
+    pub max_total_connections: Option<u32>,
+    /// How new connections are admitted once we are at `max_total_connections`.
+    pub admission_policy: AdmissionPolicy,
+    /// If set, messages to a node which has connected to us are sent over that connection until
+    /// our own connection to it is established, instead of waiting for the latter.
+    pub send_over_incoming_connections: bool,
+    /// If set, the peers in our bootstrap cache are periodically checked for reachability and the
+    /// persistently unreachable ones are evicted. If none supplied no checks are made.
+    pub cache_health_check: Option<CacheHealthCheckConfig>,
+    /// If set, connections stuck half way through being set up are periodically swept away, in
+    /// case e.g. their connect never concluded. If none supplied no sweeps are made.
+    pub stale_conn_reaper: Option<StaleConnReaperConfig>,
+    /// If set, user messages from peers are handed over in the order they were sent in, rather
+    /// than as soon as they arrive. Each message goes on a stream of its own, so they can overtake
+    /// each other on the way. Peers are asked in our handshake to sequence the messages they send
+    /// us, so this only takes effect for the ones with it set as well. If none supplied messages
+    /// are handed over as they arrive.
+    pub ordered_delivery: Option<OrderedDeliveryConfig>,
+    /// If set, user messages replayed after reconnecting are tagged so that the peers which had
+    /// received them before can tell, and the replays from peers which received ours before are
+    /// dropped rather than handed over again. Only messages tracked for replay are tagged, so it
+    /// takes `auto_reconnect` to be set as well for the messages we send. If none supplied nothing
+    /// is tagged or dropped.
+    pub dedup: Option<DedupConfig>,
+    /// If set, connected peers we haven't heard from for this long are reported via
+    /// `Event::PeerUnresponsive`, and via `Event::PeerResponsive` once they are heard from again.
+    /// Peers quiet for half of it are sent a health check to answer. If none supplied no checks
+    /// are made.
+    ///
+    /// The time is in milliseconds.
+    pub unresponsive_peer_msec: Option<u64>,
+    /// If set, the backlog of what we have yet to hand over to the application and what it has
+    /// yet to take off us is watched, with its changes reported via `Event::Backpressure`. If none
+    /// supplied no checks are made.
+    pub backpressure: Option<BackpressureConfig>,
+    /// Time changes to our bootstrap cache are collected for before being reported in one
+    /// `Event::BootstrapCacheUpdated`. If none supplied we'll default to the documented constant.
+    ///
+    /// The interval is in milliseconds.
+    pub bootstrap_cache_update_debounce_msec: Option<u64>,
+    /// Interval, in seconds, at which `QuicP2p::lifetime_stats` are saved next to our bootstrap
+    /// cache, and once more on shutdown. They carry on from the saved ones after a restart. If
+    /// none supplied they are kept in memory only and start afresh each time.
+    pub lifetime_stats_snapshot_sec: Option<u64>,
+    /// Number of bytes written to a peer in one go before the writes to other peers get their
+    /// turn. Smaller values keep small messages to other peers low-latency during big transfers.
+    /// If none supplied we'll default to the one of `traffic_profile`.
+    pub send_quantum_bytes: Option<u32>,
+    /// Bundle of defaults for the keep-alive interval, stream windows, send quantum and send rate,
+    /// suiting how we are used. It can be switched at runtime via `QuicP2p::reconfigure`.
+    pub traffic_profile: TrafficProfile,
+    /// Logical channels we accept user messages on besides the default one (see
+    /// `QuicP2p::send_on_channel`). These are announced to peers in our handshake and messages on
+    /// any other channel are dropped.
+    pub channels: Vec<u8>,
+    /// Time a peer gets to send us a whole message once it has started doing so. Streams stalling
+    /// beyond it are cancelled and the peer is penalised. If none supplied we'll default to the
+    /// documented constant.
+    ///
+    /// The timeout is in milliseconds. A value of 0 disables this feature.
+    pub read_timeout_msec: Option<u64>,
+    /// Time a peer gets to take a whole message from us once we have started sending it. Streams
+    /// stalling beyond it, e.g. because the peer stopped reading, are cancelled and
+    /// `Event::UnsentUserMessage` is fired for user messages. If none supplied we'll default to
+    /// the documented constant.
+    ///
+    /// The timeout is in milliseconds. A value of 0 disables this feature.
+    pub write_timeout_msec: Option<u64>,
+    /// Time a peer connecting to us gets to introduce itself via its handshake once the QUIC
+    /// connection is up. Connections of peers which don't are closed, so they can't hold on to a
+    /// slot in our connection table. If none supplied we'll default to the documented constant.
+    ///
+    /// The timeout is in milliseconds. A value of 0 disables this feature.
+    pub handshake_timeout_msec: Option<u64>,
+    /// Maximum number of incomplete messages we read from a single connection at a time. Streams
+    /// opened by the peer beyond it are refused and the peer is penalised. If none supplied we'll
+    /// default to the documented constant.
+    pub max_incomplete_reads: Option<u32>,
+    /// Number of streams accepted from a single connection in a row before connects, handshakes
+    /// and the other connections get their turn. Smaller values keep connects responsive while a
+    /// peer floods us with messages. If none supplied we'll default to the documented constant.
+    pub data_lane_budget: Option<u32>,
+    /// Application protocol identifiers offered and accepted via ALPN, most preferred first.
+    /// Connections which don't negotiate one of these are refused, which keeps e.g. different
+    /// protocol versions or tools apart. If empty ALPN is not used.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Messages to a client which isn't connected are held for this long in case it reconnects,
+    /// recognised by its certificate, and are delivered if it does. `Event::UnsentUserMessage` is
+    /// fired for them otherwise. If none supplied they are given up on straight away.
+    ///
+    /// The grace period is in milliseconds.
+    pub client_send_grace_msec: Option<u64>,
+    /// Options applied to our UDP socket, e.g. bigger buffers for high-throughput nodes. Ones the
+    /// platform doesn't support or the OS refuses are skipped with a warning.
+    pub socket_options: SocketOptions,
+    /// Parameters of the self-signed certificates we generate, i.e. when `our_complete_cert` isn't
+    /// supplied and when rotating our certificate.
+    pub cert_params: CertParams,
+    /// How long before our certificate expires `Event::CertificateExpiring` is fired, as peers
+    /// can't connect to us once it has. Only nodes are warned, as clients present no certificate.
+    /// If none supplied we'll default to the documented constant.
+    ///
+    /// The time is in seconds.
+    pub cert_expiry_warning_sec: Option<u64>,
+    /// Rotate our certificate to a freshly generated one (see `QuicP2p::rotate_certificate`) once
+    /// it's about to expire, right after firing `Event::CertificateExpiring`.
+    pub auto_rotate_cert: bool,
+    /// Whether the intermediate phases of connections (`Event::ConnectingTo`,
+    /// `Event::HandshakeCompleted` and `Event::StreamOpened`) are reported too, e.g. for
+    /// debugging slow connects.
+    pub event_verbosity: EventVerbosity,
+    /// Number of events each receiver from `QuicP2p::subscribe` can have queued up. Events beyond
+    /// it are dropped for that receiver only. If none supplied we'll default to the documented
+    /// constant.
+    pub event_subscriber_queue_len: Option<u32>,
+    /// What to do once the receiver of the events given to `Builder::new` is dropped, as then no
+    /// one is left to hear from us.
+    pub on_event_channel_closed: EventChannelClosedPolicy,
+    /// Send every user message received back to its sender, prefixed with `ECHO_MARKER` and in
+    /// reply to the original if that has an id. The messages are still reported as usual. This
+    /// lets black-box throughput and latency tests run against a remote deployment without any
+    /// code of their own on the remote end. Never turn it on for peers you don't trust, as it
+    /// doubles as a traffic amplifier.
+    pub echo_service: bool,
+    /// Treat the connection getting into a state it never should have, i.e. a bug on our side, as
+    /// an `Error::IllegalState`: it's logged and only the connection concerned is severed. If
+    /// unset such bugs panic, taking the whole event loop down, which is handier while developing.
+    pub strict: bool,
+    /// Length in bytes of the longest file we take from a peer sending it with
+    /// `QuicP2p::send_file`. Offers of longer ones are refused without asking the user. If none
+    /// supplied we'll default to the documented constant.
+    pub max_file_len: Option<u64>,
+    /// Number of files we are offered or are receiving at a time, counting the partial ones kept
+    /// from transfers cut short. Offers beyond it are refused without asking the user. If none
+    /// supplied we'll default to the documented constant.
+    pub max_incoming_files: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hard_coded_contacts: Default::default(),
+            dns_cache_ttl_sec: Default::default(),
+            bootstrap_cache_dir: Default::default(),
+            port: Default::default(),
+            port_fallback: Default::default(),
+            bind_addr: Default::default(),
+            external_addr: Default::default(),
+            external_port: Default::default(),
+            max_msg_size_allowed: Default::default(),
+            idle_timeout_msec: Default::default(),
+            keep_alive_interval_msec: Default::default(),
+            stream_receive_window: Default::default(),
+            connection_receive_window: Default::default(),
+            our_complete_cert: Default::default(),
+            our_type: Default::default(),
+            listen: true,
+            mutual_tls: Default::default(),
+            server_name: None,
+            network_id: Default::default(),
+            auto_reconnect: Default::default(),
+            dial_backoff: Default::default(),
+            per_peer_buffer_limit: Default::default(),
+            upstream_proxy: Default::default(),
+            reputation: Default::default(),
+            max_contacts_to_share: Default::default(),
+            contacts_request_interval_sec: Default::default(),
+            reverse_connect_interval_sec: Default::default(),
+            bootstrap_member_budget_msec: Default::default(),
+            bootstrap_grace_msec: Default::default(),
+            max_concurrent_connects: Default::default(),
+            max_total_connections: Default::default(),
+            admission_policy: Default::default(),
+            send_over_incoming_connections: Default::default(),
+            cache_health_check: Default::default(),
+            stale_conn_reaper: Default::default(),
+            ordered_delivery: Default::default(),
+            dedup: Default::default(),
+            unresponsive_peer_msec: Default::default(),
+            backpressure: Default::default(),
+            bootstrap_cache_update_debounce_msec: Default::default(),
+            lifetime_stats_snapshot_sec: Default::default(),
+            send_quantum_bytes: Default::default(),
+            traffic_profile: Default::default(),
+            channels: Default::default(),
+            read_timeout_msec: Default::default(),
+            write_timeout_msec: Default::default(),
+            handshake_timeout_msec: Default::default(),
+            max_incomplete_reads: Default::default(),
+            data_lane_budget: Default::default(),
+            alpn_protocols: Default::default(),
+            client_send_grace_msec: Default::default(),
+            socket_options: Default::default(),
+            cert_params: Default::default(),
+            cert_expiry_warning_sec: Default::default(),
+            auto_rotate_cert: Default::default(),
+            event_verbosity: Default::default(),
+            event_subscriber_queue_len: Default::default(),
+            on_event_channel_closed: Default::default(),
+            echo_service: Default::default(),
+            strict: Default::default(),
+            max_file_len: Default::default(),
+            max_incoming_files: Default::default(),
+        }
+    }
 }
 
 impl Config {
@@ -53,7 +366,11 @@ impl Config {
         let config_path = config_path(user_override)?;
 
         if config_path.exists() {
-            Ok(utils::read_from_disk(&config_path)?)
+            utils::read_from_disk(&config_path).or_else(|e| {
+                utils::read_from_disk::<BaselineConfig>(&config_path)
+                    .map(Config::from)
+                    .map_err(|_| e)
+            })
         } else {
             let config_dir = config_path
                 .parent()
@@ -79,6 +396,45 @@ impl Config {
     }
 }
 
+/// PKCS#8 (v1) encoding of an Ed25519 private key, up to the 32 byte seed which follows it.
+pub(crate) const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// Form of `Config` in the config files written by the first release. Those are in a binary format
+/// without field names, so the fields added since can't be told missing and defaulted by serde.
+#[derive(Deserialize)]
+struct BaselineConfig {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+}
+
+impl From<BaselineConfig> for Config {
+    fn from(baseline: BaselineConfig) -> Self {
+        Self {
+            hard_coded_contacts: baseline
+                .hard_coded_contacts
+                .into_iter()
+                .map(Contact::from)
+                .collect(),
+            port: baseline.port,
+            bind_addr: baseline.ip,
+            max_msg_size_allowed: baseline.max_msg_size_allowed,
+            idle_timeout_msec: baseline.idle_timeout_msec,
+            keep_alive_interval_msec: baseline.keep_alive_interval_msec,
+            our_complete_cert: baseline.our_complete_cert,
+            our_type: baseline.our_type,
+            ..Default::default()
+        }
+    }
+}
+
 /// To be used to read and write our certificate and private key to disk esp. as a part of our
 /// configuration file
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -95,11 +451,40 @@ impl SerialisableCertificate {
             unwrap!(quinn::Certificate::from_der(&self.cert_der)),
         )
     }
-}
 
-impl Default for SerialisableCertificate {
-    fn default() -> Self {
-        let cert = rcgen::generate_simple_self_signed(vec!["MaidSAFE.net".to_string()]);
+    /// Self-signed certificate with a fresh key, as described by the given parameters, issued for
+    /// `DEFAULT_SERVER_NAME`.
+    pub fn generate(cert_params: &CertParams) -> Self {
+        Self::generate_for(DEFAULT_SERVER_NAME, cert_params)
+    }
+
+    /// Self-signed certificate with a fresh key, as described by the given parameters, issued for
+    /// the given server name (see `Config::server_name`).
+    pub fn generate_for(server_name: &str, cert_params: &CertParams) -> Self {
+        let mut params = cert_params.to_rcgen(server_name);
+        params.alg = cert_params.key_type.alg();
+        let cert = rcgen::Certificate::from_params(params);
+
+        Self {
+            cert_der: cert.serialize_der(),
+            key_der: cert.serialize_private_key_der(),
+        }
+    }
+
+    /// Self-signed certificate with an Ed25519 key derived from `seed` alone, whatever the key type
+    /// in the given parameters. The certificate is the same for the same seed only if those don't
+    /// limit its validity, as that starts when it's generated.
+    pub(crate) fn from_seed(seed: [u8; 32], server_name: &str, cert_params: &CertParams) -> Self {
+        let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+        pkcs8.extend_from_slice(&seed);
+
+        let mut params = cert_params.to_rcgen(server_name);
+        params.alg = &rcgen::PKCS_ED25519;
+        params.key_pair = Some(unwrap!(rcgen::KeyPair::from_der(&pkcs8)));
+        let mut serial = [0; 8];
+        serial.copy_from_slice(&seed[..8]);
+        params.serial_number = Some(u64::from_be_bytes(serial));
+        let cert = rcgen::Certificate::from_params(params);
 
         Self {
             cert_der: cert.serialize_der(),
@@ -108,6 +493,12 @@ impl Default for SerialisableCertificate {
     }
 }
 
+impl Default for SerialisableCertificate {
+    fn default() -> Self {
+        Self::generate(&Default::default())
+    }
+}
+
 impl fmt::Debug for SerialisableCertificate {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -118,6 +509,316 @@ impl fmt::Debug for SerialisableCertificate {
     }
 }
 
+/// Type of the key of the certificates we generate.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum CertKeyType {
+    Ed25519,
+    /// ECDSA with the P-256 curve and SHA-256
+    EcdsaP256,
+}
+
+impl CertKeyType {
+    fn alg(self) -> &'static rcgen::SignatureAlgorithm {
+        match self {
+            CertKeyType::Ed25519 => &rcgen::PKCS_ED25519,
+            CertKeyType::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+        }
+    }
+}
+
+impl Default for CertKeyType {
+    fn default() -> Self {
+        CertKeyType::EcdsaP256
+    }
+}
+
+/// Parameters of the self-signed certificates we generate, e.g. to meet a deployment's policy or
+/// to test the handling of expired certificates.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct CertParams {
+    /// Number of days the certificate is valid for from when it's generated. If none supplied it
+    /// never practically expires.
+    pub validity_days: Option<u32>,
+    /// Common name of the subject. If none supplied a generic one is used.
+    pub common_name: Option<String>,
+    pub key_type: CertKeyType,
+}
+
+impl CertParams {
+    /// Certificate parameters for rcgen issued for the given server name, without the signature
+    /// algorithm.
+    fn to_rcgen(&self, server_name: &str) -> rcgen::CertificateParams {
+        let mut params = rcgen::CertificateParams::new(vec![server_name.to_string()]);
+        if let Some(ref common_name) = self.common_name {
+            let mut distinguished_name = rcgen::DistinguishedName::new();
+            distinguished_name.push(rcgen::DnType::CommonName, common_name.clone());
+            params.distinguished_name = distinguished_name;
+        }
+        if let Some(validity_days) = self.validity_days {
+            let now = chrono::Utc::now();
+            params.not_before = now;
+            params.not_after = now + chrono::Duration::days(i64::from(validity_days));
+        }
+        params
+    }
+}
+
+/// How failed connections to nodes are to be re-established.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Consecutive reconnect attempts after which we give up on the peer
+    pub max_attempts: u32,
+    /// Delay before each reconnect attempt in milliseconds
+    pub retry_delay_msec: u64,
+    /// Maximum number of unacknowledged user messages buffered per peer for replay. Oldest ones
+    /// are dropped when this is exceeded.
+    pub max_unacked_msgs: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            retry_delay_msec: 1_000,
+            max_unacked_msgs: 100,
+        }
+    }
+}
+
+/// SOCKS5 proxy our UDP traffic is relayed through via a UDP association (RFC 1928). Peers see us
+/// at an address of the proxy, and our address is best learnt from the peers rather than
+/// configured. Only peers we connect to are relayed, datagrams from any other peer are dropped, so
+/// nodes behind a proxy are never reachable for peers they haven't connected to.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ProxyConfig {
+    /// Address of the proxy's TCP port the association is asked for on
+    pub addr: SocketAddr,
+    /// Username and password to authenticate with (RFC 1929) if the proxy asks for them
+    pub credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProxyConfig {{ addr: {}, credentials: ", self.addr)?;
+        match self.credentials {
+            Some((ref username, _)) => write!(f, "Some(({:?}, <HIDDEN>)) }}", username),
+            None => write!(f, "None }}"),
+        }
+    }
+}
+
+/// How long dials to an address are held back after they fail.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct DialBackoffConfig {
+    /// Backoff after the first failure in milliseconds
+    pub base_msec: u64,
+    /// Backoff is never longer than this, in milliseconds
+    pub max_msec: u64,
+}
+
+impl Default for DialBackoffConfig {
+    fn default() -> Self {
+        Self {
+            base_msec: 500,
+            max_msec: 60_000,
+        }
+    }
+}
+
+/// Thresholds, in penalty points, at which misbehaving peers are acted upon.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct ReputationConfig {
+    /// Score at which new incoming connections from the peer are refused for a while
+    pub throttle_threshold: u32,
+    /// How long, in seconds, a throttled peer is refused for
+    pub throttle_duration_sec: u64,
+    /// Score at which the peer is disconnected and refused for good
+    pub blacklist_threshold: u32,
+    /// Incoming connection attempts per minute beyond which the peer is penalised
+    pub max_connect_attempts_per_min: u32,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            throttle_threshold: 50,
+            throttle_duration_sec: 60,
+            blacklist_threshold: 100,
+            max_connect_attempts_per_min: 30,
+        }
+    }
+}
+
+/// How the peers in our bootstrap cache are checked for reachability in the background.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct CacheHealthCheckConfig {
+    /// Interval between checks in seconds
+    pub interval_sec: u64,
+    /// Number of cached peers checked each time, least recently checked first
+    pub peers_per_check: u32,
+    /// Consecutive failed connects after which the peer is evicted from the cache
+    pub max_consecutive_failures: u32,
+}
+
+impl Default for CacheHealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval_sec: 300,
+            peers_per_check: 3,
+            max_consecutive_failures: 3,
+        }
+    }
+}
+
+/// How connections stuck half way through being set up are swept away.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct StaleConnReaperConfig {
+    /// Interval between sweeps in seconds
+    pub sweep_interval_sec: u64,
+    /// Time in seconds a connection gets to complete before it's swept away
+    pub max_incomplete_sec: u64,
+}
+
+impl Default for StaleConnReaperConfig {
+    fn default() -> Self {
+        Self {
+            sweep_interval_sec: 30,
+            max_incomplete_sec: 120,
+        }
+    }
+}
+
+/// How big a backlog is tolerated before it's reported, see `Config::backpressure`. It's `Elevated`
+/// from half of either maximum on and `Critical` from either maximum on.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct BackpressureConfig {
+    /// Events the application has yet to take. Only counted if the sender given to
+    /// `Builder::new` is from `event_channel`.
+    pub max_event_depth: usize,
+    /// Bytes of messages from the peers we have read but not handed over yet, e.g. held back for
+    /// ordered delivery, in total
+    pub max_unread_bytes: usize,
+    /// While the backlog is critical, stop accepting new streams from the peers which sent us the
+    /// most, one more with each check, until it's back to normal. QUIC flow control then holds the
+    /// peers back.
+    pub pause_noisiest_peers: bool,
+    /// Interval between checks in milliseconds
+    pub check_interval_msec: u64,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        Self {
+            max_event_depth: 10_000,
+            max_unread_bytes: 64 * 1024 * 1024,
+            pause_noisiest_peers: false,
+            check_interval_msec: 100,
+        }
+    }
+}
+
+/// How far messages from a peer may get ahead of each other before being handed over anyway, see
+/// `Config::ordered_delivery`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct OrderedDeliveryConfig {
+    /// Maximum number of messages held back while waiting for an earlier one. Once more arrive
+    /// the earlier one is given up on and the ones held back are handed over. Messages further
+    /// ahead of the earlier one than this are dropped and the peer penalised, so this shouldn't be
+    /// below the number of streams a peer can have open at a time.
+    pub window: u32,
+    /// Time in milliseconds an earlier message is waited for before the ones held back are handed
+    /// over anyway. If it arrives later it's handed over on arrival.
+    pub timeout_msec: u64,
+}
+
+impl Default for OrderedDeliveryConfig {
+    fn default() -> Self {
+        Self {
+            window: 256,
+            timeout_msec: 2_000,
+        }
+    }
+}
+
+/// How many of the messages from a peer are remembered to drop their replays, see
+/// `Config::dedup`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct DedupConfig {
+    /// Number of the latest tagged messages remembered per peer. Replays of older ones are handed
+    /// over again, so this is best kept above the `max_unacked_msgs` of the peers.
+    pub window: u32,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self { window: 1024 }
+    }
+}
+
+/// What to do with a new connection, ours or the peer's, once we are at
+/// `Config::max_total_connections`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum AdmissionPolicy {
+    /// Refuse the new connection
+    RejectNew,
+    /// Make room by closing the connection of the client we heard from least recently, refusing
+    /// the new connection only if there's no client to close
+    EvictIdleClient,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        AdmissionPolicy::RejectNew
+    }
+}
+
+/// What to do once no one is taking our events, see `Config::on_event_channel_closed`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum EventChannelClosedPolicy {
+    /// Carry on as usual, the events being dropped
+    Continue,
+    /// Close all the connections, telling the peers we are shutting down, and stop the event loop.
+    /// The `QuicP2p` handles are of no use after that.
+    Shutdown,
+}
+
+impl Default for EventChannelClosedPolicy {
+    fn default() -> Self {
+        EventChannelClosedPolicy::Continue
+    }
+}
+
+/// How our traffic is shaped, see `Config::traffic_profile`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum TrafficProfile {
+    /// Low latency for the user's own traffic, no limits
+    Interactive,
+    /// Stay out of the way of the other applications, e.g. the user's browsing: sending is
+    /// limited to `BACKGROUND_MAX_SEND_RATE`, with small windows and infrequent keep-alives
+    Background,
+    /// Big windows and send quanta for throughput over large transfers
+    Bulk,
+}
+
+impl Default for TrafficProfile {
+    fn default() -> Self {
+        TrafficProfile::Interactive
+    }
+}
+
+/// Options applied to the UDP socket our endpoint runs on. Anything left unset keeps the OS
+/// default. See `Stats::socket_options` for the values actually in effect.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct SocketOptions {
+    /// Size of the socket receive buffer in bytes (`SO_RCVBUF`)
+    pub recv_buf: Option<usize>,
+    /// Size of the socket send buffer in bytes (`SO_SNDBUF`)
+    pub send_buf: Option<usize>,
+    /// Differentiated services code point our packets are marked with (0 to 63). Not supported
+    /// on Windows, where marking is left to the system's QoS policies.
+    pub dscp: Option<u8>,
+}
+
 /// Whether we are a client or a node
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
 pub enum OurType {
@@ -150,6 +851,7 @@ fn config_path(user_override: Option<&Dirs>) -> R<PathBuf> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::handshake_auth;
     use crate::utils;
     use crate::utils::testing::test_dirs;
 
@@ -165,4 +867,73 @@ mod tests {
 
         assert_eq!(cfg, read_cfg);
     }
+
+    #[test]
+    fn baseline_config_files_are_read_with_the_fields_added_since_defaulted() {
+        let node_info = NodeInfo {
+            peer_addr: unwrap!("127.0.0.1:5483".parse()),
+            peer_cert_der: vec![1, 2, 3],
+        };
+        let expected = Config {
+            hard_coded_contacts: vec![Contact::from(node_info.clone())].into_iter().collect(),
+            port: Some(5483),
+            bind_addr: Some(unwrap!("127.0.0.1".parse())),
+            idle_timeout_msec: Some(10_000),
+            our_type: OurType::Client,
+            ..Default::default()
+        };
+
+        let json = r#"{
+            "hard_coded_contacts": [{"peer_addr": "127.0.0.1:5483", "peer_cert_der": [1, 2, 3]}],
+            "port": 5483,
+            "ip": "127.0.0.1",
+            "max_msg_size_allowed": null,
+            "idle_timeout_msec": 10000,
+            "keep_alive_interval_msec": null,
+            "our_complete_cert": null,
+            "our_type": "Client"
+        }"#;
+        let cfg: Config = unwrap!(serde_json::from_str(json));
+        assert!(cfg.listen);
+        assert_eq!(cfg, expected);
+
+        // Laid out as the first release wrote it
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(unwrap!(config_path.parent())));
+        let baseline = (
+            vec![node_info].into_iter().collect::<HashSet<_>>(),
+            Some(5483u16),
+            Some(unwrap!("127.0.0.1".parse::<IpAddr>())),
+            None::<u32>,
+            Some(10_000u64),
+            None::<u32>,
+            None::<SerialisableCertificate>,
+            OurType::Client,
+        );
+        unwrap!(utils::write_to_disk(&config_path, &baseline));
+        let cfg = unwrap!(Config::read_or_construct_default(Some(&dir)));
+        assert!(cfg.listen);
+        assert_eq!(cfg, expected);
+    }
+
+    #[test]
+    fn generated_certs_of_either_key_type_sign_our_handshakes() {
+        for &key_type in &[CertKeyType::Ed25519, CertKeyType::EcdsaP256] {
+            let cert = SerialisableCertificate::generate(&CertParams {
+                validity_days: Some(1),
+                common_name: Some("test node".to_string()),
+                key_type,
+            });
+            let recipient_cert = SerialisableCertificate::default();
+            let (nonce, signature) =
+                unwrap!(handshake_auth::sign(&cert, &recipient_cert.cert_der, None));
+            unwrap!(handshake_auth::verify(
+                &cert.cert_der,
+                &recipient_cert.cert_der,
+                &nonce,
+                &signature
+            ));
+        }
+    }
 }
@@ -7,45 +7,73 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::config::OurType;
-use crate::connection::{BootstrapGroupMaker, Connection, FromPeer, QConn, ToPeer};
-use crate::context::ctx_mut;
+use crate::cert_expiry;
+use crate::clock;
+use crate::config::{DialBackoffConfig, OurType};
+use crate::connection::{
+    self, BootstrapGroupMaker, Connection, FromPeer, PendingSend, QConn, ToPeer,
+};
+use crate::context::{Context, Ctx};
+use crate::dns::{self, HostContact};
 use crate::error::Error;
-use crate::event::Event;
+use crate::event::{Event, UnsentReason};
+use crate::handshake_auth;
 use crate::peer_config;
 use crate::utils;
-use crate::wire_msg::{Handshake, WireMsg};
-use crate::{communicate, NodeInfo, Peer, R};
+use crate::wire_msg::{CloseReason, Handshake, WireMsg};
+use crate::{admission, communicate, NodeInfo, Peer, PeerKind, R};
+use std::collections::HashMap;
 use std::mem;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::future::{self, Either};
 use tokio::prelude::{Future, Stream};
-use tokio::runtime::current_thread;
+
+/// Once the backoff table holds this many addresses, the ones no longer backed off are forgotten.
+const MAX_BACKED_OFF_ADDRS: usize = 1_000;
 
 /// Connect to the given peer
 pub fn connect_to(
+    ctx: &Ctx,
     peer_info: NodeInfo,
-    send_after_connect: Option<WireMsg>,
+    send_after_connect: Option<PendingSend>,
     bootstrap_group_maker: Option<&BootstrapGroupMaker>,
 ) -> R<()> {
     let peer_addr = peer_info.peer_addr;
 
-    let peer_cfg = match peer_config::new_client_cfg(&peer_info.peer_cert_der) {
+    let peer_cfg = match peer_config::new_client_cfg(ctx, &peer_info.peer_cert_der) {
         Ok(cfg) => cfg,
         Err(e) => {
-            handle_connect_err(peer_addr, &e);
+            handle_connect_err(ctx, peer_addr, &e);
             return Err(e);
         }
     };
 
-    let r = ctx_mut(|c| {
+    let r = ctx.with_mut(|c| {
+        if c.reputation.is_blacklisted(&peer_addr) {
+            return Err(Error::PeerBlacklisted(peer_addr));
+        }
+        if c.dial_backoff.is_backed_off(&peer_addr, c.clock.now()) {
+            return Err(Error::ConnectBackoff(peer_addr));
+        }
+        let is_new = c.connections.get(&peer_addr).map_or(true, |conn| {
+            conn.to_peer.is_no_connection() && conn.from_peer.is_no_connection()
+        });
+        if is_new && !admission::admit(c, peer_addr) {
+            return Err(Error::ConnectionLimitReached(peer_addr));
+        }
+
         let event_tx = c.event_tx.clone();
+        let clock = c.clock.clone();
 
         let (terminator, rx) = utils::connect_terminator();
 
         let conn = c.connections.entry(peer_addr).or_insert_with(|| {
             Connection::new(
+                ctx,
                 peer_addr,
                 event_tx,
+                clock,
                 bootstrap_group_maker
                     .map(|m| m.add_member_and_get_group_ref(peer_addr, terminator.clone())),
             )
@@ -55,7 +83,10 @@ pub fn connect_to(
             // TODO see if this can be the default from-peer for OurType::Client
             if c.our_type == OurType::Client {
                 if !conn.from_peer.is_no_connection() {
-                    panic!("Logic Error - cannot expect Network to reverse connect to a client");
+                    return Err(c.sever_illegal_state(
+                        peer_addr,
+                        "cannot expect Network to reverse connect to a client".to_string(),
+                    ));
                 }
                 conn.from_peer = FromPeer::NotNeeded;
             }
@@ -74,45 +105,354 @@ pub fn connect_to(
                 terminator: terminator.clone(),
                 peer_cert_der: peer_info.peer_cert_der,
                 pending_sends,
+                started_at: c.clock.now(),
             };
-            c.quic_ep()
-                .connect_with(peer_cfg, &peer_addr, "MaidSAFE.net")
-                .map_err(Error::from)
-                .and_then(move |new_client_conn_fut| {
-                    let terminator_leaf = rx
-                        .map_err(move |_| {
-                            handle_connect_err(peer_addr, &Error::ConnectionCancelled)
-                        })
-                        .for_each(move |_| {
-                            handle_connect_err(peer_addr, &Error::ConnectionCancelled);
-                            Err(())
-                        });
-                    let handle_new_connection_res_leaf =
-                        new_client_conn_fut.then(move |new_peer_conn_res| {
-                            handle_new_connection_res(peer_addr, new_peer_conn_res);
-                            Ok::<_, ()>(())
-                        });
-                    let leaf = terminator_leaf
-                        .select(handle_new_connection_res_leaf)
-                        .then(|_| Ok(()));
-
-                    current_thread::spawn(leaf);
-
-                    Ok(())
-                })
+
+            let connect = QueuedConnect {
+                peer_addr,
+                peer_cfg,
+                terminator_rx: rx,
+            };
+            if c.max_concurrent_connects
+                .map_or(false, |max| c.connects_in_flight.len() >= max)
+            {
+                trace!(
+                    "Too many connects in flight - queueing the one to {}",
+                    peer_addr
+                );
+                c.queued_connects.push_back(connect);
+                Ok(())
+            } else {
+                start_connect(ctx, c, connect)
+            }
         } else {
             Err(Error::DuplicateConnectionToPeer(peer_addr))
         }
     });
 
     if let Err(e) = r.as_ref() {
-        handle_connect_err(peer_addr, e);
+        handle_connect_err(ctx, peer_addr, e);
     }
 
     r
 }
 
+/// Connect to every address the hard coded contact given by hostname resolves to.
+pub fn connect_to_host(
+    ctx: &Ctx,
+    contact: HostContact,
+    bootstrap_group_maker: Option<BootstrapGroupMaker>,
+) {
+    let ctx_clone = ctx.clone();
+    let leaf = resolve_host(ctx, contact.clone()).map(move |addrs| {
+        for peer_addr in addrs {
+            let node_info = contact.node_info(peer_addr);
+            let _ = connect_to(&ctx_clone, node_info, None, bootstrap_group_maker.as_ref());
+        }
+    });
+
+    ctx.spawn(leaf);
+}
+
+/// Addresses the contact resolves to, taken from the cache unless that's expired or stale. Fresh
+/// lookups replace the addresses the contact is hard coded at in our bootstrap cache.
+fn resolve_host(
+    ctx: &Ctx,
+    contact: HostContact,
+) -> impl Future<Item = Vec<SocketAddr>, Error = ()> {
+    let (cached, resolver) = ctx.with(|c| {
+        (
+            c.host_contacts
+                .cached(&contact, c.clock.now())
+                .map(<[_]>::to_vec),
+            c.host_contacts.resolver(),
+        )
+    });
+    if let Some(addrs) = cached {
+        return Either::A(future::ok(addrs));
+    }
+
+    let ctx = ctx.clone();
+    let lookup = dns::resolve(resolver, contact.host.clone(), contact.port).then(move |res| {
+        let addrs = match res {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                info!("Failed to resolve {}:{}: {}", contact.host, contact.port, e);
+                return Err(());
+            }
+        };
+        debug!("{}:{} resolved to {:?}", contact.host, contact.port, addrs);
+
+        ctx.with_mut(|c| {
+            let now = c.clock.now();
+            for gone in c.host_contacts.record(&contact, addrs.clone(), now) {
+                c.bootstrap_cache
+                    .remove_hard_coded_contact(&contact.node_info(gone));
+            }
+            for peer_addr in &addrs {
+                c.bootstrap_cache
+                    .add_hard_coded_contact(contact.node_info(*peer_addr));
+            }
+        });
+
+        Ok(addrs)
+    });
+
+    Either::B(lookup)
+}
+
+/// Addresses dials to which recently failed, held back for a while before being dialled again.
+pub struct DialBackoff {
+    records: HashMap<SocketAddr, BackoffRecord>,
+    cfg: Option<DialBackoffConfig>,
+}
+
+struct BackoffRecord {
+    consecutive_failures: u32,
+    until: Instant,
+}
+
+impl DialBackoff {
+    /// Dials are only held back if `cfg` is given.
+    pub fn new(cfg: Option<DialBackoffConfig>) -> Self {
+        Self {
+            records: Default::default(),
+            cfg,
+        }
+    }
+
+    /// Whether dials to the address are to be refused at `now`.
+    pub fn is_backed_off(&self, peer_addr: &SocketAddr, now: Instant) -> bool {
+        self.remaining(peer_addr, now) > Duration::from_secs(0)
+    }
+
+    /// Time left till the address may be dialled again.
+    pub fn remaining(&self, peer_addr: &SocketAddr, now: Instant) -> Duration {
+        match self.records.get(peer_addr) {
+            Some(record) if record.until > now => record.until - now,
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// The dial to the address failed at `now`: hold the next one back for twice as long as the
+    /// previous one, up to the configured maximum.
+    pub fn record_failure(&mut self, peer_addr: SocketAddr, now: Instant) {
+        let cfg = match self.cfg {
+            Some(cfg) => cfg,
+            None => return,
+        };
+
+        if self.records.len() >= MAX_BACKED_OFF_ADDRS {
+            self.records.retain(|_, record| record.until > now);
+        }
+
+        let record = self.records.entry(peer_addr).or_insert(BackoffRecord {
+            consecutive_failures: 0,
+            until: now,
+        });
+        let doublings = record.consecutive_failures.min(31);
+        let backoff_msec = cfg
+            .base_msec
+            .saturating_mul(1u64 << doublings)
+            .min(cfg.max_msec);
+        record.consecutive_failures += 1;
+        record.until = now + Duration::from_millis(backoff_msec);
+    }
+
+    /// The dial to the address succeeded so it's not held back anymore.
+    pub fn record_success(&mut self, peer_addr: &SocketAddr) {
+        let _ = self.records.remove(peer_addr);
+    }
+}
+
+/// Connect which is waiting for a free slot due to the limit on concurrent connects.
+pub struct QueuedConnect {
+    peer_addr: SocketAddr,
+    peer_cfg: quinn::ClientConfig,
+    terminator_rx: tokio::sync::mpsc::Receiver<()>,
+}
+
+impl QueuedConnect {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+fn start_connect(ctx: &Ctx, c: &mut Context, connect: QueuedConnect) -> R<()> {
+    let QueuedConnect {
+        peer_addr,
+        peer_cfg,
+        terminator_rx,
+    } = connect;
+
+    let wire_addr = c.transport.wire_addr(peer_addr)?;
+    let new_client_conn_fut = c
+        .quic_ep()
+        .connect_with(peer_cfg, &wire_addr, &c.server_name)?;
+    let _ = c.connects_in_flight.insert(peer_addr, c.clock.now());
+    if let Err(e) = c.event_tx.send(Event::ConnectingTo { peer_addr }) {
+        info!("Could not fire event: {:?}", e);
+    }
+
+    let ctx_on_err = ctx.clone();
+    let ctx_on_cancel = ctx.clone();
+    let ctx_on_conn = ctx.clone();
+    let terminator_leaf = terminator_rx
+        .map_err(move |_| handle_connect_err(&ctx_on_err, peer_addr, &Error::ConnectionCancelled))
+        .for_each(move |_| {
+            handle_connect_err(&ctx_on_cancel, peer_addr, &Error::ConnectionCancelled);
+            Err(())
+        });
+    let handle_new_connection_res_leaf = new_client_conn_fut.then(move |new_peer_conn_res| {
+        handle_new_connection_res(&ctx_on_conn, peer_addr, new_peer_conn_res);
+        Ok::<_, ()>(())
+    });
+    let leaf = terminator_leaf
+        .select(handle_new_connection_res_leaf)
+        .then(|_| Ok(()));
+
+    ctx.spawn(leaf);
+
+    Ok(())
+}
+
+/// How a connect in flight ended.
+enum ConnectOutcome {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// The connect to the peer is no longer in flight. Note the outcome for ranking the bootstrap
+/// cache and start as many of the queued connects as the limit now allows. This must not be called
+/// while the `Context` is already borrowed.
+fn finish_connect(ctx: &Ctx, peer_addr: SocketAddr, outcome: ConnectOutcome) {
+    let failed_connects = ctx.with_mut(|c| {
+        if let Some(started_at) = c.connects_in_flight.remove(&peer_addr) {
+            let now = c.clock.now();
+            match outcome {
+                ConnectOutcome::Succeeded => {
+                    c.bootstrap_cache
+                        .record_connect_success(peer_addr, now.duration_since(started_at));
+                    c.dial_backoff.record_success(&peer_addr);
+                    #[cfg(feature = "metrics")]
+                    c.metrics
+                        .record_connect_success(now.duration_since(started_at));
+                }
+                ConnectOutcome::Failed => {
+                    c.bootstrap_cache.record_connect_failure(peer_addr);
+                    c.dial_backoff.record_failure(peer_addr, now);
+                    #[cfg(feature = "metrics")]
+                    c.metrics.record_connect_failure();
+                }
+                ConnectOutcome::Cancelled => (),
+            }
+        }
+        let is_connected = match outcome {
+            ConnectOutcome::Succeeded => true,
+            ConnectOutcome::Failed | ConnectOutcome::Cancelled => false,
+        };
+        c.batch_connects
+            .resolve(&c.event_tx, peer_addr, is_connected);
+
+        let mut failed_connects = Vec::new();
+        while c
+            .max_concurrent_connects
+            .map_or(true, |max| c.connects_in_flight.len() < max)
+        {
+            let connect = match c.queued_connects.pop_front() {
+                Some(connect) => connect,
+                None => break,
+            };
+
+            // The connection might have been dropped while it was waiting in the queue
+            let is_still_wanted = c
+                .connections
+                .get(&connect.peer_addr)
+                .map_or(false, |conn| conn.to_peer.is_initiated());
+            if !is_still_wanted {
+                c.batch_connects
+                    .resolve(&c.event_tx, connect.peer_addr, false);
+                continue;
+            }
+
+            let queued_peer_addr = connect.peer_addr;
+            if let Err(e) = start_connect(ctx, c, connect) {
+                failed_connects.push((queued_peer_addr, e));
+            }
+        }
+
+        failed_connects
+    });
+
+    for (peer_addr, e) in failed_connects {
+        handle_connect_err(ctx, peer_addr, &e);
+    }
+}
+
+/// Re-establish the connection to a node whose connection to us failed and send it the messages
+/// it hadn't received yet. Gives up once the configured number of consecutive attempts are made.
+pub fn reconnect(ctx: &Ctx, node_info: NodeInfo, msgs: Vec<PendingSend>) {
+    let peer_addr = node_info.peer_addr;
+
+    let retry_delay = ctx.with_mut(|c| {
+        let policy = c.auto_reconnect?;
+        let attempts = c.reconnect_attempts.entry(peer_addr).or_insert(0);
+        if *attempts >= policy.max_attempts {
+            info!(
+                "Giving up reconnecting to peer {} after {} attempts. Dropping {} messages.",
+                peer_addr,
+                attempts,
+                msgs.len()
+            );
+            let _ = c.reconnect_attempts.remove(&peer_addr);
+            return None;
+        }
+        *attempts += 1;
+        // Don't retry before the dial would be let through anyway
+        let backoff = c.dial_backoff.remaining(&peer_addr, c.clock.now());
+        let retry_delay = Duration::from_millis(policy.retry_delay_msec).max(backoff);
+        Some(c.clock.delay(c.clock.now() + retry_delay))
+    });
+    let retry_delay = match retry_delay {
+        Some(delay) => delay,
+        None => return,
+    };
+
+    let ctx_clone = ctx.clone();
+    let leaf = retry_delay.then(move |r| {
+        let ctx = &ctx_clone;
+        if let Err(e) = r {
+            info!("Error in reconnect delay: {:?}", e);
+        }
+
+        trace!("Reconnecting to peer: {}", peer_addr);
+
+        if msgs.is_empty() {
+            if let Err(e) = connect_to(ctx, node_info, None, None) {
+                debug!("Could not reconnect to peer {}: {}", peer_addr, e);
+            }
+        } else {
+            // The first message initiates the connection and the rest get queued behind it
+            for msg in msgs {
+                communicate::try_send_to_peer(ctx, node_info.clone().into(), msg);
+            }
+        }
+
+        ctx.with_mut(|c| {
+            if let Some(conn) = c.connections.get_mut(&peer_addr) {
+                conn.we_contacted_peer = true;
+            }
+        });
+
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
 fn handle_new_connection_res(
+    ctx: &Ctx,
     peer_addr: SocketAddr,
     new_peer_conn_res: Result<
         (
@@ -123,21 +463,41 @@ fn handle_new_connection_res(
         quinn::ConnectionError,
     >,
 ) {
-    let (conn_driver, q_conn, incoming_streams) = match new_peer_conn_res {
+    // Failures are accounted for by `handle_connect_err`
+    let (conn_driver, mut q_conn, incoming_streams) = match new_peer_conn_res {
         Ok((conn_driver, q_conn, incoming_streams)) => {
             (conn_driver, QConn::from(q_conn), incoming_streams)
         }
-        Err(e) => return handle_connect_err(peer_addr, &From::from(e)),
+        Err(e) => return handle_connect_err(ctx, peer_addr, &From::from(e)),
     };
-    current_thread::spawn(
-        conn_driver.map_err(move |e| handle_connect_err(peer_addr, &From::from(e))),
-    );
+    ctx.spawn_driver(conn_driver, move |ctx, e| {
+        handle_connect_err(ctx, peer_addr, &From::from(e))
+    });
+
+    if !ctx.with(|c| peer_config::is_alpn_accepted(&c.alpn_protocols, &q_conn)) {
+        q_conn.set_close_reason(CloseReason::Refused);
+        return handle_connect_err(ctx, peer_addr, &Error::AlpnMismatch(peer_addr));
+    }
+    ctx.with(|c| {
+        let event = Event::HandshakeCompleted {
+            peer_addr,
+            elapsed: c
+                .connects_in_flight
+                .get(&peer_addr)
+                .map(|started_at| c.clock.now().duration_since(*started_at)),
+        };
+        if let Err(e) = c.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+    });
+    finish_connect(ctx, peer_addr, ConnectOutcome::Succeeded);
 
     trace!("Successfully connected to peer: {}", peer_addr);
 
-    let mut should_accept_incoming = false;
+    let mut is_conn_kept = false;
+    let mut reverse_connect_requesters = Vec::new();
 
-    ctx_mut(|c| {
+    ctx.with_mut(|c| {
         let conn = match c.connections.get_mut(&peer_addr) {
             Some(conn) => conn,
             None => {
@@ -150,80 +510,150 @@ fn handle_new_connection_res(
             }
         };
 
+        let _ = c.reconnect_attempts.remove(&peer_addr);
+
         let mut to_peer_prev = mem::replace(&mut conn.to_peer, Default::default());
-        let (peer_cert_der, pending_sends) = match to_peer_prev {
+        let (peer_cert_der, pending_sends, started_at) = match to_peer_prev {
             ToPeer::Initiated {
                 ref mut peer_cert_der,
                 ref mut pending_sends,
+                started_at,
                 ..
             } => (
                 mem::replace(peer_cert_der, Default::default()),
                 mem::replace(pending_sends, Default::default()),
+                started_at,
             ),
             // TODO analyse if this is actually reachable in some wierd case where things were in
             // the event loop and resolving now etc
-            x => unreachable!(
-                "TODO We can handle new connection only because it was previously \
-                 initiated: {:?}",
-                x
-            ),
+            x => {
+                let _ = c.sever_illegal_state(
+                    peer_addr,
+                    format!(
+                        "we can handle new connection only because it was previously \
+                         initiated: {:?}",
+                        x
+                    ),
+                );
+                return;
+            }
         };
 
+        let now = c.clock.now();
+        conn.quic_established_at = Some(now);
+        conn.quic_handshake_elapsed = Some(now.duration_since(started_at));
+        let timings = conn.connect_timings();
+
         let node_info = NodeInfo {
             peer_addr,
             peer_cert_der: peer_cert_der.clone(),
         };
-        if conn.we_contacted_peer {
-            c.bootstrap_cache.add_peer(node_info.clone());
-        }
 
         match conn.from_peer {
             FromPeer::NoConnection => {
-                communicate::write_to_peer_connection(
-                    peer_addr,
-                    &q_conn,
-                    WireMsg::Handshake(Handshake::Node {
-                        cert_der: c.our_complete_cert.cert_der.clone(),
-                    }),
-                );
+                match handshake_auth::sign(&c.our_complete_cert, &peer_cert_der, c.rng.as_ref()) {
+                    Ok((nonce, signature)) => communicate::write_to_peer_connection(
+                        ctx,
+                        peer_addr,
+                        &q_conn,
+                        WireMsg::Handshake(Handshake::Node {
+                            cert_der: c.our_complete_cert.cert_der.clone(),
+                            network_id: c.network_id.clone(),
+                            user_data: c.our_handshake_data.clone(),
+                            nonce,
+                            signature,
+                            observed_addr: peer_addr,
+                            channels: c.channels.clone(),
+                            sent_at_msec: clock::unix_time_msec(&c.clock),
+                            ordered_delivery: c.ordered_delivery.is_some(),
+                        }),
+                    ),
+                    Err(e) => warn!("Could not sign our handshake to peer {}: {}", peer_addr, e),
+                }
             }
             FromPeer::NotNeeded => {
-                communicate::write_to_peer_connection(
-                    peer_addr,
-                    &q_conn,
-                    WireMsg::Handshake(Handshake::Client),
-                );
+                match handshake_auth::sign(&c.our_complete_cert, &peer_cert_der, c.rng.as_ref()) {
+                    Ok((nonce, signature)) => communicate::write_to_peer_connection(
+                        ctx,
+                        peer_addr,
+                        &q_conn,
+                        WireMsg::Handshake(Handshake::Client {
+                            cert_der: c.our_complete_cert.cert_der.clone(),
+                            network_id: c.network_id.clone(),
+                            user_data: c.our_handshake_data.clone(),
+                            nonce,
+                            signature,
+                            observed_addr: peer_addr,
+                            channels: c.channels.clone(),
+                            sent_at_msec: clock::unix_time_msec(&c.clock),
+                            ordered_delivery: c.ordered_delivery.is_some(),
+                        }),
+                    ),
+                    Err(e) => warn!("Could not sign our handshake to peer {}: {}", peer_addr, e),
+                }
 
-                let event = if let Some(bootstrap_group_ref) = conn.bootstrap_group_ref.take() {
-                    bootstrap_group_ref.terminate_group(true);
-                    Event::BootstrappedTo { node: node_info }
+                // Nodes don't handshake with clients so this is as much as we can validate them
+                conn.peer_kind = Some(PeerKind::Node);
+                if conn.we_contacted_peer {
+                    c.bootstrap_cache.add_peer(node_info.clone());
+                }
+
+                let user_data = conn.peer_user_data.clone();
+                let bootstrap_elapsed = conn
+                    .bootstrap_group_ref
+                    .take()
+                    .and_then(|group_ref| group_ref.succeed());
+                let event = if let Some(elapsed) = bootstrap_elapsed {
+                    Event::BootstrappedTo {
+                        node: node_info,
+                        user_data,
+                        elapsed,
+                        timings,
+                    }
                 } else {
                     Event::ConnectedTo {
                         peer: node_info.into(),
+                        user_data,
+                        timings,
                     }
                 };
 
+                c.lifetime_stats.record_peer_connected();
                 if let Err(e) = c.event_tx.send(event) {
                     info!("Could not fire event: {:?}", e);
                 }
-
-                should_accept_incoming = true;
             }
+            // If the peer hasn't introduced itself yet, the event will be fired and the pending
+            // reads dispatched once its handshake arrives.
             FromPeer::Established {
                 ref mut pending_reads,
                 ..
-            } => {
-                let event = if let Some(bootstrap_group_ref) = conn.bootstrap_group_ref.take() {
-                    bootstrap_group_ref.terminate_group(true);
+            } if conn.peer_handshake_rxd => {
+                if conn.we_contacted_peer {
+                    c.bootstrap_cache.add_peer(node_info.clone());
+                }
+
+                let user_data = conn.peer_user_data.clone();
+                let bootstrap_elapsed = conn
+                    .bootstrap_group_ref
+                    .take()
+                    .and_then(|group_ref| group_ref.succeed());
+                let event = if let Some(elapsed) = bootstrap_elapsed {
                     Event::BootstrappedTo {
                         node: node_info.clone(),
+                        user_data,
+                        elapsed,
+                        timings,
                     }
                 } else {
                     Event::ConnectedTo {
                         peer: node_info.clone().into(),
+                        user_data,
+                        timings,
                     }
                 };
 
+                c.lifetime_stats.record_peer_connected();
                 if let Err(e) = c.event_tx.send(event) {
                     info!("Could not fire event: {:?}", e);
                 }
@@ -232,6 +662,7 @@ fn handle_new_connection_res(
 
                 for pending_read in pending_reads.drain(..) {
                     communicate::dispatch_wire_msg(
+                        ctx,
                         peer.clone(),
                         &q_conn,
                         c.our_ext_addr_tx.take(),
@@ -239,45 +670,166 @@ fn handle_new_connection_res(
                         pending_read,
                         &mut c.bootstrap_cache,
                         conn.we_contacted_peer,
+                        c.echo_service,
                     );
                 }
             }
+            FromPeer::Established { .. } => (),
         }
 
-        for pending_send in pending_sends {
-            communicate::write_to_peer_connection(peer_addr, &q_conn, pending_send);
+        let node_info = NodeInfo {
+            peer_addr,
+            peer_cert_der: peer_cert_der.clone(),
+        };
+        for pending_send in
+            connection::fire_expired_sends(&c.event_tx, &node_info, pending_sends, c.clock.now())
+        {
+            communicate::send_to_peer_connection(ctx, peer_addr, &q_conn, pending_send);
         }
 
         conn.to_peer = ToPeer::Established {
             peer_cert_der,
             q_conn,
         };
+
+        reverse_connect_requesters = mem::replace(&mut conn.reverse_connect_requesters, Vec::new());
+        is_conn_kept = true;
     });
 
-    if should_accept_incoming {
-        communicate::read_from_peer(peer_addr, incoming_streams);
+    // Clients send over the connection we make to them and so can nodes which are configured to
+    // send over incoming connections
+    if is_conn_kept {
+        communicate::read_from_peer(ctx, peer_addr, incoming_streams);
     }
+
+    communicate::report_reverse_connect_result(ctx, reverse_connect_requesters, peer_addr, true);
 }
 
-fn handle_connect_err(peer_addr: SocketAddr, e: &Error) {
+fn handle_connect_err(ctx: &Ctx, peer_addr: SocketAddr, e: &Error) {
     debug!(
         "Error connecting to peer {}: {:?} - Details: {}",
         peer_addr, e, e
     );
 
-    if let Error::DuplicateConnectionToPeer(_) = e {
-        return;
+    match e {
+        Error::DuplicateConnectionToPeer(_) | Error::ConnectBackoff(_) => return,
+        _ => (),
     }
 
-    ctx_mut(|c| {
-        if let Some(conn) = c.connections.remove(&peer_addr) {
-            if !conn.from_peer.is_no_connection() {
-                info!(
-                    "Peer {} has a connection to us but we couldn't connect to it. \
-                     All connections to this peer will now be severed.",
-                    peer_addr
+    let outcome = if let Error::ConnectionCancelled = e {
+        ConnectOutcome::Cancelled
+    } else {
+        ConnectOutcome::Failed
+    };
+    // A hard coded contact given by hostname might have moved on to other addresses
+    if let ConnectOutcome::Failed = outcome {
+        if let Some(contact) = ctx.with_mut(|c| c.host_contacts.dial_failed(&peer_addr)) {
+            ctx.spawn(resolve_host(ctx, contact).map(|_| ()));
+        }
+    }
+    finish_connect(ctx, peer_addr, outcome);
+
+    let (reconnect_info, reverse_connect_requesters) = ctx.with_mut(|c| {
+        let mut conn = match c.connections.remove(&peer_addr) {
+            Some(conn) => conn,
+            None => return (None, Vec::new()),
+        };
+        // TLS fails the same whatever is wrong with the certificate, so tell expiry apart ourselves
+        let is_peer_cert_expired = match conn.to_peer {
+            ToPeer::Initiated {
+                ref peer_cert_der, ..
+            } => cert_expiry::is_expired(peer_cert_der, c.clock.system_time()),
+            _ => false,
+        };
+        if is_peer_cert_expired {
+            let reason = CloseReason::PeerCertExpired;
+            conn.set_close_reason(reason);
+            warn!("Certificate of peer {} has expired", peer_addr);
+            let event = Event::ConnectionFailure { peer_addr, reason };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        } else if let Some(reason) = CloseReason::from_peer_close(e) {
+            conn.set_close_reason(reason);
+            // The connection never completed, so dropping it doesn't report the failure. This one
+            // would otherwise look like an opaque timeout to the user.
+            if reason == CloseReason::QuicVersionMismatch && !conn.is_connected() {
+                warn!(
+                    "Peer {} speaks none of our QUIC versions {:?}",
+                    peer_addr,
+                    crate::SUPPORTED_QUIC_VERSIONS
                 );
+                let event = Event::ConnectionFailure { peer_addr, reason };
+                if let Err(e) = c.event_tx.send(event) {
+                    info!("Could not fire event: {:?}", e);
+                }
             }
         }
-    })
+        if !conn.from_peer.is_no_connection() {
+            info!(
+                "Peer {} has a connection to us but we couldn't connect to it. \
+                 All connections to this peer will now be severed.",
+                peer_addr
+            );
+        }
+
+        // Keep trying if this was an attempt to reconnect
+        let reconnect_info = if c.reconnect_attempts.contains_key(&peer_addr) {
+            conn.take_reconnect_info()
+        } else {
+            None
+        };
+        // Whatever wasn't taken along for the reconnect is not going to make it
+        conn.fire_pending_sends_unsent(UnsentReason::ConnectFailed);
+
+        (
+            reconnect_info,
+            mem::replace(&mut conn.reverse_connect_requesters, Vec::new()),
+        )
+    });
+
+    communicate::report_reverse_connect_result(ctx, reverse_connect_requesters, peer_addr, false);
+
+    if let Some((node_info, msgs)) = reconnect_info {
+        reconnect(ctx, node_info, msgs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dial_backoff_doubles_up_to_the_max_and_is_reset_on_success() {
+        let peer_addr: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+        let now = Instant::now();
+        let mut backoff = DialBackoff::new(Some(DialBackoffConfig {
+            base_msec: 100,
+            max_msec: 300,
+        }));
+        assert!(!backoff.is_backed_off(&peer_addr, now));
+
+        for expected_msec in &[100, 200, 300, 300] {
+            backoff.record_failure(peer_addr, now);
+            assert_eq!(
+                backoff.remaining(&peer_addr, now),
+                Duration::from_millis(*expected_msec)
+            );
+        }
+        assert!(backoff.is_backed_off(&peer_addr, now + Duration::from_millis(299)));
+        assert!(!backoff.is_backed_off(&peer_addr, now + Duration::from_millis(300)));
+
+        backoff.record_success(&peer_addr);
+        assert!(!backoff.is_backed_off(&peer_addr, now));
+    }
+
+    #[test]
+    fn dials_are_never_backed_off_unless_configured() {
+        let peer_addr: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+        let now = Instant::now();
+        let mut backoff = DialBackoff::new(None);
+
+        backoff.record_failure(peer_addr, now);
+        assert!(!backoff.is_backed_off(&peer_addr, now));
+    }
 }
@@ -13,31 +13,51 @@
 //! termination of the group would result in immediate cancellation of rest of the attempts
 //! currently being made by the members of the group and thus an eventual destruction of all such
 //! members to not continue to use resources as we no longer require them.
+//!
+//! Members can be given a latency budget, so that one slow proxy doesn't hold up the failure of
+//! the whole group, and a grace period after the first success, so that proxies about to connect
+//! too aren't cut off.
 
-use crate::event::Event;
+use crate::clock::SharedClock;
+use crate::event::{Event, EventTx};
 use crate::utils::ConnectTerminator;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::rc::Rc;
-use std::sync::mpsc::Sender;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+use tokio::prelude::Future;
+use tokio::runtime::current_thread;
 
 /// Creator of a `BootstrapGroup`. Use this to obtain the reference to the undelying group.
 ///
 /// Destroy the maker once all references of the group have been obtained to not hold the internal
 /// references for longer than needed. The maker going out of scope is enough for it's destruction.
+/// Clones refer to the same group, e.g. to add the members found by a DNS lookup once it's done.
+#[derive(Clone)]
 pub struct BootstrapGroupMaker {
     group: Rc<RefCell<BootstrapGroup>>,
 }
 
 impl BootstrapGroupMaker {
-    /// Create a handle that refers to a newly created underlying group.
-    pub fn new(event_tx: Sender<Event>) -> Self {
+    /// Create a handle that refers to a newly created underlying group. Members still connecting
+    /// once `member_budget` has passed since they were added are terminated, and the rest of the
+    /// members are terminated `grace` after the first one succeeds.
+    pub fn new(
+        event_tx: EventTx,
+        clock: SharedClock,
+        member_budget: Option<Duration>,
+        grace: Duration,
+    ) -> Self {
         Self {
             group: Rc::new(RefCell::new(BootstrapGroup {
                 is_bootstrap_successful_yet: false,
                 // TODO remove magic number
                 terminators: HashMap::with_capacity(300),
+                started_at: clock.now(),
+                clock,
+                member_budget,
+                grace,
                 event_tx,
             })),
         }
@@ -58,6 +78,11 @@ impl BootstrapGroupMaker {
             let _ = terminator.try_send(());
         }
 
+        let member_budget = self.group.borrow().member_budget;
+        if let Some(member_budget) = member_budget {
+            spawn_member_budget_timer(&self.group, peer_addr, member_budget);
+        }
+
         BootstrapGroupRef {
             peer_addr,
             group: self.group.clone(),
@@ -76,19 +101,33 @@ pub struct BootstrapGroupRef {
 }
 
 impl BootstrapGroupRef {
-    /// Prematurely terminate all members of the underlying `BootstrapGroup`. Also indicate if this
-    /// is because the bootstrapping was successful (in which case no failure event will be
-    /// auto-fired).
-    pub fn terminate_group(&self, is_due_to_success: bool) {
-        let mut group = self.group.borrow_mut();
-
-        if is_due_to_success {
+    /// This member connected. If it's the first one, the bootstrap is successful, so no failure
+    /// event will be auto-fired, and the rest of the members are terminated once the grace period
+    /// is over. Returns the time since the group was created if it's the first one.
+    pub fn succeed(&self) -> Option<Duration> {
+        let (elapsed, grace, clock) = {
+            let mut group = self.group.borrow_mut();
+            if group.is_bootstrap_successful_yet {
+                return None;
+            }
             group.is_bootstrap_successful_yet = true;
-        }
+            let elapsed = group.clock.now().duration_since(group.started_at);
+            (elapsed, group.grace, group.clock.clone())
+        };
 
-        for (_, mut terminator) in group.terminators.drain() {
-            let _ = terminator.try_send(());
+        if grace == Duration::from_secs(0) {
+            self.group.borrow_mut().terminate_all();
+        } else {
+            let group = Rc::downgrade(&self.group);
+            current_thread::spawn(clock.delay(clock.now() + grace).then(move |_| {
+                if let Some(group) = group.upgrade() {
+                    group.borrow_mut().terminate_all();
+                }
+                Ok(())
+            }));
         }
+
+        Some(elapsed)
     }
 }
 
@@ -101,7 +140,47 @@ impl Drop for BootstrapGroupRef {
 struct BootstrapGroup {
     is_bootstrap_successful_yet: bool,
     terminators: HashMap<SocketAddr, ConnectTerminator>,
-    event_tx: Sender<Event>,
+    started_at: Instant,
+    clock: SharedClock,
+    member_budget: Option<Duration>,
+    grace: Duration,
+    event_tx: EventTx,
+}
+
+impl BootstrapGroup {
+    fn terminate_all(&mut self) {
+        for (_, mut terminator) in self.terminators.drain() {
+            let _ = terminator.try_send(());
+        }
+    }
+}
+
+/// Terminate the member if it's still connecting once its budget is spent. The timer doesn't keep
+/// the group alive, so a group whose members have all failed is reported straight away.
+fn spawn_member_budget_timer(
+    group: &Rc<RefCell<BootstrapGroup>>,
+    peer_addr: SocketAddr,
+    member_budget: Duration,
+) {
+    let clock = group.borrow().clock.clone();
+    let group: Weak<RefCell<BootstrapGroup>> = Rc::downgrade(group);
+    let leaf = clock.delay(clock.now() + member_budget).then(move |_| {
+        let group = match group.upgrade() {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+        let terminator = group.borrow_mut().terminators.remove(&peer_addr);
+        if let Some(mut terminator) = terminator {
+            debug!(
+                "Proxy {} didn't connect within the budget of {:?} - giving up on it",
+                peer_addr, member_budget
+            );
+            let _ = terminator.try_send(());
+        }
+        Ok(())
+    });
+
+    current_thread::spawn(leaf);
 }
 
 impl Drop for BootstrapGroup {
@@ -10,22 +10,31 @@
 pub use self::bootstrap_group::{BootstrapGroupMaker, BootstrapGroupRef};
 pub use self::from_peer::FromPeer;
 pub use self::q_conn::QConn;
-pub use self::to_peer::ToPeer;
+pub use self::reorder_buf::ReorderBuf;
+pub use self::retransmit_buf::RetransmitBuf;
+pub use self::stream_reads::StreamReads;
+pub use self::to_peer::{PendingSend, ToPeer};
 
-use crate::context::ctx_mut;
-use crate::event::Event;
+use crate::clock::SharedClock;
+use crate::context::Ctx;
+use crate::event::{ConnectTimings, Event, EventTx, UnsentReason};
+use crate::wire_msg::{CloseReason, WireMsg};
+use crate::{ClientInfo, NodeInfo, Peer, PeerKind, DEFAULT_CHANNEL};
+use std::any::Any;
 use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::fmt;
+use std::mem;
 use std::net::SocketAddr;
-use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
-use tokio::prelude::Future;
-use tokio::runtime::current_thread;
-use tokio::timer::Delay;
+use tokio::prelude::{task, Future};
 
 mod bootstrap_group;
 mod from_peer;
 mod q_conn;
+mod reorder_buf;
+mod retransmit_buf;
+mod stream_reads;
 mod to_peer;
 
 const KILL_INCOMPLETE_CONN_SEC: u64 = 60;
@@ -46,39 +55,292 @@ pub struct Connection {
     /// This flag indicates whether upper layer attempted to connect/send something to the other
     /// end of this connection.
     pub we_contacted_peer: bool,
+    /// Whether the peer has introduced itself to us via its `Handshake` yet
+    pub peer_handshake_rxd: bool,
+    /// Application data the peer attached to its `Handshake`
+    pub peer_user_data: Option<bytes::Bytes>,
+    /// Kind of the peer, once known from its `Handshake` or from us connecting to it as a node
+    pub peer_kind: Option<PeerKind>,
+    /// Identity the peer presented in its `Handshake`, if it's a client
+    pub client_info: Option<ClientInfo>,
+    /// Channels the peer announced in its `Handshake` it accepts messages on, besides the default
+    /// one
+    pub peer_channels: Option<Vec<u8>>,
+    /// Whether the peer announced in its `Handshake` that it wants the user messages we send it
+    /// sequenced
+    pub peer_ordered_delivery: Option<bool>,
+    /// Sequence number of the next user message we send the peer, if we sequence them
+    pub next_seq: u64,
+    /// Sequenced user messages from the peer held back until the ones before them arrive
+    pub reorder_buf: ReorderBuf,
+    /// User messages sent to the peer that it hasn't acknowledged yet. Only populated if
+    /// auto-reconnect is enabled.
+    pub unacked_msgs: RetransmitBuf,
+    /// Peers which asked us to connect to this peer and are awaiting the outcome
+    pub reverse_connect_requesters: Vec<SocketAddr>,
+    /// Messages from the peer we have started reading but not finished yet
+    pub stream_reads: StreamReads,
+    /// Send halves of the bi-directional streams the peer sent us messages with ids on, by those
+    /// ids. Our reply to such a message goes back over its stream.
+    pub reply_streams: HashMap<u64, quinn::SendStream>,
+    /// State the user attached via `QuicP2p::set_peer_context`, dropped along with the connection
+    pub user_context: Option<Box<dyn Any + Send>>,
+    /// When we started tracking the peer, i.e. connecting to it or accepting its connection
+    pub created_at: Instant,
+    /// When we last received a message from the peer, `created_at` if we haven't yet
+    pub last_heard_at: Instant,
+    /// Whether the liveness watchdog reported the peer as unresponsive, see
+    /// `Config::unresponsive_peer_msec`
+    pub is_unresponsive: bool,
+    /// How far the clock of the peer is ahead of ours in milliseconds, negative if behind. `None`
+    /// until we have heard its handshake or an answer to a health check.
+    pub clock_skew_msec: Option<i64>,
+    /// Whether we stopped accepting new streams from the peer for the backlog to drain, see
+    /// `Config::backpressure`
+    pub is_read_paused: bool,
+    /// Data lane task of the connection, parked while reading from the peer is paused
+    pub paused_reader: Option<task::Task>,
+    /// Bytes of messages read from the peer since the last backpressure check
+    pub recent_read_bytes: usize,
+    /// When the QUIC handshake completed, of our connection to the peer if we made one and of its
+    /// connection to us otherwise
+    pub quic_established_at: Option<Instant>,
+    /// How long the QUIC handshake of our connection to the peer took, from setting out to connect
+    pub quic_handshake_elapsed: Option<Duration>,
     peer_addr: SocketAddr,
-    event_tx: Sender<Event>,
+    event_tx: EventTx,
+    clock: SharedClock,
+    close_reason: CloseReason,
 }
 
 impl Connection {
     /// New Connection with defaults
     pub fn new(
+        ctx: &Ctx,
         peer_addr: SocketAddr,
-        event_tx: Sender<Event>,
+        event_tx: EventTx,
+        clock: SharedClock,
         bootstrap_group_ref: Option<BootstrapGroupRef>,
     ) -> Self {
-        spawn_incomplete_conn_killer(peer_addr);
+        spawn_incomplete_conn_killer(ctx, peer_addr, &clock);
 
         Self {
             to_peer: Default::default(),
             from_peer: Default::default(),
             bootstrap_group_ref,
             we_contacted_peer: false,
+            peer_handshake_rxd: false,
+            peer_user_data: None,
+            peer_kind: None,
+            client_info: None,
+            peer_channels: None,
+            peer_ordered_delivery: None,
+            next_seq: 0,
+            reorder_buf: Default::default(),
+            unacked_msgs: Default::default(),
+            reverse_connect_requesters: Default::default(),
+            stream_reads: Default::default(),
+            reply_streams: Default::default(),
+            user_context: None,
+            created_at: clock.now(),
+            last_heard_at: clock.now(),
+            is_unresponsive: false,
+            clock_skew_msec: None,
+            is_read_paused: false,
+            paused_reader: None,
+            recent_read_bytes: 0,
+            quic_established_at: None,
+            quic_handshake_elapsed: None,
             peer_addr,
             event_tx,
+            clock,
+            close_reason: Default::default(),
+        }
+    }
+
+    /// How long connecting to the peer took, for the event telling the user it's connected now.
+    pub fn connect_timings(&self) -> ConnectTimings {
+        let quic_established_at = self.quic_established_at.unwrap_or(self.created_at);
+        ConnectTimings {
+            quic_handshake: self.quic_handshake_elapsed,
+            wire_handshake: self.clock.now().duration_since(quic_established_at),
+        }
+    }
+
+    /// Reason the connection is going to be closed for. The peer is told about it when we close
+    /// the connection and the user when the connection is dropped.
+    pub fn set_close_reason(&mut self, close_reason: CloseReason) {
+        self.close_reason = close_reason;
+        if let ToPeer::Established { ref mut q_conn, .. } = self.to_peer {
+            q_conn.set_close_reason(close_reason);
+        }
+        if let FromPeer::Established { ref mut q_conn, .. } = self.from_peer {
+            q_conn.set_close_reason(close_reason);
+        }
+    }
+
+    /// Bytes we are holding on behalf of the peer: sends waiting for the connection to be
+    /// established, reads waiting for the peer to be fully connected or for earlier messages and
+    /// messages it hasn't acknowledged yet.
+    pub fn buffered_bytes(&self) -> usize {
+        let pending_sends = if let ToPeer::Initiated {
+            ref pending_sends, ..
+        } = self.to_peer
+        {
+            pending_sends
+                .iter()
+                .map(|pending_send| pending_send.msg.user_data_len())
+                .sum()
+        } else {
+            0
+        };
+
+        pending_sends + self.unread_bytes() + self.unacked_msgs.size_bytes()
+    }
+
+    /// Bytes of the messages we have read from the peer but not handed over yet: waiting for the
+    /// peer to be fully connected or for earlier messages.
+    pub fn unread_bytes(&self) -> usize {
+        let pending_reads = if let FromPeer::Established {
+            ref pending_reads, ..
+        } = self.from_peer
+        {
+            pending_reads.iter().map(WireMsg::user_data_len).sum()
+        } else {
+            0
+        };
+
+        pending_reads + self.reorder_buf.size_bytes()
+    }
+
+    /// Whether the connection with the peer is complete, i.e. the user has been told about it.
+    pub fn is_connected(&self) -> bool {
+        (self.to_peer.is_established() || self.to_peer.is_not_needed())
+            && (self.from_peer.is_established() || self.from_peer.is_not_needed())
+    }
+
+    /// Whether the connection is stuck half way: either side is still being set up, or neither is
+    /// needed at all.
+    pub fn is_incomplete(&self) -> bool {
+        (!self.to_peer.is_established() && !self.to_peer.is_not_needed())
+            || (!self.from_peer.is_established() && !self.from_peer.is_not_needed())
+            || (self.to_peer.is_not_needed() && self.from_peer.is_not_needed())
+    }
+
+    /// The peer as far as we know it: a node once our connection to it is established, a client
+    /// once it has introduced itself.
+    pub fn peer(&self) -> Option<Peer> {
+        match self.to_peer {
+            ToPeer::Established {
+                ref peer_cert_der, ..
+            } => Some(Peer::Node {
+                node_info: NodeInfo {
+                    peer_addr: self.peer_addr,
+                    peer_cert_der: peer_cert_der.clone(),
+                },
+            }),
+            _ => self.client_info.clone().map(|client_info| Peer::Client {
+                peer_addr: self.peer_addr,
+                client_info,
+            }),
+        }
+    }
+
+    /// Whether the peer accepts messages on the channel. Assumed so until we know otherwise from
+    /// its handshake.
+    pub fn accepts_channel(&self, channel: u8) -> bool {
+        channel == DEFAULT_CHANNEL
+            || self
+                .peer_channels
+                .as_ref()
+                .map_or(true, |channels| channels.contains(&channel))
+    }
+
+    /// Whether we are buffering more than the given limit on behalf of the peer.
+    pub fn is_overloaded(&self, limit: Option<usize>) -> bool {
+        limit.map_or(false, |limit| self.buffered_bytes() > limit)
+    }
+
+    /// Take out what is needed to re-establish our connection to the peer: its details and the
+    /// messages that are yet to be delivered to it, oldest first. Messages past their expiry are
+    /// given up on instead. Returns `None` if we had not connected to the peer in the first place
+    /// (e.g. it's a client).
+    pub fn take_reconnect_info(&mut self) -> Option<(NodeInfo, Vec<PendingSend>)> {
+        let (peer_cert_der, pending_sends) = match self.to_peer {
+            ToPeer::Initiated {
+                ref peer_cert_der,
+                ref mut pending_sends,
+                ..
+            } => (
+                peer_cert_der.clone(),
+                mem::replace(pending_sends, Default::default()),
+            ),
+            ToPeer::Established {
+                ref peer_cert_der, ..
+            } => (peer_cert_der.clone(), Vec::new()),
+            ToPeer::NoConnection | ToPeer::NotNeeded => return None,
+        };
+
+        let node_info = NodeInfo {
+            peer_addr: self.peer_addr,
+            peer_cert_der,
+        };
+        let pending_sends =
+            fire_expired_sends(&self.event_tx, &node_info, pending_sends, self.clock.now());
+        let msgs = self
+            .unacked_msgs
+            .drain()
+            .map(|(id, msg)| PendingSend::replay(id, msg))
+            .chain(pending_sends)
+            .collect();
+
+        Some((node_info, msgs))
+    }
+
+    /// Fire `Event::UnsentUserMessage` for every message waiting for our connection to the peer,
+    /// for when that isn't going to be established after all.
+    pub fn fire_pending_sends_unsent(&mut self, reason: UnsentReason) {
+        let (peer_cert_der, pending_sends) = match self.to_peer {
+            ToPeer::Initiated {
+                ref peer_cert_der,
+                ref mut pending_sends,
+                ..
+            } => (
+                peer_cert_der.clone(),
+                mem::replace(pending_sends, Default::default()),
+            ),
+            _ => return,
+        };
+
+        let peer: Peer = NodeInfo {
+            peer_addr: self.peer_addr,
+            peer_cert_der,
+        }
+        .into();
+        for msg in pending_sends
+            .into_iter()
+            .filter_map(|pending_send| pending_send.msg.into_user_msg())
+        {
+            let event = Event::UnsentUserMessage {
+                peer: peer.clone(),
+                msg,
+                reason,
+            };
+            if let Err(e) = self.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
         }
     }
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
-        if (self.to_peer.is_established() || self.to_peer.is_not_needed())
-            && (self.from_peer.is_established() || self.from_peer.is_not_needed())
-        {
+        if self.is_connected() {
             // No need to log these as this will fire even when the QuicP2p handle is dropped and at
             // that point there might be no one listening so sender will error out
             let _ = self.event_tx.send(Event::ConnectionFailure {
                 peer_addr: self.peer_addr,
+                reason: self.close_reason,
             });
         }
     }
@@ -94,30 +356,56 @@ impl fmt::Debug for Connection {
     }
 }
 
-fn spawn_incomplete_conn_killer(peer_addr: SocketAddr) {
-    let leaf =
-        Delay::new(Instant::now() + Duration::from_secs(KILL_INCOMPLETE_CONN_SEC)).then(move |r| {
+/// Fire `Event::UnsentUserMessage` for the sends past their expiry at `now`, returning the rest.
+pub fn fire_expired_sends(
+    event_tx: &EventTx,
+    node_info: &NodeInfo,
+    pending_sends: Vec<PendingSend>,
+    now: Instant,
+) -> Vec<PendingSend> {
+    let (expired, live): (Vec<_>, Vec<_>) = pending_sends
+        .into_iter()
+        .partition(|pending_send| pending_send.is_expired(now));
+
+    for msg in expired
+        .into_iter()
+        .filter_map(|pending_send| pending_send.msg.into_user_msg())
+    {
+        let event = Event::UnsentUserMessage {
+            peer: node_info.clone().into(),
+            msg,
+            reason: UnsentReason::Expired,
+        };
+        if let Err(e) = event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+    }
+
+    live
+}
+
+fn spawn_incomplete_conn_killer(ctx: &Ctx, peer_addr: SocketAddr, clock: &SharedClock) {
+    let ctx_clone = ctx.clone();
+    let leaf = clock
+        .delay(clock.now() + Duration::from_secs(KILL_INCOMPLETE_CONN_SEC))
+        .then(move |r| {
             if let Err(e) = r {
                 info!("Error in incomplete connection killer delay: {:?}", e);
             }
 
-            ctx_mut(|c| {
+            ctx_clone.with_mut(|c| {
                 let conn = if let Entry::Occupied(oe) = c.connections.entry(peer_addr) {
                     oe
                 } else {
                     return;
                 };
 
-                if (!conn.get().to_peer.is_established() && !conn.get().to_peer.is_not_needed())
-                    || (!conn.get().from_peer.is_established()
-                        && !conn.get().from_peer.is_not_needed())
-                    || (conn.get().to_peer.is_not_needed() && conn.get().from_peer.is_not_needed())
-                {
+                if conn.get().is_incomplete() {
                     trace!(
                         "Killing a non-completing connection for peer: {}",
                         peer_addr
                     );
-                    conn.remove();
+                    conn.remove().set_close_reason(CloseReason::Evicted);
                 }
             });
 
@@ -127,5 +415,48 @@ fn spawn_incomplete_conn_killer(peer_addr: SocketAddr) {
     // TODO find a way to cancel this timer if we know the connection is done. Otherwise it
     // might delay a clean exit of event loop if we were to use current_thread::run() instead
     // of block_on as just now in event_loop.rs
-    current_thread::spawn(leaf);
+    ctx.spawn(leaf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Peer;
+    use std::sync::mpsc;
+
+    #[test]
+    fn expired_sends_are_reported_and_the_rest_kept_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let event_tx = EventTx::new(tx, Default::default(), Default::default());
+        let node_info = NodeInfo {
+            peer_addr: unwrap!("127.0.0.1:1000".parse()),
+            peer_cert_der: vec![1, 2, 3],
+        };
+        let now = Instant::now();
+        let user_msg = |data: u8| WireMsg::UserMsg(bytes::Bytes::from(vec![data]));
+
+        let pending_sends = vec![
+            PendingSend::new(user_msg(0), None),
+            PendingSend::new(user_msg(1), Some(now)),
+            PendingSend::new(user_msg(2), Some(now + Duration::from_secs(1))),
+        ];
+        let msgs: Vec<_> = fire_expired_sends(&event_tx, &node_info, pending_sends, now)
+            .into_iter()
+            .filter_map(|pending_send| pending_send.msg.into_user_msg())
+            .collect();
+        assert_eq!(
+            msgs,
+            vec![bytes::Bytes::from(vec![0]), bytes::Bytes::from(vec![2])]
+        );
+
+        match unwrap!(rx.try_recv()) {
+            Event::UnsentUserMessage { peer, msg, reason } => {
+                assert_eq!(peer, Peer::Node { node_info });
+                assert_eq!(msg, bytes::Bytes::from(vec![1]));
+                assert_eq!(reason, UnsentReason::Expired);
+            }
+            x => panic!("Unexpected event: {:?}", x),
+        }
+        assert!(rx.try_recv().is_err());
+    }
 }
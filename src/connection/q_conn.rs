@@ -7,16 +7,35 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::wire_msg::CloseReason;
 use std::ops::{Deref, DerefMut};
 
 /// A quic-connection wrapper that will destroy the connection on drop
 pub struct QConn {
     q_conn: quinn::Connection,
+    close_reason: CloseReason,
+}
+
+impl QConn {
+    /// Reason to give the peer when the connection is closed on drop.
+    pub fn set_close_reason(&mut self, close_reason: CloseReason) {
+        self.close_reason = close_reason;
+    }
+
+    /// Certificate the peer authenticated itself with in TLS, if it presented any.
+    pub fn peer_cert_der(&self) -> Option<Vec<u8>> {
+        self.q_conn
+            .peer_der_certificates()
+            .and_then(|certs| certs.first().map(|cert| cert.as_der().to_vec()))
+    }
 }
 
 impl From<quinn::Connection> for QConn {
     fn from(q_conn: quinn::Connection) -> Self {
-        Self { q_conn }
+        Self {
+            q_conn,
+            close_reason: Default::default(),
+        }
     }
 }
 
@@ -36,6 +55,6 @@ impl DerefMut for QConn {
 
 impl Drop for QConn {
     fn drop(&mut self) {
-        self.q_conn.close(0, &[]);
+        self.q_conn.close(self.close_reason.code().into(), &[]);
     }
 }
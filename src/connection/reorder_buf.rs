@@ -0,0 +1,236 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::wire_msg::WireMsg;
+use std::collections::BTreeMap;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Sequenced user messages from the peer which arrived ahead of an earlier one, held back until it
+/// arrives or is given up on. See `Config::ordered_delivery`.
+#[derive(Default)]
+pub struct ReorderBuf {
+    next_seq: u64,
+    /// Messages by their sequence numbers, `None` for the ones skipped
+    held: BTreeMap<u64, Option<WireMsg>>,
+    /// When we started waiting for `next_seq`, if we are
+    gap_since: Option<Instant>,
+}
+
+impl ReorderBuf {
+    /// Take the message in. Returns the messages which can be handed over now, in order. Messages
+    /// behind ones given up on are handed over as they arrive. The peer starting over from zero,
+    /// e.g. after reconnecting, hands over all the messages held back first.
+    ///
+    /// Returns `None` and leaves the message out if its sequence number is more than `window` ahead
+    /// of the one we are waiting for, as no peer has that many messages in flight.
+    pub fn push(
+        &mut self,
+        seq: u64,
+        msg: WireMsg,
+        window: usize,
+        now: Instant,
+    ) -> Option<Vec<WireMsg>> {
+        self.take(seq, Some(msg), window, now)
+    }
+
+    /// Take the place of the message in the sequence without handing it over, e.g. as it's a
+    /// duplicate. Returns the messages which can be handed over now, in order, or `None` as per
+    /// `push`.
+    pub fn skip(&mut self, seq: u64, window: usize, now: Instant) -> Option<Vec<WireMsg>> {
+        self.take(seq, None, window, now)
+    }
+
+    /// Give up on the message waited for if we have been waiting for at least `timeout`. Returns
+    /// the messages which can be handed over now, in order.
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<WireMsg> {
+        match self.gap_since {
+            Some(gap_since) if now.duration_since(gap_since) >= timeout => (),
+            _ => return Vec::new(),
+        }
+
+        self.skip_gap();
+        let released = self.release();
+        self.gap_since = if self.held.is_empty() {
+            None
+        } else {
+            Some(now)
+        };
+
+        released
+    }
+
+    /// When we started waiting for the message the ones held back are waiting on, if we are.
+    pub fn gap_since(&self) -> Option<Instant> {
+        self.gap_since
+    }
+
+    /// Total size of the messages held back in bytes.
+    pub fn size_bytes(&self) -> usize {
+        self.held
+            .values()
+            .flatten()
+            .map(WireMsg::user_data_len)
+            .sum()
+    }
+
+    fn take(
+        &mut self,
+        seq: u64,
+        msg: Option<WireMsg>,
+        window: usize,
+        now: Instant,
+    ) -> Option<Vec<WireMsg>> {
+        if seq.saturating_sub(self.next_seq) > window as u64 {
+            return None;
+        }
+
+        let mut released = Vec::new();
+        if seq == 0 && self.next_seq != 0 {
+            released.extend(self.skip_all());
+        }
+        if seq < self.next_seq {
+            released.extend(msg);
+            return Some(released);
+        }
+
+        let next_seq = self.next_seq;
+        let _ = self.held.insert(seq, msg);
+        released.extend(self.release());
+        if self.held.len() > window {
+            self.skip_gap();
+            released.extend(self.release());
+        }
+        self.gap_since = match self.gap_since {
+            _ if self.held.is_empty() => None,
+            Some(gap_since) if self.next_seq == next_seq => Some(gap_since),
+            _ => Some(now),
+        };
+
+        Some(released)
+    }
+
+    fn skip_gap(&mut self) {
+        if let Some(&seq) = self.held.keys().next() {
+            self.next_seq = seq;
+        }
+    }
+
+    fn skip_all(&mut self) -> Vec<WireMsg> {
+        self.next_seq = 0;
+        self.gap_since = None;
+        let held = mem::replace(&mut self.held, Default::default());
+        held.into_iter().filter_map(|(_, msg)| msg).collect()
+    }
+
+    fn release(&mut self) -> Vec<WireMsg> {
+        let mut released = Vec::new();
+        while let Some(msg) = self.held.remove(&self.next_seq) {
+            released.extend(msg);
+            self.next_seq += 1;
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(n: u8) -> WireMsg {
+        WireMsg::UserMsg(bytes::Bytes::from(vec![n]))
+    }
+
+    fn ns(msgs: Vec<WireMsg>) -> Vec<u8> {
+        msgs.into_iter()
+            .map(|msg| match msg {
+                WireMsg::UserMsg(m) => m[0],
+                x => panic!("Unexpected message: {}", x),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_released_in_order() {
+        let mut buf: ReorderBuf = Default::default();
+        let now = Instant::now();
+
+        assert_eq!(ns(unwrap!(buf.push(0, msg(0), 8, now))), vec![0]);
+        assert!(unwrap!(buf.push(2, msg(2), 8, now)).is_empty());
+        assert!(unwrap!(buf.push(3, msg(3), 8, now)).is_empty());
+        assert_eq!(buf.gap_since(), Some(now));
+        assert_eq!(buf.size_bytes(), 2);
+        assert_eq!(ns(unwrap!(buf.push(1, msg(1), 8, now))), vec![1, 2, 3]);
+        assert_eq!(buf.gap_since(), None);
+    }
+
+    #[test]
+    fn gaps_are_given_up_on_past_the_window_or_the_timeout() {
+        let mut buf: ReorderBuf = Default::default();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(1);
+
+        // Window of two
+        assert_eq!(ns(unwrap!(buf.push(0, msg(0), 2, now))), vec![0]);
+        assert!(unwrap!(buf.push(2, msg(2), 2, now)).is_empty());
+        assert!(unwrap!(buf.push(3, msg(3), 2, now)).is_empty());
+        assert_eq!(ns(unwrap!(buf.push(4, msg(4), 2, now))), vec![2, 3, 4]);
+        // Late arrivals are handed over as they come
+        assert_eq!(ns(unwrap!(buf.push(1, msg(1), 2, now))), vec![1]);
+
+        assert!(unwrap!(buf.push(6, msg(6), 2, now)).is_empty());
+        assert!(buf.expire(now + timeout / 2, timeout).is_empty());
+        assert_eq!(ns(buf.expire(now + timeout, timeout)), vec![6]);
+        assert_eq!(buf.gap_since(), None);
+    }
+
+    #[test]
+    fn skipped_messages_fill_their_gap() {
+        let mut buf: ReorderBuf = Default::default();
+        let now = Instant::now();
+
+        assert!(unwrap!(buf.push(1, msg(1), 8, now)).is_empty());
+        assert!(unwrap!(buf.skip(2, 8, now)).is_empty());
+        assert_eq!(buf.size_bytes(), 1);
+        assert_eq!(ns(unwrap!(buf.skip(0, 8, now))), vec![1]);
+        assert_eq!(buf.gap_since(), None);
+        assert!(unwrap!(buf.skip(3, 8, now)).is_empty());
+        assert_eq!(ns(unwrap!(buf.push(4, msg(4), 8, now))), vec![4]);
+    }
+
+    #[test]
+    fn peer_starting_over_flushes_the_held_messages() {
+        let mut buf: ReorderBuf = Default::default();
+        let now = Instant::now();
+
+        assert_eq!(ns(unwrap!(buf.push(0, msg(0), 8, now))), vec![0]);
+        assert!(unwrap!(buf.push(2, msg(2), 8, now)).is_empty());
+        assert_eq!(ns(unwrap!(buf.push(0, msg(10), 8, now))), vec![2, 10]);
+        assert_eq!(ns(unwrap!(buf.push(1, msg(11), 8, now))), vec![11]);
+    }
+
+    #[test]
+    fn sequence_numbers_too_far_ahead_are_rejected() {
+        let mut buf: ReorderBuf = Default::default();
+        let now = Instant::now();
+        let window = 2;
+
+        // A peer trying to walk us past the end of the sequence
+        for seq in u64::max_value() - window as u64..=u64::max_value() {
+            assert!(buf.push(seq, msg(1), window, now).is_none());
+        }
+        assert!(buf.skip(u64::max_value(), window, now).is_none());
+        assert_eq!(buf.size_bytes(), 0);
+        assert_eq!(buf.gap_since(), None);
+
+        assert!(buf.push(window as u64 + 1, msg(3), window, now).is_none());
+        assert!(unwrap!(buf.push(window as u64, msg(2), window, now)).is_empty());
+        assert_eq!(ns(unwrap!(buf.push(0, msg(0), window, now))), vec![0]);
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::wire_msg::WireMsg;
+use std::collections::VecDeque;
+
+/// Bounded buffer of user messages sent to the peer which it has not acknowledged yet. These are
+/// replayed if the connection to the peer has to be re-established.
+#[derive(Default)]
+pub struct RetransmitBuf {
+    msgs: VecDeque<(u64, WireMsg)>,
+}
+
+impl RetransmitBuf {
+    /// Buffer the message under the given id. If the buffer is already holding `cap` messages the
+    /// oldest one is dropped.
+    pub fn push(&mut self, id: u64, msg: WireMsg, cap: usize) {
+        if cap == 0 {
+            return;
+        }
+        while self.msgs.len() >= cap {
+            let _ = self.msgs.pop_front();
+        }
+        self.msgs.push_back((id, msg));
+    }
+
+    /// Peer has acknowledged the message with the given id so there's no need to hold it anymore.
+    pub fn ack(&mut self, id: u64) {
+        if let Some(pos) = self.msgs.iter().position(|&(msg_id, _)| msg_id == id) {
+            let _ = self.msgs.remove(pos);
+        }
+    }
+
+    /// Total size of the buffered messages in bytes.
+    pub fn size_bytes(&self) -> usize {
+        self.msgs.iter().map(|(_, msg)| msg.user_data_len()).sum()
+    }
+
+    /// Take out all the unacknowledged messages along with their ids, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = (u64, WireMsg)> + '_ {
+        self.msgs.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_drops_oldest_messages_when_full_and_forgets_acked_ones() {
+        let mut buf: RetransmitBuf = Default::default();
+        for id in 0..4 {
+            buf.push(id, WireMsg::UserMsg(bytes::Bytes::from(vec![id as u8])), 3);
+        }
+
+        buf.ack(2);
+        buf.ack(0);
+
+        let msgs: Vec<_> = buf
+            .drain()
+            .map(|(id, msg)| match msg {
+                WireMsg::UserMsg(m) => (id, m),
+                x => panic!("Unexpected message: {}", x),
+            })
+            .collect();
+        assert_eq!(
+            msgs,
+            vec![
+                (1, bytes::Bytes::from(vec![1])),
+                (3, bytes::Bytes::from(vec![3]))
+            ]
+        );
+        assert!(buf.drain().next().is_none());
+    }
+}
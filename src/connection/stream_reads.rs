@@ -0,0 +1,93 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::utils::{self, ConnectTerminator};
+use std::collections::HashMap;
+
+/// Messages from the peer we have started reading but not finished yet. Each is read in a task of
+/// its own which stops as soon as its terminator here is fired or dropped, so a single read can be
+/// cancelled without touching the rest of the connection. Dropping this cancels all of them.
+#[derive(Default)]
+pub struct StreamReads {
+    next_id: u64,
+    terminators: HashMap<u64, ConnectTerminator>,
+}
+
+impl StreamReads {
+    /// Note a new read, returning its id and the receiver telling its task to stop.
+    pub fn start(&mut self) -> (u64, tokio::sync::mpsc::Receiver<()>) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let (terminator, rx) = utils::connect_terminator();
+        let _ = self.terminators.insert(id, terminator);
+        (id, rx)
+    }
+
+    /// The read has finished, one way or another.
+    pub fn finish(&mut self, id: u64) {
+        let _ = self.terminators.remove(&id);
+    }
+
+    /// Stop the read, returning whether it was still in progress.
+    pub fn cancel(&mut self, id: u64) -> bool {
+        match self.terminators.remove(&id) {
+            Some(mut terminator) => {
+                let _ = terminator.try_send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop all the reads, returning how many there were.
+    pub fn cancel_all(&mut self) -> usize {
+        let ids: Vec<_> = self.terminators.keys().cloned().collect();
+        ids.into_iter().filter(|id| self.cancel(*id)).count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.terminators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terminators.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::prelude::{future, Stream};
+    use tokio::runtime::current_thread;
+
+    /// Whether the read's task would be told to stop when polling the receiver now.
+    fn is_stopped(mut rx: tokio::sync::mpsc::Receiver<()>) -> bool {
+        let poll = unwrap!(current_thread::block_on_all(future::lazy(move || {
+            Ok::<_, ()>(rx.poll())
+        })));
+        unwrap!(poll).is_ready()
+    }
+
+    #[test]
+    fn cancelling_a_read_stops_only_its_task() {
+        let mut reads: StreamReads = Default::default();
+        let (cancelled_id, cancelled_rx) = reads.start();
+        let (_, kept_rx) = reads.start();
+        assert_eq!(reads.len(), 2);
+
+        assert!(reads.cancel(cancelled_id));
+        assert!(!reads.cancel(cancelled_id));
+        assert_eq!(reads.len(), 1);
+        assert!(is_stopped(cancelled_rx));
+        assert!(!is_stopped(kept_rx));
+
+        assert_eq!(reads.cancel_all(), 1);
+        assert!(reads.is_empty());
+    }
+}
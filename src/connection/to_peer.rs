@@ -11,6 +11,7 @@ use crate::connection::QConn;
 use crate::utils::ConnectTerminator;
 use crate::wire_msg::WireMsg;
 use std::fmt;
+use std::time::Instant;
 
 /// Represent various stages of connection from us to the peer.
 pub enum ToPeer {
@@ -19,7 +20,9 @@ pub enum ToPeer {
     Initiated {
         terminator: ConnectTerminator,
         peer_cert_der: Vec<u8>,
-        pending_sends: Vec<WireMsg>,
+        pending_sends: Vec<PendingSend>,
+        /// When we set out to connect, for `ConnectTimings`
+        started_at: Instant,
     },
     Established {
         peer_cert_der: Vec<u8>,
@@ -27,6 +30,41 @@ pub enum ToPeer {
     },
 }
 
+/// Message waiting for the connection to the peer to be established.
+pub struct PendingSend {
+    pub msg: WireMsg,
+    /// The message is not worth sending after this
+    pub expires_at: Option<Instant>,
+    /// Id the message was tracked under for replay when it was sent before, if it's a replay
+    pub unacked_msg_id: Option<u64>,
+}
+
+impl PendingSend {
+    pub fn new(msg: WireMsg, expires_at: Option<Instant>) -> Self {
+        Self {
+            msg,
+            expires_at,
+            unacked_msg_id: None,
+        }
+    }
+
+    /// Replay of the message sent before which the peer didn't acknowledge. It keeps its id so
+    /// that the peer can tell it's a replay, see `Config::dedup`.
+    pub fn replay(unacked_msg_id: u64, msg: WireMsg) -> Self {
+        Self {
+            msg,
+            expires_at: None,
+            unacked_msg_id: Some(unacked_msg_id),
+        }
+    }
+
+    /// Whether the message is past its expiry at `now`.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
 impl ToPeer {
     pub fn is_not_needed(&self) -> bool {
         if let ToPeer::NotNeeded = *self {
@@ -44,7 +82,6 @@ impl ToPeer {
         }
     }
 
-    #[allow(unused)]
     pub fn is_initiated(&self) -> bool {
         if let ToPeer::Initiated { .. } = *self {
             true
@@ -0,0 +1,51 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::connection::{Connection, FromPeer, QConn, ToPeer};
+
+/// QUIC is always secured with TLS 1.3 - there's nothing else to negotiate.
+const TLS_VERSION: &str = "TLSv1.3";
+
+/// Parameters negotiated for a connection, obtained via `QuicP2p::connection_details`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConnectionDetails {
+    /// Application protocol agreed on via ALPN, if any was offered
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Version of TLS securing the connection
+    pub tls_version: &'static str,
+    /// Cipher suite in use. Always `None` for now as quinn doesn't expose it yet.
+    pub cipher_suite: Option<String>,
+    /// Whether the connection was resumed with 0-RTT data. Always `false` for now as we don't
+    /// attempt 0-RTT.
+    pub used_0rtt: bool,
+    /// Whether this is the connection we made to the peer, as opposed to the one it made to us
+    pub is_outgoing: bool,
+}
+
+impl ConnectionDetails {
+    /// Details of the connection to the peer, preferring the one we made. `None` if none is
+    /// established yet.
+    pub(crate) fn new(conn: &Connection) -> Option<Self> {
+        match (&conn.to_peer, &conn.from_peer) {
+            (ToPeer::Established { q_conn, .. }, _) => Some(Self::from_q_conn(q_conn, true)),
+            (_, FromPeer::Established { q_conn, .. }) => Some(Self::from_q_conn(q_conn, false)),
+            _ => None,
+        }
+    }
+
+    fn from_q_conn(q_conn: &QConn, is_outgoing: bool) -> Self {
+        Self {
+            alpn_protocol: q_conn.protocol().map(|protocol| protocol.into_vec()),
+            tls_version: TLS_VERSION,
+            cipher_suite: None,
+            used_0rtt: false,
+            is_outgoing,
+        }
+    }
+}
@@ -7,110 +7,516 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::backpressure::BackpressureLevel;
+use crate::batch_connect::BatchConnects;
+use crate::batch_send::BatchSends;
 use crate::bootstrap_cache::BootstrapCache;
-use crate::config::{OurType, SerialisableCertificate};
+use crate::cert_rotation::ServerCert;
+use crate::client_grace::HeldClientSends;
+use crate::clock::SharedClock;
+use crate::config::{
+    AdmissionPolicy, CertParams, DialBackoffConfig, OrderedDeliveryConfig, OurType,
+    ReputationConfig, RetryPolicy, SerialisableCertificate, SocketOptions,
+};
+use crate::connect::{DialBackoff, QueuedConnect};
 use crate::connection::Connection;
-use crate::event::Event;
+use crate::dedup::Dedup;
+use crate::dns::HostContacts;
+use crate::event::EventTx;
+#[cfg(feature = "testing")]
+use crate::fault_injection::Faults;
+use crate::handshake_auth::SeenNonces;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::multicast::Multicasts;
+use crate::observed_addrs::ObservedAddrs;
+use crate::reputation::Reputation;
+use crate::rng::SharedRng;
+use crate::send_scheduler::SendScheduler;
+use crate::stats::LifetimeStatsTracker;
+use crate::transfer::Transfers;
+use crate::transport::SharedTransport;
+use crate::utils::ConnectTerminator;
+use crate::wire_msg::CloseReason;
+#[cfg(feature = "wire-tap")]
+use crate::wire_tap::WireTap;
+use crate::{Error, NodeInfo};
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
 use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use tokio::prelude::{Async, Future, Poll};
+use tokio::runtime::current_thread;
 
-thread_local! {
-    pub static CTX: RefCell<Option<Context>> = RefCell::new(None);
-}
+/// Contexts of the instances running on an event loop thread, by instance id. Owned by the event
+/// loop itself, which hands every message posted on behalf of an instance its context.
+//
+// The contexts live in the event loop thread and all our tasks run on its `current_thread`
+// runtime. Moving to a threaded runtime, with the contexts shared behind a lock, isn't possible
+// with the quinn version we use: its `Endpoint`, `Connection` and stream handles are built on `Rc`
+// and so are neither `Send` nor `Sync`. That has to wait for a quinn upgrade, which also changes
+// the connection driver API this crate is built around.
+#[derive(Default)]
+pub struct Instances(HashMap<usize, Ctx>);
 
-/// Initialise `Context`. This will panic if the context has already been initialised for the
-/// current thread context.
-pub fn initialise_ctx(context: Context) {
-    CTX.with(|ctx_refcell| {
-        let mut ctx = ctx_refcell.borrow_mut();
-        if ctx.is_some() {
+impl Instances {
+    /// Initialise the `Context` of the instance. This will panic if the context has already been
+    /// initialised for the instance.
+    pub fn initialise(&mut self, instance_id: usize, context: Context) -> Ctx {
+        if self.0.contains_key(&instance_id) {
             panic!("Context already initialised !");
-        } else {
-            *ctx = Some(context);
         }
-    })
-}
+        let ctx = Ctx::new(context);
+        let _ = self.0.insert(instance_id, ctx.clone());
+        ctx
+    }
 
-/// Check if the `Context` is already initialised for the current thread context.
-#[allow(unused)]
-pub fn is_ctx_initialised() -> bool {
-    CTX.with(|ctx_refcell| {
-        let ctx = ctx_refcell.borrow();
-        ctx.is_some()
-    })
+    /// Run `f` with the `Context` of the instance. `f` is skipped if the instance isn't initialised
+    /// yet or was closed already, the latter being the case for whatever was posted to a shared
+    /// event loop after the instance was dropped.
+    pub fn enter<F: FnOnce(&Ctx)>(&self, instance_id: usize, f: F) {
+        match self.0.get(&instance_id) {
+            Some(ctx) if !ctx.is_closed() => f(ctx),
+            _ => trace!("Instance {} isn't running - not entering it", instance_id),
+        }
+    }
+
+    /// Drop the `Context` of the instance, which ends the tasks it spawned the next time they are
+    /// polled.
+    pub fn close(&mut self, instance_id: usize) {
+        if let Some(ctx) = self.0.remove(&instance_id) {
+            ctx.close();
+        }
+    }
 }
 
-/// Obtain a referece to the `Context`. This will panic if the `Context` has not be set in the
-/// current thread context.
-pub fn ctx<F, R>(f: F) -> R
-where
-    F: FnOnce(&Context) -> R,
-{
-    CTX.with(|ctx_refcell| {
-        let ctx = ctx_refcell.borrow();
+/// Handle to the `Context` of an instance, passed to whatever runs on its behalf. The context is
+/// gone once the instance is closed, and with it the tasks spawned via `spawn`.
+#[derive(Clone)]
+pub struct Ctx(Rc<RefCell<Option<Context>>>);
+
+impl Ctx {
+    /// Hand out the `Context`. Instances get theirs from `Instances::initialise`.
+    pub fn new(context: Context) -> Self {
+        Ctx(Rc::new(RefCell::new(Some(context))))
+    }
+
+    /// Whether the instance was closed.
+    pub fn is_closed(&self) -> bool {
+        self.0.borrow().is_none()
+    }
+
+    /// Obtain a referece to the `Context`. This will panic if the instance was closed.
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Context) -> R,
+    {
+        let ctx = self.0.borrow();
         if let Some(ctx) = ctx.as_ref() {
             f(ctx)
         } else {
-            panic!("Context not initialised !");
+            panic!("Context closed !");
         }
-    })
-}
+    }
 
-/// Obtain a mutable referece to the `Context`. This will panic if the `Context` has not be set in
-/// the current thread context.
-pub fn ctx_mut<F, R>(f: F) -> R
-where
-    F: FnOnce(&mut Context) -> R,
-{
-    CTX.with(|ctx_refcell| {
-        let mut ctx = ctx_refcell.borrow_mut();
+    /// Obtain a mutable referece to the `Context`. This will panic if the instance was closed.
+    pub fn with_mut<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Context) -> R,
+    {
+        let mut ctx = self.0.borrow_mut();
         if let Some(ctx) = ctx.as_mut() {
             f(ctx)
         } else {
-            panic!("Context not initialised !");
+            panic!("Context closed !");
         }
-    })
+    }
+
+    /// Spawn the task on the event loop for as long as the instance runs. Every task touching the
+    /// `Context` must be spawned via this rather than `current_thread::spawn`.
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + 'static,
+    {
+        current_thread::spawn(UntilClosed {
+            ctx: self.clone(),
+            future,
+        })
+    }
+
+    /// Spawn the quinn driver on the event loop. Unlike other tasks it outlives the instance, so
+    /// that the connections closed along with the context are closed with the peers too. Its
+    /// errors are handled for only as long as the instance runs.
+    pub fn spawn_driver<D, H>(&self, driver: D, on_err: H)
+    where
+        D: Future<Item = ()> + 'static,
+        H: FnOnce(&Ctx, D::Error) + 'static,
+    {
+        let ctx = self.clone();
+        current_thread::spawn(driver.map_err(move |e| {
+            if !ctx.is_closed() {
+                on_err(&ctx, e)
+            }
+        }));
+    }
+
+    fn close(&self) {
+        // Dropped outside the borrow, in case anything dropped with it reaches for the context
+        let context = self.0.borrow_mut().take();
+        drop(context);
+    }
+}
+
+/// Task polled until the instance which spawned it is closed.
+struct UntilClosed<F> {
+    ctx: Ctx,
+    future: F,
+}
+
+impl<F: Future<Item = (), Error = ()>> Future for UntilClosed<F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.ctx.is_closed() {
+            return Ok(Async::Ready(()));
+        }
+        self.future.poll()
+    }
 }
 
 /// The context to the event loop. This holds all the states that are necessary to be persistant
 /// between calls to poll the event loop for the next event.
 pub struct Context {
-    pub event_tx: Sender<Event>,
+    pub event_tx: EventTx,
     pub connections: HashMap<SocketAddr, Connection>,
     pub our_ext_addr_tx: Option<Sender<SocketAddr>>,
+    pub our_connection_info: Option<NodeInfo>,
+    /// User is waiting for `Event::OurConnectionInfoReady`
+    pub our_connection_info_requested: bool,
     pub our_complete_cert: SerialisableCertificate,
+    /// Certificate presented to the peers connecting to us, kept in step with `our_complete_cert`
+    pub server_cert: ServerCert,
     pub max_msg_size_allowed: usize,
+    pub per_peer_buffer_limit: Option<usize>,
     pub idle_timeout_msec: u64,
     pub keep_alive_interval_msec: u32,
+    pub stream_receive_window: u64,
+    pub connection_receive_window: u64,
     pub our_type: OurType,
+    /// Whether our endpoint accepts connections at all
+    pub listen: bool,
+    /// Whether nodes authenticate themselves in TLS when connecting to each other
+    pub mutual_tls: bool,
+    /// Name we ask for via SNI and expect to be asked for
+    pub server_name: String,
+    /// IP address we advertise instead of discovering it
+    pub external_addr: Option<IpAddr>,
+    /// Port we advertise instead of the one we are bound to
+    pub external_port: Option<u16>,
+    pub network_id: String,
+    pub auto_reconnect: Option<RetryPolicy>,
+    /// Addresses dials to which are held back after failing
+    pub dial_backoff: DialBackoff,
+    /// Consecutive reconnect attempts made so far to each of the peers being reconnected to
+    pub reconnect_attempts: HashMap<SocketAddr, u32>,
+    /// Used to uniquely identify messages awaiting acknowledgement across all connections, and their
+    /// replays to peers, see `Config::dedup`
+    pub next_unacked_msg_id: u64,
+    /// Misbehaviour of peers
+    pub reputation: Reputation,
+    /// Nonces of the node handshakes received so far
+    pub seen_handshake_nonces: SeenNonces,
+    /// Addresses peers reported reaching us at
+    pub observed_addrs: ObservedAddrs,
+    pub max_contacts_to_share: usize,
+    pub contacts_request_interval_sec: u64,
+    /// When we last shared our contacts with each of the peers which asked for them within the
+    /// last `contacts_request_interval_sec`
+    pub contacts_shared_at: HashMap<SocketAddr, Instant>,
+    /// Peers we asked for contacts which haven't sent them yet
+    pub contacts_requested_from: HashSet<SocketAddr>,
+    pub reverse_connect_interval_sec: u64,
+    /// When each of the peers which asked us to connect to a node on their behalf within the last
+    /// `reverse_connect_interval_sec` did so
+    pub reverse_connects_at: HashMap<SocketAddr, Instant>,
+    pub max_concurrent_connects: Option<usize>,
+    /// Peers we are currently handshaking with and when we started doing so
+    pub connects_in_flight: HashMap<SocketAddr, Instant>,
+    /// Connects waiting for the number of connects in flight to drop below the limit
+    pub queued_connects: VecDeque<QueuedConnect>,
+    /// Maximum number of entries in `connections`, see `admission`
+    pub max_total_connections: Option<usize>,
+    pub admission_policy: AdmissionPolicy,
+    /// Time each proxy gets to connect when bootstrapping
+    pub bootstrap_member_budget: Option<Duration>,
+    /// Time the other proxies get once we have bootstrapped to one
+    pub bootstrap_grace: Duration,
+    pub send_over_incoming_connections: bool,
+    /// Interleaves the writes to different peers
+    pub send_scheduler: SendScheduler,
+    /// Channels we accept user messages on besides the default one
+    pub channels: Vec<u8>,
+    /// Time a peer gets to complete a message once it has started sending it
+    pub read_timeout: Option<Duration>,
+    /// Time a peer gets to take a whole message from us once we have started sending it
+    pub write_timeout: Option<Duration>,
+    /// Time a peer connecting to us gets to send its handshake
+    pub handshake_timeout: Option<Duration>,
+    /// Maximum number of incomplete messages read from a single connection at a time
+    pub max_incomplete_reads: usize,
+    /// Streams accepted from a single connection in a row before yielding to the other tasks
+    pub data_lane_budget: usize,
+    /// Application protocols we offer and accept via ALPN. Empty if we don't use ALPN.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// How long messages to disconnected clients are held for
+    pub client_send_grace: Option<Duration>,
+    /// Messages to disconnected clients awaiting their return
+    pub held_client_sends: HeldClientSends,
+    /// Socket options asked for, applied again whenever we rebind
+    pub socket_options: SocketOptions,
+    /// Socket options in effect on our endpoint, as reported by the OS
+    pub effective_socket_options: SocketOptions,
+    /// Parameters of the certificates we generate when rotating ours
+    pub cert_params: CertParams,
+    /// Whether we send user messages back to their senders, see `Config::echo_service`
+    pub echo_service: bool,
+    /// Whether illegal states sever the connection instead of panicking, see `Config::strict`
+    pub strict: bool,
+    /// Whether user messages from peers are handed over in order, see `Config::ordered_delivery`
+    pub ordered_delivery: Option<OrderedDeliveryConfig>,
+    /// Level of the backlog last reported, see `Config::backpressure`
+    pub backpressure_level: BackpressureLevel,
+    /// Ids of the messages from peers remembered to drop their replays, see `Config::dedup`
+    pub dedup: Option<Dedup>,
     pub bootstrap_cache: BootstrapCache,
+    /// Hard coded contacts given by hostname and what they resolved to
+    pub host_contacts: HostContacts,
+    pub lifetime_stats: LifetimeStatsTracker,
+    /// Time source for our timers, the system clock unless a test supplied its own
+    pub clock: SharedClock,
+    /// Binds the socket our endpoint runs over, a UDP socket of the OS unless the user supplied
+    /// their own
+    pub transport: SharedTransport,
+    /// Stops the listener when fired
+    pub listener_terminator: Option<ConnectTerminator>,
+    /// New incoming connections are refused while this is unset, except from the nodes we are
+    /// connecting to ourselves
+    pub is_accepting_incoming: bool,
+    pub our_handshake_data: Option<bytes::Bytes>,
+    /// Batches of connects asked for via `QuicP2p::connect_to_many` still in progress
+    pub batch_connects: BatchConnects,
+    /// Sends asked for via `QuicP2p::send_to_many` still in progress
+    pub multicasts: Multicasts,
+    /// Batches asked for via `QuicP2p::send_batch` still in progress
+    pub batch_sends: BatchSends,
+    /// Files being sent with `QuicP2p::send_file` and received from peers
+    pub transfers: Transfers,
+    /// RNG supplied by the user for nonces and certificates, the OS randomness used otherwise
+    pub rng: Option<SharedRng>,
+    #[cfg(feature = "wire-tap")]
+    pub wire_tap: Option<Box<dyn WireTap>>,
+    #[cfg(feature = "testing")]
+    pub faults: Faults,
+    #[cfg(feature = "metrics")]
+    pub metrics: Metrics,
     quic_ep: quinn::Endpoint,
 }
 
+/// What a `Context` is set up with, resolved from the `Config` and the builder once when we start.
+/// The fields are the ones of the `Context` of the same names, or what those are made from.
+pub struct ContextSettings {
+    pub event_tx: EventTx,
+    pub our_complete_cert: SerialisableCertificate,
+    pub server_cert: ServerCert,
+    pub max_msg_size_allowed: usize,
+    pub per_peer_buffer_limit: Option<usize>,
+    pub idle_timeout_msec: u64,
+    pub keep_alive_interval_msec: u32,
+    pub stream_receive_window: u64,
+    pub connection_receive_window: u64,
+    pub our_type: OurType,
+    pub listen: bool,
+    pub mutual_tls: bool,
+    pub server_name: String,
+    pub external_addr: Option<IpAddr>,
+    pub external_port: Option<u16>,
+    pub network_id: String,
+    pub auto_reconnect: Option<RetryPolicy>,
+    pub dial_backoff: Option<DialBackoffConfig>,
+    pub reputation: Option<ReputationConfig>,
+    pub max_contacts_to_share: usize,
+    pub contacts_request_interval_sec: u64,
+    pub reverse_connect_interval_sec: u64,
+    pub max_concurrent_connects: Option<usize>,
+    pub max_total_connections: Option<usize>,
+    pub admission_policy: AdmissionPolicy,
+    pub bootstrap_member_budget: Option<Duration>,
+    pub bootstrap_grace: Duration,
+    pub send_over_incoming_connections: bool,
+    pub send_quantum_bytes: usize,
+    pub max_send_rate: Option<u64>,
+    pub channels: Vec<u8>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub handshake_timeout: Option<Duration>,
+    pub max_incomplete_reads: usize,
+    pub data_lane_budget: usize,
+    pub alpn_protocols: Vec<Vec<u8>>,
+    pub client_send_grace: Option<Duration>,
+    pub socket_options: SocketOptions,
+    pub effective_socket_options: SocketOptions,
+    pub cert_params: CertParams,
+    pub echo_service: bool,
+    pub strict: bool,
+    pub transfers: Transfers,
+    pub ordered_delivery: Option<OrderedDeliveryConfig>,
+    pub bootstrap_cache: BootstrapCache,
+    pub host_contacts: HostContacts,
+    pub lifetime_stats: LifetimeStatsTracker,
+    pub clock: SharedClock,
+    pub transport: SharedTransport,
+}
+
 impl Context {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(
-        event_tx: Sender<Event>,
-        our_complete_cert: SerialisableCertificate,
-        max_msg_size_allowed: usize,
-        idle_timeout_msec: u64,
-        keep_alive_interval_msec: u32,
-        our_type: OurType,
-        bootstrap_cache: BootstrapCache,
-        quic_ep: quinn::Endpoint,
-    ) -> Self {
+    pub fn new(settings: ContextSettings, quic_ep: quinn::Endpoint) -> Self {
+        let ContextSettings {
+            event_tx,
+            our_complete_cert,
+            server_cert,
+            max_msg_size_allowed,
+            per_peer_buffer_limit,
+            idle_timeout_msec,
+            keep_alive_interval_msec,
+            stream_receive_window,
+            connection_receive_window,
+            our_type,
+            listen,
+            mutual_tls,
+            server_name,
+            external_addr,
+            external_port,
+            network_id,
+            auto_reconnect,
+            dial_backoff,
+            reputation,
+            max_contacts_to_share,
+            contacts_request_interval_sec,
+            reverse_connect_interval_sec,
+            max_concurrent_connects,
+            max_total_connections,
+            admission_policy,
+            bootstrap_member_budget,
+            bootstrap_grace,
+            send_over_incoming_connections,
+            send_quantum_bytes,
+            max_send_rate,
+            channels,
+            read_timeout,
+            write_timeout,
+            handshake_timeout,
+            max_incomplete_reads,
+            data_lane_budget,
+            alpn_protocols,
+            client_send_grace,
+            socket_options,
+            effective_socket_options,
+            cert_params,
+            echo_service,
+            strict,
+            transfers,
+            ordered_delivery,
+            bootstrap_cache,
+            host_contacts,
+            lifetime_stats,
+            clock,
+            transport,
+        } = settings;
+        let mut send_scheduler = SendScheduler::new(send_quantum_bytes);
+        send_scheduler.set_max_rate(max_send_rate, clock.now());
+
         Self {
             event_tx,
             connections: Default::default(),
             our_ext_addr_tx: Default::default(),
+            our_connection_info: None,
+            our_connection_info_requested: false,
             our_complete_cert,
+            server_cert,
             max_msg_size_allowed,
+            per_peer_buffer_limit,
             idle_timeout_msec,
             keep_alive_interval_msec,
+            stream_receive_window,
+            connection_receive_window,
             our_type,
+            listen,
+            mutual_tls,
+            server_name,
+            external_addr,
+            external_port,
+            network_id,
+            auto_reconnect,
+            dial_backoff: DialBackoff::new(dial_backoff),
+            reconnect_attempts: Default::default(),
+            next_unacked_msg_id: 0,
+            reputation: Reputation::new(reputation),
+            seen_handshake_nonces: Default::default(),
+            observed_addrs: Default::default(),
+            max_contacts_to_share,
+            contacts_request_interval_sec,
+            contacts_shared_at: Default::default(),
+            contacts_requested_from: Default::default(),
+            reverse_connect_interval_sec,
+            reverse_connects_at: Default::default(),
+            max_concurrent_connects,
+            connects_in_flight: Default::default(),
+            queued_connects: Default::default(),
+            max_total_connections,
+            admission_policy,
+            bootstrap_member_budget,
+            bootstrap_grace,
+            send_over_incoming_connections,
+            send_scheduler,
+            channels,
+            read_timeout,
+            write_timeout,
+            handshake_timeout,
+            max_incomplete_reads,
+            data_lane_budget,
+            alpn_protocols,
+            client_send_grace,
+            held_client_sends: Default::default(),
+            socket_options,
+            effective_socket_options,
+            cert_params,
+            echo_service,
+            strict,
+            ordered_delivery,
+            backpressure_level: Default::default(),
+            dedup: None,
             bootstrap_cache,
+            host_contacts,
+            lifetime_stats,
+            clock,
+            transport,
+            listener_terminator: None,
+            is_accepting_incoming: true,
+            our_handshake_data: None,
+            batch_connects: Default::default(),
+            multicasts: Default::default(),
+            batch_sends: Default::default(),
+            transfers,
+            rng: None,
+            #[cfg(feature = "wire-tap")]
+            wire_tap: None,
+            #[cfg(feature = "testing")]
+            faults: Default::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
             quic_ep,
         }
     }
@@ -118,4 +524,61 @@ impl Context {
     pub fn quic_ep(&self) -> &quinn::Endpoint {
         &self.quic_ep
     }
+
+    /// Our configured external address along with our external port or else the port we are
+    /// bound to, if there's one.
+    pub fn external_connection_addr(&self) -> Option<SocketAddr> {
+        let ip = self.external_addr?;
+        let port = match self.external_port {
+            Some(port) => port,
+            None => self.quic_ep().local_addr().ok()?.port(),
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// The address we are reached at with our configured external port, if there's one.
+    pub fn with_external_port(&self, mut addr: SocketAddr) -> SocketAddr {
+        if let Some(port) = self.external_port {
+            addr.set_port(port);
+        }
+        addr
+    }
+
+    /// Replace our endpoint with the given one, returning the previous one.
+    pub fn replace_quic_ep(&mut self, quic_ep: quinn::Endpoint) -> quinn::Endpoint {
+        mem::replace(&mut self.quic_ep, quic_ep)
+    }
+
+    /// Drop the connection to the peer, telling it why. Returns whether there was a connection.
+    pub fn close_connection(&mut self, peer_addr: &SocketAddr, reason: CloseReason) -> bool {
+        match self.connections.remove(peer_addr) {
+            Some(mut conn) => {
+                conn.set_close_reason(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The connection to the peer got into a state it never should have. In strict mode the
+    /// connection is severed and the error returned, otherwise this panics.
+    pub fn sever_illegal_state(&mut self, peer_addr: SocketAddr, what: String) -> Error {
+        let e = Error::IllegalState(peer_addr, what);
+        if !self.strict {
+            panic!("Logic Error - {}", e);
+        }
+
+        warn!("{} - severing the connection", e);
+        let _ = self.close_connection(&peer_addr, CloseReason::IllegalState);
+        e
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        self.lifetime_stats.save();
+        for conn in self.connections.values_mut() {
+            conn.set_close_reason(CloseReason::Shutdown);
+        }
+    }
 }
@@ -0,0 +1,135 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::connection::{Connection, FromPeer, ToPeer};
+use crate::context::Context;
+use crate::{NodeInfo, PeerKind};
+use std::net::SocketAddr;
+
+/// Dump of our internal state for diagnosing e.g. connections stuck half way through being set
+/// up, obtained via `QuicP2p::debug_dump`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct DebugSnapshot {
+    /// Every connection we hold, ordered by the peer address
+    pub connections: Vec<ConnectionDump>,
+    /// Peers we are handshaking with
+    pub connects_in_flight: Vec<SocketAddr>,
+    /// Peers whose connects wait for a free slot, in the order they'll be started
+    pub queued_connects: Vec<SocketAddr>,
+    /// Contents of our bootstrap cache, most recently validated last
+    pub bootstrap_cache: Vec<NodeInfo>,
+    /// Our connection info, if resolved already
+    pub our_connection_info: Option<NodeInfo>,
+}
+
+impl DebugSnapshot {
+    /// Take a snapshot of the given context
+    pub(crate) fn new(c: &Context) -> Self {
+        let mut connections: Vec<_> = c
+            .connections
+            .iter()
+            .map(|(peer_addr, conn)| ConnectionDump::new(*peer_addr, conn))
+            .collect();
+        connections.sort_by_key(|conn| conn.peer_addr);
+
+        let mut connects_in_flight: Vec<_> = c.connects_in_flight.keys().cloned().collect();
+        connects_in_flight.sort();
+
+        Self {
+            connections,
+            connects_in_flight,
+            queued_connects: c
+                .queued_connects
+                .iter()
+                .map(|connect| connect.peer_addr())
+                .collect(),
+            bootstrap_cache: c.bootstrap_cache.peers().iter().cloned().collect(),
+            our_connection_info: c.our_connection_info.clone(),
+        }
+    }
+}
+
+/// State of a single connection, see `DebugSnapshot`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ConnectionDump {
+    pub peer_addr: SocketAddr,
+    /// Stage of the connection from us to the peer
+    pub to_peer: ToPeerState,
+    /// Stage of the connection from the peer to us
+    pub from_peer: FromPeerState,
+    /// Messages waiting for our connection to the peer to be established
+    pub pending_sends: usize,
+    /// Messages from the peer waiting for the connection to be fully set up
+    pub pending_reads: usize,
+    /// Messages from the peer we have started reading but not finished yet
+    pub incomplete_reads: usize,
+    /// Bytes of user messages the peer hasn't acknowledged yet
+    pub unacked_bytes: usize,
+    /// Whether the connection is one of the attempts of a bootstrap
+    pub in_bootstrap_group: bool,
+    /// Whether the peer has introduced itself to us via its handshake yet
+    pub peer_handshake_rxd: bool,
+    /// Kind of the peer, if known yet
+    pub peer_kind: Option<PeerKind>,
+    /// Whether we contacted the peer ourselves, rather than it only connecting to us
+    pub we_contacted_peer: bool,
+}
+
+impl ConnectionDump {
+    fn new(peer_addr: SocketAddr, conn: &Connection) -> Self {
+        let (to_peer, pending_sends) = match conn.to_peer {
+            ToPeer::NoConnection => (ToPeerState::NoConnection, 0),
+            ToPeer::NotNeeded => (ToPeerState::NotNeeded, 0),
+            ToPeer::Initiated {
+                ref pending_sends, ..
+            } => (ToPeerState::Initiated, pending_sends.len()),
+            ToPeer::Established { .. } => (ToPeerState::Established, 0),
+        };
+        let (from_peer, pending_reads) = match conn.from_peer {
+            FromPeer::NoConnection => (FromPeerState::NoConnection, 0),
+            FromPeer::NotNeeded => (FromPeerState::NotNeeded, 0),
+            FromPeer::Established {
+                ref pending_reads, ..
+            } => (FromPeerState::Established, pending_reads.len()),
+        };
+
+        Self {
+            peer_addr,
+            to_peer,
+            from_peer,
+            pending_sends,
+            pending_reads,
+            incomplete_reads: conn.stream_reads.len(),
+            unacked_bytes: conn.unacked_msgs.size_bytes(),
+            in_bootstrap_group: conn.bootstrap_group_ref.is_some(),
+            peer_handshake_rxd: conn.peer_handshake_rxd,
+            peer_kind: conn.peer_kind,
+            we_contacted_peer: conn.we_contacted_peer,
+        }
+    }
+}
+
+/// Stage of the connection from us to the peer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum ToPeerState {
+    NoConnection,
+    /// The peer is a client so we only talk over its connection to us
+    NotNeeded,
+    Initiated,
+    Established,
+}
+
+/// Stage of the connection from the peer to us.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum FromPeerState {
+    NoConnection,
+    /// We are a client so the peer only talks over our connection to it
+    NotNeeded,
+    Established,
+}
@@ -0,0 +1,158 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Deduplication of user messages replayed after reconnecting, see `Config::dedup`. A message is
+//! replayed if the peer hadn't acknowledged it when the connection failed, which doesn't mean it
+//! hadn't received it. The messages we track for replay are tagged with the id they are tracked
+//! under, which they keep when replayed, and the ids of the tagged messages from each peer are
+//! remembered to drop their replays before they are handed over. The windows outlive the
+//! connections, as replays come over the next one.
+
+use crate::config::DedupConfig;
+use crate::context::Ctx;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Number of peers whose ids are remembered. The ones heard from least recently are forgotten
+/// first.
+const MAX_PEERS: usize = 1024;
+
+/// Ids of the tagged messages from peers remembered so far.
+pub struct Dedup {
+    window: usize,
+    windows: HashMap<SocketAddr, DedupWindow>,
+    /// Replays dropped so far
+    pub hits: u64,
+}
+
+/// Latest ids from one peer.
+#[derive(Default)]
+struct DedupWindow {
+    order: VecDeque<u64>,
+    ids: HashSet<u64>,
+    last_used: Option<Instant>,
+}
+
+impl DedupWindow {
+    /// Remember the id, forgetting the oldest one past `window` ids. Returns whether it's new.
+    fn insert(&mut self, id: u64, window: usize) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        while self.order.len() > window {
+            if let Some(oldest) = self.order.pop_front() {
+                let _ = self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+impl Dedup {
+    fn new(cfg: DedupConfig) -> Self {
+        Self {
+            window: cfg.window as usize,
+            windows: Default::default(),
+            hits: 0,
+        }
+    }
+
+    /// Remember the id of the message from the peer. Returns whether it's a replay of one we had
+    /// received already.
+    fn is_duplicate(&mut self, peer_addr: SocketAddr, id: u64, now: Instant) -> bool {
+        if !self.windows.contains_key(&peer_addr) && self.windows.len() >= MAX_PEERS {
+            let least_recent = self
+                .windows
+                .iter()
+                .min_by_key(|(_, window)| window.last_used)
+                .map(|(peer_addr, _)| *peer_addr);
+            if let Some(least_recent) = least_recent {
+                let _ = self.windows.remove(&least_recent);
+            }
+        }
+
+        let window = self
+            .windows
+            .entry(peer_addr)
+            .or_insert_with(Default::default);
+        window.last_used = Some(now);
+        if window.insert(id, self.window) {
+            return false;
+        }
+
+        self.hits += 1;
+        true
+    }
+}
+
+/// Start tagging the messages we track for replay and dropping the replays from peers. The ids
+/// carry on from a random one, so that our messages from before a restart are not taken for
+/// replays of the ones after it.
+pub fn start(ctx: &Ctx, cfg: DedupConfig) {
+    let mut first_id = [0; 8];
+    if SystemRandom::new().fill(&mut first_id).is_err() {
+        warn!("Could not randomise the ids of our messages - they start from zero");
+    }
+
+    ctx.with_mut(|c| {
+        c.next_unacked_msg_id = u64::from_be_bytes(first_id);
+        c.dedup = Some(Dedup::new(cfg));
+    })
+}
+
+/// Whether the tagged message from the peer is a replay of one we had received already, in which
+/// case it's to be dropped. This must not be called while the `Context` is already borrowed.
+pub fn is_duplicate(ctx: &Ctx, peer_addr: SocketAddr, dedup_id: u64) -> bool {
+    let is_duplicate = ctx.with_mut(|c| {
+        let now = c.clock.now();
+        c.dedup
+            .as_mut()
+            .map_or(false, |dedup| dedup.is_duplicate(peer_addr, dedup_id, now))
+    });
+
+    if is_duplicate {
+        debug!(
+            "Dropping message {} from peer {} as we had received it already",
+            dedup_id, peer_addr
+        );
+        #[cfg(feature = "metrics")]
+        metrics::record_duplicate_dropped(ctx);
+    }
+
+    is_duplicate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_within_the_window_are_duplicates() {
+        let mut dedup = Dedup::new(DedupConfig { window: 2 });
+        let peer0: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+        let peer1: SocketAddr = unwrap!("127.0.0.1:1001".parse());
+        let now = Instant::now();
+
+        assert!(!dedup.is_duplicate(peer0, 1, now));
+        assert!(!dedup.is_duplicate(peer0, 2, now));
+        assert!(dedup.is_duplicate(peer0, 1, now));
+        // Ids are per peer
+        assert!(!dedup.is_duplicate(peer1, 1, now));
+        // Past the window the oldest ones are forgotten
+        assert!(!dedup.is_duplicate(peer0, 3, now));
+        assert!(!dedup.is_duplicate(peer0, 1, now));
+        assert!(dedup.is_duplicate(peer0, 3, now));
+        assert_eq!(dedup.hits, 2);
+    }
+}
@@ -24,6 +24,11 @@ impl OverRide {
             path: PathBuf::from(path.to_owned()),
         }
     }
+
+    /// Like `new`, for a path which might not be valid UTF-8.
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
 }
 
 pub enum Dirs {
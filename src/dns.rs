@@ -0,0 +1,231 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Hard coded contacts given by hostname, see `NodeAddr`. The names are looked up off the event
+//! loop via a `Resolver`, the one of the OS unless the user supplied their own via
+//! `Builder::with_resolver`. What they resolve to is cached for `Config::dns_cache_ttl_sec`, or
+//! until dialling one of the addresses fails, and every one of the addresses is dialled.
+
+use crate::error::Error;
+use crate::peer::{Contact, NodeAddr, NodeInfo};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::prelude::Future;
+use tokio::sync::oneshot;
+
+/// Looks up the addresses of the hard coded contacts given by hostname.
+pub trait Resolver: Send + Sync {
+    /// Every address the host resolves to, each with the given port. It's called on a thread of
+    /// its own, so it's fine to block.
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolver shared by the builder and the event loop.
+pub type SharedResolver = Arc<dyn Resolver>;
+
+/// Resolver of the OS, the default.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// Hard coded contact given by hostname.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostContact {
+    pub host: String,
+    pub port: u16,
+    pub peer_cert_der: Vec<u8>,
+}
+
+impl HostContact {
+    /// The node at one of the addresses the host resolved to.
+    pub fn node_info(&self, peer_addr: SocketAddr) -> NodeInfo {
+        NodeInfo {
+            peer_addr,
+            peer_cert_der: self.peer_cert_der.clone(),
+        }
+    }
+}
+
+/// Split the hard coded contacts into the ones given by IP address and the ones to be resolved.
+pub fn split_contacts(contacts: &HashSet<Contact>) -> (HashSet<NodeInfo>, Vec<HostContact>) {
+    let mut node_infos = HashSet::new();
+    let mut host_contacts = Vec::new();
+
+    for contact in contacts {
+        match contact.peer_addr {
+            NodeAddr::Socket(_) => {
+                let _ = node_infos.insert(unwrap!(contact.node_info()));
+            }
+            NodeAddr::Host { ref host, port } => host_contacts.push(HostContact {
+                host: host.clone(),
+                port,
+                peer_cert_der: contact.peer_cert_der.clone(),
+            }),
+        }
+    }
+
+    (node_infos, host_contacts)
+}
+
+/// Look the host up on a thread of its own, resolving to at least one address.
+pub fn resolve(
+    resolver: SharedResolver,
+    host: String,
+    port: u16,
+) -> impl Future<Item = Vec<SocketAddr>, Error = Error> {
+    let (tx, rx) = oneshot::channel();
+    let _ = thread::spawn(move || {
+        let _ = tx.send(resolver.resolve(&host, port));
+    });
+
+    rx.from_err().and_then(|res| match res {
+        Ok(ref addrs) if addrs.is_empty() => Err(Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Host resolved to no addresses",
+        ))),
+        res => res.map_err(Error::Io),
+    })
+}
+
+/// The hard coded contacts given by hostname and what they last resolved to.
+pub struct HostContacts {
+    contacts: Vec<HostContact>,
+    resolutions: HashMap<(String, u16), Resolution>,
+    resolver: SharedResolver,
+    ttl: Duration,
+}
+
+struct Resolution {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+    /// Dialling one of the addresses failed since
+    is_stale: bool,
+}
+
+impl HostContacts {
+    pub fn new(contacts: Vec<HostContact>, resolver: SharedResolver, ttl: Duration) -> Self {
+        Self {
+            contacts,
+            resolutions: Default::default(),
+            resolver,
+            ttl,
+        }
+    }
+
+    pub fn contacts(&self) -> &[HostContact] {
+        &self.contacts
+    }
+
+    pub fn resolver(&self) -> SharedResolver {
+        self.resolver.clone()
+    }
+
+    /// What the contact resolved to, unless it's older than the TTL or went stale.
+    pub fn cached(&self, contact: &HostContact, now: Instant) -> Option<&[SocketAddr]> {
+        self.resolutions
+            .get(&(contact.host.clone(), contact.port))
+            .filter(|res| !res.is_stale && now.duration_since(res.resolved_at) < self.ttl)
+            .map(|res| &res.addrs[..])
+    }
+
+    /// Note what the contact resolved to. Returns the addresses it resolved to before and no
+    /// longer does.
+    pub fn record(
+        &mut self,
+        contact: &HostContact,
+        addrs: Vec<SocketAddr>,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        let key = (contact.host.clone(), contact.port);
+        let gone = self.resolutions.get(&key).map_or_else(Vec::new, |old| {
+            old.addrs
+                .iter()
+                .filter(|addr| !addrs.contains(addr))
+                .cloned()
+                .collect()
+        });
+        let _ = self.resolutions.insert(
+            key,
+            Resolution {
+                addrs,
+                resolved_at: now,
+                is_stale: false,
+            },
+        );
+
+        gone
+    }
+
+    /// Dialling the address failed: the host it was resolved from, if any, is to be resolved
+    /// again. Returns the contact the first time round only.
+    pub fn dial_failed(&mut self, peer_addr: &SocketAddr) -> Option<HostContact> {
+        let (host, port) = self
+            .resolutions
+            .iter_mut()
+            .find(|(_, res)| !res.is_stale && res.addrs.contains(peer_addr))
+            .map(|(host_port, res)| {
+                res.is_stale = true;
+                host_port.clone()
+            })?;
+
+        self.contacts
+            .iter()
+            .find(|contact| contact.host == host && contact.port == port)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact() -> HostContact {
+        HostContact {
+            host: "seed.example.com".to_string(),
+            port: 5483,
+            peer_cert_der: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn resolutions_are_cached_until_they_expire_or_a_dial_fails() {
+        let contact = contact();
+        let mut hosts = HostContacts::new(
+            vec![contact.clone()],
+            Arc::new(SystemResolver),
+            Duration::from_secs(60),
+        );
+        let addr0: SocketAddr = unwrap!("10.0.0.1:5483".parse());
+        let addr1: SocketAddr = unwrap!("10.0.0.2:5483".parse());
+        let now = Instant::now();
+
+        assert!(hosts.cached(&contact, now).is_none());
+        assert!(hosts.record(&contact, vec![addr0, addr1], now).is_empty());
+        assert_eq!(hosts.cached(&contact, now), Some(&[addr0, addr1][..]));
+        assert!(hosts
+            .cached(&contact, now + Duration::from_secs(60))
+            .is_none());
+
+        assert_eq!(hosts.dial_failed(&addr1), Some(contact.clone()));
+        assert_eq!(hosts.dial_failed(&addr0), None);
+        assert!(hosts.cached(&contact, now).is_none());
+
+        let addr2: SocketAddr = unwrap!("10.0.0.3:5483".parse());
+        assert_eq!(hosts.record(&contact, vec![addr0, addr2], now), vec![addr1]);
+        assert_eq!(hosts.cached(&contact, now), Some(&[addr0, addr2][..]));
+    }
+}
@@ -59,9 +59,54 @@ quick_error! {
              display("Bincode error: {}", e)
              from()
          }
+         InvalidWireMsg(reason: &'static str) {
+             display("Invalid wire message: {}", reason)
+         }
+         WireMsgTooLarge(size: usize) {
+             display("Wire message of {} bytes is larger than allowed", size)
+         }
+         HandshakeAuth(reason: &'static str) {
+             display("Handshake authentication failed: {}", reason)
+         }
+         PeerBlacklisted(peer_addr: SocketAddr) {
+             display("Peer {} is blacklisted for misbehaving", peer_addr)
+         }
+         PeerNotConnected(peer_addr: SocketAddr) {
+             display("There's no established connection with peer {}", peer_addr)
+         }
+         AlpnMismatch(peer_addr: SocketAddr) {
+             display("Peer {} doesn't speak any of the application protocols we accept", peer_addr)
+         }
+         ReadTimedOut(peer_addr: SocketAddr) {
+             display("Peer {} stalled in the middle of sending a message", peer_addr)
+         }
          OperationNotAllowed {
              display("This operation is not allowed for us")
          }
+         InvalidConfig(reason: &'static str) {
+             display("Invalid configuration: {}", reason)
+         }
+         InvalidNodeAddr(addr: String) {
+             display("Invalid node address {}: expected IP:port or host:port", addr)
+         }
+         ConnectBackoff(peer_addr: SocketAddr) {
+             display("Not dialling {} again yet as the previous dials to it failed", peer_addr)
+         }
+         ConnectionLimitReached(peer_addr: SocketAddr) {
+             display("Not connecting to {} as we are at our connection limit", peer_addr)
+         }
+         IllegalState(peer_addr: SocketAddr, what: String) {
+             display("Illegal state of the connection to {}: {}", peer_addr, what)
+         }
+         CertRotation(reason: &'static str) {
+             display("Certificate rotation failed: {}", reason)
+         }
+         NoCodec {
+             display("No codec was registered via `Builder::with_codec`")
+         }
+         CodecTypeMismatch {
+             display("Message is not of the type handled by the registered codec")
+         }
         /// Connection Cancelled
         ConnectionCancelled {
             display("Connection was actively cancelled")
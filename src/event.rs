@@ -1,6 +1,25 @@
+use crate::backpressure::BackpressureLevel;
+#[cfg(feature = "codec")]
+use crate::codec::SharedCodec;
+use crate::shutdown::ShutdownTrigger;
+use crate::transfer::FileHash;
+use crate::wire_msg::CloseReason;
+#[cfg(feature = "codec")]
+use crate::R;
 use crate::{utils, NodeInfo, Peer};
+#[cfg(feature = "codec")]
+use std::any::Any;
 use std::fmt;
 use std::net::SocketAddr;
+use std::ops::BitOr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{
+    self, Receiver, RecvError, RecvTimeoutError, SendError, Sender, SyncSender, TryRecvError,
+    TrySendError,
+};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 /// QuicP2p Events to the user
 #[derive(Debug)]
@@ -8,35 +27,871 @@ pub enum Event {
     BootstrapFailure,
     BootstrappedTo {
         node: NodeInfo,
+        /// Application data the peer attached to its handshake, if any
+        user_data: Option<bytes::Bytes>,
+        /// Time from the start of the bootstrap until we were connected to this node, for tuning
+        /// `Config::bootstrap_member_budget_msec` and `Config::bootstrap_grace_msec`
+        elapsed: Duration,
+        /// How long connecting to this node took, leg by leg
+        timings: ConnectTimings,
     },
     ConnectionFailure {
         peer_addr: SocketAddr,
+        /// Why the connection was closed, by us or by the peer
+        reason: CloseReason,
     },
     ConnectedTo {
         peer: Peer,
+        /// Application data the peer attached to its handshake, if any
+        user_data: Option<bytes::Bytes>,
+        /// How long connecting to the peer took, leg by leg
+        timings: ConnectTimings,
     },
     NewMessage {
-        peer_addr: SocketAddr,
+        /// The sender: a node along with its certificate or a client along with its identity
+        peer: Peer,
+        msg: bytes::Bytes,
+        /// Id the sender tagged the message with, if any
+        msg_id: Option<u64>,
+        /// Id of our message this is a reply to, if any
+        in_reply_to: Option<u64>,
+        /// Logical channel the message was sent on
+        channel: u8,
+    },
+    /// Message sent with `QuicP2p::send_typed`, decoded with the codec registered via
+    /// `Builder::with_codec`. Downcast it to the codec's message type. Without a codec registered
+    /// such messages come in `NewMessage` as is.
+    ///
+    /// Only available with the `codec` feature.
+    #[cfg(feature = "codec")]
+    NewTypedMessage {
+        peer: Peer,
+        msg: Box<dyn Any + Send>,
+    },
+    /// We gave up on delivering the message to the peer.
+    UnsentUserMessage {
+        peer: Peer,
         msg: bytes::Bytes,
+        reason: UnsentReason,
+    },
+    /// Every message of the batch asked for via `QuicP2p::send_batch` was written to the peer.
+    /// `token` is the one given to that call.
+    SentUserMessageBatch {
+        peer: Peer,
+        token: u64,
+    },
+    /// We gave up on delivering some or all of the messages of the batch asked for via
+    /// `QuicP2p::send_batch`. `msgs` are those not delivered, in the order they were given in.
+    UnsentUserMessageBatch {
+        peer: Peer,
+        msgs: Vec<bytes::Bytes>,
+        token: u64,
+        reason: UnsentReason,
+    },
+    /// We are accepting connections on `addr`, e.g. after starting up or `restart_listener`. With
+    /// `Config::port_fallback` the port can differ from the one asked for.
+    ListenerStarted {
+        addr: SocketAddr,
+    },
+    /// Our connection info, requested earlier without blocking, is now known.
+    OurConnectionInfoReady {
+        node_info: NodeInfo,
+    },
+    /// We were buffering more data on behalf of the peer than we allow. The connection to it is
+    /// dropped right after this is fired.
+    PeerOverloaded {
+        peer_addr: SocketAddr,
+    },
+    /// We are at `Config::max_total_connections` and a new connection to or from `peer_addr` was
+    /// up for admission. `evicted` is the client whose connection was closed to make room for it,
+    /// if any. The new connection was refused otherwise.
+    ConnectionLimitReached {
+        peer_addr: SocketAddr,
+        evicted: Option<SocketAddr>,
+    },
+    /// The peer broke the protocol, e.g. by presenting a forged or replayed handshake. The
+    /// connection to it is dropped.
+    ProtocolViolation {
+        peer_addr: SocketAddr,
+        reason: String,
+    },
+    /// Contacts the peer sent in response to `QuicP2p::request_contacts`
+    ContactsReceived {
+        peer_addr: SocketAddr,
+        contacts: Vec<NodeInfo>,
+    },
+    /// Outcome of our request to `via` to connect to `target_addr`
+    ReverseConnectResult {
+        via: SocketAddr,
+        target_addr: SocketAddr,
+        success: bool,
+    },
+    /// The connected node rotated its certificate (see `QuicP2p::rotate_certificate`). Our
+    /// bootstrap cache is updated already, `new` is what to connect to the node with from now on.
+    PeerCertificateRotated {
+        old: NodeInfo,
+        new: NodeInfo,
+    },
+    /// Our certificate expires in `expires_in`, zero if it already has, after which peers can't
+    /// connect to us. It's fired `Config::cert_expiry_warning_sec` ahead, once per certificate,
+    /// and followed by its rotation with `Config::auto_rotate_cert`.
+    CertificateExpiring {
+        expires_in: Duration,
+    },
+    /// A node we know by its certificate showed up at another address, e.g. after its IP address
+    /// changed. Our bootstrap cache is updated already, `new` is what to connect to the node with
+    /// from now on.
+    PeerAddressChanged {
+        old: NodeInfo,
+        new: NodeInfo,
+    },
+    /// Peers were added to or removed from our bootstrap cache, e.g. to sync it elsewhere. Changes
+    /// are collected for `Config::bootstrap_cache_update_debounce_msec` before being reported.
+    BootstrapCacheUpdated {
+        added: Vec<NodeInfo>,
+        removed: Vec<NodeInfo>,
+    },
+    /// Every connect asked for via `QuicP2p::connect_to_many` has succeeded or failed. The
+    /// individual connection events are fired as usual too.
+    BatchConnectComplete {
+        connected: Vec<NodeInfo>,
+        failed: Vec<NodeInfo>,
+    },
+    /// We haven't heard from the connected peer for `Config::unresponsive_peer_msec`, though the
+    /// connection to it is still up. `since` is when we last did.
+    PeerUnresponsive {
+        peer_addr: SocketAddr,
+        since: Instant,
+    },
+    /// We heard from the peer again after it was reported via `Event::PeerUnresponsive`.
+    PeerResponsive {
+        peer_addr: SocketAddr,
+    },
+    /// Every write of the message asked for via `QuicP2p::send_to_many` has finished. `token` is
+    /// the one given to that call.
+    SendToManyComplete {
+        token: u64,
+        sent: Vec<SocketAddr>,
+        failed: Vec<SocketAddr>,
+    },
+    /// The peer offers to send us a file with `QuicP2p::send_file`. Accept it with
+    /// `QuicP2p::accept_file` or refuse it with `QuicP2p::refuse_file`. Until then it counts
+    /// towards `Config::max_incoming_files`.
+    FileOffered {
+        peer: Peer,
+        hash: FileHash,
+        len: u64,
+    },
+    /// The peer sent us a file with `QuicP2p::send_file` and it arrived whole, matching its hash.
+    /// It's at `temp_path` for the user to move to wherever it belongs.
+    FileReceived {
+        peer: Peer,
+        temp_path: PathBuf,
+        hash: FileHash,
+    },
+    /// `transferred` of the `total` bytes of the file with the given hash have been sent to or
+    /// received from the peer so far, counting the ones the recipient had already.
+    FileTransferProgress {
+        peer_addr: SocketAddr,
+        hash: FileHash,
+        transferred: u64,
+        total: u64,
+    },
+    /// The transfer of the file to or from the peer failed, or the peer refused it. What the
+    /// recipient has of it is kept unless it refused it, so sending the file again resumes the
+    /// transfer.
+    FileTransferFailed {
+        peer_addr: SocketAddr,
+        hash: FileHash,
+        reason: String,
+    },
+    /// We started connecting to the node. Only fired with `EventVerbosity::Verbose`.
+    ConnectingTo {
+        peer_addr: SocketAddr,
+    },
+    /// QUIC handshake with the peer completed, i.e. the connection is up though the peer hasn't
+    /// introduced itself yet. Only fired with `EventVerbosity::Verbose`.
+    HandshakeCompleted {
+        peer_addr: SocketAddr,
+        /// Time since we started connecting. `None` for connections the peer made to us.
+        elapsed: Option<Duration>,
+    },
+    /// The peer opened a stream to send us a message over. Only fired with
+    /// `EventVerbosity::Verbose`.
+    StreamOpened {
+        peer_addr: SocketAddr,
+    },
+    /// The backlog of the events the application has yet to take and of the messages from peers
+    /// we have yet to hand over went to `level`, see `Config::backpressure`.
+    Backpressure {
+        level: BackpressureLevel,
+    },
+    /// Only ever sent to receivers from `QuicP2p::subscribe`: this many events didn't fit in the
+    /// queue of the receiver because it didn't keep up, and were dropped for it.
+    EventsDropped {
+        count: u64,
     },
     /// No more messages will be fired after this
     // TODO Currently used only for testing
     Finish,
 }
 
+impl Event {
+    /// Category of the event, used to filter out the events the user isn't interested in.
+    pub fn category(&self) -> EventFilter {
+        match *self {
+            Event::BootstrapFailure
+            | Event::BootstrappedTo { .. }
+            | Event::ConnectionFailure { .. }
+            | Event::ConnectedTo { .. }
+            | Event::ListenerStarted { .. }
+            | Event::OurConnectionInfoReady { .. }
+            | Event::ContactsReceived { .. }
+            | Event::ReverseConnectResult { .. }
+            | Event::PeerCertificateRotated { .. }
+            | Event::CertificateExpiring { .. }
+            | Event::PeerAddressChanged { .. }
+            | Event::BootstrapCacheUpdated { .. }
+            | Event::BatchConnectComplete { .. }
+            | Event::PeerUnresponsive { .. }
+            | Event::PeerResponsive { .. } => EventFilter::CONNECTIVITY,
+            Event::NewMessage { .. }
+            | Event::UnsentUserMessage { .. }
+            | Event::SentUserMessageBatch { .. }
+            | Event::UnsentUserMessageBatch { .. }
+            | Event::SendToManyComplete { .. }
+            | Event::FileOffered { .. }
+            | Event::FileReceived { .. }
+            | Event::FileTransferProgress { .. }
+            | Event::FileTransferFailed { .. } => EventFilter::DATA,
+            #[cfg(feature = "codec")]
+            Event::NewTypedMessage { .. } => EventFilter::DATA,
+            Event::PeerOverloaded { .. }
+            | Event::ConnectionLimitReached { .. }
+            | Event::ProtocolViolation { .. }
+            | Event::Backpressure { .. }
+            | Event::ConnectingTo { .. }
+            | Event::HandshakeCompleted { .. }
+            | Event::StreamOpened { .. } => EventFilter::DIAGNOSTICS,
+            Event::EventsDropped { .. } | Event::Finish => EventFilter::ALL,
+        }
+    }
+
+    /// Copy of the event for a subscriber. `None` for typed messages, which can't be copied.
+    fn try_clone(&self) -> Option<Self> {
+        let event = match *self {
+            Event::BootstrapFailure => Event::BootstrapFailure,
+            Event::BootstrappedTo {
+                ref node,
+                ref user_data,
+                elapsed,
+                timings,
+            } => Event::BootstrappedTo {
+                node: node.clone(),
+                user_data: user_data.clone(),
+                elapsed,
+                timings,
+            },
+            Event::ConnectionFailure { peer_addr, reason } => {
+                Event::ConnectionFailure { peer_addr, reason }
+            }
+            Event::ConnectedTo {
+                ref peer,
+                ref user_data,
+                timings,
+            } => Event::ConnectedTo {
+                peer: peer.clone(),
+                user_data: user_data.clone(),
+                timings,
+            },
+            Event::NewMessage {
+                ref peer,
+                ref msg,
+                msg_id,
+                in_reply_to,
+                channel,
+            } => Event::NewMessage {
+                peer: peer.clone(),
+                msg: msg.clone(),
+                msg_id,
+                in_reply_to,
+                channel,
+            },
+            #[cfg(feature = "codec")]
+            Event::NewTypedMessage { .. } => return None,
+            Event::UnsentUserMessage {
+                ref peer,
+                ref msg,
+                reason,
+            } => Event::UnsentUserMessage {
+                peer: peer.clone(),
+                msg: msg.clone(),
+                reason,
+            },
+            Event::SentUserMessageBatch { ref peer, token } => Event::SentUserMessageBatch {
+                peer: peer.clone(),
+                token,
+            },
+            Event::UnsentUserMessageBatch {
+                ref peer,
+                ref msgs,
+                token,
+                reason,
+            } => Event::UnsentUserMessageBatch {
+                peer: peer.clone(),
+                msgs: msgs.clone(),
+                token,
+                reason,
+            },
+            Event::ListenerStarted { addr } => Event::ListenerStarted { addr },
+            Event::OurConnectionInfoReady { ref node_info } => Event::OurConnectionInfoReady {
+                node_info: node_info.clone(),
+            },
+            Event::PeerOverloaded { peer_addr } => Event::PeerOverloaded { peer_addr },
+            Event::ConnectionLimitReached { peer_addr, evicted } => {
+                Event::ConnectionLimitReached { peer_addr, evicted }
+            }
+            Event::ProtocolViolation {
+                peer_addr,
+                ref reason,
+            } => Event::ProtocolViolation {
+                peer_addr,
+                reason: reason.clone(),
+            },
+            Event::ContactsReceived {
+                peer_addr,
+                ref contacts,
+            } => Event::ContactsReceived {
+                peer_addr,
+                contacts: contacts.clone(),
+            },
+            Event::ReverseConnectResult {
+                via,
+                target_addr,
+                success,
+            } => Event::ReverseConnectResult {
+                via,
+                target_addr,
+                success,
+            },
+            Event::PeerCertificateRotated { ref old, ref new } => Event::PeerCertificateRotated {
+                old: old.clone(),
+                new: new.clone(),
+            },
+            Event::CertificateExpiring { expires_in } => Event::CertificateExpiring { expires_in },
+            Event::PeerAddressChanged { ref old, ref new } => Event::PeerAddressChanged {
+                old: old.clone(),
+                new: new.clone(),
+            },
+            Event::BootstrapCacheUpdated {
+                ref added,
+                ref removed,
+            } => Event::BootstrapCacheUpdated {
+                added: added.clone(),
+                removed: removed.clone(),
+            },
+            Event::BatchConnectComplete {
+                ref connected,
+                ref failed,
+            } => Event::BatchConnectComplete {
+                connected: connected.clone(),
+                failed: failed.clone(),
+            },
+            Event::PeerUnresponsive { peer_addr, since } => {
+                Event::PeerUnresponsive { peer_addr, since }
+            }
+            Event::PeerResponsive { peer_addr } => Event::PeerResponsive { peer_addr },
+            Event::SendToManyComplete {
+                token,
+                ref sent,
+                ref failed,
+            } => Event::SendToManyComplete {
+                token,
+                sent: sent.clone(),
+                failed: failed.clone(),
+            },
+            Event::FileOffered {
+                ref peer,
+                hash,
+                len,
+            } => Event::FileOffered {
+                peer: peer.clone(),
+                hash,
+                len,
+            },
+            Event::FileReceived {
+                ref peer,
+                ref temp_path,
+                hash,
+            } => Event::FileReceived {
+                peer: peer.clone(),
+                temp_path: temp_path.clone(),
+                hash,
+            },
+            Event::FileTransferProgress {
+                peer_addr,
+                hash,
+                transferred,
+                total,
+            } => Event::FileTransferProgress {
+                peer_addr,
+                hash,
+                transferred,
+                total,
+            },
+            Event::FileTransferFailed {
+                peer_addr,
+                hash,
+                ref reason,
+            } => Event::FileTransferFailed {
+                peer_addr,
+                hash,
+                reason: reason.clone(),
+            },
+            Event::ConnectingTo { peer_addr } => Event::ConnectingTo { peer_addr },
+            Event::HandshakeCompleted { peer_addr, elapsed } => {
+                Event::HandshakeCompleted { peer_addr, elapsed }
+            }
+            Event::StreamOpened { peer_addr } => Event::StreamOpened { peer_addr },
+            Event::Backpressure { level } => Event::Backpressure { level },
+            Event::EventsDropped { count } => Event::EventsDropped { count },
+            Event::Finish => Event::Finish,
+        };
+        Some(event)
+    }
+
+    /// Whether this is only fired with `EventVerbosity::Verbose`.
+    pub fn is_verbose(&self) -> bool {
+        match *self {
+            Event::ConnectingTo { .. }
+            | Event::HandshakeCompleted { .. }
+            | Event::StreamOpened { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// How much detail the user is told about. Set via `Config::event_verbosity`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum EventVerbosity {
+    /// Outcomes only: connections made and lost, messages etc.
+    Normal,
+    /// Intermediate phases of connections and messages too, e.g. for debugging slow connects
+    Verbose,
+}
+
+impl Default for EventVerbosity {
+    fn default() -> Self {
+        EventVerbosity::Normal
+    }
+}
+
+/// How long connecting to a peer took, see `Event::ConnectedTo` and `Event::BootstrappedTo`. For
+/// tuning the bootstrap fan-out and the connect and handshake timeouts.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ConnectTimings {
+    /// From setting out to connect to the peer until the QUIC handshake with it completed,
+    /// including any time the connect was queued for behind `Config::max_concurrent_connects`.
+    /// `None` if we never connected to the peer, only it to us.
+    pub quic_handshake: Option<Duration>,
+    /// From the QUIC handshake completing until the peer was connected, i.e. until its handshake
+    /// arrived and, for nodes, we were connected both ways. The QUIC handshake is that of our
+    /// connection to the peer if we made one.
+    pub wire_handshake: Duration,
+}
+
+impl ConnectTimings {
+    /// Time the whole connect took, as far as we can tell.
+    pub fn total(&self) -> Duration {
+        self.quic_handshake.unwrap_or_default() + self.wire_handshake
+    }
+}
+
+/// Why we gave up on delivering a message, see `Event::UnsentUserMessage` and
+/// `Event::UnsentUserMessageBatch`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnsentReason {
+    /// The client stayed disconnected for longer than `Config::client_send_grace_msec`
+    ClientDisconnected,
+    /// The message expired before the connection to the peer was established (see
+    /// `QuicP2p::send_with_expiry`)
+    Expired,
+    /// The peer didn't take the message within `Config::write_timeout_msec`, e.g. because it
+    /// stopped reading
+    WriteTimedOut,
+    /// The write to the peer failed or timed out, e.g. because the connection to it was lost
+    WriteFailed,
+    /// We had no connection to the peer to write to
+    NotConnected,
+    /// Our connection to the peer, which the message was waiting for, couldn't be established
+    ConnectFailed,
+}
+
+/// Set of event categories the user subscribes to. Categories can be combined with `|`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EventFilter(u8);
+
+impl EventFilter {
+    /// Bootstrapping, connections made and lost, and our connection info
+    pub const CONNECTIVITY: Self = EventFilter(0b001);
+    /// Messages from peers
+    pub const DATA: Self = EventFilter(0b010);
+    /// Misbehaving or overloaded peers
+    pub const DIAGNOSTICS: Self = EventFilter(0b100);
+    /// Every event
+    pub const ALL: Self = EventFilter(0b111);
+
+    /// Whether all the categories in `other` are in this set too.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any of the categories in `other` are in this set.
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        EventFilter::ALL
+    }
+}
+
+impl BitOr for EventFilter {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        EventFilter(self.0 | rhs.0)
+    }
+}
+
+/// Sender of events to the user which silently drops the ones not subscribed to or more verbose
+/// than asked for. Every event goes to the sender given to the builder and a copy of it to each
+/// receiver from `QuicP2p::subscribe`.
+#[derive(Clone)]
+pub struct EventTx {
+    tx: EventSender,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    filter: EventFilter,
+    verbosity: EventVerbosity,
+    /// Pulled once the sender given to the builder finds its receiver gone, if we are to shut down
+    /// then, see `Config::on_event_channel_closed`
+    shutdown_trigger: Option<ShutdownTrigger>,
+    /// Decodes the typed messages handed over to the user
+    #[cfg(feature = "codec")]
+    codec: Option<SharedCodec>,
+}
+
+impl EventTx {
+    pub fn new<T: Into<EventSender>>(
+        tx: T,
+        filter: EventFilter,
+        verbosity: EventVerbosity,
+    ) -> Self {
+        Self {
+            tx: tx.into(),
+            subscribers: Default::default(),
+            filter,
+            verbosity,
+            shutdown_trigger: None,
+            #[cfg(feature = "codec")]
+            codec: None,
+        }
+    }
+
+    pub fn with_shutdown_trigger(mut self, shutdown_trigger: Option<ShutdownTrigger>) -> Self {
+        self.shutdown_trigger = shutdown_trigger;
+        self
+    }
+
+    #[cfg(feature = "codec")]
+    pub fn with_codec(mut self, codec: Option<SharedCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Decode a message sent with `QuicP2p::send_typed`. `None` if the user registered no codec.
+    #[cfg(feature = "codec")]
+    pub fn decode(&self, msg: &[u8]) -> Option<R<Box<dyn Any + Send>>> {
+        self.codec.as_ref().map(|codec| codec.decode_any(msg))
+    }
+
+    /// New receiver of the events fired from now on, with room for `queue_len` of them.
+    pub fn subscribe(&self, queue_len: usize) -> Receiver<Event> {
+        let (tx, rx) = mpsc::sync_channel(queue_len);
+        self.subscribers().push(Subscriber { tx, dropped: 0 });
+        rx
+    }
+
+    pub fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        let is_wanted = self.filter.intersects(event.category())
+            && (self.verbosity == EventVerbosity::Verbose || !event.is_verbose());
+        if is_wanted {
+            self.subscribers()
+                .retain(|subscriber| subscriber.offer(&event));
+            let res = self.tx.send(event);
+            if let (Err(_), Some(shutdown_trigger)) = (&res, &self.shutdown_trigger) {
+                shutdown_trigger.trigger();
+            }
+            res
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of events the user has yet to take, if the sender given to the builder is from
+    /// `event_channel`.
+    pub fn depth(&self) -> Option<usize> {
+        self.tx
+            .depth
+            .as_ref()
+            .map(|depth| depth.load(Ordering::Relaxed))
+    }
+
+    fn subscribers(&self) -> MutexGuard<Vec<Subscriber>> {
+        match self.subscribers.lock() {
+            Ok(subscribers) => subscribers,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Receiver from `QuicP2p::subscribe`.
+struct Subscriber {
+    tx: SyncSender<Event>,
+    /// Events dropped since the receiver was last told about it
+    dropped: u64,
+}
+
+impl Subscriber {
+    /// Queue a copy of the event. A slow receiver never holds us up: the events it has no room
+    /// for are dropped and it's told how many once it has room again. `false` once the receiver
+    /// is gone.
+    fn offer(&mut self, event: &Event) -> bool {
+        if self.dropped > 0 {
+            let dropped = Event::EventsDropped {
+                count: self.dropped,
+            };
+            match self.tx.try_send(dropped) {
+                Ok(()) => self.dropped = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped += 1;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        let event = match event.try_clone() {
+            Some(event) => event,
+            None => return true,
+        };
+        match self.tx.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Channel for the events telling how many of them the application has yet to take, so they count
+/// towards the backlog watched via `Config::backpressure`. Give the sender to `Builder::new` in
+/// place of the one of `std::sync::mpsc::channel`.
+pub fn event_channel() -> (EventSender, EventReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let depth = Arc::new(AtomicUsize::new(0));
+    let sender = EventSender {
+        tx,
+        depth: Some(depth.clone()),
+    };
+    (sender, EventReceiver { rx, depth })
+}
+
+/// Sender of the events to the user, either from `event_channel` or a plain `Sender<Event>`.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: Sender<Event>,
+    /// Events sent and not yet received, for the senders from `event_channel`
+    depth: Option<Arc<AtomicUsize>>,
+}
+
+impl EventSender {
+    fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        // Counted before it's sent so the receiver never takes it off a depth of zero
+        if let Some(ref depth) = self.depth {
+            let _ = depth.fetch_add(1, Ordering::Relaxed);
+        }
+        self.tx.send(event).map_err(|e| {
+            if let Some(ref depth) = self.depth {
+                let _ = depth.fetch_sub(1, Ordering::Relaxed);
+            }
+            e
+        })
+    }
+}
+
+impl From<Sender<Event>> for EventSender {
+    fn from(tx: Sender<Event>) -> Self {
+        Self { tx, depth: None }
+    }
+}
+
+/// Receiver of the events from `event_channel`. It's used like `std::sync::mpsc::Receiver`.
+pub struct EventReceiver {
+    rx: Receiver<Event>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl EventReceiver {
+    pub fn recv(&self) -> Result<Event, RecvError> {
+        self.rx.recv().map(|event| self.taken(event))
+    }
+
+    pub fn try_recv(&self) -> Result<Event, TryRecvError> {
+        self.rx.try_recv().map(|event| self.taken(event))
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout).map(|event| self.taken(event))
+    }
+
+    /// Iterator blocking for the events until the sender is gone.
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        self.rx.iter().map(move |event| self.taken(event))
+    }
+
+    /// Iterator over the events already there.
+    pub fn try_iter(&self) -> impl Iterator<Item = Event> + '_ {
+        self.rx.try_iter().map(move |event| self.taken(event))
+    }
+
+    /// Number of events sent that are yet to be taken.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    fn taken(&self, event: Event) -> Event {
+        let _ = self.depth.fetch_sub(1, Ordering::Relaxed);
+        event
+    }
+}
+
 impl fmt::Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Event::NewMessage {
-                ref peer_addr,
+                ref peer,
                 ref msg,
+                msg_id,
+                in_reply_to,
+                channel,
             } => write!(
                 f,
-                "Event::NewMessage {{ peer_addr: {}, msg: {} }}",
-                peer_addr,
-                utils::bin_data_format(&*msg)
+                "Event::NewMessage {{ peer: {}, msg: {}, msg_id: {:?}, in_reply_to: {:?}, \
+                 channel: {} }}",
+                peer,
+                utils::bin_data_format(&*msg),
+                msg_id,
+                in_reply_to,
+                channel
             ),
             ref blah => write!(f, "{}", blah),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn only_subscribed_categories_are_delivered() {
+        let (tx, rx) = mpsc::channel();
+        let event_tx = EventTx::new(
+            tx,
+            EventFilter::CONNECTIVITY | EventFilter::DIAGNOSTICS,
+            Default::default(),
+        );
+
+        unwrap!(event_tx.send(Event::NewMessage {
+            peer: Peer::Node {
+                node_info: NodeInfo {
+                    peer_addr: unwrap!("127.0.0.1:1000".parse()),
+                    peer_cert_der: Vec::new(),
+                },
+            },
+            msg: bytes::Bytes::from(vec![1]),
+            msg_id: None,
+            in_reply_to: None,
+            channel: 0,
+        }));
+        unwrap!(event_tx.send(Event::BootstrapFailure));
+        unwrap!(event_tx.send(Event::Finish));
+
+        match unwrap!(rx.try_recv()) {
+            Event::BootstrapFailure => (),
+            x => panic!("Unexpected event: {:?}", x),
+        }
+        match unwrap!(rx.try_recv()) {
+            Event::Finish => (),
+            x => panic!("Unexpected event: {:?}", x),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn events_from_the_event_channel_are_counted_until_taken() {
+        let (tx, rx) = event_channel();
+        let event_tx = EventTx::new(tx, Default::default(), Default::default());
+
+        for _ in 0..3 {
+            unwrap!(event_tx.send(Event::BootstrapFailure));
+        }
+        assert_eq!(event_tx.depth(), Some(3));
+        assert_eq!(rx.try_iter().count(), 3);
+        assert_eq!(rx.depth(), 0);
+
+        let (tx, _rx) = mpsc::channel();
+        assert_eq!(
+            EventTx::new(tx, Default::default(), Default::default()).depth(),
+            None
+        );
+    }
+
+    #[test]
+    fn slow_subscribers_are_told_how_many_events_they_missed() {
+        let (tx, rx) = mpsc::channel();
+        let event_tx = EventTx::new(tx, Default::default(), Default::default());
+        let slow_rx = event_tx.subscribe(1);
+
+        for _ in 0..3 {
+            unwrap!(event_tx.send(Event::BootstrapFailure));
+        }
+        assert_eq!(rx.try_iter().count(), 3);
+
+        match unwrap!(slow_rx.try_recv()) {
+            Event::BootstrapFailure => (),
+            x => panic!("Unexpected event: {:?}", x),
+        }
+        unwrap!(event_tx.send(Event::Finish));
+        match unwrap!(slow_rx.try_recv()) {
+            Event::EventsDropped { count: 2 } => (),
+            x => panic!("Unexpected event: {:?}", x),
+        }
+        // No room for the one which came along with the report
+        unwrap!(event_tx.send(Event::Finish));
+        match unwrap!(slow_rx.try_recv()) {
+            Event::EventsDropped { count: 1 } => (),
+            x => panic!("Unexpected event: {:?}", x),
+        }
+
+        drop(slow_rx);
+        unwrap!(event_tx.send(Event::Finish));
+        assert!(event_tx.subscribers().is_empty());
+    }
+}
@@ -7,7 +7,19 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+//! The event loop all our networking runs on: a tokio current-thread runtime on a thread of its
+//! own, which the synchronous API posts work to. The runtime is never exposed, so the application
+//! doesn't need to run tokio itself, though it can't be swapped for another one either as quinn's
+//! endpoint is bound to the tokio reactor.
+//!
+//! Instances get an event loop of their own unless they are built to share one, in which case each
+//! runs on it with a context of its own. The event loop owns the contexts of the instances running
+//! on it and hands each message posted on behalf of an instance the context of that instance.
+
+use crate::context::{Ctx, Instances};
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use tokio::prelude::Stream;
 use tokio::runtime::current_thread;
@@ -16,7 +28,7 @@ use tokio::sync::mpsc::{self, UnboundedSender};
 /// Post messages to event loop
 pub fn post<F>(tx: &mut UnboundedSender<EventLoopMsg>, f: F)
 where
-    F: FnOnce() + Send + 'static,
+    F: FnOnce(&mut Instances) + Send + 'static,
 {
     let msg = EventLoopMsg::new(f);
     if let Err(e) = tx.try_send(msg) {
@@ -25,18 +37,18 @@ where
 }
 
 /// Message that event loop can accept in order to be requested to do something
-pub struct EventLoopMsg(Option<Box<FnMut() + Send>>);
+pub struct EventLoopMsg(Option<Box<FnMut(&mut Instances) + Send>>);
 
 impl EventLoopMsg {
     /// Create a new message to be posted to the event loop
     pub fn new<F>(f: F) -> Self
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce(&mut Instances) + Send + 'static,
     {
         let mut f = Some(f);
-        EventLoopMsg(Some(Box::new(move || {
+        EventLoopMsg(Some(Box::new(move |instances| {
             let f = unwrap!(f.take());
-            f()
+            f(instances)
         })))
     }
 
@@ -69,9 +81,10 @@ impl EventLoop {
         let j = unwrap!(thread::Builder::new()
             .name("QuicP2p-Event-Loop".into())
             .spawn(move || {
+                let mut instances = Instances::default();
                 let event_loop_future = rx.map_err(|_| ()).for_each(move |ev_loop_msg| {
                     if let Some(mut f) = ev_loop_msg.0 {
-                        f();
+                        f(&mut instances);
                         Ok(())
                     } else {
                         Err(())
@@ -90,12 +103,17 @@ impl EventLoop {
         &mut self.tx
     }
 
+    /// Sender posting to the event loop, for posting from within it.
+    pub fn sender(&self) -> UnboundedSender<EventLoopMsg> {
+        self.tx.clone()
+    }
+
     /// Post messages to event loop
-    pub fn post<F>(&mut self, f: F)
+    pub fn post<F>(&self, f: F)
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce(&mut Instances) + Send + 'static,
     {
-        post(&mut self.tx, f)
+        post(&mut self.tx.clone(), f)
     }
 }
 
@@ -110,3 +128,74 @@ impl Drop for EventLoop {
         }
     }
 }
+
+/// Event loop thread several `QuicP2p` instances can share, see `Builder::with_event_loop`. It's
+/// shut down once this handle and every instance built on it are dropped.
+#[derive(Clone)]
+pub struct SharedEventLoop(pub(crate) Arc<EventLoop>);
+
+impl SharedEventLoop {
+    pub fn new() -> Self {
+        SharedEventLoop(Arc::new(EventLoop::spawn()))
+    }
+}
+
+impl Default for SharedEventLoop {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An instance's share of its event loop. The context of the instance is closed once this is
+/// dropped, which is what ends the instance if the event loop is shared and outlives it.
+pub struct Instance {
+    id: usize,
+    el: Arc<EventLoop>,
+}
+
+impl Instance {
+    pub fn new(el: Arc<EventLoop>) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            el,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Sender posting to the event loop, for posting from within it. Messages have to enter the
+    /// context of the instance themselves.
+    pub fn sender(&self) -> UnboundedSender<EventLoopMsg> {
+        self.el.sender()
+    }
+
+    /// Post the initialisation of the context of the instance to the event loop, see
+    /// `Instances::initialise`.
+    pub fn post_initialise<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Instances) + Send + 'static,
+    {
+        self.el.post(f)
+    }
+
+    /// Post messages to event loop, to be run with the context of the instance. They are dropped
+    /// unrun if it isn't initialised yet or was closed already.
+    pub fn post<F>(&self, f: F)
+    where
+        F: FnOnce(&Ctx) + Send + 'static,
+    {
+        let id = self.id;
+        self.el.post(move |instances| instances.enter(id, f))
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.el.post(move |instances| instances.close(id))
+    }
+}
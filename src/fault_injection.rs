@@ -0,0 +1,90 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Faults injected on demand so that failure paths can be exercised in tests without resorting to
+//! OS-level packet mangling. Only compiled in with the `testing` feature.
+
+use crate::context::Ctx;
+use crate::error::Error;
+use crate::utils;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::{future, Future};
+
+/// Fault to inject via `QuicP2p::inject_fault`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FaultSpec {
+    /// Silently drop the next `count` user messages received from any peer
+    DropInbound { count: usize },
+    /// Delay every message sent from now on by the given number of milliseconds. Zero removes the
+    /// delay.
+    DelayOutbound { delay_msec: u64 },
+    /// Abort the connection to the peer as if it had failed
+    AbortConnection { peer_addr: SocketAddr },
+}
+
+/// Faults currently in effect
+#[derive(Default)]
+pub struct Faults {
+    drop_inbound: usize,
+    delay_outbound_msec: u64,
+}
+
+/// Put the fault into effect. This must not be called while the `Context` is already borrowed.
+pub fn inject(ctx: &Ctx, fault: FaultSpec) {
+    info!("Injecting fault: {:?}", fault);
+
+    match fault {
+        FaultSpec::DropInbound { count } => ctx.with_mut(|c| c.faults.drop_inbound = count),
+        FaultSpec::DelayOutbound { delay_msec } => {
+            ctx.with_mut(|c| c.faults.delay_outbound_msec = delay_msec)
+        }
+        FaultSpec::AbortConnection { peer_addr } => utils::handle_communication_err(
+            ctx,
+            peer_addr,
+            &Error::ConnectionCancelled,
+            "Injected fault",
+        ),
+    }
+}
+
+/// Whether the user message just received is to be dropped. This must not be called while the
+/// `Context` is already borrowed.
+pub fn should_drop_inbound(ctx: &Ctx) -> bool {
+    ctx.with_mut(|c| {
+        if c.faults.drop_inbound == 0 {
+            return false;
+        }
+        c.faults.drop_inbound -= 1;
+        true
+    })
+}
+
+/// Hold the given future back by the outbound delay in effect when it's first polled.
+pub fn delay_outbound<F>(ctx: &Ctx, f: F) -> impl Future<Item = F::Item, Error = F::Error>
+where
+    F: Future,
+{
+    let ctx = ctx.clone();
+    future::lazy(move || {
+        Ok(ctx.with(|c| {
+            let delay = Duration::from_millis(c.faults.delay_outbound_msec);
+            c.clock.delay(c.clock.now() + delay)
+        }))
+    })
+    .and_then(|delay| {
+        delay.then(|r| {
+            if let Err(e) = r {
+                info!("Error in injected outbound delay: {:?}", e);
+            }
+            Ok(())
+        })
+    })
+    .and_then(move |()| f)
+}
@@ -0,0 +1,330 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Handles exposing only the operations valid for the type we run as. These are obtained with
+//! `Builder::build_node` and `Builder::build_client` respectively.
+
+#[cfg(feature = "testing")]
+use crate::FaultSpec;
+use crate::{
+    ClientInfo, ConnectionDetails, DebugSnapshot, Event, FileHash, LifetimeStats, MergeStrategy,
+    NodeInfo, Peer, QuicP2p, RankedPeer, SelfTestReport, SerialisableCertificate, StateSnapshot,
+    Stats, TrafficProfile, R,
+};
+use std::any::Any;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// Generates the methods shared by all the handles, forwarding them to the inner `QuicP2p`.
+macro_rules! common_methods {
+    () => {
+        /// Bootstrap to a proxy
+        pub fn bootstrap(&self) {
+            self.0.bootstrap()
+        }
+
+        /// Connect to the given node. See `QuicP2p::connect_to`.
+        pub fn connect_to(&self, peer_info: NodeInfo) {
+            self.0.connect_to(peer_info)
+        }
+
+        /// Connect to all the given nodes at once. See `QuicP2p::connect_to_many`.
+        pub fn connect_to_many(&self, peers: Vec<NodeInfo>) {
+            self.0.connect_to_many(peers)
+        }
+
+        /// Stop reading the messages the peer is sending us just now. See
+        /// `QuicP2p::cancel_reads_from`.
+        pub fn cancel_reads_from(&self, peer_addr: SocketAddr) -> R<usize> {
+            self.0.cancel_reads_from(peer_addr)
+        }
+
+        /// Disconnect from the given peer
+        pub fn disconnect_from(&self, peer_addr: SocketAddr) {
+            self.0.disconnect_from(peer_addr)
+        }
+
+        /// Send message to peer. See `QuicP2p::send`.
+        pub fn send(&self, peer: Peer, msg: bytes::Bytes) {
+            self.0.send(peer, msg)
+        }
+
+        /// Send the same message to all the given peers. See `QuicP2p::send_to_many`.
+        pub fn send_to_many(&self, peers: &[Peer], msg: bytes::Bytes, token: u64) {
+            self.0.send_to_many(peers, msg, token)
+        }
+
+        /// Send a burst of messages to the peer. See `QuicP2p::send_batch`.
+        pub fn send_batch(&self, peer: Peer, msgs: Vec<bytes::Bytes>, token: u64) {
+            self.0.send_batch(peer, msgs, token)
+        }
+
+        /// Send the file at `path` to the peer in chunks. See `QuicP2p::send_file`.
+        pub fn send_file(&self, peer: Peer, path: PathBuf) -> R<FileHash> {
+            self.0.send_file(peer, path)
+        }
+
+        /// Send message to peer, giving up on it after `expiry`. See `QuicP2p::send_with_expiry`.
+        pub fn send_with_expiry(&self, peer: Peer, msg: bytes::Bytes, expiry: Duration) {
+            self.0.send_with_expiry(peer, msg, expiry)
+        }
+
+        /// Send message to peer encoded by the registered codec. See `QuicP2p::send_typed`.
+        ///
+        /// Only available with the `codec` feature.
+        #[cfg(feature = "codec")]
+        pub fn send_typed<T: 'static>(&self, peer: Peer, msg: &T) -> R<()> {
+            self.0.send_typed(peer, msg)
+        }
+
+        /// Send message to peer tagged with the given id. See `QuicP2p::send_with_id`.
+        pub fn send_with_id(&self, peer: Peer, msg: bytes::Bytes, msg_id: u64) {
+            self.0.send_with_id(peer, msg, msg_id)
+        }
+
+        /// Send message to peer in reply to its message with the id `in_reply_to`. See
+        /// `QuicP2p::send_reply`.
+        pub fn send_reply(&self, peer: Peer, msg: bytes::Bytes, msg_id: u64, in_reply_to: u64) {
+            self.0.send_reply(peer, msg, msg_id, in_reply_to)
+        }
+
+        /// Send message to peer on the given logical channel. See `QuicP2p::send_on_channel`.
+        pub fn send_on_channel(&self, peer: Peer, msg: bytes::Bytes, channel: u8) {
+            self.0.send_on_channel(peer, msg, channel)
+        }
+
+        /// Channels the peer accepts messages on besides the default one. See
+        /// `QuicP2p::peer_channels`.
+        pub fn peer_channels(&self, peer_addr: SocketAddr) -> R<Option<Vec<u8>>> {
+            self.0.peer_channels(peer_addr)
+        }
+
+        /// Ask the connected peer for contacts from its bootstrap cache. See
+        /// `QuicP2p::request_contacts`.
+        pub fn request_contacts(&self, peer_addr: SocketAddr) {
+            self.0.request_contacts(peer_addr)
+        }
+
+        /// QUIC versions we speak. See `QuicP2p::supported_quic_versions`.
+        pub fn supported_quic_versions(&self) -> Vec<u32> {
+            self.0.supported_quic_versions()
+        }
+
+        /// Switch to the given traffic profile. See `QuicP2p::reconfigure`.
+        pub fn reconfigure(&self, traffic_profile: TrafficProfile) {
+            self.0.reconfigure(traffic_profile)
+        }
+
+        /// New receiver of every event fired from now on. See `QuicP2p::subscribe`.
+        pub fn subscribe(&self) -> R<Receiver<Event>> {
+            self.0.subscribe()
+        }
+
+        /// Snapshot of our current state.
+        pub fn stats(&self) -> R<Stats> {
+            self.0.stats()
+        }
+
+        /// Counters accumulated over all our runs. See `QuicP2p::lifetime_stats`.
+        pub fn lifetime_stats(&self) -> R<LifetimeStats> {
+            self.0.lifetime_stats()
+        }
+
+        /// Dump of our internal state for debugging. See `QuicP2p::debug_dump`.
+        pub fn debug_dump(&self) -> R<DebugSnapshot> {
+            self.0.debug_dump()
+        }
+
+        /// Our traffic metrics in the Prometheus text format. See `QuicP2p::render_prometheus`.
+        ///
+        /// Only available with the `metrics` feature.
+        #[cfg(feature = "metrics")]
+        pub fn render_prometheus(&self) -> String {
+            self.0.render_prometheus()
+        }
+
+        /// Snapshot of the peer knowledge worth carrying over a process restart.
+        pub fn export_state(&self) -> R<StateSnapshot> {
+            self.0.export_state()
+        }
+
+        /// Parameters negotiated for the established connection with the peer.
+        pub fn connection_details(&self, peer_addr: SocketAddr) -> R<ConnectionDetails> {
+            self.0.connection_details(peer_addr)
+        }
+
+        /// Estimate of how far the clock of the peer is ahead of ours in milliseconds. See
+        /// `QuicP2p::peer_clock_skew`.
+        pub fn peer_clock_skew(&self, peer_addr: SocketAddr) -> R<Option<i64>> {
+            self.0.peer_clock_skew(peer_addr)
+        }
+
+        /// Attach state of our own to the connection with the peer. See
+        /// `QuicP2p::set_peer_context`.
+        pub fn set_peer_context(
+            &self,
+            peer_addr: SocketAddr,
+            context: Box<dyn Any + Send>,
+        ) -> R<()> {
+            self.0.set_peer_context(peer_addr, context)
+        }
+
+        /// Run `f` on the state attached to the connection with the peer. See
+        /// `QuicP2p::with_peer_context`.
+        pub fn with_peer_context<F, T>(&self, peer_addr: SocketAddr, f: F) -> R<T>
+        where
+            F: FnOnce(Option<&mut (dyn Any + Send)>) -> T + Send + 'static,
+            T: Send + 'static,
+        {
+            self.0.with_peer_context(peer_addr, f)
+        }
+
+        /// Peers whose connects are queued due to `Config::max_concurrent_connects`.
+        pub fn pending_connects(&self) -> R<Vec<SocketAddr>> {
+            self.0.pending_connects()
+        }
+
+        /// Nodes we are currently connected to.
+        pub fn connected_nodes(&self) -> R<Vec<NodeInfo>> {
+            self.0.connected_nodes()
+        }
+
+        /// Clients currently connected to us.
+        pub fn connected_clients(&self) -> R<Vec<SocketAddr>> {
+            self.0.connected_clients()
+        }
+
+        /// Penalty points the peer has accumulated for misbehaving.
+        pub fn peer_score(&self, peer_addr: SocketAddr) -> R<u32> {
+            self.0.peer_score(peer_addr)
+        }
+
+        /// Retrieves current bootstrap cache.
+        pub fn bootstrap_cache(&self) -> R<Vec<NodeInfo>> {
+            self.0.bootstrap_cache()
+        }
+
+        /// Retrieves current bootstrap cache, most promising peers first.
+        pub fn bootstrap_cache_ranked(&self) -> R<Vec<RankedPeer>> {
+            self.0.bootstrap_cache_ranked()
+        }
+
+        /// Cached peers, e.g. to seed the cache of a new node with.
+        pub fn export_bootstrap_cache(&self) -> R<Vec<NodeInfo>> {
+            self.0.export_bootstrap_cache()
+        }
+
+        /// Merge the given peers into our bootstrap cache as per the strategy. Returns how many
+        /// were added or had their certificate replaced.
+        pub fn import_bootstrap_cache(
+            &self,
+            peers: Vec<NodeInfo>,
+            strategy: MergeStrategy,
+        ) -> R<usize> {
+            self.0.import_bootstrap_cache(peers, strategy)
+        }
+
+        /// Inject a fault to exercise failure paths in tests.
+        ///
+        /// Only available with the `testing` feature.
+        #[cfg(feature = "testing")]
+        pub fn inject_fault(&self, fault: FaultSpec) {
+            self.0.inject_fault(fault)
+        }
+    };
+}
+
+/// Handle to an instance running as a node: it listens for and accepts incoming connections.
+#[derive(Clone)]
+pub struct Node(pub(crate) QuicP2p);
+
+impl Node {
+    common_methods!();
+
+    /// Get our connection info to give to others for them to connect to us. See
+    /// `QuicP2p::our_connection_info`.
+    pub fn our_connection_info(&self) -> R<NodeInfo> {
+        self.0.our_connection_info()
+    }
+
+    /// Get our connection info without blocking on the network. See
+    /// `QuicP2p::our_connection_info_nonblocking`.
+    pub fn our_connection_info_nonblocking(&self) -> R<Option<NodeInfo>> {
+        self.0.our_connection_info_nonblocking()
+    }
+
+    /// Addresses we might be reached at, most likely first. See `QuicP2p::candidate_addresses`.
+    pub fn candidate_addresses(&self) -> R<Vec<SocketAddr>> {
+        self.0.candidate_addresses()
+    }
+
+    /// Addresses peers reported reaching us at. See `QuicP2p::observed_addresses`.
+    pub fn observed_addresses(&self) -> R<Vec<(SocketAddr, usize)>> {
+        self.0.observed_addresses()
+    }
+
+    /// Forget our connection info and resolve it afresh in the background.
+    pub fn refresh_our_connection_info(&self) -> R<()> {
+        self.0.refresh_our_connection_info()
+    }
+
+    /// Rebind our endpoint to the given port. See `QuicP2p::restart_listener`.
+    pub fn restart_listener(&self, new_port: Option<u16>) -> R<()> {
+        self.0.restart_listener(new_port)
+    }
+
+    /// Replace our certificate with a freshly generated one. See `QuicP2p::rotate_certificate`.
+    pub fn rotate_certificate(&self) -> R<SerialisableCertificate> {
+        self.0.rotate_certificate()
+    }
+
+    /// Check our own endpoint end to end. See `QuicP2p::self_test`.
+    pub fn self_test(&self) -> R<SelfTestReport> {
+        self.0.self_test()
+    }
+
+    /// Stop accepting new incoming connections. See `QuicP2p::pause_accepting`.
+    pub fn pause_accepting(&self) {
+        self.0.pause_accepting()
+    }
+
+    /// Resume accepting new incoming connections after `pause_accepting`.
+    pub fn resume_accepting(&self) {
+        self.0.resume_accepting()
+    }
+
+    /// Ask the node `via` to connect to `target_info`. See `QuicP2p::request_reverse_connect`.
+    pub fn request_reverse_connect(&self, via: NodeInfo, target_info: NodeInfo) {
+        self.0.request_reverse_connect(via, target_info)
+    }
+}
+
+/// Handle to an instance running as a client: it only connects out to nodes and never listens,
+/// so there's no connection info of ours to hand out.
+///
+/// ```compile_fail
+/// # use std::sync::mpsc;
+/// let (tx, _rx) = mpsc::channel();
+/// let client = quic_p2p::Builder::new(tx).build_client().unwrap();
+/// let _ = client.our_connection_info();
+/// ```
+#[derive(Clone)]
+pub struct Client(pub(crate) QuicP2p);
+
+impl Client {
+    common_methods!();
+
+    /// Get the information identifying us to the nodes we connect to. See
+    /// `QuicP2p::our_client_info`.
+    pub fn our_client_info(&self) -> ClientInfo {
+        self.0.our_client_info()
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Proof that the sender of a handshake holds the private key of the certificate it presents,
+//! along with protection against captured handshakes being replayed. The signature also covers the
+//! certificate of the recipient, i.e. the one it presented to us in TLS, so a handshake it receives
+//! can't be passed on to anyone else. Also used to vouch for the certificate a peer rotates to.
+
+use crate::config::SerialisableCertificate;
+use crate::error::Error;
+use crate::rng::SharedRng;
+use crate::R;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use std::collections::{HashSet, VecDeque};
+
+/// Length of the nonce in bytes
+pub const NONCE_LEN: usize = 32;
+/// Number of handshake nonces we remember in order to detect replays
+const MAX_SEEN_NONCES: usize = 10_000;
+/// Prefixed to the data signed for certificate rotations so that those signatures can't be passed
+/// off as handshake ones or vice versa.
+const CERT_ROTATION_CONTEXT: &[u8] = b"quic-p2p certificate rotation";
+
+/// Random value making every handshake unique
+pub type Nonce = [u8; NONCE_LEN];
+
+/// Sign a fresh nonce together with our certificate and the one the recipient presented to us in
+/// TLS. The nonce is drawn from the given RNG if any, the OS randomness otherwise.
+pub fn sign(
+    our_complete_cert: &SerialisableCertificate,
+    recipient_cert_der: &[u8],
+    nonce_rng: Option<&SharedRng>,
+) -> R<(Nonce, Vec<u8>)> {
+    let rng = SystemRandom::new();
+
+    let mut nonce = [0; NONCE_LEN];
+    match nonce_rng {
+        Some(nonce_rng) => nonce_rng.fill_bytes(&mut nonce),
+        None => rng
+            .fill(&mut nonce)
+            .map_err(|_| Error::HandshakeAuth("could not generate a nonce"))?,
+    }
+
+    let data = signed_data(&nonce, &our_complete_cert.cert_der, recipient_cert_der);
+    let signature =
+        sign_data(&rng, &our_complete_cert.key_der, &data).map_err(Error::HandshakeAuth)?;
+
+    Ok((nonce, signature))
+}
+
+/// Verify the signature over the nonce and certificates using the public key in the sender's
+/// certificate. `our_cert_der` is the certificate we present in TLS, so a handshake meant for
+/// someone else fails here.
+pub fn verify(cert_der: &[u8], our_cert_der: &[u8], nonce: &Nonce, signature: &[u8]) -> R<()> {
+    let data = signed_data(nonce, cert_der, our_cert_der);
+    verify_data(cert_der, &data, signature).map_err(Error::HandshakeAuth)
+}
+
+/// Sign the certificate we are rotating to with the key of our current one, vouching that the
+/// new certificate is still us.
+pub fn sign_cert_rotation(
+    our_complete_cert: &SerialisableCertificate,
+    new_cert_der: &[u8],
+) -> R<Vec<u8>> {
+    let rng = SystemRandom::new();
+    let data = cert_rotation_data(new_cert_der);
+    sign_data(&rng, &our_complete_cert.key_der, &data).map_err(Error::CertRotation)
+}
+
+/// Verify the certificate the peer is rotating to is vouched for by the key of its current one.
+pub fn verify_cert_rotation(old_cert_der: &[u8], new_cert_der: &[u8], signature: &[u8]) -> R<()> {
+    let data = cert_rotation_data(new_cert_der);
+    verify_data(old_cert_der, &data, signature).map_err(Error::CertRotation)
+}
+
+fn sign_data(rng: &SystemRandom, key_der: &[u8], data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if let Ok(key_pair) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key_der) {
+        Ok(key_pair
+            .sign(rng, data)
+            .map_err(|_| "could not sign the data")?
+            .as_ref()
+            .to_vec())
+    } else if let Ok(key_pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(key_der) {
+        Ok(key_pair.sign(data).as_ref().to_vec())
+    } else {
+        Err("our private key is neither an ECDSA P-256 nor an Ed25519 one")
+    }
+}
+
+fn verify_data(cert_der: &[u8], data: &[u8], signature: &[u8]) -> Result<(), &'static str> {
+    let cert = webpki::EndEntityCert::from(cert_der).map_err(|_| "invalid certificate")?;
+    let is_valid = [&webpki::ECDSA_P256_SHA256, &webpki::ED25519]
+        .iter()
+        .any(|alg| cert.verify_signature(alg, data, signature).is_ok());
+    if is_valid {
+        Ok(())
+    } else {
+        Err("signature doesn't match the certificate")
+    }
+}
+
+fn cert_rotation_data(new_cert_der: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(CERT_ROTATION_CONTEXT.len() + new_cert_der.len());
+    data.extend_from_slice(CERT_ROTATION_CONTEXT);
+    data.extend_from_slice(new_cert_der);
+    data
+}
+
+// Both certificates are DER and hence self-delimiting, so no lengths are needed in between.
+fn signed_data(nonce: &Nonce, cert_der: &[u8], recipient_cert_der: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(NONCE_LEN + cert_der.len() + recipient_cert_der.len());
+    data.extend_from_slice(nonce);
+    data.extend_from_slice(cert_der);
+    data.extend_from_slice(recipient_cert_der);
+    data
+}
+
+/// Nonces of the handshakes received so far. Only the most recent ones are remembered so that
+/// this doesn't grow indefinitely.
+#[derive(Default)]
+pub struct SeenNonces {
+    nonces: HashSet<Nonce>,
+    order: VecDeque<Nonce>,
+}
+
+impl SeenNonces {
+    /// Remember the nonce. Returns `false` if it had been seen already, i.e. the handshake is a
+    /// replay.
+    pub fn insert(&mut self, nonce: Nonce) -> bool {
+        if !self.nonces.insert(nonce) {
+            return false;
+        }
+
+        self.order.push_back(nonce);
+        if self.order.len() > MAX_SEEN_NONCES {
+            if let Some(oldest) = self.order.pop_front() {
+                let _ = self.nonces.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_are_verified_against_the_certificate() {
+        let our_complete_cert = SerialisableCertificate::default();
+        let recipient_cert = SerialisableCertificate::default();
+        let (nonce, signature) = unwrap!(sign(&our_complete_cert, &recipient_cert.cert_der, None));
+
+        unwrap!(verify(
+            &our_complete_cert.cert_der,
+            &recipient_cert.cert_der,
+            &nonce,
+            &signature
+        ));
+
+        let mut other_nonce = nonce;
+        other_nonce[0] ^= 0xff;
+        assert!(verify(
+            &our_complete_cert.cert_der,
+            &recipient_cert.cert_der,
+            &other_nonce,
+            &signature
+        )
+        .is_err());
+
+        let other_cert = SerialisableCertificate::default();
+        assert!(verify(
+            &other_cert.cert_der,
+            &recipient_cert.cert_der,
+            &nonce,
+            &signature
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn signatures_are_bound_to_the_recipient() {
+        let our_complete_cert = SerialisableCertificate::default();
+        let recipient_cert = SerialisableCertificate::default();
+        let (nonce, signature) = unwrap!(sign(&our_complete_cert, &recipient_cert.cert_der, None));
+
+        // The recipient passing our handshake on to another node mustn't let it pass for us
+        let other_recipient_cert = SerialisableCertificate::default();
+        assert!(verify(
+            &our_complete_cert.cert_der,
+            &other_recipient_cert.cert_der,
+            &nonce,
+            &signature
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rotated_certificates_are_vouched_for_by_the_old_key() {
+        let old_cert = SerialisableCertificate::default();
+        let new_cert = SerialisableCertificate::default();
+        let signature = unwrap!(sign_cert_rotation(&old_cert, &new_cert.cert_der));
+
+        unwrap!(verify_cert_rotation(
+            &old_cert.cert_der,
+            &new_cert.cert_der,
+            &signature
+        ));
+        assert!(verify_cert_rotation(&new_cert.cert_der, &new_cert.cert_der, &signature).is_err());
+
+        let other_cert = SerialisableCertificate::default();
+        assert!(
+            verify_cert_rotation(&old_cert.cert_der, &other_cert.cert_der, &signature).is_err()
+        );
+    }
+
+    #[test]
+    fn replayed_nonces_are_detected() {
+        let mut seen_nonces = SeenNonces::default();
+        assert!(seen_nonces.insert([1; NONCE_LEN]));
+        assert!(seen_nonces.insert([2; NONCE_LEN]));
+        assert!(!seen_nonces.insert([1; NONCE_LEN]));
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Local network interfaces and the addresses we might be reached at through them.
+
+use crate::context::Context;
+use crate::R;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// Addresses we might be reached at, most likely first. See `QuicP2p::candidate_addresses`.
+pub fn candidate_addrs(c: &Context) -> R<Vec<SocketAddr>> {
+    let local_addr = c.quic_ep().local_addr()?;
+    let interface_ips = if local_addr.ip().is_unspecified() {
+        local_ips().unwrap_or_else(|e| {
+            info!("Could not list the local interfaces: {:?} - {}", e, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+
+    Ok(order_candidates(
+        c.external_connection_addr(),
+        c.observed_addrs.consensus(),
+        local_addr,
+        interface_ips,
+    ))
+}
+
+fn order_candidates(
+    external: Option<SocketAddr>,
+    observed: Option<SocketAddr>,
+    local_addr: SocketAddr,
+    interface_ips: Vec<IpAddr>,
+) -> Vec<SocketAddr> {
+    let mut candidates: Vec<SocketAddr> = external.into_iter().chain(observed).collect();
+
+    if local_addr.ip().is_unspecified() {
+        // An IPv4 socket can't be reached over IPv6, while an IPv6 one usually takes both
+        let mut reachable: Vec<_> = interface_ips
+            .into_iter()
+            .filter(|ip| !ip.is_unspecified() && (local_addr.is_ipv6() || ip.is_ipv4()))
+            .collect();
+        reachable.sort_by_key(|ip| ip.is_loopback());
+        candidates.extend(
+            reachable
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, local_addr.port())),
+        );
+    } else {
+        candidates.push(local_addr);
+    }
+
+    let mut deduped = Vec::with_capacity(candidates.len());
+    for candidate in candidates {
+        if !deduped.contains(&candidate) {
+            deduped.push(candidate);
+        }
+    }
+    deduped
+}
+
+/// IP addresses of the local network interfaces.
+#[cfg(unix)]
+pub fn local_ips() -> io::Result<Vec<IpAddr>> {
+    use std::ptr;
+
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ips = Vec::new();
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() {
+        let ifaddr = unsafe { &*cursor };
+        if let Some(ip) = unsafe { to_ip(ifaddr.ifa_addr) } {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+        cursor = ifaddr.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Ok(ips)
+}
+
+/// IP addresses of the local network interfaces. Listing them isn't supported on this platform so
+/// there are none.
+#[cfg(not(unix))]
+pub fn local_ips() -> io::Result<Vec<IpAddr>> {
+    Ok(Vec::new())
+}
+
+#[cfg(unix)]
+unsafe fn to_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    if addr.is_null() {
+        return None;
+    }
+
+    match libc::c_int::from((*addr).sa_family) {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                addr.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn v4(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), port)
+    }
+
+    #[test]
+    fn external_and_observed_come_before_the_bound_address() {
+        let external = v4(203, 0, 113, 1, 5000);
+        let observed = v4(198, 51, 100, 7, 6000);
+        let local = v4(192, 168, 0, 2, 5000);
+
+        assert_eq!(
+            order_candidates(Some(external), Some(observed), local, Vec::new()),
+            vec![external, observed, local]
+        );
+        assert_eq!(
+            order_candidates(None, Some(local), local, Vec::new()),
+            vec![local]
+        );
+    }
+
+    #[test]
+    fn interfaces_are_listed_when_bound_to_unspecified() {
+        let local = v4(0, 0, 0, 0, 5000);
+        let interface_ips = vec![
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)),
+        ];
+
+        assert_eq!(
+            order_candidates(None, None, local, interface_ips),
+            vec![v4(192, 168, 0, 2, 5000), v4(127, 0, 0, 1, 5000)]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_ips_include_loopback() {
+        let ips = unwrap!(local_ips());
+        assert!(ips.iter().any(|ip| ip.is_loopback()));
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Scheduling lanes on our single runtime. The control lane - the event loop, connects,
+//! handshakes and timers - runs as tasks of its own, while each connection gets a data lane task
+//! accepting the streams the peer opens. A data lane only handles a budget of streams in a row
+//! before yielding, so a peer flooding us with streams can't hold up the control lane or the other
+//! connections.
+
+use tokio::prelude::task;
+use tokio::prelude::{Async, Poll, Stream};
+
+/// Wrap the stream of work for a connection so it yields after `budget` items in a row.
+pub fn data_lane<S: Stream>(stream: S, budget: usize) -> Budgeted<S> {
+    let budget = std::cmp::max(budget, 1);
+    Budgeted {
+        inner: stream,
+        budget,
+        remaining: budget,
+    }
+}
+
+/// Stream yielding to the other tasks once it has produced its budget of items in a row.
+pub struct Budgeted<S> {
+    inner: S,
+    budget: usize,
+    remaining: usize,
+}
+
+impl<S: Stream> Stream for Budgeted<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.remaining == 0 {
+            // Go to the back of the run queue, letting everyone else waiting take their turn
+            self.remaining = self.budget;
+            task::current().notify();
+            return Ok(Async::NotReady);
+        }
+
+        let item = self.inner.poll()?;
+        match item {
+            Async::Ready(Some(_)) => self.remaining -= 1,
+            // We are parked anyway so we start afresh when woken up
+            Async::NotReady => self.remaining = self.budget,
+            Async::Ready(None) => (),
+        }
+
+        Ok(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tokio::prelude::{future, stream, Future};
+    use tokio::runtime::current_thread;
+
+    const FLOOD_SIZE: usize = 10_000;
+
+    /// Number of flood items handled before the control task got to run.
+    fn flood_items_before_control(budget: Option<usize>) -> usize {
+        let handled = Rc::new(Cell::new(0));
+        let handled_at_control = Rc::new(Cell::new(None));
+
+        let mut rt = unwrap!(current_thread::Runtime::new());
+
+        let flood = stream::iter_ok::<_, ()>(0..FLOOD_SIZE);
+        let handled_clone = handled.clone();
+        let handle_item = move |_| {
+            handled_clone.set(handled_clone.get() + 1);
+            Ok(())
+        };
+        match budget {
+            Some(budget) => rt.spawn(data_lane(flood, budget).for_each(handle_item)),
+            None => rt.spawn(flood.for_each(handle_item)),
+        };
+
+        let handled_clone = handled.clone();
+        let handled_at_control_clone = handled_at_control.clone();
+        rt.spawn(future::lazy(move || {
+            handled_at_control_clone.set(Some(handled_clone.get()));
+            Ok(())
+        }));
+
+        unwrap!(rt.run());
+
+        assert_eq!(handled.get(), FLOOD_SIZE);
+        unwrap!(handled_at_control.get())
+    }
+
+    #[test]
+    fn unbudgeted_flood_holds_up_control() {
+        assert_eq!(flood_items_before_control(None), FLOOD_SIZE);
+    }
+
+    #[test]
+    fn data_lane_yields_to_control_within_budget() {
+        assert!(flood_items_before_control(Some(16)) <= 16);
+        assert!(flood_items_before_control(Some(0)) <= 1);
+    }
+}
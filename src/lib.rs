@@ -16,64 +16,248 @@ extern crate serde_derive;
 #[macro_use]
 extern crate unwrap;
 
-pub use config::{Config, OurType, SerialisableCertificate};
+pub use backpressure::{BackpressureLevel, ReadBacklog};
+pub use bootstrap_cache::{MergeStrategy, RankedPeer};
+#[cfg(feature = "testing")]
+pub use clock::{Clock, ManualClock};
+#[cfg(feature = "codec")]
+pub use codec::{BincodeCodec, Codec};
+pub use config::{
+    AdmissionPolicy, BackpressureConfig, CacheHealthCheckConfig, CertKeyType, CertParams, Config,
+    DedupConfig, DialBackoffConfig, EventChannelClosedPolicy, OrderedDeliveryConfig, OurType,
+    ProxyConfig, ReputationConfig, RetryPolicy, SerialisableCertificate, SocketOptions,
+    StaleConnReaperConfig, TrafficProfile,
+};
+pub use connection_details::ConnectionDetails;
+pub use debug_dump::{ConnectionDump, DebugSnapshot, FromPeerState, ToPeerState};
+pub use dns::{Resolver, SystemResolver};
 pub use error::Error;
-pub use event::Event;
-pub use peer::{NodeInfo, Peer};
-pub use peer_config::{DEFAULT_IDLE_TIMEOUT_MSEC, DEFAULT_KEEP_ALIVE_INTERVAL_MSEC};
+pub use event::{
+    event_channel, ConnectTimings, Event, EventFilter, EventReceiver, EventSender, EventVerbosity,
+    UnsentReason,
+};
+pub use event_loop::SharedEventLoop;
+#[cfg(feature = "testing")]
+pub use fault_injection::FaultSpec;
+pub use handles::{Client, Node};
+#[cfg(feature = "net-sim")]
+pub use net_sim::{NetSim, NetSimConfig};
+pub use peer::{ClientInfo, Contact, NodeAddr, NodeInfo, Peer, PeerKind};
+pub use peer_config::{
+    DEFAULT_CONNECTION_RECEIVE_WINDOW, DEFAULT_IDLE_TIMEOUT_MSEC, DEFAULT_KEEP_ALIVE_INTERVAL_MSEC,
+    DEFAULT_STREAM_RECEIVE_WINDOW,
+};
+pub use self_test::SelfTestReport;
+pub use state::StateSnapshot;
+pub use stats::{LifetimeStats, Stats};
+pub use traffic_profile::BACKGROUND_MAX_SEND_RATE;
+pub use transfer::FileHash;
+pub use transport::{Transport, UdpTransport};
 pub use utils::R;
+pub use wire_msg::CloseReason;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub use wire_msg::WireMsg;
+#[cfg(feature = "wire-tap")]
+pub use wire_tap::{Direction, WireTap};
 
 use crate::wire_msg::WireMsg;
 use bootstrap_cache::BootstrapCache;
-use context::{ctx, ctx_mut, initialise_ctx, Context};
-use event_loop::EventLoop;
+use cert_rotation::ServerCert;
+use clock::{SharedClock, SystemClock};
+#[cfg(feature = "codec")]
+use codec::SharedCodec;
+use connection::ToPeer;
+use context::{Context, ContextSettings, Ctx};
+use dirs::{Dirs, OverRide};
+use dns::{HostContacts, SharedResolver};
+use event::EventTx;
+use event_loop::{EventLoop, Instance};
+use rand_core::{RngCore, SeedableRng};
+use rng::SharedRng;
+use shutdown::ShutdownTrigger;
+use socks5::Socks5Transport;
+use stats::LifetimeStatsTracker;
+use std::any::Any;
+use std::cmp;
 use std::collections::VecDeque;
+use std::fs::File;
 use std::mem;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::mpsc::{self, Sender};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::prelude::Future;
-use tokio::runtime::current_thread;
+use traffic_profile::TrafficSettings;
+use transport::SharedTransport;
 
+mod admission;
+mod backpressure;
+mod batch_connect;
+mod batch_send;
 mod bootstrap;
 mod bootstrap_cache;
+mod cache_health;
+mod cert_expiry;
+mod cert_rotation;
+mod client_grace;
+mod clock;
+mod clock_skew;
+#[cfg(feature = "codec")]
+mod codec;
 mod communicate;
 mod config;
 mod connect;
 mod connection;
+mod connection_details;
 mod context;
+mod debug_dump;
+mod dedup;
 mod dirs;
+mod dns;
 mod error;
 mod event;
 mod event_loop;
+#[cfg(feature = "testing")]
+mod fault_injection;
+mod handles;
+mod handshake_auth;
+mod interfaces;
+mod lanes;
 mod listener;
+mod liveness;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod multicast;
+#[cfg(feature = "net-sim")]
+mod net_sim;
+mod observed_addrs;
+mod ordering;
 mod peer;
 mod peer_config;
+mod reaper;
+mod reputation;
+mod rng;
+mod self_test;
+mod send_scheduler;
+mod shutdown;
+mod socket;
+mod socks5;
+mod state;
+mod stats;
+#[cfg(feature = "testing")]
+pub mod test_utils;
+mod traffic_profile;
+mod transfer;
+mod transport;
 mod utils;
 mod wire_msg;
+#[cfg(feature = "wire-tap")]
+mod wire_tap;
 
 /// Default maximum allowed message size. We'll error out on any bigger messages and probably
 /// shutdown the connection. This value can be overridden via the `Config` option.
 pub const DEFAULT_MAX_ALLOWED_MSG_SIZE: usize = 500 * 1024 * 1024; // 500MiB
+/// Default maximum number of contacts shared with a peer asking for them. This value can be
+/// overridden via the `Config` option.
+pub const DEFAULT_MAX_CONTACTS_TO_SHARE: usize = 20;
+/// Default minimum interval in seconds between two requests for contacts from the same peer. This
+/// value can be overridden via the `Config` option.
+pub const DEFAULT_CONTACTS_REQUEST_INTERVAL_SEC: u64 = 10;
+/// Default minimum interval in seconds between two reverse connect requests from the same peer.
+/// This value can be overridden via the `Config` option.
+pub const DEFAULT_REVERSE_CONNECT_INTERVAL_SEC: u64 = 10;
+/// Default length in bytes of the longest file we take from a peer. This value can be overridden
+/// via the `Config` option.
+pub const DEFAULT_MAX_FILE_LEN: u64 = 1024 * 1024 * 1024; // 1 GiB
+/// Default number of files we are offered or are receiving at a time. This value can be
+/// overridden via the `Config` option.
+pub const DEFAULT_MAX_INCOMING_FILES: usize = 16;
+/// Default number of bytes written to a peer in one go before the writes to other peers get their
+/// turn. This value can be overridden via the `Config` option.
+pub const DEFAULT_SEND_QUANTUM_BYTES: usize = 64 * 1024; // 64 KiB
+/// Default time in milliseconds changes to our bootstrap cache are collected for before being
+/// reported. This value can be overridden via the `Config` option.
+pub const DEFAULT_BOOTSTRAP_CACHE_UPDATE_DEBOUNCE_MSEC: u64 = 1_000;
+/// Default time in milliseconds a peer gets to send us a whole message once it has started doing
+/// so. This value can be overridden via the `Config` option.
+pub const DEFAULT_READ_TIMEOUT_MSEC: u64 = 120_000; // 2 minutes
+/// Default time in milliseconds a peer gets to take a whole message from us once we have started
+/// sending it. This value can be overridden via the `Config` option.
+pub const DEFAULT_WRITE_TIMEOUT_MSEC: u64 = 120_000; // 2 minutes
+/// Default time in milliseconds a peer connecting to us gets to send its handshake. This value can
+/// be overridden via the `Config` option.
+pub const DEFAULT_HANDSHAKE_TIMEOUT_MSEC: u64 = 10_000; // 10 seconds
+/// Default maximum number of incomplete messages we read from a single connection at a time. This
+/// value can be overridden via the `Config` option.
+pub const DEFAULT_MAX_INCOMPLETE_READS: usize = 256;
+/// Default number of streams accepted from a single connection in a row before connects,
+/// handshakes and the other connections get their turn. This value can be overridden via the
+/// `Config` option.
+pub const DEFAULT_DATA_LANE_BUDGET: usize = 16;
+/// Default number of events each receiver from `QuicP2p::subscribe` can have queued up. This value
+/// can be overridden via the `Config` option.
+pub const DEFAULT_EVENT_SUBSCRIBER_QUEUE_LEN: usize = 1_024;
+/// Default time in seconds what the hard coded contacts given by hostname resolved to is cached
+/// for. This value can be overridden via the `Config` option.
+pub const DEFAULT_DNS_CACHE_TTL_SEC: u64 = 300; // 5 minutes
+/// Default time in seconds before our certificate expires that we warn about it. This value can be
+/// overridden via the `Config` option.
+pub const DEFAULT_CERT_EXPIRY_WARNING_SEC: u64 = 7 * 24 * 3600; // 1 week
+/// Name our certificates are issued for and peers are asked for via SNI, see `Config::server_name`.
+pub const DEFAULT_SERVER_NAME: &str = "MaidSAFE.net";
+/// Logical channel user messages are sent on unless another one is asked for. It's always accepted.
+pub const DEFAULT_CHANNEL: u8 = 0;
+/// Prefix of the messages an echo service sends back, see `Config::echo_service`.
+pub const ECHO_MARKER: &[u8] = b"quic-p2p-echo:";
+/// QUIC versions spoken by the quinn release we are built with, as offered in version negotiation.
+pub const SUPPORTED_QUIC_VERSIONS: &[u32] = &[0xff00_0014]; // draft-20
 /// In the absence of a port supplied by the user via the config we will first try using this
 /// before using a random port.
 pub const DEFAULT_PORT_TO_TRY: u16 = 443;
 
 /// Builder for `QuicP2p`. Convenient for setting various parameters and creating `QuicP2p`.
 pub struct Builder {
-    event_tx: Sender<Event>,
+    event_tx: EventSender,
+    event_filter: EventFilter,
     cfg: Option<Config>,
     proxies: VecDeque<NodeInfo>,
     use_proxies_exclusively: bool,
+    handshake_data: Option<bytes::Bytes>,
+    state: Option<StateSnapshot>,
+    rng: Option<SharedRng>,
+    clock: Option<SharedClock>,
+    transport: Option<SharedTransport>,
+    resolver: Option<SharedResolver>,
+    event_loop: Option<SharedEventLoop>,
+    #[cfg(feature = "codec")]
+    codec: Option<SharedCodec>,
+    #[cfg(feature = "wire-tap")]
+    wire_tap: Option<Box<dyn WireTap>>,
 }
 
 impl Builder {
-    /// New `Builder`
-    pub fn new(event_tx: Sender<Event>) -> Self {
+    /// New `Builder` firing the events over `event_tx`, a plain `Sender<Event>` or the sender of
+    /// `event_channel`.
+    pub fn new<T: Into<EventSender>>(event_tx: T) -> Self {
         Self {
-            event_tx,
+            event_tx: event_tx.into(),
+            event_filter: Default::default(),
             cfg: Default::default(),
             proxies: Default::default(),
             use_proxies_exclusively: Default::default(),
+            handshake_data: None,
+            state: None,
+            rng: None,
+            clock: None,
+            transport: None,
+            resolver: None,
+            event_loop: None,
+            #[cfg(feature = "codec")]
+            codec: None,
+            #[cfg(feature = "wire-tap")]
+            wire_tap: None,
         }
     }
 
@@ -101,27 +285,222 @@ impl Builder {
         self
     }
 
+    /// Only deliver the events in the given categories, e.g. `EventFilter::CONNECTIVITY` for a
+    /// consumer not interested in the messages from peers. Every event is delivered by default.
+    pub fn with_event_filter(mut self, event_filter: EventFilter) -> Self {
+        self.event_filter = event_filter;
+        self
+    }
+
+    /// Application data to attach to every handshake we make.
+    ///
+    /// Useful for exchanging a small amount of metadata (network name, node age, version etc.) at
+    /// connection time. The peer receives it in `Event::ConnectedTo` or `Event::BootstrappedTo`.
+    /// Note that nodes don't handshake with the clients connecting to them so clients will always
+    /// see `None` for the nodes they connect to.
+    pub fn with_handshake_data(mut self, data: bytes::Bytes) -> Self {
+        self.handshake_data = Some(data);
+        self
+    }
+
+    /// State exported by an earlier instance, e.g. before a process restart.
+    ///
+    /// The blacklist is restored and the peers we were connected to are connected to straight away.
+    pub fn with_state(mut self, state: StateSnapshot) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Draw our randomness from the given RNG instead of the OS, so that e.g. test runs seeding it
+    /// the same can be replayed exactly.
+    ///
+    /// It's used for our certificate, unless one is supplied in the config, for the ones we rotate
+    /// to and for the nonces of our handshakes. Signatures by ECDSA keys still use the OS
+    /// randomness and so do the ports the OS picks for us. Never use a predictable RNG outside of
+    /// tests: anyone able to replay it can impersonate us.
+    pub fn with_rng<T: RngCore + SeedableRng + Send + 'static>(mut self, rng: T) -> Self {
+        self.rng = Some(SharedRng::new(rng));
+        self
+    }
+
+    /// Take the time from the given clock instead of the system, e.g. a `ManualClock` moving time
+    /// on instantly so that tests of timeouts and backoffs don't take minutes.
+    ///
+    /// It drives our own timers: timeouts of connects, handshakes, reads and writes, dial backoff,
+    /// reconnects, client grace periods and the periodic tasks. The idle timeout and keep-alives
+    /// of the QUIC transport are run by quinn and keep following the system clock.
+    ///
+    /// Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Bind the socket our endpoint runs over via the given transport instead of binding a UDP
+    /// socket of the OS directly. The port and socket options asked for in the config are passed
+    /// on to it, and the transport is used again on `QuicP2p::restart_listener`.
+    pub fn with_transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Look up the hard coded contacts given by hostname via the given resolver instead of the
+    /// one of the OS, e.g. to query a particular DNS server or to map names in tests.
+    pub fn with_resolver<T: Resolver + 'static>(mut self, resolver: T) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Run on the given event loop rather than on a thread of our own, e.g. to keep many instances
+    /// of a test network or of a simulation on one thread. Instances sharing an event loop are
+    /// isolated from each other all the same: each has its own connections, state and
+    /// configuration, and is shut down once its last handle is dropped.
+    pub fn with_event_loop(mut self, event_loop: &SharedEventLoop) -> Self {
+        self.event_loop = Some(event_loop.clone());
+        self
+    }
+
+    /// Codec for the messages sent with `QuicP2p::send_typed` and received in
+    /// `Event::NewTypedMessage`. Plain messages are unaffected by it.
+    ///
+    /// Only available with the `codec` feature.
+    #[cfg(feature = "codec")]
+    pub fn with_codec<C: Codec>(mut self, codec: C) -> Self {
+        self.codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// Observe every frame sent to or received from peers.
+    ///
+    /// Only available with the `wire-tap` feature.
+    #[cfg(feature = "wire-tap")]
+    pub fn with_wire_tap(mut self, wire_tap: Box<dyn WireTap>) -> Self {
+        self.wire_tap = Some(wire_tap);
+        self
+    }
+
     /// Construct `QuicP2p` with supplied parameters earlier, ready to be used.
+    ///
+    /// Whether we run as a node or a client is decided by `Config::our_type` at runtime. Prefer
+    /// `build_node` or `build_client` which only expose the operations valid for the type.
     pub fn build(self) -> R<QuicP2p> {
-        let mut qp2p = if let Some(cfg) = self.cfg {
-            QuicP2p::with_config(self.event_tx, cfg)
-        } else {
-            QuicP2p::new(self.event_tx)?
+        self.build_as(None)
+    }
+
+    /// Construct a node with supplied parameters earlier, overriding `Config::our_type`.
+    pub fn build_node(self) -> R<Node> {
+        Ok(Node(self.build_as(Some(OurType::Node))?))
+    }
+
+    /// Construct a client with supplied parameters earlier, overriding `Config::our_type`.
+    pub fn build_client(self) -> R<Client> {
+        Ok(Client(self.build_as(Some(OurType::Client))?))
+    }
+
+    fn build_as(self, our_type: Option<OurType>) -> R<QuicP2p> {
+        let mut cfg = match self.cfg {
+            Some(cfg) => cfg,
+            None => Config::read_or_construct_default(None)?,
         };
+        if let Some(our_type) = our_type {
+            cfg.our_type = our_type;
+        }
+        if cfg.our_type == OurType::Node && !cfg.listen {
+            return Err(Error::InvalidConfig("Nodes must listen for connections"));
+        }
+        if let Some(ref server_name) = cfg.server_name {
+            if webpki::DNSNameRef::try_from_ascii_str(server_name).is_err() {
+                return Err(Error::InvalidConfig("Server name must be a valid DNS name"));
+            }
+        }
+        if let Some(ref rng) = self.rng {
+            if cfg.our_complete_cert.is_none() {
+                let server_name = cfg
+                    .server_name
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SERVER_NAME.to_string());
+                cfg.our_complete_cert = Some(rng.gen_cert(&server_name, &cfg.cert_params));
+            }
+        }
 
-        qp2p.activate()?;
+        let el = match self.event_loop {
+            Some(event_loop) => event_loop.0,
+            None => Arc::new(EventLoop::spawn()),
+        };
+        let qp2p = QuicP2p::with_config(cfg, el);
+        let qp2p = match self.clock {
+            Some(clock) => QuicP2p { clock, ..qp2p },
+            None => qp2p,
+        };
+        let qp2p = match (self.transport, qp2p.cfg.upstream_proxy.clone()) {
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidConfig(
+                    "An upstream proxy can't be combined with a transport of our own",
+                ))
+            }
+            (Some(transport), None) => QuicP2p { transport, ..qp2p },
+            (None, Some(proxy)) => {
+                let idle_timeout = Duration::from_millis(
+                    qp2p.cfg
+                        .idle_timeout_msec
+                        .unwrap_or(peer_config::DEFAULT_IDLE_TIMEOUT_MSEC),
+                );
+                QuicP2p {
+                    transport: Arc::new(Socks5Transport::new(proxy, idle_timeout)),
+                    ..qp2p
+                }
+            }
+            (None, None) => qp2p,
+        };
+        let qp2p = match self.resolver {
+            Some(resolver) => QuicP2p { resolver, ..qp2p },
+            None => qp2p,
+        };
+        #[cfg(feature = "codec")]
+        let qp2p = QuicP2p {
+            codec: self.codec.clone(),
+            ..qp2p
+        };
+
+        let shutdown_trigger = match qp2p.cfg.on_event_channel_closed {
+            EventChannelClosedPolicy::Shutdown => {
+                Some(ShutdownTrigger::new(qp2p.el.sender(), qp2p.el.id()))
+            }
+            EventChannelClosedPolicy::Continue => None,
+        };
+        let event_tx = EventTx::new(self.event_tx, self.event_filter, qp2p.cfg.event_verbosity)
+            .with_shutdown_trigger(shutdown_trigger);
+        #[cfg(feature = "codec")]
+        let event_tx = event_tx.with_codec(self.codec);
+        qp2p.activate(event_tx)?;
 
         let use_proxies_exclusively = self.use_proxies_exclusively;
         let proxies = self.proxies;
-
-        qp2p.el.post(move || {
-            ctx_mut(|c| {
+        let handshake_data = self.handshake_data;
+        let state = self.state;
+        let rng = self.rng;
+        #[cfg(feature = "wire-tap")]
+        let wire_tap = self.wire_tap;
+
+        qp2p.el.post(move |ctx| {
+            ctx.with_mut(|c| {
                 if use_proxies_exclusively {
                     let _ = mem::replace(c.bootstrap_cache.peers_mut(), proxies);
                 } else {
                     c.bootstrap_cache.peers_mut().extend(proxies.into_iter());
                 }
-            })
+                c.our_handshake_data = handshake_data;
+                c.rng = rng;
+                #[cfg(feature = "wire-tap")]
+                {
+                    c.wire_tap = wire_tap;
+                }
+            });
+
+            if let Some(state) = state {
+                state::restore(ctx, state);
+            }
         });
 
         Ok(qp2p)
@@ -129,42 +508,71 @@ impl Builder {
 }
 
 /// Main QuicP2p instance to communicate with QuicP2p
+///
+/// This is a cheap handle to the underlying event loop: it can be cloned and shared across
+/// threads. The instance is shut down once the last handle is dropped, and with it the event loop
+/// unless that's shared with other instances (see `Builder::with_event_loop`).
+#[derive(Clone)]
 pub struct QuicP2p {
-    event_tx: Sender<Event>,
-    cfg: Config,
-    us: Option<NodeInfo>,
-    el: EventLoop,
+    cfg: Arc<Config>,
+    /// Our share of the event loop, which we post everything to
+    el: Arc<Instance>,
+    clock: SharedClock,
+    transport: SharedTransport,
+    resolver: SharedResolver,
+    #[cfg(feature = "codec")]
+    codec: Option<SharedCodec>,
 }
 
 impl QuicP2p {
     /// Bootstrap to a proxy
-    pub fn bootstrap(&mut self) {
-        self.el.post(|| {
-            bootstrap::start();
+    pub fn bootstrap(&self) {
+        self.el.post(|ctx| {
+            bootstrap::start(ctx);
         })
     }
 
     /// Connect to the given peer. This will error out if the peer is already in the process of
     /// being connected to OR for any other connection failure reasons.
-    pub fn connect_to(&mut self, peer_info: NodeInfo) {
-        self.el.post(move || {
+    pub fn connect_to(&self, peer_info: NodeInfo) {
+        self.el.post(move |ctx| {
             let peer_addr = peer_info.peer_addr;
-            if let Err(e) = connect::connect_to(peer_info, None, None) {
+            if let Err(e) = connect::connect_to(ctx, peer_info, None, None) {
                 info!(
                     "(TODO return this) Could not connect to the asked peer: {}",
                     e
                 );
             } else {
-                Self::set_we_contacted_peer(&peer_addr);
+                Self::set_we_contacted_peer(ctx, &peer_addr);
             }
         });
     }
 
+    /// Connect to all the given nodes at once, e.g. a whole section. Nodes we are connected or
+    /// connecting to already aren't connected to again and `Config::max_concurrent_connects` is
+    /// respected. `Event::BatchConnectComplete` summarises the outcome once every connect has
+    /// succeeded or failed, in addition to the usual events for each.
+    pub fn connect_to_many(&self, peers: Vec<NodeInfo>) {
+        self.el.post(move |ctx| batch_connect::start(ctx, peers));
+    }
+
+    /// Stop reading the messages the peer is sending us just now, e.g. an unwanted huge one,
+    /// without disconnecting from it. Returns how many were being read. Messages it sends from
+    /// now on are read as usual.
+    pub fn cancel_reads_from(&self, peer_addr: SocketAddr) -> R<usize> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(communicate::cancel_reads_from(ctx, peer_addr));
+        });
+
+        rx.recv()?
+    }
+
     /// Disconnect from the given peer
-    pub fn disconnect_from(&mut self, peer_addr: SocketAddr) {
-        self.el.post(move || {
-            ctx_mut(|c| {
-                if c.connections.remove(&peer_addr).is_none() {
+    pub fn disconnect_from(&self, peer_addr: SocketAddr) {
+        self.el.post(move |ctx| {
+            ctx.with_mut(|c| {
+                if !c.close_connection(&peer_addr, CloseReason::Shutdown) {
                     debug!("Asked to disconnect from an unknown peer");
                 }
             })
@@ -176,33 +584,237 @@ impl QuicP2p {
     /// If the peer is not connected, it will attempt to connect to it first
     /// and then send the message. This can be called multiple times while the peer is still being
     /// connected to - all the sends will be buffered until the peer is connected to.
-    pub fn send(&mut self, peer: Peer, msg: bytes::Bytes) {
-        self.el.post(move || {
-            let peer_addr = peer.peer_addr();
-            communicate::try_write_to_peer(peer, WireMsg::UserMsg(msg));
-            Self::set_we_contacted_peer(&peer_addr);
+    pub fn send(&self, peer: Peer, msg: bytes::Bytes) {
+        self.send_wire_msg(peer, WireMsg::UserMsg(msg), None);
+    }
+
+    /// Send the same message to all the given peers, sharing its buffer among them. Unlike `send`
+    /// this doesn't connect to anyone - peers we aren't connected to are reported as failed.
+    /// `Event::SendToManyComplete` carrying `token` is fired once every write has finished.
+    pub fn send_to_many(&self, peers: &[Peer], msg: bytes::Bytes, token: u64) {
+        let peers = peers.to_vec();
+        self.el
+            .post(move |ctx| multicast::start(ctx, peers, msg, token));
+    }
+
+    /// Send a burst of messages to the peer, written back-to-back on as few streams as they fit
+    /// in. The peer gets them in `Event::NewMessage` one by one. Those sharing a stream arrive in
+    /// order, but a burst too big for a single stream can be reordered at its boundaries.
+    ///
+    /// Like `send_to_many` this doesn't connect to the peer. Instead of an event per message, a
+    /// single `Event::SentUserMessageBatch` or `Event::UnsentUserMessageBatch` carrying `token`
+    /// is fired once every write has finished.
+    pub fn send_batch(&self, peer: Peer, msgs: Vec<bytes::Bytes>, token: u64) {
+        self.el
+            .post(move |ctx| batch_send::start(ctx, peer, msgs, token));
+    }
+
+    /// Send the file at `path` to the peer in chunks, connecting to it first if need be. The file
+    /// is identified by the SHA-256 hash of its content, which is returned and which the peer
+    /// checks it against before handing it over in `Event::FileReceived`. Both ends are kept
+    /// posted via `Event::FileTransferProgress` and `Event::FileTransferFailed`. Sending the file
+    /// again after a failed transfer picks up from where the peer had got to.
+    ///
+    /// The peer gets to accept or refuse the file first, see `Event::FileOffered`. If it refuses
+    /// it, `Event::FileTransferFailed` is fired.
+    ///
+    /// The file is hashed and opened on the calling thread. Fails if it can't be read.
+    pub fn send_file(&self, peer: Peer, path: PathBuf) -> R<FileHash> {
+        let (len, hash) = transfer::hash_file(&path)?;
+        let file = File::open(&path)?;
+        self.el
+            .post(move |ctx| transfer::send_file(ctx, peer, file, len, hash));
+        Ok(hash)
+    }
+
+    /// Accept the file the peer offered in `Event::FileOffered`, having it sent to us.
+    pub fn accept_file(&self, peer_addr: SocketAddr, hash: FileHash) {
+        self.el
+            .post(move |ctx| transfer::accept_file(ctx, peer_addr, hash));
+    }
+
+    /// Refuse the file the peer offered in `Event::FileOffered`. Also drops what we have of a file
+    /// from the peer whose transfer was cut short, so it no longer counts towards
+    /// `Config::max_incoming_files`.
+    pub fn refuse_file(&self, peer_addr: SocketAddr, hash: FileHash) {
+        self.el
+            .post(move |ctx| transfer::refuse_file(ctx, peer_addr, hash));
+    }
+
+    /// Send message to peer, giving up on it if it's still waiting for the connection to the peer
+    /// to be established once `expiry` has passed.
+    ///
+    /// The expiry counts from this call. Messages given up on are reported back via
+    /// `Event::UnsentUserMessage` with `UnsentReason::Expired`. Otherwise this behaves exactly
+    /// like `send`.
+    pub fn send_with_expiry(&self, peer: Peer, msg: bytes::Bytes, expiry: Duration) {
+        let expires_at = self.clock.now() + expiry;
+        self.send_wire_msg(peer, WireMsg::UserMsg(msg), Some(expires_at));
+    }
+
+    /// Send a message of the type handled by the codec registered via `Builder::with_codec`.
+    ///
+    /// The peer receives it decoded in `Event::NewTypedMessage` if it has registered a codec too,
+    /// or as is in `Event::NewMessage` otherwise. Fails if we have no codec or the message isn't
+    /// of its type. Otherwise this behaves exactly like `send`.
+    ///
+    /// Only available with the `codec` feature.
+    #[cfg(feature = "codec")]
+    pub fn send_typed<T: 'static>(&self, peer: Peer, msg: &T) -> R<()> {
+        let codec = self.codec.as_ref().ok_or(Error::NoCodec)?;
+        let msg = codec.encode_any(msg)?;
+        self.send_wire_msg(peer, WireMsg::TypedUserMsg(msg), None);
+        Ok(())
+    }
+
+    /// Send message to peer tagged with the given id.
+    ///
+    /// The peer is handed the id along with the message in `Event::NewMessage` and can refer to it
+    /// when replying (see `send_reply`). Otherwise this behaves exactly like `send`.
+    pub fn send_with_id(&self, peer: Peer, msg: bytes::Bytes, msg_id: u64) {
+        self.send_wire_msg(
+            peer,
+            WireMsg::UserMsgEnvelope {
+                msg,
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                channel: DEFAULT_CHANNEL,
+            },
+            None,
+        );
+    }
+
+    /// Send message to peer tagged with the given id, in reply to its message with the id
+    /// `in_reply_to`.
+    ///
+    /// If the peer sent its message over a bi-directional stream, the reply goes back over that
+    /// stream rather than a new one.
+    pub fn send_reply(&self, peer: Peer, msg: bytes::Bytes, msg_id: u64, in_reply_to: u64) {
+        self.send_wire_msg(
+            peer,
+            WireMsg::UserMsgEnvelope {
+                msg,
+                msg_id: Some(msg_id),
+                in_reply_to: Some(in_reply_to),
+                channel: DEFAULT_CHANNEL,
+            },
+            None,
+        );
+    }
+
+    /// Send message to peer on the given logical channel.
+    ///
+    /// Each channel gets its own streams and takes its own turns writing, so e.g. a bulk transfer
+    /// on one channel doesn't hold up control messages on another, and a channel whose streams
+    /// are blocked by flow control doesn't hold up the rest. The peer is handed the channel along
+    /// with the message in `Event::NewMessage`. Peers only accept the default channel and the ones
+    /// listed in their `Config::channels`, which they announce in their handshake. Messages on
+    /// channels the peer is known not to accept are not sent.
+    pub fn send_on_channel(&self, peer: Peer, msg: bytes::Bytes, channel: u8) {
+        if channel == DEFAULT_CHANNEL {
+            return self.send(peer, msg);
+        }
+        self.send_wire_msg(
+            peer,
+            WireMsg::UserMsgEnvelope {
+                msg,
+                msg_id: None,
+                in_reply_to: None,
+                channel,
+            },
+            None,
+        );
+    }
+
+    /// Channels the peer announced it accepts messages on, besides the default one. `None` if the
+    /// peer hasn't introduced itself via its handshake yet.
+    pub fn peer_channels(&self, peer_addr: SocketAddr) -> R<Option<Vec<u8>>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let channels = ctx.with(|c| {
+                c.connections
+                    .get(&peer_addr)
+                    .map(|conn| conn.peer_channels.clone())
+                    .ok_or(Error::PeerNotConnected(peer_addr))
+            });
+            let _ = tx.send(channels);
+        });
+
+        rx.recv()?
+    }
+
+    /// Ask the node `via` to connect to `target_info`.
+    ///
+    /// This is useful when `target_info` (typically ourselves) can't be reached directly, e.g.
+    /// because it's behind a NAT, but can reach `via`. The outcome is reported with
+    /// `Event::ReverseConnectResult`. `via` only dials nodes it knows already, i.e. which are in
+    /// its bootstrap cache or connected to it, and ignores requests that are too frequent.
+    pub fn request_reverse_connect(&self, via: NodeInfo, target_info: NodeInfo) {
+        self.el.post(move |ctx| {
+            let via_addr = via.peer_addr;
+            communicate::try_write_to_peer(
+                ctx,
+                via.into(),
+                WireMsg::ReverseConnect { target_info },
+            );
+            Self::set_we_contacted_peer(ctx, &via_addr);
+        });
+    }
+
+    /// Ask the connected peer for contacts from its bootstrap cache.
+    ///
+    /// Useful for new nodes to learn about more of the network. The contacts are delivered via
+    /// `Event::ContactsReceived`. Peers ignore requests that are too frequent, and we ignore
+    /// contacts peers send without us asking for them.
+    pub fn request_contacts(&self, peer_addr: SocketAddr) {
+        self.el.post(move |ctx| {
+            ctx.with_mut(|c| {
+                // Peers we are no longer connected to won't answer our earlier requests
+                let connections = &c.connections;
+                c.contacts_requested_from
+                    .retain(|addr| connections.contains_key(addr));
+                let _ = c.contacts_requested_from.insert(peer_addr);
+            });
+            communicate::write_to_peer(ctx, peer_addr, WireMsg::GetContacts);
         });
     }
 
     /// Get our connection info to give to others for them to connect to us
     ///
-    /// Will use hard coded contacts to ask for our endpoint. If no contact is given then we'll
-    /// simply build our connection info by querying the underlying bound socket for our address.
-    /// Note that if such an obtained address is of unspecified category we will ignore that as
-    /// such an address cannot be reached and hence not useful.
+    /// If `Config::external_addr` is given it's used along with our port. Otherwise, if enough of
+    /// the peers which connected to us agree on the address they reached us at, that address is
+    /// used (see `observed_addresses`). Otherwise will use hard coded contacts to ask
+    /// for our endpoint. If no contact is given then we'll simply build our connection info by
+    /// querying the underlying bound socket for our address. Note that if such an obtained
+    /// address is of unspecified category we will ignore that as such an address cannot be
+    /// reached and hence not useful. Whichever way the address is found, `Config::external_port`
+    /// replaces its port if given.
+    ///
+    /// Clients, including outbound-only ones (see `Config::listen`), can't be connected to so this
+    /// errors out for them. See `our_client_info`.
     // FIXME calling this mutliple times concurrently just now could have it hanging as only one tx
     // is registered and that replaces any previous tx registered. Fix by using a vec of txs
-    pub fn our_connection_info(&mut self) -> R<NodeInfo> {
-        if let Some(ref us) = self.us {
-            return Ok(us.clone());
+    pub fn our_connection_info(&self) -> R<NodeInfo> {
+        if self.cfg.our_type == OurType::Client {
+            return Err(Error::OperationNotAllowed);
+        }
+        if let Some(us) = self.cached_our_connection_info()? {
+            return Ok(us);
         }
 
-        let our_addr = match self.query_ip_echo_service() {
+        let our_addr = match self.external_connection_addr()? {
+            Some(addr) => Ok(addr),
+            None => match self.observed_addr_consensus()? {
+                Some(addr) => Ok(addr),
+                None => self.query_ip_echo_service(),
+            },
+        };
+        let our_addr = match our_addr {
             Ok(addr) => addr,
             Err(e @ Error::NoEndpointEchoServerFound) => {
                 let (tx, rx) = mpsc::channel();
-                self.el.post(move || {
-                    let local_addr_res = ctx(|c| c.quic_ep().local_addr());
+                self.el.post(move |ctx| {
+                    let local_addr_res = ctx.with(|c| c.quic_ep().local_addr());
                     unwrap!(tx.send(local_addr_res));
                 });
                 let addr = unwrap!(rx.recv())?;
@@ -214,6 +826,10 @@ impl QuicP2p {
             }
             Err(e) => return Err(e),
         };
+        let our_addr = match self.cfg.external_port {
+            Some(port) => SocketAddr::new(our_addr.ip(), port),
+            None => our_addr,
+        };
 
         let our_cert_der = self.our_certificate_der();
 
@@ -222,16 +838,465 @@ impl QuicP2p {
             peer_cert_der: our_cert_der,
         };
 
-        self.us = Some(us.clone());
+        let us_clone = us.clone();
+        self.el
+            .post(move |ctx| ctx.with_mut(|c| c.our_connection_info = Some(us_clone)));
 
         Ok(us)
     }
 
+    /// Get our connection info without blocking on the network.
+    ///
+    /// If our connection info is already known it's returned straight away. Otherwise it's
+    /// resolved in the background, `None` is returned for now and `Event::OurConnectionInfoReady`
+    /// is fired once it's known.
+    pub fn our_connection_info_nonblocking(&self) -> R<Option<NodeInfo>> {
+        if self.cfg.our_type == OurType::Client {
+            return Err(Error::OperationNotAllowed);
+        }
+        if let Some(us) = self.cached_our_connection_info()? {
+            return Ok(Some(us));
+        }
+
+        self.resolve_our_connection_info()?;
+
+        Ok(None)
+    }
+
+    /// QUIC versions we speak. Peers built with a quinn release speaking none of these can't
+    /// connect to us nor we to them, see `CloseReason::QuicVersionMismatch`.
+    pub fn supported_quic_versions(&self) -> Vec<u32> {
+        SUPPORTED_QUIC_VERSIONS.to_vec()
+    }
+
+    /// Get the information identifying us as a client to the nodes we connect to. Unlike our
+    /// connection info this holds no endpoint as clients can't be connected to.
+    pub fn our_client_info(&self) -> ClientInfo {
+        ClientInfo {
+            peer_cert_der: self.our_certificate_der(),
+        }
+    }
+
+    /// Addresses we might be reached at, most likely first: our configured external address, the
+    /// one the peers which connected to us agree on and then our bound address. If we are bound to
+    /// all the interfaces (0.0.0.0), the addresses of the local interfaces are listed instead of
+    /// the latter, loopback ones last.
+    ///
+    /// These are candidates for the address in our `NodeInfo` when it can't be resolved otherwise,
+    /// e.g. on a multi-homed host without echo services to ask.
+    pub fn candidate_addresses(&self) -> R<Vec<SocketAddr>> {
+        if self.cfg.our_type == OurType::Client {
+            return Err(Error::OperationNotAllowed);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(ctx.with(interfaces::candidate_addrs));
+        });
+
+        rx.recv()?
+    }
+
+    /// Addresses the peers which connected to us reported reaching us at, along with the number of
+    /// peers which reported each, most reported first.
+    ///
+    /// This is how we are seen from the outside, e.g. the public address a NAT maps us to.
+    pub fn observed_addresses(&self) -> R<Vec<(SocketAddr, usize)>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(ctx.with(|c| c.observed_addrs.counts()));
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Forget our connection info and resolve it afresh in the background, e.g. after a suspected
+    /// change of our address. `Event::OurConnectionInfoReady` is fired once done.
+    pub fn refresh_our_connection_info(&self) -> R<()> {
+        self.el
+            .post(|ctx| ctx.with_mut(|c| c.our_connection_info = None));
+        self.resolve_our_connection_info()
+    }
+
+    /// Rebind our endpoint to the given port, or to a random one if none is given.
+    ///
+    /// This is useful when a firewall rule or a port collision forces a port change at runtime.
+    /// Our bootstrap cache and configuration are retained. Existing connections are closed and the
+    /// nodes we were connected to are connected to afresh from the new endpoint, so expect the
+    /// corresponding `ConnectionFailure` and `ConnectedTo` events.
+    pub fn restart_listener(&self, new_port: Option<u16>) -> R<()> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(listener::restart(ctx, new_port));
+        });
+
+        rx.recv()?
+    }
+
+    /// Replace our certificate with a freshly generated one, returning it.
+    ///
+    /// Long-lived nodes can use this to rotate their keys without losing their identity: the new
+    /// certificate is announced to the connected peers signed with the key of the old one, and
+    /// they update their bootstrap caches accordingly (see `Event::PeerCertificateRotated`).
+    /// Existing connections are kept alive while new ones are made with the new certificate.
+    /// Persist the returned certificate (`Config::our_complete_cert`) to keep using it across
+    /// restarts.
+    pub fn rotate_certificate(&self) -> R<SerialisableCertificate> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(cert_rotation::rotate(ctx));
+        });
+
+        rx.recv()?
+    }
+
+    /// Check our own endpoint end to end by connecting to our listener over the loopback.
+    ///
+    /// This exercises binding, the certificate and QUIC handshake, and the stream path, so
+    /// operators can diagnose a broken deployment before joining a network. Blocks until the test
+    /// completes or times out.
+    pub fn self_test(&self) -> R<SelfTestReport> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| self_test::run(ctx, tx));
+
+        Ok(rx.recv()?)
+    }
+
+    /// Stop accepting new incoming connections, e.g. during overload or maintenance.
+    ///
+    /// Existing connections are kept. Nodes we connect to can still connect back to us, as that
+    /// is needed to complete the connection to them.
+    pub fn pause_accepting(&self) {
+        self.el
+            .post(|ctx| ctx.with_mut(|c| c.is_accepting_incoming = false));
+    }
+
+    /// Resume accepting new incoming connections after `pause_accepting`.
+    pub fn resume_accepting(&self) {
+        self.el
+            .post(|ctx| ctx.with_mut(|c| c.is_accepting_incoming = true));
+    }
+
+    /// Switch to the given traffic profile, e.g. to `TrafficProfile::Background` when the user
+    /// moves our application to the background. Settings given explicitly in our `Config` are
+    /// kept.
+    ///
+    /// The send rate takes effect straight away. The stream windows and keep-alive interval apply
+    /// to the connections made from now on, and to the ones made to us only once the listener is
+    /// restarted (see `restart_listener`).
+    pub fn reconfigure(&self, traffic_profile: TrafficProfile) {
+        let settings = TrafficSettings::new(&self.cfg, traffic_profile);
+        self.el.post(move |ctx| ctx.with_mut(|c| settings.apply(c)));
+    }
+
+    /// New receiver of every event fired from now on, alongside the sender given to the builder,
+    /// e.g. for another subsystem of the application. Each receiver has a queue of its own of
+    /// `Config::event_subscriber_queue_len`. A receiver not keeping up never holds us up: the
+    /// events it has no room for are dropped for it and it's told how many via
+    /// `Event::EventsDropped` once it has room again. Typed messages are only handed to the sender
+    /// given to the builder, as they can't be copied. Dropping the receiver unsubscribes it.
+    pub fn subscribe(&self) -> R<Receiver<Event>> {
+        let queue_len = self
+            .cfg
+            .event_subscriber_queue_len
+            .map_or(DEFAULT_EVENT_SUBSCRIBER_QUEUE_LEN, |len| len as usize);
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let event_rx = ctx.with(|c| c.event_tx.subscribe(queue_len));
+            let _ = tx.send(event_rx);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Snapshot of our current state.
+    pub fn stats(&self) -> R<Stats> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let stats = ctx.with(Stats::new);
+            let _ = tx.send(stats);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Backlogs of the messages from the connected peers, the biggest first. See
+    /// `Config::backpressure`.
+    pub fn read_backlogs(&self) -> R<Vec<ReadBacklog>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let mut backlogs: Vec<_> = ctx.with(|c| {
+                c.connections
+                    .iter()
+                    .map(|(peer_addr, conn)| ReadBacklog::new(*peer_addr, conn))
+                    .collect()
+            });
+            backlogs.sort_by_key(|backlog| {
+                cmp::Reverse((backlog.unread_bytes, backlog.incomplete_reads))
+            });
+            let _ = tx.send(backlogs);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Counters accumulated over all our runs, including the ones before restarts if
+    /// `Config::lifetime_stats_snapshot_sec` is set.
+    pub fn lifetime_stats(&self) -> R<LifetimeStats> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(ctx.with(|c| c.lifetime_stats.current()));
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Dump of our internal state, e.g. to diagnose connections stuck half way through being set
+    /// up without resorting to trace logs.
+    pub fn debug_dump(&self) -> R<DebugSnapshot> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let snapshot = ctx.with(DebugSnapshot::new);
+            let _ = tx.send(snapshot);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Counters and histograms of our traffic since we started, in the Prometheus text exposition
+    /// format. Serving them, e.g. over HTTP for scraping, is up to the caller.
+    ///
+    /// Only available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn render_prometheus(&self) -> String {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let rendered = ctx.with(|c| c.metrics.render());
+            unwrap!(tx.send(rendered));
+        });
+
+        unwrap!(rx.recv())
+    }
+
+    /// Snapshot of the peer knowledge worth carrying over a process restart. Pass it to
+    /// `Builder::with_state` on the next start.
+    pub fn export_state(&self) -> R<StateSnapshot> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let state = ctx.with(StateSnapshot::new);
+            let _ = tx.send(state);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Inject a fault to exercise failure paths in tests.
+    ///
+    /// Only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn inject_fault(&self, fault: FaultSpec) {
+        self.el.post(move |ctx| fault_injection::inject(ctx, fault));
+    }
+
+    /// Parameters negotiated for the established connection with the peer, for diagnostics.
+    pub fn connection_details(&self, peer_addr: SocketAddr) -> R<ConnectionDetails> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let details = ctx.with(|c| {
+                c.connections
+                    .get(&peer_addr)
+                    .and_then(ConnectionDetails::new)
+                    .ok_or(Error::PeerNotConnected(peer_addr))
+            });
+            let _ = tx.send(details);
+        });
+
+        rx.recv()?
+    }
+
+    /// Estimate of how far the clock of the peer is ahead of ours in milliseconds, negative if
+    /// it's behind, e.g. to line up logs across peers. It's first taken from the peer's handshake,
+    /// which leaves it off by the time that took to get here, and refined with every health check
+    /// round trip, e.g. of the liveness watchdog (see `Config::unresponsive_peer_msec`). `None`
+    /// if we have had neither yet, e.g. for a node we are a client of. Fails if there's no
+    /// connection with the peer.
+    pub fn peer_clock_skew(&self, peer_addr: SocketAddr) -> R<Option<i64>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let skew = ctx.with(|c| {
+                c.connections
+                    .get(&peer_addr)
+                    .map(|conn| conn.clock_skew_msec)
+                    .ok_or(Error::PeerNotConnected(peer_addr))
+            });
+            let _ = tx.send(skew);
+        });
+
+        rx.recv()?
+    }
+
+    /// Attach state of our own to the connection with the peer, replacing any attached before.
+    ///
+    /// It's dropped along with the connection, so unlike a map of peers kept alongside it can't
+    /// drift from our connection table. Fails if there's no connection with the peer.
+    pub fn set_peer_context(&self, peer_addr: SocketAddr, context: Box<dyn Any + Send>) -> R<()> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let res = ctx.with_mut(|c| {
+                let conn = c
+                    .connections
+                    .get_mut(&peer_addr)
+                    .ok_or(Error::PeerNotConnected(peer_addr))?;
+                conn.user_context = Some(context);
+                Ok(())
+            });
+            let _ = tx.send(res);
+        });
+
+        rx.recv()?
+    }
+
+    /// Run `f` on the state attached to the connection with the peer via `set_peer_context`, e.g.
+    /// while handling an event from the peer. `f` gets `None` if nothing is attached and this fails
+    /// if there's no connection with the peer.
+    ///
+    /// `f` runs on the event loop, so it must not call back into `QuicP2p`.
+    pub fn with_peer_context<F, T>(&self, peer_addr: SocketAddr, f: F) -> R<T>
+    where
+        F: FnOnce(Option<&mut (dyn Any + Send)>) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let res = ctx.with_mut(|c| {
+                let conn = c
+                    .connections
+                    .get_mut(&peer_addr)
+                    .ok_or(Error::PeerNotConnected(peer_addr))?;
+                Ok(f(conn.user_context.as_mut().map(|context| &mut **context)))
+            });
+            let _ = tx.send(res);
+        });
+
+        rx.recv()?
+    }
+
+    /// Peers whose connects are queued due to `Config::max_concurrent_connects`, in the order they
+    /// will be started.
+    pub fn pending_connects(&self) -> R<Vec<SocketAddr>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let pending = ctx.with(|c| {
+                c.queued_connects
+                    .iter()
+                    .map(|connect| connect.peer_addr())
+                    .collect()
+            });
+            let _ = tx.send(pending);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Nodes we are currently connected to.
+    pub fn connected_nodes(&self) -> R<Vec<NodeInfo>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let nodes = ctx.with(|c| {
+                c.connections
+                    .iter()
+                    .filter(|(_, conn)| {
+                        conn.is_connected() && conn.peer_kind == Some(PeerKind::Node)
+                    })
+                    .filter_map(|(peer_addr, conn)| match conn.to_peer {
+                        ToPeer::Established {
+                            ref peer_cert_der, ..
+                        } => Some(NodeInfo {
+                            peer_addr: *peer_addr,
+                            peer_cert_der: peer_cert_der.clone(),
+                        }),
+                        _ => None,
+                    })
+                    .collect()
+            });
+            let _ = tx.send(nodes);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Clients currently connected to us.
+    pub fn connected_clients(&self) -> R<Vec<SocketAddr>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let clients = ctx.with(|c| {
+                c.connections
+                    .iter()
+                    .filter(|(_, conn)| {
+                        conn.is_connected() && conn.peer_kind == Some(PeerKind::Client)
+                    })
+                    .map(|(peer_addr, _)| *peer_addr)
+                    .collect()
+            });
+            let _ = tx.send(clients);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Penalty points the peer has accumulated for misbehaving. Zero for well-behaved peers.
+    pub fn peer_score(&self, peer_addr: SocketAddr) -> R<u32> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let score = ctx.with(|c| c.reputation.score(&peer_addr));
+            let _ = tx.send(score);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Retrieves current node bootstrap cache, most promising peers to bootstrap off first.
+    ///
+    /// Peers are ranked by the success rate of our connects to them and then by how quickly those
+    /// succeeded. This is the order in which they are tried when bootstrapping.
+    pub fn bootstrap_cache_ranked(&self) -> R<Vec<RankedPeer>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let ranked = ctx.with(|c| c.bootstrap_cache.ranked());
+            let _ = tx.send(ranked);
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    /// Cached peers, e.g. to seed the cache of a new node with via `import_bootstrap_cache`.
+    pub fn export_bootstrap_cache(&self) -> R<Vec<NodeInfo>> {
+        self.bootstrap_cache()
+    }
+
+    /// Merge the given peers, e.g. contacts harvested elsewhere, into our bootstrap cache as per
+    /// the strategy. Peers cached already are never duplicated and hard coded contacts are left
+    /// out. The cache is synced to disk straight away. Returns how many peers were added or had
+    /// their certificate replaced.
+    pub fn import_bootstrap_cache(
+        &self,
+        peers: Vec<NodeInfo>,
+        strategy: MergeStrategy,
+    ) -> R<usize> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let imported = ctx.with_mut(|c| c.bootstrap_cache.import(peers, strategy));
+            let _ = tx.send(imported);
+        });
+
+        Ok(rx.recv()?)
+    }
+
     /// Retrieves current node bootstrap cache.
-    pub fn bootstrap_cache(&mut self) -> R<Vec<NodeInfo>> {
+    pub fn bootstrap_cache(&self) -> R<Vec<NodeInfo>> {
         let (tx, rx) = mpsc::channel();
-        self.el.post(move || {
-            let cache = ctx(|c| c.bootstrap_cache.peers().iter().cloned().collect());
+        self.el.post(move |ctx| {
+            let cache = ctx.with(|c| c.bootstrap_cache.peers().iter().cloned().collect());
             let _ = tx.send(cache);
         });
         let cache = rx.recv()?;
@@ -239,25 +1304,20 @@ impl QuicP2p {
         Ok(cache)
     }
 
-    fn new(event_tx: Sender<Event>) -> R<Self> {
-        Ok(Self::with_config(
-            event_tx,
-            Config::read_or_construct_default(None)?,
-        ))
-    }
-
-    fn with_config(event_tx: Sender<Event>, cfg: Config) -> Self {
-        let el = EventLoop::spawn();
+    fn with_config(cfg: Config, el: Arc<EventLoop>) -> Self {
         Self {
-            event_tx,
-            cfg,
-            us: None,
-            el,
+            cfg: Arc::new(cfg),
+            el: Arc::new(Instance::new(el)),
+            clock: Arc::new(SystemClock),
+            transport: Arc::new(UdpTransport),
+            resolver: Arc::new(SystemResolver),
+            #[cfg(feature = "codec")]
+            codec: None,
         }
     }
 
     /// Must be called only once. There can only be one context per `QuicP2p` instance.
-    fn activate(&mut self) -> R<()> {
+    fn activate(&self, event_tx: EventTx) -> R<()> {
         let (port, is_user_supplied) = self
             .cfg
             .port
@@ -265,124 +1325,409 @@ impl QuicP2p {
             .unwrap_or((DEFAULT_PORT_TO_TRY, false));
         let ip = self
             .cfg
-            .ip
+            .bind_addr
             .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::UNSPECIFIED));
         let max_msg_size_allowed = self
             .cfg
             .max_msg_size_allowed
             .map(|size| size as usize)
             .unwrap_or(DEFAULT_MAX_ALLOWED_MSG_SIZE);
+        let per_peer_buffer_limit = self.cfg.per_peer_buffer_limit.map(|limit| limit as usize);
         let idle_timeout_msec = self
             .cfg
             .idle_timeout_msec
             .unwrap_or(peer_config::DEFAULT_IDLE_TIMEOUT_MSEC);
-        let keep_alive_interval_msec = self
-            .cfg
-            .keep_alive_interval_msec
-            .unwrap_or(peer_config::DEFAULT_KEEP_ALIVE_INTERVAL_MSEC);
+        let traffic_settings = TrafficSettings::new(&self.cfg, self.cfg.traffic_profile);
+        let keep_alive_interval_msec = traffic_settings.keep_alive_interval_msec;
+        let stream_receive_window = traffic_settings.stream_receive_window;
+        let connection_receive_window = traffic_settings.connection_receive_window;
         let our_type = self.cfg.our_type;
-        let hard_coded_contacts = self.cfg.hard_coded_contacts.clone();
-
-        let tx = self.event_tx.clone();
-
-        let ((key, cert), our_complete_cert) = {
-            let our_complete_cert = self
-                .cfg
-                .our_complete_cert
-                .clone()
-                .unwrap_or_else(Default::default);
-            (
-                our_complete_cert.obtain_priv_key_and_cert(),
-                our_complete_cert,
-            )
+        let listen = self.cfg.listen;
+        let mutual_tls = self.cfg.mutual_tls;
+        let server_name = self
+            .cfg
+            .server_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SERVER_NAME.to_string());
+        let external_addr = self.cfg.external_addr;
+        let external_port = self.cfg.external_port;
+        let network_id = self.cfg.network_id.clone();
+        let auto_reconnect = self.cfg.auto_reconnect;
+        let dial_backoff = self.cfg.dial_backoff;
+        let reputation = self.cfg.reputation;
+        let max_contacts_to_share = self
+            .cfg
+            .max_contacts_to_share
+            .map(|max| max as usize)
+            .unwrap_or(DEFAULT_MAX_CONTACTS_TO_SHARE);
+        let contacts_request_interval_sec = self
+            .cfg
+            .contacts_request_interval_sec
+            .unwrap_or(DEFAULT_CONTACTS_REQUEST_INTERVAL_SEC);
+        let reverse_connect_interval_sec = self
+            .cfg
+            .reverse_connect_interval_sec
+            .unwrap_or(DEFAULT_REVERSE_CONNECT_INTERVAL_SEC);
+        let max_concurrent_connects = self.cfg.max_concurrent_connects.map(|max| max as usize);
+        let max_total_connections = self.cfg.max_total_connections.map(|max| max as usize);
+        let admission_policy = self.cfg.admission_policy;
+        let bootstrap_member_budget = self
+            .cfg
+            .bootstrap_member_budget_msec
+            .map(Duration::from_millis);
+        let bootstrap_grace = Duration::from_millis(self.cfg.bootstrap_grace_msec.unwrap_or(0));
+        let send_over_incoming_connections = self.cfg.send_over_incoming_connections;
+        let cache_health_check = self.cfg.cache_health_check;
+        let stale_conn_reaper = self.cfg.stale_conn_reaper;
+        let ordered_delivery = self.cfg.ordered_delivery;
+        let dedup = self.cfg.dedup;
+        let unresponsive_peer = self.cfg.unresponsive_peer_msec.map(Duration::from_millis);
+        let backpressure = self.cfg.backpressure;
+        let cert_expiry_warning = Duration::from_secs(
+            self.cfg
+                .cert_expiry_warning_sec
+                .unwrap_or(DEFAULT_CERT_EXPIRY_WARNING_SEC),
+        );
+        let auto_rotate_cert = self.cfg.auto_rotate_cert;
+        let lifetime_stats_snapshot_sec = self.cfg.lifetime_stats_snapshot_sec;
+        let bootstrap_cache_update_debounce = Duration::from_millis(
+            self.cfg
+                .bootstrap_cache_update_debounce_msec
+                .unwrap_or(DEFAULT_BOOTSTRAP_CACHE_UPDATE_DEBOUNCE_MSEC),
+        );
+        let send_quantum_bytes = traffic_settings.send_quantum_bytes;
+        let max_send_rate = traffic_settings.max_send_rate;
+        let channels = self.cfg.channels.clone();
+        let alpn_protocols = self.cfg.alpn_protocols.clone();
+        let socket_options = self.cfg.socket_options;
+        let client_send_grace = self.cfg.client_send_grace_msec.map(Duration::from_millis);
+        let read_timeout = match self
+            .cfg
+            .read_timeout_msec
+            .unwrap_or(DEFAULT_READ_TIMEOUT_MSEC)
+        {
+            0 => None,
+            msec => Some(Duration::from_millis(msec)),
         };
-        let bootstrap_cache = BootstrapCache::new(hard_coded_contacts, None)?;
+        let write_timeout = match self
+            .cfg
+            .write_timeout_msec
+            .unwrap_or(DEFAULT_WRITE_TIMEOUT_MSEC)
+        {
+            0 => None,
+            msec => Some(Duration::from_millis(msec)),
+        };
+        let handshake_timeout = match self
+            .cfg
+            .handshake_timeout_msec
+            .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_MSEC)
+        {
+            0 => None,
+            msec => Some(Duration::from_millis(msec)),
+        };
+        let max_incomplete_reads = self
+            .cfg
+            .max_incomplete_reads
+            .map(|max| max as usize)
+            .unwrap_or(DEFAULT_MAX_INCOMPLETE_READS);
+        let data_lane_budget = self
+            .cfg
+            .data_lane_budget
+            .map(|budget| budget as usize)
+            .unwrap_or(DEFAULT_DATA_LANE_BUDGET);
+        let (hard_coded_contacts, host_contacts) =
+            dns::split_contacts(&self.cfg.hard_coded_contacts);
+        let dns_cache_ttl = Duration::from_secs(
+            self.cfg
+                .dns_cache_ttl_sec
+                .unwrap_or(DEFAULT_DNS_CACHE_TTL_SEC),
+        );
+        let cert_params = self.cfg.cert_params.clone();
+        let echo_service = self.cfg.echo_service;
+        let strict = self.cfg.strict;
+        let max_file_len = self.cfg.max_file_len.unwrap_or(DEFAULT_MAX_FILE_LEN);
+        let max_incoming_files = self
+            .cfg
+            .max_incoming_files
+            .map(|max| max as usize)
+            .unwrap_or(DEFAULT_MAX_INCOMING_FILES);
+
+        let (udp, effective_socket_options) = if !is_user_supplied {
+            match self.transport.bind(ip, port, &socket_options) {
+                Ok(bound) => bound,
+                Err(e) => {
+                    info!(
+                        "Failed to bind to port: {} - Error: {:?} - {}. Trying random port.",
+                        DEFAULT_PORT_TO_TRY, e, e
+                    );
+                    self.transport.bind(ip, 0, &socket_options)?
+                }
+            }
+        } else if self.cfg.port_fallback {
+            socket::bind_with_fallback(&*self.transport, ip, port, &socket_options)?
+        } else {
+            self.transport.bind(ip, port, &socket_options)?
+        };
+
+        let clock = self.clock.clone();
+        let transport = self.transport.clone();
+        let host_contacts = HostContacts::new(host_contacts, self.resolver.clone(), dns_cache_ttl);
 
-        self.el.post(move || {
+        let our_complete_cert =
+            self.cfg.our_complete_cert.clone().unwrap_or_else(|| {
+                SerialisableCertificate::generate_for(&server_name, &cert_params)
+            });
+        let server_cert = ServerCert::new(&our_complete_cert, server_name.clone())?;
+        let cache_dirs = self
+            .cfg
+            .bootstrap_cache_dir
+            .clone()
+            .map(|dir| Dirs::Overide(OverRide::with_path(dir)));
+        // Only a certificate supplied by the user identifies us across restarts
+        let cache_namespace = self
+            .cfg
+            .our_complete_cert
+            .as_ref()
+            .map(|cert| utils::cert_fingerprint(&cert.cert_der));
+        let bootstrap_cache = BootstrapCache::with_namespace(
+            hard_coded_contacts,
+            cache_dirs.as_ref(),
+            cache_namespace.as_ref().map(String::as_str),
+        )?;
+        let transfers = transfer::Transfers::new(max_file_len, max_incoming_files)?;
+        let lifetime_stats = LifetimeStatsTracker::load(
+            lifetime_stats_snapshot_sec.map(|_| stats::snapshot_path(bootstrap_cache.path())),
+            self.clock.clone(),
+        );
+        let settings = ContextSettings {
+            event_tx,
+            our_complete_cert,
+            server_cert,
+            max_msg_size_allowed,
+            per_peer_buffer_limit,
+            idle_timeout_msec,
+            keep_alive_interval_msec,
+            stream_receive_window,
+            connection_receive_window,
+            our_type,
+            listen,
+            mutual_tls,
+            server_name,
+            external_addr,
+            external_port,
+            network_id,
+            auto_reconnect,
+            dial_backoff,
+            reputation,
+            max_contacts_to_share,
+            contacts_request_interval_sec,
+            reverse_connect_interval_sec,
+            max_concurrent_connects,
+            max_total_connections,
+            admission_policy,
+            bootstrap_member_budget,
+            bootstrap_grace,
+            send_over_incoming_connections,
+            send_quantum_bytes,
+            max_send_rate,
+            channels,
+            read_timeout,
+            write_timeout,
+            handshake_timeout,
+            max_incomplete_reads,
+            data_lane_budget,
+            alpn_protocols,
+            client_send_grace,
+            socket_options,
+            effective_socket_options,
+            cert_params,
+            echo_service,
+            strict,
+            transfers,
+            ordered_delivery,
+            bootstrap_cache,
+            host_contacts,
+            lifetime_stats,
+            clock,
+            transport,
+        };
+        let instance_id = self.el.id();
+
+        self.el.post_initialise(move |instances| {
             let our_cfg = unwrap!(peer_config::new_our_cfg(
                 idle_timeout_msec,
                 keep_alive_interval_msec,
-                cert,
-                key
+                stream_receive_window,
+                connection_receive_window,
+                &settings.alpn_protocols,
+                &settings.server_cert,
+                mutual_tls
             ));
 
             let mut ep_builder = quinn::Endpoint::builder();
-            ep_builder.listen(our_cfg);
-            let (dr, ep, incoming_connections) = {
-                match UdpSocket::bind(&(ip, port)) {
-                    Ok(udp) => unwrap!(ep_builder.with_socket(udp)),
-                    Err(e) => {
-                        if is_user_supplied {
-                            panic!(
-                                "Could not bind to the user supplied port: {}! Error: {:?}- {}",
-                                port, e, e
-                            );
-                        }
-                        info!(
-                            "Failed to bind to port: {} - Error: {:?} - {}. Trying random port.",
-                            DEFAULT_PORT_TO_TRY, e, e
-                        );
-                        unwrap!(ep_builder.bind(&(ip, 0)))
-                    }
-                }
-            };
+            if listen {
+                ep_builder.listen(our_cfg);
+            }
+            let (dr, ep, incoming_connections) = unwrap!(ep_builder.with_socket(udp));
 
-            let ctx = Context::new(
-                tx,
-                our_complete_cert,
-                max_msg_size_allowed,
-                idle_timeout_msec,
-                keep_alive_interval_msec,
-                our_type,
-                bootstrap_cache,
-                ep,
-            );
-            initialise_ctx(ctx);
+            let context = Context::new(settings, ep);
+            let ctx = instances.initialise(instance_id, context);
 
-            current_thread::spawn(dr.map_err(|e| warn!("Error in quinn Driver: {:?}", e)));
+            ctx.spawn_driver(dr, |_, e| warn!("Error in quinn Driver: {:?}", e));
+
+            if our_type != OurType::Client && listen {
+                listener::listen(&ctx, incoming_connections);
+            }
+
+            if let Some(cache_health_check) = cache_health_check {
+                cache_health::start(&ctx, cache_health_check);
+            }
+
+            if let Some(stale_conn_reaper) = stale_conn_reaper {
+                reaper::start(&ctx, stale_conn_reaper);
+            }
 
-            if our_type != OurType::Client {
-                listener::listen(incoming_connections);
+            if let Some(unresponsive_peer) = unresponsive_peer {
+                liveness::start(&ctx, unresponsive_peer);
             }
+
+            if let Some(backpressure) = backpressure {
+                backpressure::start(&ctx, backpressure);
+            }
+
+            if let Some(dedup) = dedup {
+                dedup::start(&ctx, dedup);
+            }
+
+            if our_type == OurType::Node {
+                cert_expiry::start(&ctx, cert_expiry_warning, auto_rotate_cert);
+            }
+
+            if let Some(interval_sec) = lifetime_stats_snapshot_sec {
+                stats::start_snapshots(&ctx, interval_sec);
+            }
+
+            bootstrap_cache::start_update_events(&ctx, bootstrap_cache_update_debounce);
+        });
+
+        Ok(())
+    }
+
+    fn cached_our_connection_info(&self) -> R<Option<NodeInfo>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let us = ctx.with(|c| c.our_connection_info.clone());
+            let _ = tx.send(us);
         });
 
+        Ok(rx.recv()?)
+    }
+
+    fn observed_addr_consensus(&self) -> R<Option<SocketAddr>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(ctx.with(|c| c.observed_addrs.consensus()));
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    fn external_connection_addr(&self) -> R<Option<SocketAddr>> {
+        if self.cfg.external_addr.is_none() {
+            return Ok(None);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move |ctx| {
+            let _ = tx.send(ctx.with(|c| c.external_connection_addr()));
+        });
+
+        Ok(rx.recv()?)
+    }
+
+    fn resolve_our_connection_info(&self) -> R<()> {
+        let is_bound_to_unspecified = self.cfg.bind_addr.map_or(true, |ip| ip.is_unspecified());
+        if self.cfg.hard_coded_contacts.is_empty()
+            && self.cfg.external_addr.is_none()
+            && is_bound_to_unspecified
+            && self.observed_addr_consensus()?.is_none()
+        {
+            return Err(Error::NoEndpointEchoServerFound);
+        }
+
+        self.el.post(communicate::resolve_our_connection_info);
+
         Ok(())
     }
 
-    fn our_certificate_der(&mut self) -> Vec<u8> {
+    fn our_certificate_der(&self) -> Vec<u8> {
         let (tx, rx) = mpsc::channel();
 
-        self.el.post(move || {
-            let our_cert_der = ctx(|c| c.our_complete_cert.cert_der.clone());
+        self.el.post(move |ctx| {
+            let our_cert_der = ctx.with(|c| c.our_complete_cert.cert_der.clone());
             unwrap!(tx.send(our_cert_der));
         });
 
         unwrap!(rx.recv())
     }
 
-    fn query_ip_echo_service(&mut self) -> R<SocketAddr> {
+    fn query_ip_echo_service(&self) -> R<SocketAddr> {
         // FIXME: For the purpose of simplicity we are asking only one peer just now. In production
         // ask multiple until one answers OR we exhaust the list
-        let node_info = if let Some(node_info) = self.cfg.hard_coded_contacts.iter().next() {
-            node_info.clone()
-        } else {
-            return Err(Error::NoEndpointEchoServerFound);
+        let contact = match self.cfg.hard_coded_contacts.iter().next() {
+            Some(contact) => contact,
+            None => return Err(Error::NoEndpointEchoServerFound),
+        };
+        let node_info = match contact.peer_addr {
+            NodeAddr::Socket(peer_addr) => NodeInfo {
+                peer_addr,
+                peer_cert_der: contact.peer_cert_der.clone(),
+            },
+            // We block for the answer anyway
+            NodeAddr::Host { ref host, port } => NodeInfo {
+                peer_addr: *self
+                    .resolver
+                    .resolve(host, port)?
+                    .first()
+                    .ok_or(Error::NoEndpointEchoServerFound)?,
+                peer_cert_der: contact.peer_cert_der.clone(),
+            },
         };
         let echo_server = Peer::Node { node_info };
 
         let (tx, rx) = mpsc::channel();
 
-        self.el.post(move || {
-            ctx_mut(|c| c.our_ext_addr_tx = Some(tx));
-            communicate::try_write_to_peer(echo_server, WireMsg::EndpointEchoReq)
+        self.el.post(move |ctx| {
+            ctx.with_mut(|c| c.our_ext_addr_tx = Some(tx));
+            communicate::try_write_to_peer(ctx, echo_server, WireMsg::EndpointEchoReq)
         });
 
         Ok(unwrap!(rx.recv()))
     }
 
+    fn send_wire_msg(&self, peer: Peer, wire_msg: WireMsg, expires_at: Option<Instant>) {
+        self.el.post(move |ctx| {
+            let peer_addr = peer.peer_addr();
+            let channel = wire_msg.channel();
+            let is_accepted = ctx.with(|c| {
+                c.connections
+                    .get(&peer_addr)
+                    .map_or(true, |conn| conn.accepts_channel(channel))
+            });
+            if !is_accepted {
+                return info!(
+                    "Not sending message to peer {} on channel {} it doesn't accept",
+                    peer_addr, channel
+                );
+            }
+            communicate::try_write_to_peer_with_expiry(ctx, peer, wire_msg, expires_at);
+            Self::set_we_contacted_peer(ctx, &peer_addr);
+        });
+    }
+
     #[inline]
-    fn set_we_contacted_peer(peer_addr: &SocketAddr) {
-        ctx_mut(|c| {
+    fn set_we_contacted_peer(ctx: &Ctx, peer_addr: &SocketAddr) {
+        ctx.with_mut(|c| {
             if let Some(conn) = c.connections.get_mut(peer_addr) {
                 conn.we_contacted_peer = true;
             }
@@ -404,7 +1749,7 @@ mod tests {
 
     #[test]
     fn echo_service() {
-        let (mut qp2p0, _rx) = new_random_qp2p_for_unit_test(false, Default::default());
+        let (qp2p0, _rx) = new_random_qp2p_for_unit_test(false, Default::default());
 
         // Confirm there's no echo service available for us
         match qp2p0.query_ip_echo_service() {
@@ -417,7 +1762,7 @@ mod tests {
         let qp2p0_info = unwrap!(qp2p0.our_connection_info());
         let qp2p0_port = qp2p0_info.peer_addr.port();
 
-        let (mut qp2p1, rx1) = {
+        let (qp2p1, rx1) = {
             let mut hcc: HashSet<_> = Default::default();
             assert!(hcc.insert(qp2p0_info.clone()));
             new_random_qp2p_for_unit_test(true, hcc)
@@ -430,7 +1775,7 @@ mod tests {
         assert_ne!(qp2p0_port, qp2p1_port);
         assert_eq!(qp2p1_port, qp2p1_info.peer_addr.port());
 
-        let (mut qp2p2, _rx) = {
+        let (qp2p2, _rx) = {
             let mut hcc: HashSet<_> = Default::default();
             assert!(hcc.insert(qp2p0_info.clone()));
             new_random_qp2p_for_unit_test(true, hcc)
@@ -445,7 +1790,7 @@ mod tests {
         qp2p2.send(qp2p1_info.into(), data.clone());
 
         match unwrap!(rx1.recv()) {
-            Event::ConnectedTo { peer } => assert_eq!(
+            Event::ConnectedTo { peer, .. } => assert_eq!(
                 peer,
                 Peer::Node {
                     node_info: qp2p2_info.clone()
@@ -454,8 +1799,13 @@ mod tests {
             x => panic!("Received unexpected event: {:?}", x),
         }
         match unwrap!(rx1.recv()) {
-            Event::NewMessage { peer_addr, msg } => {
-                assert_eq!(peer_addr, qp2p2_info.peer_addr);
+            Event::NewMessage { peer, msg, .. } => {
+                assert_eq!(
+                    peer,
+                    Peer::Node {
+                        node_info: qp2p2_info.clone()
+                    }
+                );
                 assert_eq!(msg, data);
             }
             x => panic!("Received unexpected event: {:?}", x),
@@ -464,10 +1814,10 @@ mod tests {
 
     #[test]
     fn multistreaming_and_no_head_of_queue_blocking() {
-        let (mut qp2p0, rx0) = new_random_qp2p_for_unit_test(false, Default::default());
+        let (qp2p0, rx0) = new_random_qp2p_for_unit_test(false, Default::default());
         let qp2p0_info = unwrap!(qp2p0.our_connection_info());
 
-        let (mut qp2p1, rx1) = {
+        let (qp2p1, rx1) = {
             let mut hcc: HashSet<_> = Default::default();
             assert!(hcc.insert(qp2p0_info.clone()));
             new_random_qp2p_for_unit_test(true, hcc)
@@ -497,6 +1847,7 @@ mod tests {
                 match rx0.recv() {
                     Ok(Event::ConnectedTo {
                         peer: Peer::Node { node_info },
+                        ..
                     }) => assert_eq!(node_info.peer_addr, qp2p1_addr),
                     Ok(x) => panic!("Expected Event::ConnectedTo - got {:?}", x),
                     Err(e) => panic!(
@@ -506,7 +1857,8 @@ mod tests {
                 };
                 for i in 0..3 {
                     match rx0.recv() {
-                        Ok(Event::NewMessage { peer_addr, msg }) => {
+                        Ok(Event::NewMessage { peer, msg, .. }) => {
+                            let peer_addr = peer.peer_addr();
                             assert_eq!(peer_addr, qp2p1_addr);
                             if i != 2 {
                                 assert!(
@@ -533,6 +1885,7 @@ mod tests {
                 match rx1.recv() {
                     Ok(Event::ConnectedTo {
                         peer: Peer::Node { node_info },
+                        ..
                     }) => assert_eq!(node_info.peer_addr, qp2p0_addr),
                     Ok(x) => panic!("Expected Event::ConnectedTo - got {:?}", x),
                     Err(e) => panic!(
@@ -541,8 +1894,8 @@ mod tests {
                     ),
                 };
                 match rx1.recv() {
-                    Ok(Event::NewMessage { peer_addr, msg }) => {
-                        assert_eq!(peer_addr, qp2p0_addr);
+                    Ok(Event::NewMessage { peer, msg, .. }) => {
+                        assert_eq!(peer.peer_addr(), qp2p0_addr);
                         assert_eq!(msg, msg_to_qp2p1_clone);
                     }
                     Ok(x) => panic!("Expected Event::NewMessage - got {:?}", x),
@@ -570,11 +1923,11 @@ mod tests {
 
     #[test]
     fn connect_to_marks_that_we_attempted_to_contact_the_peer() {
-        let (mut peer1, _) = new_random_qp2p_for_unit_test(false, Default::default());
+        let (peer1, _) = new_random_qp2p_for_unit_test(false, Default::default());
         let peer1_conn_info = unwrap!(peer1.our_connection_info());
         let peer1_addr = peer1_conn_info.peer_addr;
 
-        let (mut peer2, ev_rx) = new_random_qp2p_for_unit_test(false, Default::default());
+        let (peer2, ev_rx) = new_random_qp2p_for_unit_test(false, Default::default());
         peer2.connect_to(peer1_conn_info);
 
         for event in ev_rx.iter() {
@@ -584,8 +1937,8 @@ mod tests {
         }
 
         let (tx, rx) = mpsc::channel();
-        peer2.el.post(move || {
-            let contacted = ctx(|c| unwrap!(c.connections.get(&peer1_addr)).we_contacted_peer);
+        peer2.el.post(move |ctx| {
+            let contacted = ctx.with(|c| unwrap!(c.connections.get(&peer1_addr)).we_contacted_peer);
             let _ = tx.send(contacted);
         });
         let we_contacted_peer = unwrap!(rx.recv());
@@ -593,6 +1946,170 @@ mod tests {
         assert!(we_contacted_peer);
     }
 
+    #[test]
+    fn handle_can_be_cloned_and_used_from_other_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<QuicP2p>();
+
+        let (qp2p, _rx) = new_random_qp2p_for_unit_test(false, Default::default());
+        let qp2p_clone = qp2p.clone();
+
+        let our_conn_info = unwrap!(qp2p.our_connection_info());
+        let j = unwrap!(std::thread::Builder::new()
+            .name("QuicP2p-clone-test-thread".to_string())
+            .spawn(move || unwrap!(qp2p_clone.our_connection_info())));
+
+        assert_eq!(unwrap!(j.join()), our_conn_info);
+    }
+
+    mod strict {
+        use super::*;
+        use crate::connection::{Connection, FromPeer};
+        use std::thread;
+        use std::time::Duration;
+
+        fn strict_qp2p(our_type: OurType) -> QuicP2p {
+            let (tx, _rx) = mpsc::channel();
+            let mut cfg = Config::with_default_cert();
+            cfg.port = Some(0);
+            cfg.bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            cfg.our_type = our_type;
+            cfg.strict = true;
+            unwrap!(Builder::new(tx).with_config(cfg).build())
+        }
+
+        fn is_connected(qp2p: &QuicP2p, peer_addr: SocketAddr) -> bool {
+            let (tx, rx) = mpsc::channel();
+            qp2p.el.post(move |ctx| {
+                let _ = tx.send(ctx.with(|c| c.connections.contains_key(&peer_addr)));
+            });
+            unwrap!(rx.recv())
+        }
+
+        fn insert_conn(ctx: &Ctx, peer_addr: SocketAddr, from_peer: FromPeer, to_peer: ToPeer) {
+            ctx.with_mut(|c| {
+                let mut conn =
+                    Connection::new(ctx, peer_addr, c.event_tx.clone(), c.clock.clone(), None);
+                conn.from_peer = from_peer;
+                conn.to_peer = to_peer;
+                let _ = c.connections.insert(peer_addr, conn);
+            })
+        }
+
+        #[test]
+        fn client_connecting_to_a_node_connected_to_it_severs_the_connection() {
+            let (node, _rx) = new_random_qp2p_for_unit_test(false, Default::default());
+            let node_info = unwrap!(node.our_connection_info());
+            let peer_addr = node_info.peer_addr;
+            let client = strict_qp2p(OurType::Client);
+
+            let (tx, rx) = mpsc::channel();
+            client.el.post(move |ctx| {
+                insert_conn(ctx, peer_addr, FromPeer::NotNeeded, ToPeer::NoConnection);
+                let _ = tx.send(connect::connect_to(ctx, node_info, None, None));
+            });
+
+            match unwrap!(rx.recv()) {
+                Err(Error::IllegalState(addr, _)) => assert_eq!(addr, peer_addr),
+                x => panic!("Unexpected result: {:?}", x),
+            }
+            assert!(!is_connected(&client, peer_addr));
+            assert!(client.stats().is_ok());
+        }
+
+        #[test]
+        fn connection_made_without_being_initiated_is_severed() {
+            let (node, _rx) = new_random_qp2p_for_unit_test(false, Default::default());
+            let node_info = unwrap!(node.our_connection_info());
+            let peer_addr = node_info.peer_addr;
+            let qp2p = strict_qp2p(OurType::Node);
+
+            qp2p.el.post(move |ctx| {
+                unwrap!(connect::connect_to(ctx, node_info, None, None));
+                ctx.with_mut(|c| {
+                    let conn = unwrap!(c.connections.get_mut(&peer_addr));
+                    if let ToPeer::Initiated { terminator, .. } =
+                        mem::replace(&mut conn.to_peer, ToPeer::NotNeeded)
+                    {
+                        // Dropping it would cancel the connect
+                        mem::forget(terminator);
+                    }
+                });
+            });
+
+            // Connected to and severed straight away
+            for _ in 0..100 {
+                if !is_connected(&qp2p, peer_addr) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            assert!(!is_connected(&qp2p, peer_addr));
+            assert!(qp2p.stats().is_ok());
+        }
+
+        #[test]
+        fn message_from_a_peer_we_have_no_connection_with_severs_the_connection() {
+            let peer_addr: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+            let qp2p = strict_qp2p(OurType::Node);
+
+            qp2p.el.post(move |ctx| {
+                insert_conn(ctx, peer_addr, FromPeer::NoConnection, ToPeer::NotNeeded);
+                communicate::handle_wire_msg(ctx, peer_addr, WireMsg::UserMsg(From::from("hi")));
+            });
+
+            assert!(!is_connected(&qp2p, peer_addr));
+            assert!(qp2p.stats().is_ok());
+        }
+
+        #[test]
+        fn control_message_dispatched_as_a_user_one_severs_the_connection() {
+            let (node, _rx) = new_random_qp2p_for_unit_test(false, Default::default());
+            let node_info = unwrap!(node.our_connection_info());
+            let peer_addr = node_info.peer_addr;
+            let (tx, ev_rx) = mpsc::channel();
+            let mut cfg = Config::with_default_cert();
+            cfg.port = Some(0);
+            cfg.bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            cfg.strict = true;
+            let qp2p = unwrap!(Builder::new(tx).with_config(cfg).build());
+            qp2p.connect_to(node_info.clone());
+            unwrap!(ev_rx.iter().find(|event| match event {
+                Event::ConnectedTo { .. } => true,
+                _ => false,
+            }));
+
+            qp2p.el.post(move |ctx| {
+                ctx.with_mut(|c| {
+                    let conn = unwrap!(c.connections.get(&peer_addr));
+                    if let ToPeer::Established { ref q_conn, .. } = conn.to_peer {
+                        communicate::dispatch_wire_msg(
+                            ctx,
+                            node_info.into(),
+                            q_conn,
+                            None,
+                            &c.event_tx,
+                            WireMsg::HealthCheckReq { sent_at_msec: 0 },
+                            &mut c.bootstrap_cache,
+                            true,
+                            false,
+                        );
+                    }
+                })
+            });
+
+            // Severed once the dispatch is done
+            for _ in 0..100 {
+                if !is_connected(&qp2p, peer_addr) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            assert!(!is_connected(&qp2p, peer_addr));
+            assert!(qp2p.stats().is_ok());
+        }
+    }
+
     fn new_random_qp2p_for_unit_test(
         is_addr_unspecified: bool,
         contacts: HashSet<NodeInfo>,
@@ -600,10 +2117,10 @@ mod tests {
         let (tx, rx) = mpsc::channel();
         let qp2p = {
             let mut cfg = Config::with_default_cert();
-            cfg.hard_coded_contacts = contacts;
+            cfg.hard_coded_contacts = contacts.into_iter().map(Contact::from).collect();
             cfg.port = Some(0);
             if !is_addr_unspecified {
-                cfg.ip = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+                cfg.bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
             }
             unwrap!(Builder::new(tx).with_config(cfg).build())
         };
@@ -7,75 +7,255 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::communicate;
+use crate::clock::SharedClock;
+use crate::config::OurType;
 use crate::connection::{Connection, FromPeer, QConn, ToPeer};
-use crate::context::ctx_mut;
+use crate::context::{Context, Ctx};
 use crate::event::Event;
-use crate::utils;
-use crate::NodeInfo;
+use crate::wire_msg::CloseReason;
+use crate::{admission, communicate, connect, peer_config, utils, NodeInfo, R};
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::prelude::{Future, Stream};
-use tokio::runtime::current_thread;
 
 /// Start listening
-pub fn listen(incoming_connections: quinn::Incoming) {
+pub fn listen(ctx: &Ctx, incoming_connections: quinn::Incoming) {
+    let (terminator, rx) = utils::connect_terminator();
+    ctx.with_mut(|c| {
+        c.listener_terminator = Some(terminator);
+        match c.quic_ep().local_addr() {
+            Ok(addr) => {
+                if let Err(e) = c.event_tx.send(Event::ListenerStarted { addr }) {
+                    info!("Could not fire event: {:?}", e);
+                }
+            }
+            Err(e) => info!("Could not get our listening address: {:?} - {}", e, e),
+        }
+    });
+
+    let terminator_leaf = rx.map_err(|_| ()).for_each(|_| Err(()));
+    let ctx_clone = ctx.clone();
     let leaf = incoming_connections
         .map_err(|()| warn!("ERROR: Listener errored out"))
         .for_each(move |(conn_driver, q_conn, incoming)| {
-            handle_new_conn(conn_driver, q_conn, incoming);
+            handle_new_conn(&ctx_clone, conn_driver, q_conn, incoming);
             Ok(())
+        })
+        .select(terminator_leaf)
+        .then(|_| Ok(()));
+
+    ctx.spawn(leaf);
+}
+
+/// Rebind our endpoint to the given port, or a random one if none is given, keeping the rest of
+/// our state (bootstrap cache, configuration etc.). Existing connections are closed and the nodes
+/// we were connected to are connected to afresh from the new endpoint.
+pub fn restart(ctx: &Ctx, port: Option<u16>) -> R<()> {
+    let (our_cfg, ip, our_type, should_listen, socket_options, transport) =
+        ctx.with(|c| -> R<_> {
+            let our_cfg = peer_config::new_our_cfg(
+                c.idle_timeout_msec,
+                c.keep_alive_interval_msec,
+                c.stream_receive_window,
+                c.connection_receive_window,
+                &c.alpn_protocols,
+                &c.server_cert,
+                c.mutual_tls,
+            )?;
+            let ip = c.quic_ep().local_addr()?.ip();
+            Ok((
+                our_cfg,
+                ip,
+                c.our_type,
+                c.listen,
+                c.socket_options,
+                c.transport.clone(),
+            ))
+        })?;
+
+    let (udp, effective_socket_options) = transport.bind(ip, port.unwrap_or(0), &socket_options)?;
+    let mut ep_builder = quinn::Endpoint::builder();
+    if should_listen {
+        ep_builder.listen(our_cfg);
+    }
+    let (dr, ep, incoming_connections) = ep_builder.with_socket(udp)?;
+
+    let peers_to_reconnect: Vec<(NodeInfo, bool)> = ctx.with_mut(|c| {
+        if let Some(mut terminator) = c.listener_terminator.take() {
+            let _ = terminator.try_send(());
+        }
+        let _old_ep = c.replace_quic_ep(ep);
+        c.effective_socket_options = effective_socket_options;
+        c.our_connection_info = None;
+
+        let peers = c
+            .connections
+            .iter()
+            .filter_map(|(peer_addr, conn)| {
+                if let ToPeer::Established {
+                    ref peer_cert_der, ..
+                } = conn.to_peer
+                {
+                    let node_info = NodeInfo {
+                        peer_addr: *peer_addr,
+                        peer_cert_der: peer_cert_der.clone(),
+                    };
+                    Some((node_info, conn.we_contacted_peer))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for (_, mut conn) in c.connections.drain() {
+            conn.set_close_reason(CloseReason::Shutdown);
+        }
+
+        peers
+    });
+
+    ctx.spawn_driver(dr, |_, e| warn!("Error in quinn Driver: {:?}", e));
+
+    if our_type != OurType::Client && should_listen {
+        listen(ctx, incoming_connections);
+    }
+
+    for (node_info, we_contacted_peer) in peers_to_reconnect {
+        let peer_addr = node_info.peer_addr;
+        if let Err(e) = connect::connect_to(ctx, node_info, None, None) {
+            debug!(
+                "Could not reconnect to peer {} after restart: {}",
+                peer_addr, e
+            );
+            continue;
+        }
+        ctx.with_mut(|c| {
+            if let Some(conn) = c.connections.get_mut(&peer_addr) {
+                conn.we_contacted_peer = we_contacted_peer;
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Close the connection the peer made to us if it hasn't introduced itself via its handshake
+/// within `timeout`. Otherwise it would hold on to its slot in our connection table for as long as
+/// it keeps the QUIC connection alive.
+fn spawn_handshake_timer(ctx: &Ctx, peer_addr: SocketAddr, timeout: Duration, clock: &SharedClock) {
+    let ctx_clone = ctx.clone();
+    let leaf = clock.delay(clock.now() + timeout).then(move |r| {
+        let ctx = &ctx_clone;
+        if let Err(e) = r {
+            info!("Error in handshake timer: {:?}", e);
+        }
+
+        ctx.with_mut(|c| {
+            let is_handshake_overdue = c.connections.get(&peer_addr).map_or(false, |conn| {
+                conn.from_peer.is_established() && !conn.peer_handshake_rxd
+            });
+            if !is_handshake_overdue {
+                return;
+            }
+
+            debug!(
+                "Peer {} didn't send its handshake in time - closing the connection",
+                peer_addr
+            );
+            let reason = CloseReason::HandshakeTimedOut;
+            if let Some(mut conn) = c.connections.remove(&peer_addr) {
+                conn.set_close_reason(reason);
+                // Dropping the connection only reports it if it was complete
+                if !conn.is_connected() {
+                    let event = Event::ConnectionFailure { peer_addr, reason };
+                    if let Err(e) = c.event_tx.send(event) {
+                        info!("Could not fire event: {:?}", e);
+                    }
+                }
+            }
         });
 
-    current_thread::spawn(leaf);
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+/// Whether the peer is connecting to us because we are connecting to it, i.e. it's a node
+/// completing the pair of connections between us.
+fn is_expected(c: &Context, peer_addr: &SocketAddr) -> bool {
+    c.connections.get(peer_addr).map_or(false, |conn| {
+        conn.to_peer.is_initiated() || conn.to_peer.is_established()
+    })
 }
 
 fn handle_new_conn(
+    ctx: &Ctx,
     conn_driver: quinn::ConnectionDriver,
     q_conn: quinn::Connection,
     incoming_streams: quinn::IncomingStreams,
 ) {
-    let q_conn = QConn::from(q_conn);
+    let mut q_conn = QConn::from(q_conn);
+
+    let peer_addr = ctx.with(|c| c.transport.peer_addr(q_conn.remote_address()));
 
-    let peer_addr = q_conn.remote_address();
+    ctx.spawn_driver(conn_driver, move |ctx, e| {
+        utils::handle_communication_err(ctx, peer_addr, &From::from(e), "Driver failed");
+    });
+
+    if ctx.with_mut(|c| c.reputation.on_connect_attempt(peer_addr, c.clock.now())) {
+        debug!("Refusing connection from misbehaving peer: {}", peer_addr);
+        return q_conn.set_close_reason(CloseReason::Refused);
+    }
+
+    if !ctx.with(|c| c.is_accepting_incoming || is_expected(c, &peer_addr)) {
+        debug!(
+            "Refusing connection from peer {} as we are not accepting new ones",
+            peer_addr
+        );
+        return q_conn.set_close_reason(CloseReason::Refused);
+    }
+
+    if !ctx.with(|c| peer_config::is_alpn_accepted(&c.alpn_protocols, &q_conn)) {
+        debug!(
+            "Refusing connection from peer {} as it speaks a protocol we don't",
+            peer_addr
+        );
+        return q_conn.set_close_reason(CloseReason::Refused);
+    }
 
-    current_thread::spawn(conn_driver.map_err(move |e| {
-        utils::handle_communication_err(peer_addr, &From::from(e), "Driver failed");
-    }));
+    if !ctx.with_mut(|c| c.connections.contains_key(&peer_addr) || admission::admit(c, peer_addr)) {
+        return q_conn.set_close_reason(CloseReason::ConnectionLimit);
+    }
 
-    let is_duplicate = ctx_mut(|c| {
+    let is_duplicate = ctx.with_mut(|c| {
         let event_tx = c.event_tx.clone();
+        let clock = c.clock.clone();
         let conn = c
             .connections
             .entry(peer_addr)
-            .or_insert_with(|| Connection::new(peer_addr, event_tx, None));
+            .or_insert_with(|| Connection::new(ctx, peer_addr, event_tx, clock, None));
         if conn.from_peer.is_no_connection() {
             conn.from_peer = FromPeer::Established {
                 q_conn,
                 pending_reads: Default::default(),
             };
+            // Our own connection to the peer, should we make one, takes precedence
+            if conn.quic_established_at.is_none() {
+                conn.quic_established_at = Some(c.clock.now());
+            }
 
-            if let ToPeer::Established {
-                ref peer_cert_der, ..
-            } = conn.to_peer
-            {
-                let node_info = NodeInfo {
-                    peer_addr,
-                    peer_cert_der: peer_cert_der.clone(),
-                };
-
-                // TODO come back to all the connected-to events and see if we are handling all
-                // cases
-                let event = if let Some(bootstrap_group_ref) = conn.bootstrap_group_ref.take() {
-                    bootstrap_group_ref.terminate_group(true);
-                    Event::BootstrappedTo { node: node_info }
-                } else {
-                    Event::ConnectedTo {
-                        peer: node_info.into(),
-                    }
-                };
+            let event = Event::HandshakeCompleted {
+                peer_addr,
+                elapsed: None,
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
 
-                if let Err(e) = c.event_tx.send(event) {
-                    info!("ERROR in informing user about a new peer: {:?} - {}", e, e);
-                }
+            // If we had connected to the peer already, the connection event will be fired once
+            // the peer introduces itself to us via its handshake on this incoming connection.
+            if let Some(timeout) = c.handshake_timeout {
+                spawn_handshake_timer(ctx, peer_addr, timeout, &c.clock);
             }
             None
         } else {
@@ -83,10 +263,10 @@ fn handle_new_conn(
         }
     });
 
-    if let Some(_q_conn) = is_duplicate {
+    if let Some(mut q_conn) = is_duplicate {
         debug!("Not allowing duplicate connection from peer: {}", peer_addr);
-        return;
+        return q_conn.set_close_reason(CloseReason::Duplicate);
     }
 
-    communicate::read_from_peer(peer_addr, incoming_streams);
+    communicate::read_from_peer(ctx, peer_addr, incoming_streams);
 }
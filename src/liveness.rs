@@ -0,0 +1,89 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Watchdog telling the user about connected peers gone silent for longer than
+//! `Config::unresponsive_peer_msec`, and about them coming back. QUIC keep-alives never reach us,
+//! so peers quiet for half the threshold are sent a `HealthCheckReq`, which a live peer answers
+//! even when it has nothing else to say.
+
+use crate::clock;
+use crate::communicate;
+use crate::connection::Connection;
+use crate::context::Ctx;
+use crate::event::{Event, EventTx};
+use crate::wire_msg::WireMsg;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::Stream;
+
+/// Check the peers every half `threshold` for as long as the event loop runs.
+pub fn start(ctx: &Ctx, threshold: Duration) {
+    let interval = threshold / 2;
+    let ctx_clone = ctx.clone();
+    let leaf = clock::interval(&ctx.with(|c| c.clock.clone()), interval).for_each(move |_| {
+        check(&ctx_clone, threshold);
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+/// We heard from the peer, so it's responsive again if it wasn't.
+pub fn heard_from(event_tx: &EventTx, peer_addr: SocketAddr, conn: &mut Connection) {
+    if conn.is_unresponsive {
+        conn.is_unresponsive = false;
+        debug!("Peer {} is responsive again", peer_addr);
+        if let Err(e) = event_tx.send(Event::PeerResponsive { peer_addr }) {
+            info!("Could not fire event: {:?}", e);
+        }
+    }
+}
+
+fn check(ctx: &Ctx, threshold: Duration) {
+    ctx.with_mut(|c| {
+        let now = c.clock.now();
+        for (peer_addr, conn) in c.connections.iter_mut() {
+            if !conn.is_connected() {
+                continue;
+            }
+            let silence = now - conn.last_heard_at;
+
+            if silence >= threshold && !conn.is_unresponsive {
+                conn.is_unresponsive = true;
+                debug!(
+                    "Peer {} hasn't been heard from for {:?}",
+                    peer_addr, silence
+                );
+                let event = Event::PeerUnresponsive {
+                    peer_addr: *peer_addr,
+                    since: conn.last_heard_at,
+                };
+                if let Err(e) = c.event_tx.send(event) {
+                    info!("Could not fire event: {:?}", e);
+                }
+            }
+        }
+    });
+
+    ctx.with(|c| {
+        let now = c.clock.now();
+        let probe_after = threshold / 2;
+        for (peer_addr, conn) in c.connections.iter() {
+            if !conn.is_connected() || now - conn.last_heard_at < probe_after {
+                continue;
+            }
+            if let Some(q_conn) = communicate::writable_q_conn(c, conn) {
+                let req = WireMsg::HealthCheckReq {
+                    sent_at_msec: clock::unix_time_msec(&c.clock),
+                };
+                communicate::write_to_peer_connection(ctx, *peer_addr, q_conn, req);
+            }
+        }
+    })
+}
@@ -0,0 +1,311 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Counters and histograms of our traffic, rendered in the Prometheus text exposition format.
+//! Only compiled in with the `metrics` feature so that there is no cost to it otherwise. Serving
+//! them over HTTP is left to the embedder.
+
+use crate::context::Ctx;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the message size buckets, in bytes
+const MSG_SIZE_BUCKETS: &[f64] = &[
+    64.0,
+    256.0,
+    1024.0,
+    4096.0,
+    16_384.0,
+    65_536.0,
+    262_144.0,
+    1_048_576.0,
+    4_194_304.0,
+    16_777_216.0,
+];
+/// Upper bounds of the handshake duration buckets, in seconds
+const HANDSHAKE_DURATION_BUCKETS: &[f64] =
+    &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Metrics collected since we started.
+pub struct Metrics {
+    msgs_in: u64,
+    msgs_out: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    connect_successes: u64,
+    connect_failures: u64,
+    duplicates_dropped: u64,
+    msg_size_in: Histogram,
+    msg_size_out: Histogram,
+    handshake_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            msgs_in: 0,
+            msgs_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            connect_successes: 0,
+            connect_failures: 0,
+            duplicates_dropped: 0,
+            msg_size_in: Histogram::new(MSG_SIZE_BUCKETS),
+            msg_size_out: Histogram::new(MSG_SIZE_BUCKETS),
+            handshake_duration: Histogram::new(HANDSHAKE_DURATION_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_inbound(&mut self, frame_len: usize) {
+        self.msgs_in += 1;
+        self.bytes_in += frame_len as u64;
+        self.msg_size_in.observe(frame_len as f64);
+    }
+
+    pub fn record_outbound(&mut self, frame_len: usize) {
+        self.msgs_out += 1;
+        self.bytes_out += frame_len as u64;
+        self.msg_size_out.observe(frame_len as f64);
+    }
+
+    /// Note a successful connect which took `elapsed` from start to the end of the handshake.
+    pub fn record_connect_success(&mut self, elapsed: Duration) {
+        self.connect_successes += 1;
+        self.handshake_duration
+            .observe(elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9);
+    }
+
+    pub fn record_connect_failure(&mut self) {
+        self.connect_failures += 1;
+    }
+
+    pub fn record_duplicate_dropped(&mut self) {
+        self.duplicates_dropped += 1;
+    }
+
+    /// All the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "quic_p2p_messages_total",
+            "Messages exchanged with peers",
+            "counter",
+        );
+        write_sample(
+            &mut out,
+            "quic_p2p_messages_total",
+            "direction=\"in\"",
+            self.msgs_in,
+        );
+        write_sample(
+            &mut out,
+            "quic_p2p_messages_total",
+            "direction=\"out\"",
+            self.msgs_out,
+        );
+
+        write_header(
+            &mut out,
+            "quic_p2p_bytes_total",
+            "Bytes of messages, framing included, exchanged with peers",
+            "counter",
+        );
+        write_sample(
+            &mut out,
+            "quic_p2p_bytes_total",
+            "direction=\"in\"",
+            self.bytes_in,
+        );
+        write_sample(
+            &mut out,
+            "quic_p2p_bytes_total",
+            "direction=\"out\"",
+            self.bytes_out,
+        );
+
+        write_header(
+            &mut out,
+            "quic_p2p_connects_total",
+            "Connects to peers by outcome",
+            "counter",
+        );
+        write_sample(
+            &mut out,
+            "quic_p2p_connects_total",
+            "outcome=\"success\"",
+            self.connect_successes,
+        );
+        write_sample(
+            &mut out,
+            "quic_p2p_connects_total",
+            "outcome=\"failure\"",
+            self.connect_failures,
+        );
+
+        write_header(
+            &mut out,
+            "quic_p2p_duplicate_messages_dropped_total",
+            "Replays of messages from peers dropped as we had received them already",
+            "counter",
+        );
+        write_sample(
+            &mut out,
+            "quic_p2p_duplicate_messages_dropped_total",
+            "",
+            self.duplicates_dropped,
+        );
+
+        write_header(
+            &mut out,
+            "quic_p2p_message_size_bytes",
+            "Sizes of the messages, framing included, exchanged with peers",
+            "histogram",
+        );
+        self.msg_size_in
+            .render(&mut out, "quic_p2p_message_size_bytes", "direction=\"in\"");
+        self.msg_size_out
+            .render(&mut out, "quic_p2p_message_size_bytes", "direction=\"out\"");
+
+        write_header(
+            &mut out,
+            "quic_p2p_handshake_duration_seconds",
+            "Time from starting a connect to the end of its handshake",
+            "histogram",
+        );
+        self.handshake_duration
+            .render(&mut out, "quic_p2p_handshake_duration_seconds", "");
+
+        out
+    }
+}
+
+/// Note a message received from a peer. This must not be called while the `Context` is already
+/// borrowed.
+pub fn record_inbound(ctx: &Ctx, frame_len: usize) {
+    ctx.with_mut(|c| c.metrics.record_inbound(frame_len))
+}
+
+/// Note a message sent to a peer. This must not be called while the `Context` is already
+/// borrowed.
+pub fn record_outbound(ctx: &Ctx, frame_len: usize) {
+    ctx.with_mut(|c| c.metrics.record_outbound(frame_len))
+}
+
+/// Note a replay of a message from a peer dropped, see `dedup`. This must not be called while the
+/// `Context` is already borrowed.
+pub fn record_duplicate_dropped(ctx: &Ctx) {
+    ctx.with_mut(|c| c.metrics.record_duplicate_dropped())
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, the last one being the `+Inf` one
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or_else(|| self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let bucket_name = format!("{}_bucket", name);
+
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let labels = format!("{}{}le=\"{}\"", labels, separator, bound);
+            write_sample(out, &bucket_name, &labels, cumulative);
+        }
+        cumulative += self.counts[self.bounds.len()];
+        let labels_inf = format!("{}{}le=\"+Inf\"", labels, separator);
+        write_sample(out, &bucket_name, &labels_inf, cumulative);
+
+        let _ = writeln!(out, "{}_sum{} {}", name, braced(labels), self.sum);
+        write_sample(out, &format!("{}_count", name), labels, cumulative);
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &str, value: u64) {
+    let _ = writeln!(out, "{}{} {}", name, braced(labels), value);
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_in_prometheus_format() {
+        let mut metrics = Metrics::default();
+        metrics.record_inbound(100);
+        metrics.record_inbound(100_000_000);
+        metrics.record_outbound(10);
+        metrics.record_connect_success(Duration::from_millis(20));
+        metrics.record_connect_failure();
+        metrics.record_duplicate_dropped();
+
+        let rendered = metrics.render();
+        let lines: Vec<_> = rendered.lines().collect();
+
+        for expected in &[
+            "# TYPE quic_p2p_messages_total counter",
+            "quic_p2p_messages_total{direction=\"in\"} 2",
+            "quic_p2p_messages_total{direction=\"out\"} 1",
+            "quic_p2p_bytes_total{direction=\"in\"} 100000100",
+            "quic_p2p_connects_total{outcome=\"success\"} 1",
+            "quic_p2p_connects_total{outcome=\"failure\"} 1",
+            "quic_p2p_duplicate_messages_dropped_total 1",
+            "# TYPE quic_p2p_message_size_bytes histogram",
+            "quic_p2p_message_size_bytes_bucket{direction=\"in\",le=\"64\"} 0",
+            "quic_p2p_message_size_bytes_bucket{direction=\"in\",le=\"256\"} 1",
+            "quic_p2p_message_size_bytes_bucket{direction=\"in\",le=\"16777216\"} 1",
+            "quic_p2p_message_size_bytes_bucket{direction=\"in\",le=\"+Inf\"} 2",
+            "quic_p2p_message_size_bytes_count{direction=\"in\"} 2",
+            "quic_p2p_message_size_bytes_bucket{direction=\"out\",le=\"64\"} 1",
+            "quic_p2p_handshake_duration_seconds_bucket{le=\"0.01\"} 0",
+            "quic_p2p_handshake_duration_seconds_bucket{le=\"0.025\"} 1",
+            "quic_p2p_handshake_duration_seconds_count 1",
+        ] {
+            assert!(lines.contains(expected), "Missing line: {}", expected);
+        }
+    }
+}
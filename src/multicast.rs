@@ -0,0 +1,145 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Sends the same message to many peers at once. The message is framed once and the frame shared
+//! by all the writes, which the send scheduler interleaves across the connections like any others.
+//! `Event::SendToManyComplete` summarises the outcome once every write has finished.
+
+use crate::communicate;
+use crate::context::Ctx;
+use crate::event::{Event, EventTx};
+use crate::wire_msg::WireMsg;
+use crate::{Peer, DEFAULT_CHANNEL};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Sends to many peers still waiting for some of their writes.
+#[derive(Default)]
+pub struct Multicasts {
+    next_id: u64,
+    pending: HashMap<u64, Multicast>,
+}
+
+struct Multicast {
+    token: u64,
+    remaining: usize,
+    sent: Vec<SocketAddr>,
+    failed: Vec<SocketAddr>,
+}
+
+impl Multicasts {
+    fn insert(&mut self, token: u64, remaining: usize) -> u64 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let _ = self.pending.insert(
+            id,
+            Multicast {
+                token,
+                remaining,
+                sent: Vec::new(),
+                failed: Vec::new(),
+            },
+        );
+        id
+    }
+
+    /// The write to the peer finished. The send is reported once it was the last one outstanding.
+    pub fn resolve(&mut self, event_tx: &EventTx, id: u64, peer_addr: SocketAddr, is_sent: bool) {
+        let is_complete = match self.pending.get_mut(&id) {
+            Some(multicast) => {
+                if is_sent {
+                    multicast.sent.push(peer_addr);
+                } else {
+                    multicast.failed.push(peer_addr);
+                }
+                multicast.remaining -= 1;
+                multicast.remaining == 0
+            }
+            None => return debug!("Write outcome for an unknown send to many: {}", id),
+        };
+
+        if is_complete {
+            if let Some(multicast) = self.pending.remove(&id) {
+                fire_complete(event_tx, multicast.token, multicast.sent, multicast.failed);
+            }
+        }
+    }
+}
+
+/// Send the message to all the given peers. Unlike `QuicP2p::send` this doesn't connect to anyone,
+/// peers we can't write to just now are reported as failed straight away. This must not be called
+/// while the `Context` is already borrowed.
+pub fn start(ctx: &Ctx, peers: Vec<Peer>, msg: bytes::Bytes, token: u64) {
+    let mut peer_addrs: Vec<_> = peers.iter().map(Peer::peer_addr).collect();
+    peer_addrs.sort();
+    peer_addrs.dedup();
+
+    if peer_addrs.is_empty() {
+        return ctx.with(|c| fire_complete(&c.event_tx, token, Vec::new(), Vec::new()));
+    }
+
+    let wire_msg = WireMsg::UserMsg(msg);
+    let frame = match wire_msg.clone().into_frame() {
+        Ok(frame) => frame,
+        Err(e) => {
+            debug!("Could not frame the message to send to many: {}", e);
+            return ctx.with(|c| fire_complete(&c.event_tx, token, Vec::new(), peer_addrs));
+        }
+    };
+
+    let id = ctx.with_mut(|c| c.multicasts.insert(token, peer_addrs.len()));
+
+    let unwritable: Vec<_> = ctx.with(|c| {
+        peer_addrs
+            .into_iter()
+            .filter(|peer_addr| {
+                let q_conn = c
+                    .connections
+                    .get(peer_addr)
+                    .and_then(|conn| communicate::writable_q_conn(c, conn));
+                let q_conn = match q_conn {
+                    Some(q_conn) => q_conn,
+                    None => return true,
+                };
+
+                let peer_addr = *peer_addr;
+                let ctx_on_written = ctx.clone();
+                communicate::write_frame_to_peer_connection(
+                    ctx,
+                    peer_addr,
+                    q_conn,
+                    Some(wire_msg.clone()),
+                    DEFAULT_CHANNEL,
+                    frame.clone(),
+                    Some(Box::new(move |is_sent| {
+                        ctx_on_written
+                            .with_mut(|c| c.multicasts.resolve(&c.event_tx, id, peer_addr, is_sent))
+                    })),
+                );
+                false
+            })
+            .collect()
+    });
+
+    for peer_addr in unwritable {
+        trace!("Not connected to {} to send to many over", peer_addr);
+        ctx.with_mut(|c| c.multicasts.resolve(&c.event_tx, id, peer_addr, false));
+    }
+}
+
+fn fire_complete(event_tx: &EventTx, token: u64, sent: Vec<SocketAddr>, failed: Vec<SocketAddr>) {
+    let event = Event::SendToManyComplete {
+        token,
+        sent,
+        failed,
+    };
+    if let Err(e) = event_tx.send(event) {
+        info!("Could not fire event: {:?}", e);
+    }
+}
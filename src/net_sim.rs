@@ -0,0 +1,373 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Relay reproducing WAN conditions between real UDP sockets on a single machine, for integration
+//! tests. Only compiled in with the `net-sim` feature.
+//!
+//! quinn sends straight from the socket it's given, so packets can't be held back on their way out
+//! of an endpoint. Instead the relay stands in for a node: peers connect to `NetSim::addr` with
+//! the node's certificate, and every packet passing through, either way, is delayed, dropped or
+//! reordered as per the `NetSimConfig`. The node sees each peer at an address of the relay too, so
+//! its connections back to the peers go through the relay as well.
+
+use std::cmp::{self, Ordering};
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the relay threads check whether they are to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Big enough for any UDP datagram.
+const MAX_DATAGRAM_LEN: usize = 65_535;
+
+/// Conditions the packets passing through a `NetSim` are subjected to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetSimConfig {
+    /// Time every packet is held back for, in milliseconds
+    pub delay_msec: u64,
+    /// Up to this many milliseconds more, at random, for each packet
+    pub jitter_msec: u64,
+    /// Share of the packets dropped, from 0 to 1
+    pub loss: f64,
+    /// Share of the packets held back for another `delay_msec` + `jitter_msec`, but at least a
+    /// millisecond, so that the ones sent after them overtake them. From 0 to 1.
+    pub reorder: f64,
+    /// Seed of the random decisions, so that runs can be replayed
+    pub seed: u64,
+}
+
+/// Relay in front of a node, running until dropped.
+pub struct NetSim {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl NetSim {
+    /// Start relaying to and from the node at `target`, listening on a random port of the same
+    /// IP address.
+    pub fn start(target: SocketAddr, cfg: NetSimConfig) -> io::Result<Self> {
+        let front = Arc::new(UdpSocket::bind(SocketAddr::new(target.ip(), 0))?);
+        front.set_read_timeout(Some(POLL_INTERVAL))?;
+        let addr = front.local_addr()?;
+
+        let shared = Arc::new(Shared {
+            is_running: AtomicBool::new(true),
+            next_seq: AtomicUsize::new(0),
+            queue: Mutex::new(BinaryHeap::new()),
+            queue_changed: Condvar::new(),
+            impairment: Mutex::new(Impairment::new(cfg)),
+            upstream_threads: Mutex::new(Vec::new()),
+        });
+
+        let threads = vec![
+            {
+                let shared = shared.clone();
+                thread::spawn(move || relay_from_peers(&shared, &front, target))
+            },
+            {
+                let shared = shared.clone();
+                thread::spawn(move || deliver(&shared))
+            },
+        ];
+
+        Ok(Self {
+            addr,
+            shared,
+            threads,
+        })
+    }
+
+    /// Address to connect to the node at, with the node's certificate.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for NetSim {
+    fn drop(&mut self) {
+        self.shared.is_running.store(false, AtomicOrdering::SeqCst);
+        self.shared.queue_changed.notify_all();
+
+        let upstream_threads = lock(&self.shared.upstream_threads)
+            .drain(..)
+            .collect::<Vec<_>>();
+        for handle in self.threads.drain(..).chain(upstream_threads) {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Shared {
+    is_running: AtomicBool,
+    next_seq: AtomicUsize,
+    queue: Mutex<BinaryHeap<Pending>>,
+    queue_changed: Condvar,
+    impairment: Mutex<Impairment>,
+    upstream_threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Shared {
+    fn is_running(&self) -> bool {
+        self.is_running.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Queue the packet for delivery, unless it's lost.
+    fn schedule(&self, socket: &Arc<UdpSocket>, dest: SocketAddr, payload: &[u8]) {
+        let now = Instant::now();
+        let deliver_at = match lock(&self.impairment).plan(now) {
+            Some(deliver_at) => deliver_at,
+            None => return trace!("NetSim dropped a packet to {}", dest),
+        };
+
+        let seq = self.next_seq.fetch_add(1, AtomicOrdering::SeqCst);
+        lock(&self.queue).push(Pending {
+            deliver_at,
+            seq,
+            socket: socket.clone(),
+            dest,
+            payload: payload.to_vec(),
+        });
+        self.queue_changed.notify_one();
+    }
+}
+
+/// Packet waiting to be sent on.
+struct Pending {
+    deliver_at: Instant,
+    /// Keeps packets due at the same time in order
+    seq: usize,
+    socket: Arc<UdpSocket>,
+    dest: SocketAddr,
+    payload: Vec<u8>,
+}
+
+impl Pending {
+    fn key(&self) -> (Instant, usize) {
+        (self.deliver_at, self.seq)
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    /// The heap is a max-heap, so the packet due first is the greatest.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+/// Decides the fate of each packet.
+struct Impairment {
+    cfg: NetSimConfig,
+    /// xorshift64* state, never zero
+    rng_state: u64,
+}
+
+impl Impairment {
+    fn new(cfg: NetSimConfig) -> Self {
+        Self {
+            cfg,
+            rng_state: cmp::max(cfg.seed ^ 0x9e37_79b9_7f4a_7c15, 1),
+        }
+    }
+
+    /// When to send on the packet arriving at `now`, or `None` if it's lost.
+    fn plan(&mut self, now: Instant) -> Option<Instant> {
+        if self.next_f64() < self.cfg.loss {
+            return None;
+        }
+
+        let jitter_usec = self.next_f64() * self.cfg.jitter_msec as f64 * 1000.0;
+        let mut delay =
+            Duration::from_millis(self.cfg.delay_msec) + Duration::from_micros(jitter_usec as u64);
+        if self.next_f64() < self.cfg.reorder {
+            delay += Duration::from_millis(cmp::max(self.cfg.delay_msec + self.cfg.jitter_msec, 1));
+        }
+        Some(now + delay)
+    }
+
+    /// Uniformly distributed in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let x = self.rng_state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Forward the packets of the peers to the node, each peer from a socket of its own so that the
+/// node's replies can be told apart.
+fn relay_from_peers(shared: &Arc<Shared>, front: &Arc<UdpSocket>, target: SocketAddr) {
+    let mut upstreams: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+
+    while shared.is_running() {
+        let (len, peer_addr) = match front.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => {
+                warn!("NetSim failed to receive from the peers: {}", e);
+                continue;
+            }
+        };
+
+        let upstream = match upstreams.entry(peer_addr) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => match open_upstream(shared, front, peer_addr, target) {
+                Ok(upstream) => entry.insert(upstream).clone(),
+                Err(e) => {
+                    warn!(
+                        "NetSim failed to open a socket for peer {}: {}",
+                        peer_addr, e
+                    );
+                    continue;
+                }
+            },
+        };
+        shared.schedule(&upstream, target, &buf[..len]);
+    }
+}
+
+/// Socket to the node on behalf of the peer, with the node's replies forwarded to the peer.
+fn open_upstream(
+    shared: &Arc<Shared>,
+    front: &Arc<UdpSocket>,
+    peer_addr: SocketAddr,
+    target: SocketAddr,
+) -> io::Result<Arc<UdpSocket>> {
+    let upstream = Arc::new(UdpSocket::bind(SocketAddr::new(target.ip(), 0))?);
+    upstream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let handle = {
+        let shared = shared.clone();
+        let front = front.clone();
+        let upstream = upstream.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0; MAX_DATAGRAM_LEN];
+            while shared.is_running() {
+                match upstream.recv_from(&mut buf) {
+                    Ok((len, _)) => shared.schedule(&front, peer_addr, &buf[..len]),
+                    Err(ref e) if is_timeout(e) => (),
+                    Err(e) => warn!("NetSim failed to receive for peer {}: {}", peer_addr, e),
+                }
+            }
+        })
+    };
+    lock(&shared.upstream_threads).push(handle);
+
+    Ok(upstream)
+}
+
+/// Send on the queued packets as they fall due.
+fn deliver(shared: &Shared) {
+    let mut queue = lock(&shared.queue);
+    while shared.is_running() {
+        let now = Instant::now();
+        let wait = match queue.peek() {
+            Some(pending) if pending.deliver_at <= now => {
+                if let Some(pending) = queue.pop() {
+                    if let Err(e) = pending.socket.send_to(&pending.payload, pending.dest) {
+                        debug!("NetSim failed to send to {}: {}", pending.dest, e);
+                    }
+                }
+                continue;
+            }
+            Some(pending) => cmp::min(pending.deliver_at - now, POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        };
+        queue = match shared.queue_changed.wait_timeout(queue, wait) {
+            Ok((queue, _)) => queue,
+            Err(poisoned) => poisoned.into_inner().0,
+        };
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plans(cfg: NetSimConfig, now: Instant) -> Vec<Option<Instant>> {
+        let mut impairment = Impairment::new(cfg);
+        (0..1000).map(|_| impairment.plan(now)).collect()
+    }
+
+    #[test]
+    fn packets_are_delayed_within_the_jitter() {
+        let now = Instant::now();
+        let cfg = NetSimConfig {
+            delay_msec: 10,
+            jitter_msec: 5,
+            ..Default::default()
+        };
+
+        for deliver_at in plans(cfg, now) {
+            let delay = unwrap!(deliver_at) - now;
+            assert!(delay >= Duration::from_millis(10));
+            assert!(delay < Duration::from_millis(15));
+        }
+    }
+
+    #[test]
+    fn packets_are_lost_and_reordered_in_the_given_shares() {
+        let now = Instant::now();
+        let cfg = NetSimConfig {
+            delay_msec: 10,
+            loss: 0.2,
+            reorder: 0.5,
+            seed: 7,
+            ..Default::default()
+        };
+
+        let plans = plans(cfg, now);
+        let lost = plans.iter().filter(|plan| plan.is_none()).count();
+        let reordered = plans
+            .iter()
+            .filter(|plan| plan.map_or(false, |at| at - now >= Duration::from_millis(20)))
+            .count();
+        assert!(lost > 150 && lost < 250, "lost {}", lost);
+        assert!(
+            reordered > 300 && reordered < 500,
+            "reordered {}",
+            reordered
+        );
+
+        // Replayed exactly with the same seed
+        assert_eq!(self::plans(cfg, now), plans);
+    }
+}
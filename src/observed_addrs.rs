@@ -0,0 +1,94 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Number of peers which must agree on our address before we trust it.
+const MIN_CONSENSUS: usize = 2;
+/// Upper bound on the number of peers whose observations we hold.
+const MAX_REPORTERS: usize = 256;
+
+/// Addresses peers reported reaching us at in their handshakes. Each peer gets a single vote, its
+/// latest report, so a single peer can't sway the consensus.
+#[derive(Default)]
+pub struct ObservedAddrs {
+    by_reporter: HashMap<SocketAddr, SocketAddr>,
+}
+
+impl ObservedAddrs {
+    /// Note that `reporter` reached us at `observed`.
+    pub fn record(&mut self, reporter: SocketAddr, observed: SocketAddr) {
+        if observed.ip().is_unspecified() {
+            return;
+        }
+        if self.by_reporter.len() >= MAX_REPORTERS && !self.by_reporter.contains_key(&reporter) {
+            return;
+        }
+        let _ = self.by_reporter.insert(reporter, observed);
+    }
+
+    /// Observed addresses along with the number of peers which reported each, most reported first.
+    pub fn counts(&self) -> Vec<(SocketAddr, usize)> {
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        for observed in self.by_reporter.values() {
+            *counts.entry(*observed).or_insert(0) += 1;
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|(addr0, count0), (addr1, count1)| {
+            count1.cmp(count0).then_with(|| addr0.cmp(addr1))
+        });
+        counts
+    }
+
+    /// The address most peers agree on, if enough of them do and no other address is reported as
+    /// often.
+    pub fn consensus(&self) -> Option<SocketAddr> {
+        let counts = self.counts();
+        let (addr, count) = *counts.first()?;
+        let is_tied = counts.get(1).map_or(false, |&(_, other)| other == count);
+        if count < MIN_CONSENSUS || is_tied {
+            return None;
+        }
+        Some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consensus_needs_distinct_peers_in_agreement() {
+        let ours: SocketAddr = unwrap!("1.2.3.4:5000".parse());
+        let other: SocketAddr = unwrap!("5.6.7.8:5000".parse());
+        let peer = |port: u16| -> SocketAddr { unwrap!(format!("10.0.0.1:{}", port).parse()) };
+        let mut observed: ObservedAddrs = Default::default();
+
+        // A single peer repeating itself doesn't count
+        observed.record(peer(1), ours);
+        observed.record(peer(1), ours);
+        assert_eq!(observed.counts(), vec![(ours, 1)]);
+        assert_eq!(observed.consensus(), None);
+
+        observed.record(peer(2), other);
+        observed.record(peer(3), other);
+        assert_eq!(observed.consensus(), Some(other));
+
+        observed.record(peer(4), ours);
+        assert_eq!(observed.counts(), vec![(ours, 2), (other, 2)]);
+        assert_eq!(observed.consensus(), None);
+
+        // Latest report of a peer replaces its earlier one
+        observed.record(peer(2), ours);
+        assert_eq!(observed.counts(), vec![(ours, 3), (other, 1)]);
+        assert_eq!(observed.consensus(), Some(ours));
+    }
+}
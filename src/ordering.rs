@@ -0,0 +1,139 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Ordered delivery of user messages, see `Config::ordered_delivery`. With it set we sequence the
+//! user messages we send to peers unless they told us in their handshake they don't want them
+//! sequenced, as clients never hear from the nodes they connect to. Sequenced messages from peers
+//! are held back until the ones before them have arrived, given up on or timed out, and handed
+//! over as they arrive if we don't have it set ourselves.
+
+use crate::communicate;
+use crate::context::Ctx;
+use crate::reputation::{self, Violation};
+use crate::wire_msg::WireMsg;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::Future;
+
+/// Sequence number for the next user message to the peer, if it's to be sequenced. Sequence
+/// numbers are taken in the order the messages are written in.
+pub fn next_seq(ctx: &Ctx, peer_addr: SocketAddr) -> Option<u64> {
+    ctx.with_mut(|c| {
+        if c.ordered_delivery.is_none() {
+            return None;
+        }
+        let conn = c.connections.get_mut(&peer_addr)?;
+        if conn.peer_ordered_delivery == Some(false) {
+            return None;
+        }
+        let seq = conn.next_seq;
+        conn.next_seq += 1;
+        Some(seq)
+    })
+}
+
+/// Hand the sequenced message from the peer over once the ones before it are. This must not be
+/// called while the `Context` is already borrowed.
+pub fn receive(ctx: &Ctx, peer_addr: SocketAddr, seq: u64, wire_msg: WireMsg) {
+    take(ctx, peer_addr, seq, Some(wire_msg))
+}
+
+/// Let the messages from the peer behind the sequenced one be handed over without it, as it's a
+/// duplicate. This must not be called while the `Context` is already borrowed.
+pub fn skip(ctx: &Ctx, peer_addr: SocketAddr, seq: u64) {
+    take(ctx, peer_addr, seq, None)
+}
+
+fn take(ctx: &Ctx, peer_addr: SocketAddr, seq: u64, wire_msg: Option<WireMsg>) {
+    let taken = ctx.with_mut(|c| {
+        let cfg = match c.ordered_delivery {
+            Some(cfg) => cfg,
+            None => return Some((wire_msg.into_iter().collect(), None)),
+        };
+        let conn = match c.connections.get_mut(&peer_addr) {
+            Some(conn) => conn,
+            None => return Some((wire_msg.into_iter().collect(), None)),
+        };
+
+        let now = c.clock.now();
+        let gap_since = conn.reorder_buf.gap_since();
+        let window = cfg.window as usize;
+        let released = match wire_msg {
+            Some(wire_msg) => conn.reorder_buf.push(seq, wire_msg, window, now)?,
+            None => conn.reorder_buf.skip(seq, window, now)?,
+        };
+        let timeout = Duration::from_millis(cfg.timeout_msec);
+        let expiry = match conn.reorder_buf.gap_since() {
+            Some(new_gap_since) if Some(new_gap_since) != gap_since => {
+                trace!(
+                    "Holding back messages from peer {} until the earlier ones arrive",
+                    peer_addr
+                );
+                Some(timeout)
+            }
+            _ => None,
+        };
+
+        Some((released, expiry))
+    });
+    let (released, expiry) = match taken {
+        Some(taken) => taken,
+        None => {
+            debug!(
+                "Sequence number {} from peer {} is too far ahead - dropping the message",
+                seq, peer_addr
+            );
+            return reputation::penalise(ctx, peer_addr, Violation::ProtocolViolation);
+        }
+    };
+
+    hand_over(ctx, peer_addr, released);
+    if let Some(timeout) = expiry {
+        expire_after(ctx, peer_addr, timeout);
+    }
+}
+
+fn expire_after(ctx: &Ctx, peer_addr: SocketAddr, timeout: Duration) {
+    let clock = ctx.with(|c| c.clock.clone());
+    let ctx_clone = ctx.clone();
+    let leaf = clock.delay(clock.now() + timeout).map(move |()| {
+        let ctx = &ctx_clone;
+        let (released, is_still_waiting) = ctx.with_mut(|c| {
+            let now = c.clock.now();
+            match c.connections.get_mut(&peer_addr) {
+                Some(conn) => {
+                    let released = conn.reorder_buf.expire(now, timeout);
+                    if !released.is_empty() {
+                        debug!(
+                            "Gave up waiting for messages from peer {} after {:?}",
+                            peer_addr, timeout
+                        );
+                    }
+                    let is_still_waiting =
+                        !released.is_empty() && conn.reorder_buf.gap_since().is_some();
+                    (released, is_still_waiting)
+                }
+                None => (Vec::new(), false),
+            }
+        });
+
+        hand_over(ctx, peer_addr, released);
+        if is_still_waiting {
+            expire_after(ctx, peer_addr, timeout);
+        }
+    });
+
+    ctx.spawn(leaf);
+}
+
+fn hand_over(ctx: &Ctx, peer_addr: SocketAddr, msgs: Vec<WireMsg>) {
+    for wire_msg in msgs {
+        communicate::handle_wire_msg(ctx, peer_addr, wire_msg);
+    }
+}
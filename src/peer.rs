@@ -7,36 +7,64 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::error::Error;
 use crate::utils;
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Version of the human-readable form of `NodeInfo`, bumped on incompatible changes to it.
+const NODE_INFO_FORMAT_VERSION: u32 = 1;
 
 /// Representation of a peer to us.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum Peer {
-    Node { node_info: NodeInfo },
-    Client { peer_addr: SocketAddr },
+    Node {
+        node_info: NodeInfo,
+    },
+    Client {
+        peer_addr: SocketAddr,
+        client_info: ClientInfo,
+    },
+}
+
+/// Whether the peer is a node or a client, as it introduced itself in its handshake.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerKind {
+    Node,
+    Client,
 }
 
 impl Peer {
+    /// Whether the peer is a node or a client
+    pub fn kind(&self) -> PeerKind {
+        match *self {
+            Peer::Node { .. } => PeerKind::Node,
+            Peer::Client { .. } => PeerKind::Client,
+        }
+    }
+
     /// Get peer's Endpoint
     pub fn peer_addr(&self) -> SocketAddr {
         match *self {
             Peer::Node { ref node_info } => node_info.peer_addr,
-            Peer::Client { peer_addr } => peer_addr,
+            Peer::Client { peer_addr, .. } => peer_addr,
         }
     }
 
     /// Get peer's Certificate
     ///
-    /// If the peer was a node then the function returns the certificate used by it. For clients it
-    /// is not useful in our network to share certificates as we don't reverse connect to the
-    /// clients. Due to absence of the knowledge of client's certificate (as it's not exchanged in
-    /// handshake) this function retuns `None` for client peers.
+    /// Nodes are connected back to using it. For clients it only identifies them across their
+    /// connections, as we don't reverse connect to them.
     pub fn peer_cert_der(&self) -> Option<&[u8]> {
         match *self {
             Peer::Node { ref node_info } => Some(&node_info.peer_cert_der),
-            Peer::Client { .. } => None,
+            Peer::Client {
+                ref client_info, ..
+            } => Some(&client_info.peer_cert_der),
         }
     }
 }
@@ -44,7 +72,11 @@ impl Peer {
 /// Information for a peer of type `Peer::Node`.
 ///
 /// This is a necessary information needed to connect to someone.
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+///
+/// In human-readable formats such as JSON it's serialised along with the version of that form and
+/// with the certificate in base64, e.g. for config files. The forms written before, with the
+/// certificate as an array of bytes, are still read. Binary formats are unaffected.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NodeInfo {
     /// Endpoint of the node
     pub peer_addr: SocketAddr,
@@ -52,19 +84,356 @@ pub struct NodeInfo {
     pub peer_cert_der: Vec<u8>,
 }
 
+impl NodeInfo {
+    /// Short digest of the node's certificate, e.g. for telling nodes apart in logs.
+    pub fn fingerprint(&self) -> String {
+        utils::cert_fingerprint(&self.peer_cert_der)
+    }
+}
+
+/// Form of `NodeInfo` in binary formats, unchanged since the first release as it goes on the wire.
+#[derive(Serialize, Deserialize)]
+struct BinaryNodeInfo {
+    peer_addr: SocketAddr,
+    peer_cert_der: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReadableNodeInfo {
+    /// Missing from the form written before versioning
+    #[serde(default)]
+    version: u32,
+    peer_addr: SocketAddr,
+    #[serde(with = "readable_cert")]
+    peer_cert_der: Vec<u8>,
+}
+
+impl Serialize for NodeInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            ReadableNodeInfo {
+                version: NODE_INFO_FORMAT_VERSION,
+                peer_addr: self.peer_addr,
+                peer_cert_der: self.peer_cert_der.clone(),
+            }
+            .serialize(serializer)
+        } else {
+            BinaryNodeInfo {
+                peer_addr: self.peer_addr,
+                peer_cert_der: self.peer_cert_der.clone(),
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let readable = ReadableNodeInfo::deserialize(deserializer)?;
+            if readable.version > NODE_INFO_FORMAT_VERSION {
+                return Err(de::Error::custom(format!(
+                    "NodeInfo format version {} is newer than the supported {}",
+                    readable.version, NODE_INFO_FORMAT_VERSION
+                )));
+            }
+            Ok(NodeInfo {
+                peer_addr: readable.peer_addr,
+                peer_cert_der: readable.peer_cert_der,
+            })
+        } else {
+            let binary = BinaryNodeInfo::deserialize(deserializer)?;
+            Ok(NodeInfo {
+                peer_addr: binary.peer_addr,
+                peer_cert_der: binary.peer_cert_der,
+            })
+        }
+    }
+}
+
 impl Into<Peer> for NodeInfo {
     fn into(self) -> Peer {
         Peer::Node { node_info: self }
     }
 }
 
-impl fmt::Display for NodeInfo {
+/// Address a node is given by in `Config::hard_coded_contacts`: either an IP address or a hostname
+/// which is resolved when the node is dialled, each along with the port.
+///
+/// It's serialised as a string, `1.2.3.4:5483`, `[::1]:5483` or `seed.example.com:5483`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeAddr {
+    /// IP address and port of the node
+    Socket(SocketAddr),
+    /// Hostname and port of the node
+    Host { host: String, port: u16 },
+}
+
+impl FromStr for NodeAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        if let Ok(peer_addr) = s.parse() {
+            return Ok(NodeAddr::Socket(peer_addr));
+        }
+
+        let invalid = || Error::InvalidNodeAddr(s.to_string());
+        let colon = s.rfind(':').ok_or_else(invalid)?;
+        let (host, port) = (&s[..colon], &s[colon + 1..]);
+        let port = port.parse().map_err(|_| invalid())?;
+        // Colons are left in IPv6 addresses only, which must be in brackets
+        if host.is_empty() || host.contains(':') || host.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+
+        Ok(NodeAddr::Host {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NodeAddr::Socket(peer_addr) => write!(f, "{}", peer_addr),
+            NodeAddr::Host { ref host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl From<SocketAddr> for NodeAddr {
+    fn from(peer_addr: SocketAddr) -> Self {
+        NodeAddr::Socket(peer_addr)
+    }
+}
+
+impl Serialize for NodeAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Hard coded contact: like `NodeInfo` except that the node may be given by hostname.
+///
+/// Its human-readable form is the one of `NodeInfo` with the address as a string, so the contacts
+/// given by IP in config files written before read as they are.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct Contact {
+    /// Address of the node
+    pub peer_addr: NodeAddr,
+    /// Certificate of the node
+    #[serde(with = "readable_cert")]
+    pub peer_cert_der: Vec<u8>,
+}
+
+impl Contact {
+    /// The contact as `NodeInfo` if it's given by IP address.
+    pub fn node_info(&self) -> Option<NodeInfo> {
+        match self.peer_addr {
+            NodeAddr::Socket(peer_addr) => Some(NodeInfo {
+                peer_addr,
+                peer_cert_der: self.peer_cert_der.clone(),
+            }),
+            NodeAddr::Host { .. } => None,
+        }
+    }
+}
+
+impl From<NodeInfo> for Contact {
+    fn from(node_info: NodeInfo) -> Self {
+        Contact {
+            peer_addr: NodeAddr::Socket(node_info.peer_addr),
+            peer_cert_der: node_info.peer_cert_der,
+        }
+    }
+}
+
+impl fmt::Display for Contact {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "NodeInfo {{ peer_addr: {}, peer_cert_der: {} }}",
+            "{} ({})",
             self.peer_addr,
-            utils::bin_data_format(&self.peer_cert_der)
+            utils::cert_fingerprint(&self.peer_cert_der)
         )
     }
 }
+
+/// Information identifying a peer of type `Peer::Client`.
+///
+/// Clients don't listen for connections so, unlike `NodeInfo`, this holds no endpoint: clients are
+/// only ever reached over the connections they make themselves.
+///
+/// Like `NodeInfo`, the certificate is in base64 in human-readable formats.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct ClientInfo {
+    /// Certificate of the client
+    #[serde(with = "readable_cert")]
+    pub peer_cert_der: Vec<u8>,
+}
+
+impl ClientInfo {
+    /// Short digest of the client's certificate, e.g. for telling clients apart in logs.
+    pub fn fingerprint(&self) -> String {
+        utils::cert_fingerprint(&self.peer_cert_der)
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "client {}", self.fingerprint())
+    }
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.peer_addr, self.fingerprint())
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Peer::Node { ref node_info } => write!(f, "node {}", node_info),
+            Peer::Client {
+                peer_addr,
+                ref client_info,
+            } => write!(f, "client {} ({})", peer_addr, client_info.fingerprint()),
+        }
+    }
+}
+
+/// Certificates in base64 in human-readable formats, accepting the arrays of bytes written before
+/// too. Binary formats keep the bytes as they are.
+mod readable_cert {
+    use serde::de::{self, Deserializer};
+    use serde::ser::Serializer;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ReadableCert {
+        Base64(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(cert_der: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(cert_der))
+        } else {
+            cert_der.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Vec::deserialize(deserializer);
+        }
+
+        match ReadableCert::deserialize(deserializer)? {
+            ReadableCert::Base64(encoded) => base64::decode(&encoded).map_err(de::Error::custom),
+            ReadableCert::Bytes(cert_der) => Ok(cert_der),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_info() -> NodeInfo {
+        NodeInfo {
+            peer_addr: unwrap!("127.0.0.1:5000".parse()),
+            peer_cert_der: vec![1, 2, 3, 250],
+        }
+    }
+
+    #[test]
+    fn node_info_is_readable_in_json_and_unchanged_in_bincode() {
+        let node_info = node_info();
+
+        let json = unwrap!(serde_json::to_string(&node_info));
+        assert_eq!(
+            json,
+            r#"{"version":1,"peer_addr":"127.0.0.1:5000","peer_cert_der":"AQID+g=="}"#
+        );
+        assert_eq!(unwrap!(serde_json::from_str::<NodeInfo>(&json)), node_info);
+
+        let serialised = unwrap!(bincode::serialize(&node_info));
+        let legacy = unwrap!(bincode::serialize(&BinaryNodeInfo {
+            peer_addr: node_info.peer_addr,
+            peer_cert_der: node_info.peer_cert_der.clone(),
+        }));
+        assert_eq!(serialised, legacy);
+        assert_eq!(
+            unwrap!(bincode::deserialize::<NodeInfo>(&serialised)),
+            node_info
+        );
+    }
+
+    #[test]
+    fn node_info_written_before_versioning_is_still_read() {
+        let legacy = r#"{"peer_addr":"127.0.0.1:5000","peer_cert_der":[1,2,3,250]}"#;
+        assert_eq!(
+            unwrap!(serde_json::from_str::<NodeInfo>(legacy)),
+            node_info()
+        );
+
+        let from_the_future =
+            r#"{"version":2,"peer_addr":"127.0.0.1:5000","peer_cert_der":"AQID+g=="}"#;
+        assert!(serde_json::from_str::<NodeInfo>(from_the_future).is_err());
+    }
+
+    #[test]
+    fn peers_are_displayed_with_their_fingerprint() {
+        let node_info = node_info();
+        let fingerprint = node_info.fingerprint();
+        assert_eq!(fingerprint.len(), 16);
+        let peer: Peer = node_info.into();
+        assert_eq!(
+            peer.to_string(),
+            format!("node 127.0.0.1:5000 ({})", fingerprint)
+        );
+    }
+
+    #[test]
+    fn contacts_are_read_by_ip_address_or_hostname() {
+        let written_as_node_info = unwrap!(serde_json::to_string(&node_info()));
+        let contact = unwrap!(serde_json::from_str::<Contact>(&written_as_node_info));
+        assert_eq!(contact, Contact::from(node_info()));
+        assert_eq!(contact.node_info(), Some(node_info()));
+
+        let json = r#"{"peer_addr":"seed.example.com:5483","peer_cert_der":"AQID+g=="}"#;
+        let contact = unwrap!(serde_json::from_str::<Contact>(json));
+        assert_eq!(
+            contact.peer_addr,
+            NodeAddr::Host {
+                host: "seed.example.com".to_string(),
+                port: 5483,
+            }
+        );
+        assert_eq!(contact.node_info(), None);
+        assert_eq!(unwrap!(serde_json::to_string(&contact)), json);
+
+        let v6: NodeAddr = unwrap!("[::1]:5483".parse());
+        assert_eq!(v6, NodeAddr::Socket(unwrap!("[::1]:5483".parse())));
+        for invalid in &[
+            "seed.example.com",
+            ":5483",
+            "::1:5483",
+            "seed:port",
+            "a b:1",
+        ] {
+            assert!(invalid.parse::<NodeAddr>().is_err(), "{}", invalid);
+        }
+    }
+}
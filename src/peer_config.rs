@@ -7,8 +7,12 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::context::ctx;
+use crate::cert_rotation::ServerCert;
+use crate::config::OurType;
+use crate::connection::QConn;
+use crate::context::Ctx;
 use crate::R;
+use rustls::{Certificate, ClientCertVerified, ClientCertVerifier, DistinguishedNames, TLSError};
 use std::sync::Arc;
 
 /// Default interval within which if we hear nothing from the peer we declare it offline to us.
@@ -23,50 +27,149 @@ pub const DEFAULT_IDLE_TIMEOUT_MSEC: u64 = 30_000; // 30secs
 ///
 /// The value is in milliseconds.
 pub const DEFAULT_KEEP_ALIVE_INTERVAL_MSEC: u32 = 10_000; // 10secs
+/// Default number of bytes the peer may send on a single stream before we have read them. Big
+/// enough for a single stream to make use of fast links with a fair bit of latency.
+pub const DEFAULT_STREAM_RECEIVE_WINDOW: u64 = 8 * 1024 * 1024; // 8 MiB
+/// Default number of bytes the peer may send on all the streams of a connection together before
+/// we have read them.
+pub const DEFAULT_CONNECTION_RECEIVE_WINDOW: u64 = 32 * 1024 * 1024; // 32 MiB
 
-pub fn new_client_cfg(peer_cert_der: &[u8]) -> R<quinn::ClientConfig> {
+pub fn new_client_cfg(ctx: &Ctx, peer_cert_der: &[u8]) -> R<quinn::ClientConfig> {
     let peer_cert = quinn::Certificate::from_der(peer_cert_der)?;
 
     let mut peer_cfg_builder = {
         let mut client_cfg = quinn::ClientConfig::default();
-        client_cfg.transport = Arc::new(new_transport_cfg(None, None));
+        client_cfg.transport = Arc::new(ctx.with(|c| {
+            new_transport_cfg(
+                c.idle_timeout_msec,
+                c.keep_alive_interval_msec,
+                c.stream_receive_window,
+                c.connection_receive_window,
+            )
+        }));
 
         quinn::ClientConfigBuilder::new(client_cfg)
     };
     peer_cfg_builder.add_certificate_authority(peer_cert)?;
+    let alpn_protocols = ctx.with(|c| c.alpn_protocols.clone());
+    if !alpn_protocols.is_empty() {
+        let _ = peer_cfg_builder.protocols(&as_slices(&alpn_protocols));
+    }
 
-    Ok(peer_cfg_builder.build())
+    let mut peer_cfg = peer_cfg_builder.build();
+    let our_client_cert = ctx.with(|c| {
+        if c.mutual_tls && c.our_type == OurType::Node {
+            Some(c.our_complete_cert.clone())
+        } else {
+            None
+        }
+    });
+    if let Some(our_cert) = our_client_cert {
+        Arc::make_mut(&mut peer_cfg.tls_config).set_single_client_cert(
+            vec![Certificate(our_cert.cert_der)],
+            rustls::PrivateKey(our_cert.key_der),
+        );
+    }
+
+    Ok(peer_cfg)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn new_our_cfg(
     idle_timeout_msec: u64,
     keep_alive_interval_msec: u32,
-    our_cert: quinn::Certificate,
-    our_key: quinn::PrivateKey,
+    stream_receive_window: u64,
+    connection_receive_window: u64,
+    alpn_protocols: &[Vec<u8>],
+    server_cert: &ServerCert,
+    mutual_tls: bool,
 ) -> R<quinn::ServerConfig> {
     let mut our_cfg_builder = {
         let mut our_cfg = quinn::ServerConfig::default();
         our_cfg.transport = Arc::new(new_transport_cfg(
-            Some(idle_timeout_msec),
-            Some(keep_alive_interval_msec),
+            idle_timeout_msec,
+            keep_alive_interval_msec,
+            stream_receive_window,
+            connection_receive_window,
         ));
 
         quinn::ServerConfigBuilder::new(our_cfg)
     };
-    our_cfg_builder.certificate(quinn::CertificateChain::from_certs(vec![our_cert]), our_key)?;
     our_cfg_builder.use_stateless_retry(true);
+    if !alpn_protocols.is_empty() {
+        let _ = our_cfg_builder.protocols(&as_slices(alpn_protocols));
+    }
+
+    // Resolved per connection so that the certificate can be rotated without a new endpoint
+    let mut our_cfg = our_cfg_builder.build();
+    Arc::make_mut(&mut our_cfg.tls_config).cert_resolver = Arc::new(server_cert.clone());
+    if mutual_tls {
+        Arc::make_mut(&mut our_cfg.tls_config)
+            .set_client_certificate_verifier(Arc::new(AnyPeerCert));
+    }
+
+    Ok(our_cfg)
+}
+
+/// Accepts whichever certificate the connecting peer presents, if any: clients present none. Peers
+/// are self-signed so there's no authority to check them against. TLS proves the peer holds the
+/// key of its certificate, while the handshake it sends next must claim that same certificate.
+struct AnyPeerCert;
+
+impl ClientCertVerifier for AnyPeerCert {
+    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(
+        &self,
+        _sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
 
-    Ok(our_cfg_builder.build())
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        _sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        let cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let _ = webpki::EndEntityCert::from(&cert.0).map_err(TLSError::WebPKIError)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Whether the application protocol negotiated for the connection is one we accept. Anything goes
+/// if we haven't configured any.
+pub fn is_alpn_accepted(alpn_protocols: &[Vec<u8>], q_conn: &QConn) -> bool {
+    if alpn_protocols.is_empty() {
+        return true;
+    }
+    q_conn.protocol().map_or(false, |negotiated| {
+        alpn_protocols
+            .iter()
+            .any(|protocol| protocol[..] == negotiated[..])
+    })
+}
+
+fn as_slices(protocols: &[Vec<u8>]) -> Vec<&[u8]> {
+    protocols.iter().map(|protocol| &protocol[..]).collect()
 }
 
 fn new_transport_cfg(
-    idle_timeout_msec: Option<u64>,
-    keep_alive_interval_msec: Option<u32>,
+    idle_timeout_msec: u64,
+    keep_alive_interval_msec: u32,
+    stream_receive_window: u64,
+    connection_receive_window: u64,
 ) -> quinn::TransportConfig {
     let mut transport_cfg = quinn::TransportConfig::default();
-    transport_cfg.idle_timeout = idle_timeout_msec.unwrap_or_else(|| ctx(|c| c.idle_timeout_msec));
-    transport_cfg.keep_alive_interval =
-        keep_alive_interval_msec.unwrap_or_else(|| ctx(|c| c.keep_alive_interval_msec));
+    transport_cfg.idle_timeout = idle_timeout_msec;
+    transport_cfg.keep_alive_interval = keep_alive_interval_msec;
+    transport_cfg.stream_receive_window = stream_receive_window;
+    // A connection window smaller than a stream one would make the latter pointless
+    transport_cfg.receive_window = connection_receive_window.max(stream_receive_window);
 
     transport_cfg
 }
@@ -0,0 +1,85 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Background sweeps of the connections stuck half way through being set up, e.g. because their
+//! connect never concluded, so that they don't accumulate in the `Context`.
+
+use crate::clock;
+use crate::config::StaleConnReaperConfig;
+use crate::connection::ToPeer;
+use crate::context::Ctx;
+use crate::event::Event;
+use crate::wire_msg::CloseReason;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::Stream;
+
+/// Sweep every `sweep_interval_sec` for as long as the event loop runs.
+pub fn start(ctx: &Ctx, cfg: StaleConnReaperConfig) {
+    let interval = Duration::from_secs(cfg.sweep_interval_sec);
+    let max_incomplete = Duration::from_secs(cfg.max_incomplete_sec);
+    let ctx_clone = ctx.clone();
+    let leaf = clock::interval(&ctx.with(|c| c.clock.clone()), interval).for_each(move |_| {
+        sweep(&ctx_clone, max_incomplete);
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+fn sweep(ctx: &Ctx, max_incomplete: Duration) {
+    let stale: Vec<SocketAddr> = ctx.with(|c| {
+        let now = c.clock.now();
+        c.connections
+            .iter()
+            .filter(|(_, conn)| conn.is_incomplete() && now - conn.created_at >= max_incomplete)
+            .map(|(peer_addr, _)| *peer_addr)
+            .collect()
+    });
+
+    for peer_addr in stale {
+        reap(ctx, peer_addr);
+    }
+}
+
+fn reap(ctx: &Ctx, peer_addr: SocketAddr) {
+    ctx.with_mut(|c| {
+        let conn = match c.connections.get_mut(&peer_addr) {
+            Some(conn) => conn,
+            None => return,
+        };
+        info!(
+            "Reaping connection with peer {} which didn't complete in time: {:?}",
+            peer_addr, conn
+        );
+
+        // The connection never completed, so dropping it doesn't report the failure
+        let event = Event::ConnectionFailure {
+            peer_addr,
+            reason: CloseReason::Evicted,
+        };
+        if let Err(e) = c.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+
+        conn.set_close_reason(CloseReason::Evicted);
+        // A connect still in flight cleans up after itself once cancelled, e.g. freeing its slot
+        // among the concurrent connects
+        if let ToPeer::Initiated {
+            ref mut terminator, ..
+        } = conn.to_peer
+        {
+            if terminator.try_send(()).is_ok() {
+                return;
+            }
+        }
+
+        let _ = c.connections.remove(&peer_addr);
+    })
+}
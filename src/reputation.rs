@@ -0,0 +1,241 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Scoring of peer misbehaviour. Every violation adds penalty points to the peer's score and, if
+//! enforcement is configured, peers crossing the thresholds are throttled or blacklisted.
+
+use crate::config::ReputationConfig;
+use crate::context::Ctx;
+use crate::wire_msg::CloseReason;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Window over which incoming connection attempts are counted.
+const CONNECT_ATTEMPTS_WINDOW: Duration = Duration::from_secs(60);
+
+/// Kinds of misbehaviour peers are penalised for.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Violation {
+    /// Malformed message or a message not allowed by the protocol in the current state
+    ProtocolViolation,
+    /// Message, or data buffered on behalf of the peer, exceeding what we allow
+    OversizedMessage,
+    /// Handshake that is illegal or from a foreign network
+    HandshakeFailure,
+    /// More incoming connection attempts than we allow in a given window
+    ExcessiveConnectAttempts,
+    /// Message left incomplete past the read deadline, or more incomplete messages at a time than
+    /// we allow
+    StalledRead,
+}
+
+impl Violation {
+    fn penalty(self) -> u32 {
+        match self {
+            Violation::ProtocolViolation | Violation::HandshakeFailure => 10,
+            Violation::OversizedMessage => 20,
+            Violation::ExcessiveConnectAttempts | Violation::StalledRead => 5,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Record {
+    score: u32,
+    throttled_until: Option<Instant>,
+    is_blacklisted: bool,
+    connect_attempts: VecDeque<Instant>,
+}
+
+/// Scores of the peers which have misbehaved so far.
+#[derive(Default)]
+pub struct Reputation {
+    peers: HashMap<SocketAddr, Record>,
+    enforcement: Option<ReputationConfig>,
+}
+
+impl Reputation {
+    /// Peers are only throttled or blacklisted if `enforcement` is given. Scores are kept
+    /// regardless.
+    pub fn new(enforcement: Option<ReputationConfig>) -> Self {
+        Self {
+            peers: Default::default(),
+            enforcement,
+        }
+    }
+
+    /// Penalty points accumulated by the peer. Zero for well-behaved peers.
+    pub fn score(&self, peer_addr: &SocketAddr) -> u32 {
+        self.peers.get(peer_addr).map_or(0, |record| record.score)
+    }
+
+    /// Penalise the peer for the violation. Returns whether the peer is blacklisted as a result.
+    pub fn record(&mut self, peer_addr: SocketAddr, violation: Violation, now: Instant) -> bool {
+        let record = self.peers.entry(peer_addr).or_insert_with(Default::default);
+        record.score = record.score.saturating_add(violation.penalty());
+
+        let enforcement = match self.enforcement {
+            Some(enforcement) => enforcement,
+            None => return false,
+        };
+
+        if record.score >= enforcement.blacklist_threshold {
+            record.is_blacklisted = true;
+        } else if record.score >= enforcement.throttle_threshold {
+            record.throttled_until =
+                Some(now + Duration::from_secs(enforcement.throttle_duration_sec));
+        }
+
+        record.is_blacklisted
+    }
+
+    /// Note an incoming connection attempt by the peer, penalising it if it's making too many.
+    /// Returns whether the attempt is to be refused.
+    pub fn on_connect_attempt(&mut self, peer_addr: SocketAddr, now: Instant) -> bool {
+        let max_attempts = match self.enforcement {
+            Some(enforcement) => enforcement.max_connect_attempts_per_min as usize,
+            None => return false,
+        };
+
+        let is_excessive = {
+            let record = self.peers.entry(peer_addr).or_insert_with(Default::default);
+            while record.connect_attempts.front().map_or(false, |attempt| {
+                now.duration_since(*attempt) >= CONNECT_ATTEMPTS_WINDOW
+            }) {
+                let _ = record.connect_attempts.pop_front();
+            }
+            record.connect_attempts.push_back(now);
+            record.connect_attempts.len() > max_attempts
+        };
+
+        if is_excessive {
+            let _ = self.record(peer_addr, Violation::ExcessiveConnectAttempts, now);
+        }
+
+        self.is_refused(&peer_addr, now)
+    }
+
+    /// Whether we currently refuse to have anything to do with the peer.
+    pub fn is_refused(&self, peer_addr: &SocketAddr, now: Instant) -> bool {
+        self.peers.get(peer_addr).map_or(false, |record| {
+            record.is_blacklisted || record.throttled_until.map_or(false, |until| until > now)
+        })
+    }
+
+    /// Blacklist the peer straight away, e.g. when restoring an earlier blacklist.
+    pub fn blacklist(&mut self, peer_addr: SocketAddr) {
+        self.peers
+            .entry(peer_addr)
+            .or_insert_with(Default::default)
+            .is_blacklisted = true;
+    }
+
+    /// All the blacklisted peers.
+    pub fn blacklisted<'a>(&'a self) -> impl Iterator<Item = SocketAddr> + 'a {
+        self.peers
+            .iter()
+            .filter(|(_, record)| record.is_blacklisted)
+            .map(|(peer_addr, _)| *peer_addr)
+    }
+
+    /// Whether the peer is blacklisted for good.
+    pub fn is_blacklisted(&self, peer_addr: &SocketAddr) -> bool {
+        self.peers
+            .get(peer_addr)
+            .map_or(false, |record| record.is_blacklisted)
+    }
+}
+
+/// Penalise the peer for the violation, dropping the connection to it if it gets blacklisted as a
+/// result. This must not be called while the `Context` is already borrowed.
+pub fn penalise(ctx: &Ctx, peer_addr: SocketAddr, violation: Violation) {
+    ctx.with_mut(|c| {
+        debug!("Peer {} committed a violation: {:?}", peer_addr, violation);
+        if c.reputation.record(peer_addr, violation, c.clock.now()) {
+            info!(
+                "Peer {} is blacklisted - dropping the connection to it",
+                peer_addr
+            );
+            let _ = c.close_connection(&peer_addr, CloseReason::Refused);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enforcement() -> ReputationConfig {
+        ReputationConfig {
+            throttle_threshold: 20,
+            throttle_duration_sec: 10,
+            blacklist_threshold: 40,
+            max_connect_attempts_per_min: 2,
+        }
+    }
+
+    #[test]
+    fn violations_are_scored_but_not_enforced_by_default() {
+        let peer_addr: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+        let now = Instant::now();
+        let mut reputation = Reputation::new(None);
+
+        for _ in 0..10 {
+            assert!(!reputation.record(peer_addr, Violation::OversizedMessage, now));
+            assert!(!reputation.on_connect_attempt(peer_addr, now));
+        }
+
+        assert_eq!(reputation.score(&peer_addr), 200);
+        assert!(!reputation.is_refused(&peer_addr, now));
+    }
+
+    #[test]
+    fn offenders_are_throttled_and_then_blacklisted() {
+        let peer_addr: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+        let now = Instant::now();
+        let mut reputation = Reputation::new(Some(enforcement()));
+
+        assert!(!reputation.record(peer_addr, Violation::ProtocolViolation, now));
+        assert!(!reputation.is_refused(&peer_addr, now));
+
+        assert!(!reputation.record(peer_addr, Violation::HandshakeFailure, now));
+        assert!(reputation.is_refused(&peer_addr, now));
+        assert!(!reputation.is_refused(&peer_addr, now + Duration::from_secs(10)));
+
+        assert!(reputation.record(peer_addr, Violation::OversizedMessage, now));
+        assert!(reputation.is_blacklisted(&peer_addr));
+        assert!(reputation.is_refused(&peer_addr, now + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn excessive_connect_attempts_are_penalised() {
+        let peer_addr: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+        let now = Instant::now();
+        let mut reputation = Reputation::new(Some(enforcement()));
+
+        assert!(!reputation.on_connect_attempt(peer_addr, now));
+        assert!(!reputation.on_connect_attempt(peer_addr, now));
+        assert_eq!(reputation.score(&peer_addr), 0);
+
+        assert!(!reputation.on_connect_attempt(peer_addr, now));
+        assert_eq!(
+            reputation.score(&peer_addr),
+            Violation::ExcessiveConnectAttempts.penalty()
+        );
+
+        // Attempts older than the window are forgotten
+        let later = now + CONNECT_ATTEMPTS_WINDOW;
+        assert!(!reputation.on_connect_attempt(peer_addr, later));
+        assert_eq!(
+            reputation.score(&peer_addr),
+            Violation::ExcessiveConnectAttempts.penalty()
+        );
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Randomness supplied by the user via `Builder::with_rng`, typically seeded so that test runs can
+//! be replayed exactly. Without one we use the OS randomness.
+
+use crate::config::{CertParams, SerialisableCertificate};
+use rand_core::RngCore;
+use std::sync::{Arc, Mutex};
+
+/// RNG shared by the builder and the event loop.
+#[derive(Clone)]
+pub struct SharedRng(Arc<Mutex<Box<dyn RngCore + Send>>>);
+
+impl SharedRng {
+    pub fn new<T: RngCore + Send + 'static>(rng: T) -> Self {
+        SharedRng(Arc::new(Mutex::new(Box::new(rng))))
+    }
+
+    pub fn fill_bytes(&self, dest: &mut [u8]) {
+        match self.0.lock() {
+            Ok(mut rng) => rng.fill_bytes(dest),
+            Err(poisoned) => poisoned.into_inner().fill_bytes(dest),
+        }
+    }
+
+    /// Certificate for the server name with an Ed25519 key derived from our randomness.
+    pub fn gen_cert(&self, server_name: &str, cert_params: &CertParams) -> SerialisableCertificate {
+        let mut seed = [0; 32];
+        self.fill_bytes(&mut seed);
+        SerialisableCertificate::from_seed(seed, server_name, cert_params)
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Loopback check of our own endpoint, for operators to diagnose broken deployments before
+//! joining a network.
+
+use crate::clock::{self, SharedClock};
+use crate::connection::QConn;
+use crate::context::Ctx;
+use crate::error::Error;
+use crate::wire_msg::{CloseReason, WireMsg};
+use crate::{peer_config, R};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use tokio::prelude::{future, Future, Stream};
+
+/// The whole test is abandoned if it doesn't complete in this time.
+const SELF_TEST_TIMEOUT_SEC: u64 = 10;
+/// Plenty for the frame of a health check response and its two timestamps.
+const MAX_HEALTH_CHECK_RESP_LEN: usize = 64;
+
+/// Outcome of `QuicP2p::self_test`.
+#[derive(Debug, Clone, Default)]
+pub struct SelfTestReport {
+    /// Our endpoint is bound to a local address
+    pub bind_ok: bool,
+    /// QUIC and TLS handshakes with our own listener completed, so our certificate is usable
+    pub handshake_ok: bool,
+    /// Time taken for a health check message to reach our listener and its response to come
+    /// back. Only set if the round trip succeeded.
+    pub round_trip_latency: Option<Duration>,
+    /// What went wrong, if anything did
+    pub failure: Option<String>,
+}
+
+impl SelfTestReport {
+    /// Whether every stage of the test succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.bind_ok && self.handshake_ok && self.round_trip_latency.is_some()
+    }
+}
+
+/// Connect to our own listener and exchange a health check message with it, reporting the
+/// outcome to `tx`. This must not be called while the `Context` is already borrowed.
+pub fn run(ctx: &Ctx, tx: Sender<SelfTestReport>) {
+    let mut report: SelfTestReport = Default::default();
+
+    let our_addr = match ctx.with(|c| c.quic_ep().local_addr()) {
+        Ok(addr) => addr,
+        Err(e) => return fail(tx, report, format!("Endpoint is not bound: {}", e)),
+    };
+    report.bind_ok = true;
+
+    let connecting = ctx.with(|c| -> R<_> {
+        let peer_cfg = peer_config::new_client_cfg(ctx, &c.our_complete_cert.cert_der)?;
+        Ok(c.quic_ep()
+            .connect_with(peer_cfg, &loopback(our_addr), &c.server_name)?)
+    });
+    let connecting = match connecting {
+        Ok(connecting) => connecting,
+        Err(e) => return fail(tx, report, format!("Could not connect to ourselves: {}", e)),
+    };
+
+    let clock = ctx.with(|c| c.clock.clone());
+    let deadline = clock.now() + Duration::from_secs(SELF_TEST_TIMEOUT_SEC);
+
+    let ctx_clone = ctx.clone();
+    let leaf = clock::timeout_at(&clock, connecting, deadline).then(move |res| {
+        let ctx = &ctx_clone;
+        match res {
+            Ok((conn_driver, q_conn, incoming_streams)) => {
+                ctx.spawn_driver(conn_driver, |_, _| ());
+                report.handshake_ok = true;
+                round_trip(ctx, tx, report, q_conn, incoming_streams, clock, deadline);
+            }
+            Err(e) => {
+                let failure = timeout_failure(e, "Handshake with our listener failed");
+                fail(tx, report, failure);
+            }
+        }
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+fn round_trip(
+    ctx: &Ctx,
+    tx: Sender<SelfTestReport>,
+    mut report: SelfTestReport,
+    q_conn: quinn::Connection,
+    incoming_streams: quinn::IncomingStreams,
+    clock: SharedClock,
+    deadline: Instant,
+) {
+    let mut q_conn = QConn::from(q_conn);
+    let frame = WireMsg::HealthCheckReq {
+        sent_at_msec: clock::unix_time_msec(&clock),
+    }
+    .into_frame();
+    let started_at = clock.now();
+
+    let exchange = q_conn
+        .open_uni()
+        .map_err(Error::from)
+        .and_then(move |o_stream| {
+            future::result(frame)
+                .and_then(|frame| tokio::io::write_all(o_stream, frame).map_err(Error::from))
+        })
+        .and_then(|(o_stream, _)| tokio::io::shutdown(o_stream).map_err(Error::from))
+        .and_then(move |_| {
+            incoming_streams
+                .into_future()
+                .map_err(|(e, _)| Error::from(e))
+        })
+        .and_then(|(quic_stream, _)| match quic_stream {
+            Some(quinn::NewStream::Uni(i_stream)) => Ok(i_stream),
+            Some(quinn::NewStream::Bi(_, _)) | None => Err(Error::InvalidWireMsg(
+                "expected the health check response on a uni-directional stream",
+            )),
+        })
+        .and_then(|i_stream| {
+            i_stream
+                .read_to_end(MAX_HEALTH_CHECK_RESP_LEN)
+                .map_err(Error::from)
+        })
+        .and_then(|(_, raw)| match WireMsg::from_bytes_safe(raw)? {
+            WireMsg::HealthCheckResp { .. } => Ok(()),
+            _ => Err(Error::InvalidWireMsg("expected a health check response")),
+        });
+
+    let leaf = clock::timeout_at(&clock, exchange, deadline).then(move |res| {
+        q_conn.set_close_reason(CloseReason::Shutdown);
+        match res {
+            Ok(()) => {
+                report.round_trip_latency = Some(clock.now().duration_since(started_at));
+                if let Err(e) = tx.send(report) {
+                    info!("Could not report self test outcome: {:?}", e);
+                }
+            }
+            Err(e) => {
+                let failure = timeout_failure(e, "Health check exchange with our listener failed");
+                fail(tx, report, failure);
+            }
+        }
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+/// Address to reach our own endpoint at. Unspecified addresses are reached over the loopback.
+fn loopback(our_addr: SocketAddr) -> SocketAddr {
+    let ip = match our_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, our_addr.port())
+}
+
+fn timeout_failure<E: ToString>(e: Option<E>, stage: &str) -> String {
+    match e {
+        Some(e) => format!("{}: {}", stage, e.to_string()),
+        None => format!("{}: timed out", stage),
+    }
+}
+
+fn fail(tx: Sender<SelfTestReport>, mut report: SelfTestReport, failure: String) {
+    info!("Self test failed: {}", failure);
+    report.failure = Some(failure);
+    if let Err(e) = tx.send(report) {
+        info!("Could not report self test outcome: {:?}", e);
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Deficit round robin scheduling of the writes to peers. Flows, i.e. the logical channels to each
+//! peer, take turns writing at most a quantum of bytes each, so a huge transfer to one peer
+//! doesn't hold up small messages to others, nor does a bulk channel hold up the other channels to
+//! the same flow_writes.
+//!
+//! All the flows together can also be held to a maximum rate, see `Config::traffic_profile`.
+
+use crate::context::Ctx;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::prelude::task::{self, Task};
+use tokio::prelude::{Async, Future, Poll};
+
+/// Peer and logical channel the writes are for.
+pub type Flow = (SocketAddr, u8);
+
+#[derive(Default)]
+struct FlowWrites {
+    /// Writes in progress
+    writers: usize,
+    /// Bytes the flow may still write in its current turn
+    deficit: usize,
+    /// The flow's stream can't take any more data for now, so it doesn't hold up the others
+    is_blocked: bool,
+    /// Writers waiting for the flow's turn
+    parked: Vec<Task>,
+}
+
+/// Token bucket holding up to a second's worth of bytes.
+struct RateLimit {
+    bytes_per_sec: u64,
+    available: u64,
+    refilled_at: Instant,
+}
+
+impl RateLimit {
+    fn refill(&mut self, now: Instant) {
+        if now <= self.refilled_at {
+            return;
+        }
+        let elapsed = now - self.refilled_at;
+        let earned = elapsed
+            .as_secs()
+            .saturating_mul(self.bytes_per_sec)
+            .saturating_add(u64::from(elapsed.subsec_nanos()) * self.bytes_per_sec / 1_000_000_000);
+        // Leave the time short of a whole byte to the next refill
+        if earned > 0 {
+            self.available = cmp::min(self.bytes_per_sec, self.available.saturating_add(earned));
+            self.refilled_at = now;
+        }
+    }
+}
+
+/// Decides whose turn it is to write.
+pub struct SendScheduler {
+    quantum: usize,
+    max_rate: Option<RateLimit>,
+    /// Flows with writes in progress, in the order they take turns
+    active: VecDeque<Flow>,
+    flows: HashMap<Flow, FlowWrites>,
+}
+
+impl SendScheduler {
+    /// Each flow gets to write `quantum` bytes per turn.
+    pub fn new(quantum: usize) -> Self {
+        Self {
+            quantum: cmp::max(quantum, 1),
+            max_rate: None,
+            active: Default::default(),
+            flows: Default::default(),
+        }
+    }
+
+    /// Each flow gets to write `quantum` bytes per turn from its next turn on.
+    pub fn set_quantum(&mut self, quantum: usize) {
+        self.quantum = cmp::max(quantum, 1);
+    }
+
+    /// Hold all the flows together to `bytes_per_sec`, or lift the limit if none.
+    pub fn set_max_rate(&mut self, bytes_per_sec: Option<u64>, now: Instant) {
+        self.max_rate = bytes_per_sec.map(|bytes_per_sec| RateLimit {
+            bytes_per_sec: cmp::max(bytes_per_sec, 1),
+            available: bytes_per_sec,
+            refilled_at: now,
+        });
+    }
+
+    /// Number of bytes the rate limit lets us write now, or when to ask again if none.
+    pub fn rate_allowance(&mut self, now: Instant) -> Result<usize, Instant> {
+        let quantum = self.quantum as u64;
+        let rate = match self.max_rate.as_mut() {
+            Some(rate) => rate,
+            None => return Ok(usize::max_value()),
+        };
+        rate.refill(now);
+        if rate.available > 0 {
+            return Ok(cmp::min(rate.available, usize::max_value() as u64) as usize);
+        }
+
+        // Wait for a worthwhile chunk rather than waking up for every byte
+        let chunk = cmp::max(1, cmp::min(quantum, rate.bytes_per_sec / 20));
+        let wait_nanos = chunk.saturating_mul(1_000_000_000) / rate.bytes_per_sec;
+        Err(now + Duration::from_nanos(wait_nanos))
+    }
+
+    /// A write on the flow is starting.
+    pub fn start(&mut self, flow: Flow) {
+        let flow_writes = self.flows.entry(flow).or_insert_with(Default::default);
+        if flow_writes.writers == 0 {
+            self.active.push_back(flow);
+        }
+        flow_writes.writers += 1;
+    }
+
+    /// Number of bytes the flow may write now, if it's its turn.
+    pub fn grant(&mut self, flow: Flow) -> Option<usize> {
+        // We are asked again so the flow's stream must be able to take more data
+        self.flows.get_mut(&flow)?.is_blocked = false;
+
+        if self.turn() != Some(flow) {
+            return None;
+        }
+
+        let quantum = self.quantum;
+        let flow_writes = self.flows.get_mut(&flow)?;
+        if flow_writes.deficit == 0 {
+            flow_writes.deficit = quantum;
+        }
+        Some(flow_writes.deficit)
+    }
+
+    /// Wake the given task once it's the flow's turn.
+    pub fn park(&mut self, flow: Flow, task: Task) {
+        if let Some(flow_writes) = self.flows.get_mut(&flow) {
+            flow_writes.parked.push(task);
+        }
+    }
+
+    /// The flow has written `bytes`. Its turn ends once it has used up its quantum.
+    pub fn consume(&mut self, flow: Flow, bytes: usize) {
+        if let Some(rate) = self.max_rate.as_mut() {
+            rate.available = rate.available.saturating_sub(bytes as u64);
+        }
+
+        let flow_writes = match self.flows.get_mut(&flow) {
+            Some(flow_writes) => flow_writes,
+            None => return,
+        };
+        flow_writes.deficit = flow_writes.deficit.saturating_sub(bytes);
+        if flow_writes.deficit == 0 {
+            self.end_turn(flow);
+        }
+    }
+
+    /// The flow's stream can't take any more data for now, so let the others go ahead.
+    pub fn blocked(&mut self, flow: Flow) {
+        if let Some(flow_writes) = self.flows.get_mut(&flow) {
+            flow_writes.is_blocked = true;
+            self.end_turn(flow);
+        }
+    }
+
+    /// A write on the flow is done, successfully or not.
+    pub fn finish(&mut self, flow: Flow) {
+        let is_last = match self.flows.get_mut(&flow) {
+            Some(flow_writes) => {
+                flow_writes.writers = flow_writes.writers.saturating_sub(1);
+                flow_writes.writers == 0
+            }
+            None => return,
+        };
+
+        if is_last {
+            let _ = self.flows.remove(&flow);
+            self.active.retain(|active| *active != flow);
+        }
+        self.wake_turn();
+    }
+
+    /// First flow in line which isn't blocked.
+    fn turn(&self) -> Option<Flow> {
+        self.active
+            .iter()
+            .find(|flow| {
+                self.flows
+                    .get(flow)
+                    .map_or(false, |flow_writes| !flow_writes.is_blocked)
+            })
+            .cloned()
+    }
+
+    fn end_turn(&mut self, flow: Flow) {
+        if let Some(pos) = self.active.iter().position(|active| *active == flow) {
+            let _ = self.active.remove(pos);
+            self.active.push_back(flow);
+        }
+        self.wake_turn();
+    }
+
+    fn wake_turn(&mut self) {
+        let flow = match self.turn() {
+            Some(flow) => flow,
+            None => return,
+        };
+        if let Some(flow_writes) = self.flows.get_mut(&flow) {
+            for task in flow_writes.parked.drain(..) {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// Write the whole frame to the stream, taking turns with the writes on other flows. Resolves to
+/// the stream once done.
+pub fn write_all<W: AsyncWrite>(
+    ctx: &Ctx,
+    flow: Flow,
+    o_stream: W,
+    frame: bytes::Bytes,
+) -> ScheduledWrite<W> {
+    ScheduledWrite {
+        ctx: ctx.clone(),
+        flow,
+        o_stream: Some(o_stream),
+        frame,
+        written: 0,
+        is_started: false,
+        rate_delay: None,
+    }
+}
+
+pub struct ScheduledWrite<W> {
+    ctx: Ctx,
+    flow: Flow,
+    o_stream: Option<W>,
+    frame: bytes::Bytes,
+    written: usize,
+    is_started: bool,
+    /// Fires once the rate limit lets us write again
+    rate_delay: Option<Box<dyn Future<Item = (), Error = ()>>>,
+}
+
+impl<W: AsyncWrite> Future for ScheduledWrite<W> {
+    type Item = W;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let flow = self.flow;
+        if !self.is_started {
+            self.ctx.with_mut(|c| c.send_scheduler.start(flow));
+            self.is_started = true;
+        }
+
+        while self.written < self.frame.len() {
+            if let Some(rate_delay) = self.rate_delay.as_mut() {
+                if let Ok(Async::NotReady) = rate_delay.poll() {
+                    return Ok(Async::NotReady);
+                }
+                self.rate_delay = None;
+            }
+
+            let grant = self.ctx.with_mut(|c| {
+                let grant = c.send_scheduler.grant(flow);
+                if grant.is_none() {
+                    c.send_scheduler.park(flow, task::current());
+                }
+                grant
+            });
+            let grant = match grant {
+                Some(grant) => grant,
+                None => return Ok(Async::NotReady),
+            };
+            let allowance = self.ctx.with_mut(|c| {
+                let now = c.clock.now();
+                c.send_scheduler
+                    .rate_allowance(now)
+                    .map_err(|deadline| c.clock.delay(deadline))
+            });
+            let grant = match allowance {
+                Ok(allowance) => cmp::min(grant, allowance),
+                Err(rate_delay) => {
+                    self.rate_delay = Some(rate_delay);
+                    continue;
+                }
+            };
+
+            let end = cmp::min(self.frame.len(), self.written + grant);
+            let o_stream = match self.o_stream.as_mut() {
+                Some(o_stream) => o_stream,
+                None => return Err(io::ErrorKind::Other.into()),
+            };
+            match o_stream.poll_write(&self.frame[self.written..end]) {
+                Ok(Async::Ready(0)) => {
+                    self.ctx.with_mut(|c| c.send_scheduler.finish(flow));
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(Async::Ready(written)) => {
+                    self.written += written;
+                    self.ctx
+                        .with_mut(|c| c.send_scheduler.consume(flow, written));
+                }
+                Ok(Async::NotReady) => {
+                    self.ctx.with_mut(|c| c.send_scheduler.blocked(flow));
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => {
+                    self.ctx.with_mut(|c| c.send_scheduler.finish(flow));
+                    return Err(e);
+                }
+            }
+        }
+
+        self.ctx.with_mut(|c| c.send_scheduler.finish(flow));
+        match self.o_stream.take() {
+            Some(o_stream) => Ok(Async::Ready(o_stream)),
+            None => Err(io::ErrorKind::Other.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flows_take_turns_writing_a_quantum_each() {
+        let peer_addr: SocketAddr = unwrap!("127.0.0.1:1000".parse());
+        // Channels to the same peer take turns just like different peers do
+        let flow1 = (peer_addr, 0);
+        let flow2 = (peer_addr, 1);
+        let mut scheduler = SendScheduler::new(10);
+
+        scheduler.start(flow1);
+        scheduler.start(flow2);
+        assert_eq!(scheduler.grant(flow2), None);
+        assert_eq!(scheduler.grant(flow1), Some(10));
+
+        scheduler.consume(flow1, 4);
+        assert_eq!(scheduler.grant(flow1), Some(6));
+        scheduler.consume(flow1, 6);
+
+        // Quantum used up so it's the other flow's turn now
+        assert_eq!(scheduler.grant(flow1), None);
+        assert_eq!(scheduler.grant(flow2), Some(10));
+        scheduler.finish(flow2);
+
+        assert_eq!(scheduler.grant(flow1), Some(10));
+    }
+
+    #[test]
+    fn blocked_flow_does_not_hold_up_others() {
+        let flow1: Flow = (unwrap!("127.0.0.1:1000".parse()), 0);
+        let flow2: Flow = (unwrap!("127.0.0.1:2000".parse()), 0);
+        let mut scheduler = SendScheduler::new(10);
+
+        scheduler.start(flow1);
+        scheduler.start(flow2);
+        assert_eq!(scheduler.grant(flow1), Some(10));
+        scheduler.consume(flow1, 3);
+        scheduler.blocked(flow1);
+
+        assert_eq!(scheduler.grant(flow2), Some(10));
+        scheduler.consume(flow2, 10);
+
+        // Unblocked flow carries on with the rest of its quantum
+        assert_eq!(scheduler.grant(flow1), Some(7));
+    }
+
+    #[test]
+    fn rate_limit_holds_back_all_flows_together() {
+        let flow1: Flow = (unwrap!("127.0.0.1:1000".parse()), 0);
+        let flow2: Flow = (unwrap!("127.0.0.1:2000".parse()), 0);
+        let start = Instant::now();
+        let mut scheduler = SendScheduler::new(10);
+        scheduler.set_max_rate(Some(100), start);
+
+        assert_eq!(scheduler.rate_allowance(start), Ok(100));
+        scheduler.start(flow1);
+        scheduler.start(flow2);
+        scheduler.consume(flow1, 60);
+        scheduler.consume(flow2, 40);
+
+        // Used up for now, so wait for the next chunk: a twentieth of a second's worth
+        assert_eq!(
+            scheduler.rate_allowance(start),
+            Err(start + Duration::from_millis(50))
+        );
+        assert_eq!(
+            scheduler.rate_allowance(start + Duration::from_millis(250)),
+            Ok(25)
+        );
+        // Never more than a second's worth
+        assert_eq!(
+            scheduler.rate_allowance(start + Duration::from_secs(10)),
+            Ok(100)
+        );
+
+        scheduler.set_max_rate(None, start);
+        assert_eq!(scheduler.rate_allowance(start), Ok(usize::max_value()));
+    }
+
+    #[test]
+    fn sole_flow_keeps_writing() {
+        let flow: Flow = (unwrap!("127.0.0.1:1000".parse()), 0);
+        let mut scheduler = SendScheduler::new(10);
+
+        scheduler.start(flow);
+        for _ in 0..3 {
+            assert_eq!(scheduler.grant(flow), Some(10));
+            scheduler.consume(flow, 10);
+        }
+        scheduler.finish(flow);
+        assert_eq!(scheduler.grant(flow), None);
+    }
+}
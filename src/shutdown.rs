@@ -0,0 +1,92 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Shutting down on our own once the application stopped taking our events, see
+//! `Config::on_event_channel_closed`. Otherwise we'd keep our connections and timers going with
+//! no one to hear from them until the last `QuicP2p` handle is dropped, if ever.
+
+use crate::context::Ctx;
+use crate::event_loop::EventLoopMsg;
+use crate::wire_msg::CloseReason;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Shuts the instance down the first time it's triggered.
+#[derive(Clone)]
+pub struct ShutdownTrigger {
+    el_tx: UnboundedSender<EventLoopMsg>,
+    instance_id: usize,
+    is_triggered: Arc<AtomicBool>,
+}
+
+impl ShutdownTrigger {
+    pub fn new(el_tx: UnboundedSender<EventLoopMsg>, instance_id: usize) -> Self {
+        Self {
+            el_tx,
+            instance_id,
+            is_triggered: Default::default(),
+        }
+    }
+
+    /// Shut down unless we already are. The shutdown is posted to the event loop, so this is fine
+    /// to call with the `Context` borrowed, or once the event loop is gone.
+    pub fn trigger(&self) {
+        if self.is_triggered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let mut el_tx = self.el_tx.clone();
+        let instance_id = self.instance_id;
+        // Failing to post means the event loop is already gone. It's left running otherwise, as
+        // other instances may share it, with nothing of ours on it once our context is closed.
+        let msg = EventLoopMsg::new(move |instances| instances.enter(instance_id, shutdown));
+        if el_tx.try_send(msg).is_ok() {
+            let _ = el_tx.try_send(EventLoopMsg::new(move |instances| {
+                instances.close(instance_id)
+            }));
+        }
+    }
+}
+
+fn shutdown(ctx: &Ctx) {
+    warn!("No one is taking our events anymore - shutting down");
+
+    ctx.with_mut(|c| {
+        c.is_accepting_incoming = false;
+        let peer_addrs: Vec<_> = c.connections.keys().cloned().collect();
+        for peer_addr in peer_addrs {
+            let _ = c.close_connection(&peer_addr, CloseReason::Shutdown);
+        }
+        c.lifetime_stats.save();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_loop::{EventLoop, Instance};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn triggering_after_the_instance_is_dropped_leaves_the_event_loop_running() {
+        let el = Arc::new(EventLoop::spawn());
+        let instance = Instance::new(el.clone());
+        let trigger = ShutdownTrigger::new(instance.sender(), instance.id());
+
+        // Closing the instance is queued ahead of the shutdown, which then has nothing to shut
+        drop(instance);
+        trigger.trigger();
+
+        let (tx, rx) = mpsc::channel();
+        el.post(move |_| unwrap!(tx.send(())));
+        unwrap!(rx.recv_timeout(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Binding of the UDP socket our endpoint runs on, with the options asked for via
+//! `Config::socket_options` applied. Options the platform doesn't support or the OS refuses are
+//! logged and skipped rather than failing the bind.
+
+use crate::config::SocketOptions;
+use crate::transport::Transport;
+use socket2::Socket;
+use std::io;
+use std::net::{IpAddr, UdpSocket};
+
+/// DSCP is the upper six bits of the TOS / traffic class byte.
+const MAX_DSCP: u8 = 0b11_1111;
+/// Ports after a taken one tried with `Config::port_fallback` before leaving it to the OS.
+const PORT_FALLBACK_ATTEMPTS: u16 = 16;
+
+/// Bind to the given address and apply the requested options. Returns the socket along with the
+/// options in effect on it as reported by the OS, which can differ from the requested ones (e.g.
+/// Linux doubles the buffer sizes and caps them at system wide maximums).
+pub fn bind(
+    ip: IpAddr,
+    port: u16,
+    requested: &SocketOptions,
+) -> io::Result<(UdpSocket, SocketOptions)> {
+    let socket = Socket::from(UdpSocket::bind((ip, port))?);
+
+    if let Some(size) = requested.recv_buf {
+        if let Err(e) = socket.set_recv_buffer_size(size) {
+            warn!(
+                "Could not set the socket receive buffer size to {}: {}",
+                size, e
+            );
+        }
+    }
+    if let Some(size) = requested.send_buf {
+        if let Err(e) = socket.set_send_buffer_size(size) {
+            warn!(
+                "Could not set the socket send buffer size to {}: {}",
+                size, e
+            );
+        }
+    }
+    if let Some(dscp) = requested.dscp {
+        if let Err(e) = set_dscp(&socket, ip.is_ipv6(), dscp) {
+            warn!("Could not set the socket DSCP to {}: {}", dscp, e);
+        }
+    }
+
+    let effective = SocketOptions {
+        recv_buf: socket.recv_buffer_size().ok(),
+        send_buf: socket.send_buffer_size().ok(),
+        dscp: dscp(&socket, ip.is_ipv6()).unwrap_or(None),
+    };
+    debug!("Socket options in effect: {:?}", effective);
+
+    Ok((socket.into_udp_socket(), effective))
+}
+
+/// Bind via the transport, moving on to the next few ports and then to a random one if the
+/// requested port is taken.
+pub fn bind_with_fallback(
+    transport: &dyn Transport,
+    ip: IpAddr,
+    port: u16,
+    requested: &SocketOptions,
+) -> io::Result<(UdpSocket, SocketOptions)> {
+    match transport.bind(ip, port, requested) {
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => (),
+        res => return res,
+    }
+
+    let next_ports = (1..=PORT_FALLBACK_ATTEMPTS).filter_map(|offset| port.checked_add(offset));
+    for next_port in next_ports {
+        match transport.bind(ip, next_port, requested) {
+            Ok(bound) => {
+                info!("Port {} is taken - bound to {} instead", port, next_port);
+                return Ok(bound);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    info!(
+        "Port {} and the {} after it are taken - binding to a random port",
+        port, PORT_FALLBACK_ATTEMPTS
+    );
+    transport.bind(ip, 0, requested)
+}
+
+#[cfg(unix)]
+fn set_dscp(socket: &Socket, is_ipv6: bool, dscp: u8) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    if dscp > MAX_DSCP {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "DSCP must fit in six bits",
+        ));
+    }
+
+    let (level, name) = tos_option(is_ipv6);
+    let tos = libc::c_int::from(dscp << 2);
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &tos as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(unix)]
+fn dscp(socket: &Socket, is_ipv6: bool) -> io::Result<Option<u8>> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = tos_option(is_ipv6);
+    let mut tos: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut tos as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res == 0 {
+        Ok(Some((tos >> 2) as u8 & MAX_DSCP))
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(unix)]
+fn tos_option(is_ipv6: bool) -> (libc::c_int, libc::c_int) {
+    if is_ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    }
+}
+
+// Windows ignores the TOS set on sockets unless the system is configured to allow it, and offers
+// the QoS2 API instead, so DSCP marking is left to the system's policies there.
+#[cfg(not(unix))]
+fn set_dscp(_socket: &Socket, _is_ipv6: bool, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "DSCP marking is not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+fn dscp(_socket: &Socket, _is_ipv6: bool) -> io::Result<Option<u8>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn requested_options_are_applied_and_reported() {
+        let requested = SocketOptions {
+            recv_buf: Some(64 * 1024),
+            send_buf: Some(64 * 1024),
+            dscp: Some(46),
+        };
+        let (_udp, effective) = unwrap!(bind(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, &requested));
+
+        assert!(unwrap!(effective.recv_buf) >= 64 * 1024);
+        assert!(unwrap!(effective.send_buf) >= 64 * 1024);
+        if cfg!(unix) {
+            assert_eq!(effective.dscp, Some(46));
+        }
+    }
+}
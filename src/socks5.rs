@@ -0,0 +1,615 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Relaying of all our UDP traffic through a SOCKS5 proxy via a UDP association (RFC 1928), see
+//! `Config::upstream_proxy`.
+//!
+//! quinn sends straight from the socket it's given, so packets can't be wrapped for the proxy on
+//! their way out of the endpoint. Instead the endpoint's socket is bound to the loopback interface
+//! and each peer we connect to is stood in for by a socket of ours there: what quinn sends to it
+//! goes on to the peer via the proxy, and what the proxy relays from the peer is sent to quinn from
+//! it. The rest of the crate never sees those addresses, `Transport::wire_addr` and
+//! `Transport::peer_addr` translate between them and the ones of the peers.
+//!
+//! Stand-ins are only opened by `Transport::wire_addr`, i.e. for peers we connect to, and datagrams
+//! the proxy relays from any other peer are dropped. They are closed again once idle for longer
+//! than a connection over them could be. A single thread runs the relay for all of them.
+//!
+//! The association only lasts for as long as the TCP connection to the proxy it was asked for
+//! over, so that is kept open until the endpoint is rebound or we are dropped.
+
+use crate::config::{ProxyConfig, SocketOptions};
+use crate::socket;
+use crate::transport::Transport;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::prelude::{Async, Future, Poll, Stream};
+use tokio::reactor::Handle;
+use tokio::runtime::current_thread;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::timer::Interval;
+
+/// Big enough for any UDP datagram.
+const MAX_DATAGRAM_LEN: usize = 65_535;
+/// Time the proxy gets to answer each step of setting up the association.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Peers we stand in for. Connecting to further ones fails.
+const MAX_STAND_INS: usize = 1_024;
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USERNAME_PASSWORD: u8 = 2;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const REPLY_SUCCEEDED: u8 = 0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// Relays through the SOCKS5 proxy. Used in place of `UdpTransport` when a
+/// `Config::upstream_proxy` is given.
+pub struct Socks5Transport {
+    cfg: ProxyConfig,
+    /// Stand-ins idle for longer than this are closed
+    idle_timeout: Duration,
+    relay: Mutex<Option<Relay>>,
+}
+
+impl Socks5Transport {
+    /// `idle_timeout` is the one of our connections, which can't outlast a stand-in that's idle.
+    pub fn new(cfg: ProxyConfig, idle_timeout: Duration) -> Self {
+        Self {
+            cfg,
+            idle_timeout,
+            relay: Mutex::new(None),
+        }
+    }
+}
+
+impl Transport for Socks5Transport {
+    /// The address asked for is of no use, as the peers see us at the proxy whatever it is.
+    fn bind(
+        &self,
+        _ip: IpAddr,
+        _port: u16,
+        requested: &SocketOptions,
+    ) -> io::Result<(net::UdpSocket, SocketOptions)> {
+        let (udp, effective) = socket::bind(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, requested)?;
+        let relay = Relay::start(&self.cfg, udp.local_addr()?, self.idle_timeout)?;
+        let old_relay = lock(&self.relay).replace(relay);
+        // Ends the association of the endpoint we are rebinding, if any. Not under the lock as
+        // this waits for its relay thread to finish.
+        drop(old_relay);
+        Ok((udp, effective))
+    }
+
+    fn wire_addr(&self, peer_addr: SocketAddr) -> io::Result<SocketAddr> {
+        match *lock(&self.relay) {
+            Some(ref relay) => relay.stand_in(peer_addr),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No association with the SOCKS5 proxy",
+            )),
+        }
+    }
+
+    fn peer_addr(&self, wire_addr: SocketAddr) -> SocketAddr {
+        lock(&self.relay)
+            .as_ref()
+            .and_then(|relay| lock(&relay.stand_ins).peers.get(&wire_addr).cloned())
+            .unwrap_or(wire_addr)
+    }
+}
+
+/// Association with the proxy, relayed until dropped.
+struct Relay {
+    stand_ins: Arc<Mutex<StandIns>>,
+    /// Hands the sockets of new stand-ins to the relay thread. It stops once this is dropped.
+    new_stand_ins: Option<UnboundedSender<(SocketAddr, net::UdpSocket)>>,
+    thread: Option<JoinHandle<()>>,
+    _control: TcpStream,
+}
+
+impl Relay {
+    fn start(
+        cfg: &ProxyConfig,
+        quinn_addr: SocketAddr,
+        idle_timeout: Duration,
+    ) -> io::Result<Self> {
+        let mut control = TcpStream::connect_timeout(&cfg.addr, NEGOTIATION_TIMEOUT)?;
+        control.set_read_timeout(Some(NEGOTIATION_TIMEOUT))?;
+        control.set_write_timeout(Some(NEGOTIATION_TIMEOUT))?;
+        authenticate(&mut control, cfg)?;
+
+        let unspecified: IpAddr = if cfg.addr.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let outer = net::UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+        let relay_addr = associate(&mut control, cfg.addr, outer.local_addr()?)?;
+        info!(
+            "Relaying through SOCKS5 proxy {} at {}",
+            cfg.addr, relay_addr
+        );
+
+        let stand_ins: Arc<Mutex<StandIns>> = Default::default();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let thread = {
+            let stand_ins = stand_ins.clone();
+            thread::Builder::new()
+                .name("QuicP2p-Socks5-Relay".into())
+                .spawn(move || {
+                    let outer = match UdpSocket::from_std(outer, &Handle::default()) {
+                        Ok(outer) => outer,
+                        Err(e) => {
+                            warn!("Failed to relay through the SOCKS5 proxy: {}", e);
+                            return;
+                        }
+                    };
+                    let relaying = RelayTask {
+                        quinn_addr,
+                        relay_addr,
+                        idle_timeout,
+                        outer,
+                        new_stand_ins: rx,
+                        shared_stand_ins: stand_ins,
+                        stand_ins: HashMap::new(),
+                        by_peer: HashMap::new(),
+                        sweeps: Interval::new_interval(idle_timeout),
+                        buf: vec![0; MAX_DATAGRAM_LEN],
+                    };
+                    let _ = current_thread::block_on_all(relaying);
+                    debug!("Exiting the SOCKS5 relay");
+                })?
+        };
+
+        Ok(Self {
+            stand_ins,
+            new_stand_ins: Some(tx),
+            thread: Some(thread),
+            _control: control,
+        })
+    }
+
+    /// Address of the socket standing in for the peer, opened on first use.
+    fn stand_in(&self, peer_addr: SocketAddr) -> io::Result<SocketAddr> {
+        let mut stand_ins = lock(&self.stand_ins);
+        let now = Instant::now();
+        if let Some(&mut (wire_addr, ref mut last_dialled)) = stand_ins.by_peer.get_mut(&peer_addr)
+        {
+            *last_dialled = now;
+            return Ok(wire_addr);
+        }
+        if stand_ins.by_peer.len() >= MAX_STAND_INS {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Too many peers relayed through the SOCKS5 proxy",
+            ));
+        }
+
+        let socket = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let wire_addr = socket.local_addr()?;
+        let handed_over = match self.new_stand_ins {
+            Some(ref tx) => tx.clone().try_send((peer_addr, socket)).is_ok(),
+            None => false,
+        };
+        if !handed_over {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "The SOCKS5 relay has stopped",
+            ));
+        }
+        let _ = stand_ins.peers.insert(wire_addr, peer_addr);
+        let _ = stand_ins.by_peer.insert(peer_addr, (wire_addr, now));
+
+        Ok(wire_addr)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.new_stand_ins.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Error joining the SOCKS5 relay thread");
+            }
+        }
+    }
+}
+
+/// Peers we stand in for, as `Transport::wire_addr` and `Transport::peer_addr` look them up.
+#[derive(Default)]
+struct StandIns {
+    /// Address of the socket standing in for the peer and when we last asked for it
+    by_peer: HashMap<SocketAddr, (SocketAddr, Instant)>,
+    /// Peer by the address of the socket standing in for it
+    peers: HashMap<SocketAddr, SocketAddr>,
+}
+
+/// Socket standing in for a peer, owned by the relay thread.
+struct StandIn {
+    socket: UdpSocket,
+    peer_addr: SocketAddr,
+    last_active: Instant,
+}
+
+/// Relays between quinn and the proxy for all the stand-ins, on the relay thread.
+struct RelayTask {
+    /// Where the endpoint's socket is bound
+    quinn_addr: SocketAddr,
+    /// Where the proxy takes our datagrams
+    relay_addr: SocketAddr,
+    idle_timeout: Duration,
+    /// Our socket facing the proxy
+    outer: UdpSocket,
+    new_stand_ins: UnboundedReceiver<(SocketAddr, net::UdpSocket)>,
+    shared_stand_ins: Arc<Mutex<StandIns>>,
+    /// By the address of their sockets
+    stand_ins: HashMap<SocketAddr, StandIn>,
+    /// Address of the socket standing in for the peer
+    by_peer: HashMap<SocketAddr, SocketAddr>,
+    sweeps: Interval,
+    buf: Vec<u8>,
+}
+
+impl RelayTask {
+    fn add_stand_in(&mut self, peer_addr: SocketAddr, socket: net::UdpSocket) {
+        let res = socket.local_addr().and_then(|wire_addr| {
+            Ok((wire_addr, UdpSocket::from_std(socket, &Handle::default())?))
+        });
+        match res {
+            Ok((wire_addr, socket)) => {
+                let _ = self.by_peer.insert(peer_addr, wire_addr);
+                let _ = self.stand_ins.insert(
+                    wire_addr,
+                    StandIn {
+                        socket,
+                        peer_addr,
+                        last_active: Instant::now(),
+                    },
+                );
+            }
+            Err(e) => {
+                warn!("Failed to stand in for peer {}: {}", peer_addr, e);
+                let mut shared_stand_ins = lock(&self.shared_stand_ins);
+                if let Some((wire_addr, _)) = shared_stand_ins.by_peer.remove(&peer_addr) {
+                    let _ = shared_stand_ins.peers.remove(&wire_addr);
+                }
+            }
+        }
+    }
+
+    /// Pass what the proxy relays from the peers on to quinn, each from the socket standing in
+    /// for its peer.
+    fn relay_from_proxy(&mut self) {
+        loop {
+            let len = match self.outer.poll_recv_from(&mut self.buf) {
+                Ok(Async::Ready((len, from))) if from == self.relay_addr => len,
+                Ok(Async::Ready((_, from))) => {
+                    trace!("Dropping a datagram from {} instead of the proxy", from);
+                    continue;
+                }
+                Ok(Async::NotReady) => return,
+                Err(e) => {
+                    warn!("Failed to receive from the SOCKS5 proxy: {}", e);
+                    continue;
+                }
+            };
+
+            let (peer_addr, payload) = match decode_datagram(&self.buf[..len]) {
+                Some(decoded) => decoded,
+                None => {
+                    trace!("Dropping a datagram from the proxy we can't decode");
+                    continue;
+                }
+            };
+            let stand_in = match self.by_peer.get(&peer_addr) {
+                Some(wire_addr) => self.stand_ins.get_mut(wire_addr),
+                None => None,
+            };
+            let stand_in = match stand_in {
+                Some(stand_in) => stand_in,
+                None => {
+                    trace!(
+                        "Dropping a datagram from {} we haven't connected to",
+                        peer_addr
+                    );
+                    continue;
+                }
+            };
+            stand_in.last_active = Instant::now();
+            if let Err(e) = stand_in.socket.poll_send_to(payload, &self.quinn_addr) {
+                debug!(
+                    "Failed to pass on a datagram from peer {}: {}",
+                    peer_addr, e
+                );
+            }
+        }
+    }
+
+    /// Pass what quinn sends to the peers on to the proxy.
+    fn relay_to_peers(&mut self) {
+        for stand_in in self.stand_ins.values_mut() {
+            loop {
+                match stand_in.socket.poll_recv_from(&mut self.buf) {
+                    // Only quinn is to be relayed
+                    Ok(Async::Ready((len, from))) if from == self.quinn_addr => {
+                        stand_in.last_active = Instant::now();
+                        let datagram = encode_datagram(stand_in.peer_addr, &self.buf[..len]);
+                        if let Err(e) = self.outer.poll_send_to(&datagram, &self.relay_addr) {
+                            debug!(
+                                "Failed to relay a datagram to peer {}: {}",
+                                stand_in.peer_addr, e
+                            );
+                        }
+                    }
+                    Ok(Async::Ready(_)) => (),
+                    Ok(Async::NotReady) => break,
+                    Err(e) => warn!("Failed to receive for peer {}: {}", stand_in.peer_addr, e),
+                }
+            }
+        }
+    }
+
+    /// Close the stand-ins neither used nor asked for within the idle timeout.
+    fn evict_idle(&mut self) {
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        // Under the lock so a stand-in can't be handed out while it's evicted
+        let mut shared_stand_ins = lock(&self.shared_stand_ins);
+        let idle = self
+            .stand_ins
+            .iter()
+            .filter(|(_, stand_in)| {
+                let last_dialled = shared_stand_ins
+                    .by_peer
+                    .get(&stand_in.peer_addr)
+                    .map(|&(_, last_dialled)| last_dialled);
+                now.duration_since(stand_in.last_active) > idle_timeout
+                    && last_dialled.map_or(true, |at| now.duration_since(at) > idle_timeout)
+            })
+            .map(|(&wire_addr, _)| wire_addr)
+            .collect::<Vec<_>>();
+
+        for wire_addr in idle {
+            if let Some(stand_in) = self.stand_ins.remove(&wire_addr) {
+                trace!("No longer standing in for idle peer {}", stand_in.peer_addr);
+                let _ = self.by_peer.remove(&stand_in.peer_addr);
+                let _ = shared_stand_ins.by_peer.remove(&stand_in.peer_addr);
+                let _ = shared_stand_ins.peers.remove(&wire_addr);
+            }
+        }
+    }
+}
+
+impl Future for RelayTask {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.new_stand_ins.poll() {
+                Ok(Async::Ready(Some((peer_addr, socket)))) => self.add_stand_in(peer_addr, socket),
+                Ok(Async::NotReady) => break,
+                // The `Relay` is gone
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(())),
+            }
+        }
+
+        self.relay_from_proxy();
+        self.relay_to_peers();
+
+        loop {
+            match self.sweeps.poll() {
+                Ok(Async::Ready(Some(_))) => self.evict_idle(),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(e) => {
+                    warn!("Error in timer: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Agree on how we authenticate, doing so with the credentials if the proxy asks for them.
+fn authenticate(control: &mut TcpStream, cfg: &ProxyConfig) -> io::Result<()> {
+    let methods: &[u8] = if cfg.credentials.is_some() {
+        &[METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD]
+    } else {
+        &[METHOD_NO_AUTH]
+    };
+    let mut req = vec![VERSION, methods.len() as u8];
+    req.extend_from_slice(methods);
+    control.write_all(&req)?;
+
+    let mut reply = [0; 2];
+    control.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(proxy_error("The SOCKS5 proxy speaks another version"));
+    }
+    match (reply[1], &cfg.credentials) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_USERNAME_PASSWORD, &Some((ref username, ref password))) => {
+            // RFC 1929
+            if username.len() > 255 || password.len() > 255 {
+                return Err(proxy_error("SOCKS5 username or password too long"));
+            }
+            let mut req = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+            req.extend_from_slice(username.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+            control.write_all(&req)?;
+
+            control.read_exact(&mut reply)?;
+            if reply[1] != REPLY_SUCCEEDED {
+                return Err(proxy_error("The SOCKS5 proxy refused our credentials"));
+            }
+            Ok(())
+        }
+        _ => Err(proxy_error(
+            "The SOCKS5 proxy accepts none of our authentication methods",
+        )),
+    }
+}
+
+/// Ask for the association for datagrams from `our_addr`, returning where the proxy takes them.
+fn associate(
+    control: &mut TcpStream,
+    proxy_addr: SocketAddr,
+    our_addr: SocketAddr,
+) -> io::Result<SocketAddr> {
+    let mut req = vec![VERSION, CMD_UDP_ASSOCIATE, 0];
+    encode_addr(&mut req, our_addr);
+    control.write_all(&req)?;
+
+    let mut head = [0; 4];
+    control.read_exact(&mut head)?;
+    if head[0] != VERSION {
+        return Err(proxy_error("The SOCKS5 proxy speaks another version"));
+    }
+    if head[1] != REPLY_SUCCEEDED {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!(
+                "The SOCKS5 proxy refused the UDP association with reply {}",
+                head[1]
+            ),
+        ));
+    }
+
+    let relay_addr = match head[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0; 6];
+            control.read_exact(&mut addr)?;
+            decode_addr(ATYP_IPV4, &addr).map(|(addr, _)| addr)
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0; 18];
+            control.read_exact(&mut addr)?;
+            decode_addr(ATYP_IPV6, &addr).map(|(addr, _)| addr)
+        }
+        _ => None,
+    };
+    match relay_addr {
+        // Stands for the address of the proxy itself
+        Some(relay_addr) if relay_addr.ip().is_unspecified() => {
+            Ok(SocketAddr::new(proxy_addr.ip(), relay_addr.port()))
+        }
+        Some(relay_addr) => Ok(relay_addr),
+        None => Err(proxy_error(
+            "The SOCKS5 proxy gave an address for the association we can't use",
+        )),
+    }
+}
+
+/// The payload with the header telling the proxy where to send it to.
+fn encode_datagram(peer_addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    // Reserved and fragment number, datagrams are never fragmented
+    let mut datagram = vec![0, 0, 0];
+    encode_addr(&mut datagram, peer_addr);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// The peer a datagram from the proxy was relayed from and its payload. Fragments and datagrams
+/// from peers given by domain name are not supported.
+fn decode_datagram(datagram: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if datagram.len() < 4 || datagram[2] != 0 {
+        return None;
+    }
+    decode_addr(datagram[3], &datagram[4..])
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// The address of the given type at the start of `buf` and what follows it.
+fn decode_addr(atyp: u8, buf: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    let ip_len = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        // E.g. domain names
+        _ => return None,
+    };
+    if buf.len() < ip_len + 2 {
+        return None;
+    }
+
+    let ip: IpAddr = if ip_len == 4 {
+        let mut octets = [0; 4];
+        octets.copy_from_slice(&buf[..4]);
+        Ipv4Addr::from(octets).into()
+    } else {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&buf[..16]);
+        Ipv6Addr::from(octets).into()
+    };
+    let port = u16::from_be_bytes([buf[ip_len], buf[ip_len + 1]]);
+    Some((SocketAddr::new(ip, port), &buf[ip_len + 2..]))
+}
+
+fn proxy_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, reason)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams_survive_the_round_trip_through_their_header() {
+        let peers: [SocketAddr; 2] = [
+            unwrap!("198.51.100.7:5483".parse()),
+            unwrap!("[2001:db8::1]:443".parse()),
+        ];
+        for &peer_addr in &peers {
+            let datagram = encode_datagram(peer_addr, b"payload");
+            assert_eq!(
+                decode_datagram(&datagram),
+                Some((peer_addr, &b"payload"[..]))
+            );
+        }
+    }
+
+    #[test]
+    fn fragments_and_truncated_datagrams_are_rejected() {
+        let peer_addr: SocketAddr = unwrap!("198.51.100.7:5483".parse());
+        let mut datagram = encode_datagram(peer_addr, b"payload");
+
+        assert_eq!(decode_datagram(&datagram[..8]), None);
+        datagram[2] = 1;
+        assert_eq!(decode_datagram(&datagram), None);
+    }
+}
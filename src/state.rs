@@ -0,0 +1,74 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::connection::ToPeer;
+use crate::context::{Context, Ctx};
+use crate::{connect, NodeInfo, Stats};
+use std::net::SocketAddr;
+
+/// Peer knowledge worth carrying over a process restart, obtained via `QuicP2p::export_state` and
+/// restored with `Builder::with_state`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct StateSnapshot {
+    /// Nodes we were connected to, along with their certificates
+    pub peers: Vec<NodeInfo>,
+    /// Peers blacklisted for misbehaving
+    pub blacklisted: Vec<SocketAddr>,
+    /// Our stats at the time of the snapshot. These are informational only and not restored.
+    pub stats: Stats,
+}
+
+impl StateSnapshot {
+    /// Take a snapshot of the given context
+    pub(crate) fn new(c: &Context) -> Self {
+        let peers = c
+            .connections
+            .iter()
+            .filter_map(|(peer_addr, conn)| match conn.to_peer {
+                ToPeer::Established {
+                    ref peer_cert_der, ..
+                } => Some(NodeInfo {
+                    peer_addr: *peer_addr,
+                    peer_cert_der: peer_cert_der.clone(),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            peers,
+            blacklisted: c.reputation.blacklisted().collect(),
+            stats: Stats::new(c),
+        }
+    }
+}
+
+/// Restore the blacklist and reconnect to the peers from the snapshot. This must not be called
+/// while the `Context` is already borrowed.
+pub fn restore(ctx: &Ctx, snapshot: StateSnapshot) {
+    ctx.with_mut(|c| {
+        for peer_addr in snapshot.blacklisted {
+            c.reputation.blacklist(peer_addr);
+        }
+    });
+
+    for node_info in snapshot.peers {
+        let peer_addr = node_info.peer_addr;
+        if let Err(e) = connect::connect_to(ctx, node_info, None, None) {
+            debug!("Could not reconnect to peer {}: {}", peer_addr, e);
+            continue;
+        }
+
+        ctx.with_mut(|c| {
+            if let Some(conn) = c.connections.get_mut(&peer_addr) {
+                conn.we_contacted_peer = true;
+            }
+        });
+    }
+}
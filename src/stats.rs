@@ -0,0 +1,195 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::backpressure::BackpressureLevel;
+use crate::clock::{self, SharedClock};
+use crate::config::{OurType, SocketOptions};
+use crate::context::{Context, Ctx};
+use crate::utils;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::prelude::Stream;
+
+/// Extension of the lifetime stats snapshot file, named after the bootstrap cache one.
+const SNAPSHOT_FILE_EXTENSION: &str = "lifetime_stats";
+
+/// Snapshot of the state of QuicP2p, obtained via `QuicP2p::stats`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Stats {
+    /// Number of peers we have a connection to or from, including the ones still being set up
+    pub connections: usize,
+    /// Whether new incoming connections are being accepted. Always `false` for clients as they
+    /// don't listen for connections at all.
+    pub is_accepting_incoming: bool,
+    /// Options in effect on our UDP socket, as reported by the OS. These can differ from the ones
+    /// asked for, e.g. Linux doubles the buffer sizes and caps them at system wide maximums.
+    pub socket_options: SocketOptions,
+    /// Events the application has yet to take, if the sender given to `Builder::new` is from
+    /// `event_channel`
+    pub event_depth: Option<usize>,
+    /// Level of the backlog last checked, always `Normal` unless `Config::backpressure` is set
+    pub backpressure: BackpressureLevel,
+    /// Replays of messages from peers dropped as we had received them already, always zero unless
+    /// `Config::dedup` is set
+    pub duplicates_dropped: u64,
+}
+
+impl Stats {
+    /// Take a snapshot of the given context
+    pub(crate) fn new(c: &Context) -> Self {
+        Self {
+            connections: c.connections.len(),
+            is_accepting_incoming: c.our_type != OurType::Client && c.is_accepting_incoming,
+            socket_options: c.effective_socket_options,
+            event_depth: c.event_tx.depth(),
+            backpressure: c.backpressure_level,
+            duplicates_dropped: c.dedup.as_ref().map_or(0, |dedup| dedup.hits),
+        }
+    }
+}
+
+/// Counters accumulated over all our runs, obtained via `QuicP2p::lifetime_stats`. They carry on
+/// across restarts only if `Config::lifetime_stats_snapshot_sec` is set.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct LifetimeStats {
+    /// Bytes of messages, framing included, sent to peers
+    pub bytes_sent: u64,
+    /// Bytes of messages, framing included, received from peers
+    pub bytes_received: u64,
+    /// Connections to peers completed, a peer counting again each time it reconnects
+    pub peers_seen: u64,
+    /// Time we have been running for, in seconds
+    pub uptime_sec: u64,
+}
+
+/// Keeps the lifetime counters up to date and snapshots them to disk.
+pub(crate) struct LifetimeStatsTracker {
+    /// Counters as of our start plus the ones since, except for the uptime
+    totals: LifetimeStats,
+    started_at: Instant,
+    snapshot_path: Option<PathBuf>,
+    clock: SharedClock,
+}
+
+impl LifetimeStatsTracker {
+    /// Carry on from the snapshot at the given path, if there's one. Without a path the counters
+    /// start afresh and are never saved.
+    pub fn load(snapshot_path: Option<PathBuf>, clock: SharedClock) -> Self {
+        let totals = match snapshot_path {
+            Some(ref path) if path.exists() => utils::read_from_disk(path).unwrap_or_else(|e| {
+                info!(
+                    "Could not read lifetime stats from {}: {} - starting afresh",
+                    path.display(),
+                    e
+                );
+                Default::default()
+            }),
+            _ => Default::default(),
+        };
+
+        Self {
+            totals,
+            started_at: clock.now(),
+            snapshot_path,
+            clock,
+        }
+    }
+
+    pub fn current(&self) -> LifetimeStats {
+        LifetimeStats {
+            uptime_sec: self.totals.uptime_sec
+                + self.clock.now().duration_since(self.started_at).as_secs(),
+            ..self.totals
+        }
+    }
+
+    pub fn record_inbound(&mut self, frame_len: usize) {
+        self.totals.bytes_received += frame_len as u64;
+    }
+
+    pub fn record_outbound(&mut self, frame_len: usize) {
+        self.totals.bytes_sent += frame_len as u64;
+    }
+
+    pub fn record_peer_connected(&mut self) {
+        self.totals.peers_seen += 1;
+    }
+
+    /// Write the counters as they are now to the snapshot file, if we have one.
+    pub fn save(&self) {
+        if let Some(ref path) = self.snapshot_path {
+            if let Err(e) = utils::write_to_disk(path, &self.current()) {
+                info!(
+                    "Could not write lifetime stats to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Lifetime stats are kept next to the bootstrap cache at the given path, so they share its
+/// directory and namespace.
+pub fn snapshot_path(cache_path: &Path) -> PathBuf {
+    cache_path.with_extension(SNAPSHOT_FILE_EXTENSION)
+}
+
+/// Snapshot the lifetime counters every `interval_sec` for as long as the event loop runs.
+pub fn start_snapshots(ctx: &Ctx, interval_sec: u64) {
+    let interval = Duration::from_secs(interval_sec);
+    let ctx_clone = ctx.clone();
+    let leaf = clock::interval(&ctx.with(|c| c.clock.clone()), interval).for_each(move |_| {
+        ctx_clone.with(|c| c.lifetime_stats.save());
+        Ok(())
+    });
+
+    ctx.spawn(leaf);
+}
+
+/// Note a message received from a peer. This must not be called while the `Context` is already
+/// borrowed.
+pub fn record_inbound(ctx: &Ctx, frame_len: usize) {
+    ctx.with_mut(|c| c.lifetime_stats.record_inbound(frame_len))
+}
+
+/// Note a message sent to a peer. This must not be called while the `Context` is already
+/// borrowed.
+pub fn record_outbound(ctx: &Ctx, frame_len: usize) {
+    ctx.with_mut(|c| c.lifetime_stats.record_outbound(frame_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::utils::testing::test_dirs;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn lifetime_stats_carry_on_from_the_snapshot() {
+        let dirs = test_dirs();
+        unwrap!(fs::create_dir_all(dirs.cache_dir()));
+        let path = dirs.cache_dir().join("lifetime_stats");
+
+        let mut tracker = LifetimeStatsTracker::load(Some(path.clone()), Arc::new(SystemClock));
+        tracker.record_outbound(100);
+        tracker.record_inbound(40);
+        tracker.record_peer_connected();
+        tracker.save();
+
+        let mut tracker = LifetimeStatsTracker::load(Some(path), Arc::new(SystemClock));
+        tracker.record_outbound(1);
+        let stats = tracker.current();
+        assert_eq!(stats.bytes_sent, 101);
+        assert_eq!(stats.bytes_received, 40);
+        assert_eq!(stats.peers_seen, 1);
+    }
+}
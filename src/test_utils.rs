@@ -0,0 +1,114 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Harness for tests running real `QuicP2p` instances, shared with the crates built on top of us
+//! so they don't need to roll their own. Only compiled in with the `testing` feature.
+
+use crate::config::ED25519_PKCS8_PREFIX;
+use crate::{Builder, Config, Event, QuicP2p, SerialisableCertificate, DEFAULT_SERVER_NAME};
+use ring::digest;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Config for an instance listening on a random localhost port.
+pub fn test_config() -> Config {
+    Config {
+        port: Some(0),
+        bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        ..Default::default()
+    }
+}
+
+/// Start `n` instances with `test_config` and empty bootstrap caches, along with the receivers of
+/// their events.
+pub fn spawn_peers(n: usize) -> Vec<(QuicP2p, Receiver<Event>)> {
+    (0..n)
+        .map(|_| {
+            let (ev_tx, ev_rx) = mpsc::channel();
+            let quic_p2p = unwrap!(Builder::new(ev_tx)
+                .with_config(test_config())
+                .with_proxies(Default::default(), true)
+                .build());
+            (quic_p2p, ev_rx)
+        })
+        .collect()
+}
+
+/// Wait for the first event matching `pred`, skipping over the others. Returns `None` if there's
+/// no such event within `timeout` or the event sender is gone.
+pub fn wait_for_event<F>(ev_rx: &Receiver<Event>, mut pred: F, timeout: Duration) -> Option<Event>
+where
+    F: FnMut(&Event) -> bool,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        match ev_rx.recv_timeout(deadline - now) {
+            Ok(event) => {
+                if pred(&event) {
+                    return Some(event);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+/// Self-signed certificate derived from `seed` alone, so that tests can refer to a peer by the
+/// same certificate across runs. Never use this outside of tests: anyone knowing the seed can
+/// impersonate the holder.
+pub fn deterministic_cert(seed: u64) -> SerialisableCertificate {
+    let key_seed = digest::digest(&digest::SHA256, &seed.to_be_bytes());
+    let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+    pkcs8.extend_from_slice(key_seed.as_ref());
+
+    let mut params = rcgen::CertificateParams::new(vec![DEFAULT_SERVER_NAME.to_string()]);
+    params.alg = &rcgen::PKCS_ED25519;
+    params.key_pair = Some(unwrap!(rcgen::KeyPair::from_der(&pkcs8)));
+    params.serial_number = Some(seed);
+    let cert = rcgen::Certificate::from_params(params);
+
+    SerialisableCertificate {
+        cert_der: cert.serialize_der(),
+        key_der: cert.serialize_private_key_der(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake_auth;
+
+    #[test]
+    fn deterministic_certs_depend_only_on_the_seed() {
+        assert_eq!(deterministic_cert(1), deterministic_cert(1));
+        assert_ne!(
+            deterministic_cert(1).cert_der,
+            deterministic_cert(2).cert_der
+        );
+    }
+
+    #[test]
+    fn deterministic_certs_can_sign_handshakes() {
+        let cert = deterministic_cert(1);
+        let recipient_cert = deterministic_cert(2);
+        let (nonce, signature) =
+            unwrap!(handshake_auth::sign(&cert, &recipient_cert.cert_der, None));
+        unwrap!(handshake_auth::verify(
+            &cert.cert_der,
+            &recipient_cert.cert_der,
+            &nonce,
+            &signature
+        ));
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Settings bundled by each `TrafficProfile`. Any of them given explicitly in the `Config` take
+//! precedence over the profile's.
+
+use crate::config::{Config, TrafficProfile};
+use crate::context::Context;
+use crate::peer_config::{
+    DEFAULT_CONNECTION_RECEIVE_WINDOW, DEFAULT_KEEP_ALIVE_INTERVAL_MSEC,
+    DEFAULT_STREAM_RECEIVE_WINDOW,
+};
+use crate::DEFAULT_SEND_QUANTUM_BYTES;
+
+/// Maximum rate, in bytes per second, at which a `TrafficProfile::Background` instance sends.
+pub const BACKGROUND_MAX_SEND_RATE: u64 = 256 * 1024; // 256 KiB/s
+
+/// Settings a traffic profile is made of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficSettings {
+    pub keep_alive_interval_msec: u32,
+    pub stream_receive_window: u64,
+    pub connection_receive_window: u64,
+    pub send_quantum_bytes: usize,
+    /// Bytes per second we send at most, to all the peers together
+    pub max_send_rate: Option<u64>,
+}
+
+impl TrafficSettings {
+    /// Settings of the given profile, overridden by the ones set in `cfg`.
+    pub fn new(cfg: &Config, profile: TrafficProfile) -> Self {
+        let defaults = Self::of(profile);
+        Self {
+            keep_alive_interval_msec: cfg
+                .keep_alive_interval_msec
+                .unwrap_or(defaults.keep_alive_interval_msec),
+            stream_receive_window: cfg
+                .stream_receive_window
+                .unwrap_or(defaults.stream_receive_window),
+            connection_receive_window: cfg
+                .connection_receive_window
+                .unwrap_or(defaults.connection_receive_window),
+            send_quantum_bytes: cfg
+                .send_quantum_bytes
+                .map(|quantum| quantum as usize)
+                .unwrap_or(defaults.send_quantum_bytes),
+            max_send_rate: defaults.max_send_rate,
+        }
+    }
+
+    fn of(profile: TrafficProfile) -> Self {
+        match profile {
+            TrafficProfile::Interactive => Self {
+                keep_alive_interval_msec: DEFAULT_KEEP_ALIVE_INTERVAL_MSEC,
+                stream_receive_window: DEFAULT_STREAM_RECEIVE_WINDOW,
+                connection_receive_window: DEFAULT_CONNECTION_RECEIVE_WINDOW,
+                send_quantum_bytes: DEFAULT_SEND_QUANTUM_BYTES,
+                max_send_rate: None,
+            },
+            // Fewer wake-ups and small bursts, at the cost of throughput
+            TrafficProfile::Background => Self {
+                keep_alive_interval_msec: 20_000,
+                stream_receive_window: 512 * 1024,
+                connection_receive_window: 2 * 1024 * 1024,
+                send_quantum_bytes: 16 * 1024,
+                max_send_rate: Some(BACKGROUND_MAX_SEND_RATE),
+            },
+            // Big windows to keep fast links full, and fewer turns between big transfers
+            TrafficProfile::Bulk => Self {
+                keep_alive_interval_msec: DEFAULT_KEEP_ALIVE_INTERVAL_MSEC,
+                stream_receive_window: 16 * 1024 * 1024,
+                connection_receive_window: 64 * 1024 * 1024,
+                send_quantum_bytes: 256 * 1024,
+                max_send_rate: None,
+            },
+        }
+    }
+
+    /// Switch to these settings at runtime. The send quantum and rate take effect straight away,
+    /// while the transport settings apply to the connections we make from now on, and to the ones
+    /// made to us once our listener is restarted.
+    pub fn apply(&self, c: &mut Context) {
+        c.keep_alive_interval_msec = self.keep_alive_interval_msec;
+        c.stream_receive_window = self.stream_receive_window;
+        c.connection_receive_window = self.connection_receive_window;
+        c.send_scheduler.set_quantum(self.send_quantum_bytes);
+        let now = c.clock.now();
+        c.send_scheduler.set_max_rate(self.max_send_rate, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_settings_override_the_profile() {
+        let cfg = Config {
+            stream_receive_window: Some(1024),
+            ..Default::default()
+        };
+
+        let settings = TrafficSettings::new(&cfg, TrafficProfile::Background);
+        assert_eq!(settings.stream_receive_window, 1024);
+        assert_eq!(settings.connection_receive_window, 2 * 1024 * 1024);
+        assert_eq!(settings.max_send_rate, Some(BACKGROUND_MAX_SEND_RATE));
+
+        let settings = TrafficSettings::new(&cfg, TrafficProfile::Interactive);
+        assert_eq!(settings.stream_receive_window, 1024);
+        assert_eq!(
+            settings.keep_alive_interval_msec,
+            DEFAULT_KEEP_ALIVE_INTERVAL_MSEC
+        );
+        assert_eq!(settings.max_send_rate, None);
+    }
+}
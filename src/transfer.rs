@@ -0,0 +1,897 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Chunked transfer of files to peers, e.g. of the immutable data chunks vaults move around. A
+//! file is identified by the SHA-256 hash of its content, which the recipient verifies once it has
+//! all of it:
+//!
+//! 1. The sender offers the file with a `FileOffer` giving its hash and length.
+//! 2. Offers of files longer than `Config::max_file_len`, or beyond `Config::max_incoming_files`
+//!    at a time, are refused with a `FileRefuse` straight away. The others are handed to the user
+//!    in `Event::FileOffered` to accept via `QuicP2p::accept_file` or refuse via
+//!    `QuicP2p::refuse_file`.
+//! 3. The recipient accepts it with a `FileAccept` from the offset up to which it has the file
+//!    already from an earlier transfer from the same peer that was cut short, if any. Such a
+//!    transfer is resumed without asking the user again.
+//! 4. The sender sends the rest in `FileChunk`s, `MAX_CHUNKS_IN_FLIGHT` at a time, each on a
+//!    stream of its own. The recipient writes them into a file of its own in a directory private
+//!    to this instance, in whatever order they come in.
+//!
+//! Files are read, written and hashed on a thread of their own, see `Disk`, so the event loop
+//! never waits for the disk. Both ends report their progress via `Event::FileTransferProgress`.
+//! What the recipient has of a file is kept for as long as we run, or until the user refuses it,
+//! so a failed transfer is resumed by sending the file again.
+
+use crate::communicate;
+use crate::context::Ctx;
+use crate::error::Error;
+use crate::event::{Event, EventTx};
+use crate::reputation::{self, Violation};
+use crate::wire_msg::WireMsg;
+use crate::{Peer, DEFAULT_CHANNEL, R};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tokio::prelude::{future, Future};
+use tokio::sync::oneshot;
+
+/// SHA-256 hash of the content of a file, which identifies it in a transfer.
+pub type FileHash = [u8; 32];
+
+/// Bytes of the file carried by each `FileChunk`, but the last one.
+pub const FILE_CHUNK_LEN: usize = 32 * 1024;
+/// Chunks of a file being sent which are written to the peer at a time.
+const MAX_CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Files being sent and received.
+pub struct Transfers {
+    outgoing: HashMap<(SocketAddr, FileHash), Outgoing>,
+    /// Offers waiting for the user to accept or refuse them
+    offered: HashMap<(SocketAddr, FileHash), Offer>,
+    /// By peer too, so that only the peer we accepted a file from gets to write to it
+    incoming: HashMap<(SocketAddr, FileHash), Incoming>,
+    max_file_len: u64,
+    max_incoming_files: usize,
+    /// Directory the files received are written to, created on first use and only accessible to
+    /// us
+    dir: PathBuf,
+    next_file_id: u64,
+    disk: Disk,
+}
+
+impl Transfers {
+    pub fn new(max_file_len: u64, max_incoming_files: usize) -> R<Self> {
+        let mut suffix = [0; 16];
+        SystemRandom::new().fill(&mut suffix).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "Could not name the directory for the files received at random",
+            )
+        })?;
+
+        Ok(Self {
+            outgoing: Default::default(),
+            offered: Default::default(),
+            incoming: Default::default(),
+            max_file_len,
+            max_incoming_files,
+            dir: env::temp_dir().join(format!("quic-p2p-{}", hex(&suffix))),
+            next_file_id: 0,
+            disk: Default::default(),
+        })
+    }
+}
+
+impl Drop for Transfers {
+    fn drop(&mut self) {
+        // Nothing was ever written
+        if self.disk.jobs_tx.is_none() {
+            return;
+        }
+
+        // The files received whole are the user's now, but the partial ones are no use to anyone.
+        // Queued behind the files still being created so those go too.
+        let mut paths: Vec<PathBuf> = self
+            .incoming
+            .values()
+            .map(|incoming| incoming.temp_path.clone())
+            .collect();
+        paths.extend(
+            self.offered
+                .values()
+                .filter_map(|offer| offer.temp_path.clone()),
+        );
+        let dir = self.dir.clone();
+        let _ = self.disk.run(move || {
+            for path in paths {
+                let _ = fs::remove_file(path);
+            }
+            let _ = fs::remove_dir(dir);
+            Ok(())
+        });
+    }
+}
+
+struct Outgoing {
+    file: Arc<File>,
+    len: u64,
+    /// Start of the next chunk to send
+    next_offset: u64,
+    /// Bytes the peer has taken so far, counting the ones it had already
+    sent: u64,
+    in_flight: usize,
+    /// Set once the peer accepted the file, which it may only do once
+    is_accepted: bool,
+}
+
+struct Offer {
+    peer: Peer,
+    len: u64,
+    /// Set once the user accepted the offer, while the file for it is being created
+    temp_path: Option<PathBuf>,
+}
+
+struct Incoming {
+    peer: Peer,
+    file: Arc<File>,
+    temp_path: PathBuf,
+    len: u64,
+    /// Ranges of the file written so far, by start to end, never overlapping or adjacent
+    received: BTreeMap<u64, u64>,
+}
+
+impl Incoming {
+    /// Offset up to which we have the whole file.
+    fn contiguous(&self) -> u64 {
+        self.received.get(&0).cloned().unwrap_or(0)
+    }
+
+    fn received_len(&self) -> u64 {
+        self.received.iter().map(|(start, end)| end - start).sum()
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Thread the files are read, written and hashed on, one job at a time in the order they are
+/// given. So a file is e.g. only hashed once every chunk written to it before is. It's started on
+/// first use and stops once we are dropped and it has run the jobs left.
+#[derive(Default)]
+struct Disk {
+    jobs_tx: Option<mpsc::Sender<Job>>,
+}
+
+impl Disk {
+    fn run<T, F>(&mut self, job: F) -> impl Future<Item = T, Error = Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let jobs_tx = self.jobs_tx.get_or_insert_with(|| {
+            let (jobs_tx, jobs_rx) = mpsc::channel::<Job>();
+            let _ = thread::spawn(move || {
+                for job in jobs_rx {
+                    job();
+                }
+            });
+            jobs_tx
+        });
+
+        // Should the thread be gone the job is dropped along with `tx`, failing `rx`
+        let (tx, rx) = oneshot::channel();
+        let _ = jobs_tx.send(Box::new(move || {
+            let _ = tx.send(job());
+        }));
+
+        rx.from_err().and_then(|res| res.map_err(Error::Io))
+    }
+}
+
+/// Length and hash of the file at `path`.
+pub fn hash_file(path: &Path) -> io::Result<(u64, FileHash)> {
+    let mut file = File::open(path)?;
+    let mut hasher = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0; FILE_CHUNK_LEN];
+    let mut len = 0;
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        len += read as u64;
+    }
+
+    let mut hash = [0; 32];
+    hash.copy_from_slice(hasher.finish().as_ref());
+    Ok((len, hash))
+}
+
+/// Offer the file to the peer, connecting to it first if need be. `len` and `hash` are the ones
+/// of the file as per `hash_file`. This must not be called while the `Context` is already
+/// borrowed.
+pub fn send_file(ctx: &Ctx, peer: Peer, file: File, len: u64, hash: FileHash) {
+    let peer_addr = peer.peer_addr();
+    ctx.with_mut(|c| {
+        let outgoing = Outgoing {
+            file: Arc::new(file),
+            len,
+            next_offset: 0,
+            sent: 0,
+            in_flight: 0,
+            is_accepted: false,
+        };
+        if c.transfers
+            .outgoing
+            .insert((peer_addr, hash), outgoing)
+            .is_some()
+        {
+            debug!(
+                "Restarting the transfer of file {} to peer {}",
+                hex(&hash),
+                peer_addr
+            );
+        }
+    });
+
+    communicate::try_write_to_peer(ctx, peer, WireMsg::FileOffer { hash, len });
+}
+
+/// The peer offers us a file. We are called with the `Context` already borrowed so handle it once
+/// it's released.
+pub fn handle_offer(ctx: &Ctx, peer: Peer, hash: FileHash, len: u64) {
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        let peer_addr = peer.peer_addr();
+        let key = (peer_addr, hash);
+        // The offset to accept the file from if it's resumed, the reason to refuse it if it is
+        let outcome = ctx.with_mut(|c| -> Option<Result<u64, &'static str>> {
+            let transfers = &mut c.transfers;
+            if let Some(incoming) = transfers.incoming.get_mut(&key) {
+                if incoming.len != len {
+                    return Some(Err("length differs from the one of the partial file"));
+                }
+                incoming.peer = peer.clone();
+                return Some(Ok(incoming.contiguous()));
+            }
+            if transfers.offered.contains_key(&key) {
+                debug!(
+                    "Peer {} offered file {} again before we answered",
+                    peer_addr,
+                    hex(&hash)
+                );
+                return None;
+            }
+            if len > transfers.max_file_len {
+                return Some(Err("file too long"));
+            }
+            if transfers.offered.len() + transfers.incoming.len() >= transfers.max_incoming_files {
+                return Some(Err("too many files being received"));
+            }
+
+            let offer = Offer {
+                peer: peer.clone(),
+                len,
+                temp_path: None,
+            };
+            let _ = transfers.offered.insert(key, offer);
+            let event = Event::FileOffered {
+                peer: peer.clone(),
+                hash,
+                len,
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+            None
+        });
+
+        match outcome {
+            Some(Ok(offset)) => start_incoming(ctx, peer_addr, hash, offset, len),
+            Some(Err(reason)) => {
+                debug!(
+                    "Refusing file {} from peer {}: {}",
+                    hex(&hash),
+                    peer_addr,
+                    reason
+                );
+                communicate::write_to_peer(ctx, peer_addr, WireMsg::FileRefuse { hash });
+            }
+            None => (),
+        }
+        Ok(())
+    }));
+}
+
+/// The user accepts the file the peer offered. This must not be called while the `Context` is
+/// already borrowed.
+pub fn accept_file(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash) {
+    let key = (peer_addr, hash);
+    let create = ctx.with_mut(|c| {
+        let transfers = &mut c.transfers;
+        let offer = match transfers.offered.get_mut(&key) {
+            Some(offer) if offer.temp_path.is_none() => offer,
+            _ => return None,
+        };
+        let temp_path =
+            transfers
+                .dir
+                .join(format!("{}-{}.part", hex(&hash), transfers.next_file_id));
+        transfers.next_file_id += 1;
+        offer.temp_path = Some(temp_path.clone());
+
+        let dir = transfers.dir.clone();
+        let path = temp_path.clone();
+        Some((
+            transfers.disk.run(move || create_file(&dir, &path)),
+            temp_path,
+        ))
+    });
+    let (create, temp_path) = match create {
+        Some(create) => create,
+        None => {
+            return debug!(
+                "No offer of file {} from peer {} left to accept",
+                hex(&hash),
+                peer_addr
+            )
+        }
+    };
+
+    let ctx_clone = ctx.clone();
+    ctx.spawn(create.then(move |res| {
+        let ctx = &ctx_clone;
+        let offer = ctx.with_mut(|c| {
+            // Unless it was refused meanwhile, which took care of the file
+            match c.transfers.offered.get(&key) {
+                Some(offer) if offer.temp_path.as_ref() == Some(&temp_path) => (),
+                _ => return None,
+            }
+            let offer = c.transfers.offered.remove(&key)?;
+            match res {
+                Ok(file) => {
+                    let incoming = Incoming {
+                        peer: offer.peer,
+                        file: Arc::new(file),
+                        temp_path,
+                        len: offer.len,
+                        received: Default::default(),
+                    };
+                    let _ = c.transfers.incoming.insert(key, incoming);
+                    Some(Ok(offer.len))
+                }
+                Err(e) => {
+                    fire_failed(&c.event_tx, peer_addr, hash, &e.to_string());
+                    Some(Err(()))
+                }
+            }
+        });
+
+        match offer {
+            Some(Ok(len)) => start_incoming(ctx, peer_addr, hash, 0, len),
+            Some(Err(())) => {
+                communicate::write_to_peer(ctx, peer_addr, WireMsg::FileRefuse { hash })
+            }
+            None => (),
+        }
+        Ok(())
+    }));
+}
+
+/// The user refuses the file the peer offered, or drops what we have of one. This must not be
+/// called while the `Context` is already borrowed.
+pub fn refuse_file(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash) {
+    let key = (peer_addr, hash);
+    let is_known = ctx.with_mut(|c| {
+        let transfers = &mut c.transfers;
+        if let Some(offer) = transfers.offered.remove(&key) {
+            if let Some(temp_path) = offer.temp_path {
+                discard(ctx, &mut transfers.disk, temp_path);
+            }
+            true
+        } else if let Some(incoming) = transfers.incoming.remove(&key) {
+            discard(ctx, &mut transfers.disk, incoming.temp_path);
+            true
+        } else {
+            false
+        }
+    });
+
+    if is_known {
+        communicate::write_to_peer(ctx, peer_addr, WireMsg::FileRefuse { hash });
+    } else {
+        debug!("No file {} from peer {} to refuse", hex(&hash), peer_addr);
+    }
+}
+
+/// Ask the peer for the file from `offset` on. This must not be called while the `Context` is
+/// already borrowed.
+fn start_incoming(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash, offset: u64, len: u64) {
+    communicate::write_to_peer(ctx, peer_addr, WireMsg::FileAccept { hash, offset });
+    // Nothing to wait for, e.g. for an empty file
+    if offset == len {
+        ctx.with_mut(|c| complete_incoming(ctx, &mut c.transfers, peer_addr, hash));
+    }
+}
+
+/// The peer accepted our offer of the file. We are called with the `Context` already borrowed so
+/// start sending once it's released. Each offer is only accepted once, a repeat doesn't restart
+/// the transfer.
+pub fn handle_accept(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash, offset: u64) {
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        let accepted = ctx.with_mut(|c| {
+            let outgoing = match c.transfers.outgoing.get_mut(&(peer_addr, hash)) {
+                Some(outgoing) => outgoing,
+                None => return Ok(false),
+            };
+            if outgoing.is_accepted {
+                return Err("twice");
+            }
+            if offset > outgoing.len {
+                let _ = c.transfers.outgoing.remove(&(peer_addr, hash));
+                fire_failed(&c.event_tx, peer_addr, hash, "accepted from past its end");
+                return Err("from past its end");
+            }
+            outgoing.is_accepted = true;
+            outgoing.next_offset = offset;
+            outgoing.sent = offset;
+            Ok(true)
+        });
+
+        match accepted {
+            Ok(true) => pump(ctx, peer_addr, hash),
+            Ok(false) => debug!(
+                "Peer {} accepted file {} we didn't offer",
+                peer_addr,
+                hex(&hash)
+            ),
+            Err(how) => {
+                debug!("Peer {} accepted file {} {}", peer_addr, hex(&hash), how);
+                reputation::penalise(ctx, peer_addr, Violation::ProtocolViolation);
+            }
+        }
+        Ok(())
+    }));
+}
+
+/// The peer refused our offer of the file, or dropped what it had of it. We are called with the
+/// `Context` already borrowed so give up on it once it's released.
+pub fn handle_refuse(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash) {
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        fail_outgoing(ctx, peer_addr, hash, "refused by the peer");
+        Ok(())
+    }));
+}
+
+/// Part of a file from the peer. We are called with the `Context` already borrowed so write it
+/// once it's released.
+pub fn handle_chunk(ctx: &Ctx, peer: Peer, hash: FileHash, offset: u64, data: bytes::Bytes) {
+    let ctx_clone = ctx.clone();
+    ctx.spawn(future::lazy(move || {
+        let ctx = &ctx_clone;
+        let peer_addr = peer.peer_addr();
+        let write = ctx.with_mut(|c| {
+            let transfers = &mut c.transfers;
+            let incoming = match transfers.incoming.get(&(peer_addr, hash)) {
+                Some(incoming) => incoming,
+                // E.g. we refused the file while the chunk was on its way
+                None => {
+                    debug!(
+                        "Chunk of file {} we haven't accepted from peer {}",
+                        hex(&hash),
+                        peer_addr
+                    );
+                    return Ok(None);
+                }
+            };
+
+            let end = match offset.checked_add(data.len() as u64) {
+                Some(end) if end <= incoming.len => end,
+                _ => return Err(()),
+            };
+            let file = incoming.file.clone();
+            let written_to = file.clone();
+            let write = transfers.disk.run(move || {
+                let mut file: &File = &file;
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&data)?;
+                Ok(end)
+            });
+            Ok(Some((write, written_to)))
+        });
+
+        match write {
+            Ok(Some((write, file))) => {
+                let ctx_clone = ctx.clone();
+                ctx.spawn(write.then(move |res| {
+                    handle_chunk_stored(&ctx_clone, peer_addr, hash, &file, offset, res);
+                    Ok(())
+                }))
+            }
+            Ok(None) => (),
+            Err(()) => {
+                debug!(
+                    "Chunk of file {} from peer {} runs past its end",
+                    hex(&hash),
+                    peer_addr
+                );
+                reputation::penalise(ctx, peer_addr, Violation::ProtocolViolation);
+            }
+        }
+        Ok(())
+    }));
+}
+
+/// The chunk from `offset` to the end given was written to the file, or failed to be. This must
+/// not be called while the `Context` is already borrowed.
+fn handle_chunk_stored(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    hash: FileHash,
+    file: &Arc<File>,
+    offset: u64,
+    res: R<u64>,
+) {
+    let key = (peer_addr, hash);
+    ctx.with_mut(|c| {
+        let transfers = &mut c.transfers;
+        // Not if the file was refused meanwhile, even if it was accepted again since
+        match transfers.incoming.get(&key) {
+            Some(incoming) if Arc::ptr_eq(&incoming.file, file) => (),
+            _ => return,
+        }
+        let end = match res {
+            Ok(end) => end,
+            Err(e) => {
+                if let Some(incoming) = transfers.incoming.remove(&key) {
+                    discard(ctx, &mut transfers.disk, incoming.temp_path);
+                    fire_failed(&c.event_tx, peer_addr, hash, &e.to_string());
+                }
+                return;
+            }
+        };
+        let incoming = match transfers.incoming.get_mut(&key) {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        insert_range(&mut incoming.received, offset, end);
+
+        let transferred = incoming.received_len();
+        let len = incoming.len;
+        fire_progress(&c.event_tx, peer_addr, hash, transferred, len);
+        if transferred == len {
+            complete_incoming(ctx, transfers, peer_addr, hash);
+        }
+    })
+}
+
+/// Send the next chunks of the file, as many as there is room for in flight, once they are read.
+/// This must not be called while the `Context` is already borrowed.
+fn pump(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash) {
+    let read = ctx.with_mut(|c| {
+        let transfers = &mut c.transfers;
+        let outgoing = transfers.outgoing.get_mut(&(peer_addr, hash))?;
+        // E.g. the peer had the whole file already
+        if outgoing.in_flight == 0 && outgoing.next_offset >= outgoing.len {
+            fire_progress(&c.event_tx, peer_addr, hash, outgoing.len, outgoing.len);
+            let _ = transfers.outgoing.remove(&(peer_addr, hash));
+            return None;
+        }
+
+        let mut ranges = Vec::new();
+        while outgoing.in_flight < MAX_CHUNKS_IN_FLIGHT && outgoing.next_offset < outgoing.len {
+            let offset = outgoing.next_offset;
+            let chunk_len = cmp::min(FILE_CHUNK_LEN as u64, outgoing.len - offset) as usize;
+            outgoing.next_offset += chunk_len as u64;
+            outgoing.in_flight += 1;
+            ranges.push((offset, chunk_len));
+        }
+        if ranges.is_empty() {
+            return None;
+        }
+
+        let file = outgoing.file.clone();
+        Some(transfers.disk.run(move || {
+            let mut file: &File = &file;
+            ranges
+                .into_iter()
+                .map(|(offset, chunk_len)| -> io::Result<WireMsg> {
+                    let mut data = vec![0; chunk_len];
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.read_exact(&mut data)?;
+                    Ok(WireMsg::FileChunk {
+                        hash,
+                        offset,
+                        data: bytes::Bytes::from(data),
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>()
+        }))
+    });
+
+    if let Some(read) = read {
+        let ctx_clone = ctx.clone();
+        ctx.spawn(read.then(move |res| {
+            let ctx = &ctx_clone;
+            match res {
+                Ok(chunks) => write_chunks(ctx, peer_addr, hash, chunks),
+                Err(e) => fail_outgoing(ctx, peer_addr, hash, &e.to_string()),
+            }
+            Ok(())
+        }));
+    }
+}
+
+/// Write the chunks read to the peer. This must not be called while the `Context` is already
+/// borrowed.
+fn write_chunks(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash, chunks: Vec<WireMsg>) {
+    let is_connected = ctx.with(|c| {
+        // Given up on while the chunks were being read
+        if !c.transfers.outgoing.contains_key(&(peer_addr, hash)) {
+            return true;
+        }
+        let q_conn = match c
+            .connections
+            .get(&peer_addr)
+            .and_then(|conn| communicate::writable_q_conn(c, conn))
+        {
+            Some(q_conn) => q_conn,
+            None => return false,
+        };
+
+        for chunk in chunks {
+            let chunk_len = match chunk {
+                WireMsg::FileChunk { ref data, .. } => data.len() as u64,
+                _ => 0,
+            };
+            let ctx_on_written = ctx.clone();
+            communicate::write_frame_to_peer_connection(
+                ctx,
+                peer_addr,
+                q_conn,
+                None,
+                DEFAULT_CHANNEL,
+                chunk.into(),
+                Some(Box::new(move |is_sent| {
+                    handle_chunk_written(&ctx_on_written, peer_addr, hash, chunk_len, is_sent)
+                })),
+            );
+        }
+        true
+    });
+
+    if !is_connected {
+        fail_outgoing(ctx, peer_addr, hash, "not connected to the peer");
+    }
+}
+
+fn handle_chunk_written(
+    ctx: &Ctx,
+    peer_addr: SocketAddr,
+    hash: FileHash,
+    chunk_len: u64,
+    is_sent: bool,
+) {
+    if !is_sent {
+        return fail_outgoing(ctx, peer_addr, hash, "writing a chunk to the peer failed");
+    }
+
+    let is_done = ctx.with_mut(|c| {
+        let outgoing = match c.transfers.outgoing.get_mut(&(peer_addr, hash)) {
+            Some(outgoing) => outgoing,
+            None => return true,
+        };
+        outgoing.in_flight -= 1;
+        outgoing.sent += chunk_len;
+        fire_progress(&c.event_tx, peer_addr, hash, outgoing.sent, outgoing.len);
+
+        if outgoing.sent >= outgoing.len {
+            let _ = c.transfers.outgoing.remove(&(peer_addr, hash));
+            true
+        } else {
+            false
+        }
+    });
+
+    if !is_done {
+        pump(ctx, peer_addr, hash);
+    }
+}
+
+/// Give up on sending the file. This must not be called while the `Context` is already borrowed.
+fn fail_outgoing(ctx: &Ctx, peer_addr: SocketAddr, hash: FileHash, reason: &str) {
+    ctx.with_mut(|c| {
+        if c.transfers.outgoing.remove(&(peer_addr, hash)).is_some() {
+            fire_failed(&c.event_tx, peer_addr, hash, reason);
+        }
+    })
+}
+
+/// We have the whole file: hand it over to the user once it's found to match its hash, or discard
+/// it and fail the transfer if not. The peer then has to send it again, from the start.
+fn complete_incoming(ctx: &Ctx, transfers: &mut Transfers, peer_addr: SocketAddr, hash: FileHash) {
+    let incoming = match transfers.incoming.remove(&(peer_addr, hash)) {
+        Some(incoming) => incoming,
+        None => return,
+    };
+    let Incoming {
+        peer, temp_path, ..
+    } = incoming;
+
+    let path = temp_path.clone();
+    let verify = transfers.disk.run(move || hash_file(&path));
+    let ctx_clone = ctx.clone();
+    ctx.spawn(verify.then(move |res| {
+        let ctx = &ctx_clone;
+        let reason = match res {
+            Ok((_, actual)) if actual == hash => {
+                let event = Event::FileReceived {
+                    peer,
+                    temp_path,
+                    hash,
+                };
+                ctx.with(|c| {
+                    if let Err(e) = c.event_tx.send(event) {
+                        info!("Could not fire event: {:?}", e);
+                    }
+                });
+                return Ok(());
+            }
+            Ok(_) => "content doesn't match the hash".to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        ctx.with_mut(|c| {
+            discard(ctx, &mut c.transfers.disk, temp_path);
+            fire_failed(&c.event_tx, peer_addr, hash, &reason);
+        });
+        Ok(())
+    }));
+}
+
+/// Create the file for an incoming transfer in our directory, creating that too if need be. Both
+/// are created afresh, never opening whatever might be at their paths already, e.g. a link planted
+/// there. Our directory may exist already only as we named it at random.
+fn create_file(dir: &Path, path: &Path) -> io::Result<File> {
+    let mut dir_builder = fs::DirBuilder::new();
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        dir_builder.mode(0o700);
+        options.mode(0o600);
+    }
+
+    match dir_builder.create(dir) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(e),
+    }
+    options.read(true).write(true).create_new(true).open(path)
+}
+
+/// Remove the file on the disk thread, once it's done with any writes to it.
+fn discard(ctx: &Ctx, disk: &mut Disk, path: PathBuf) {
+    ctx.spawn(disk.run(move || fs::remove_file(path)).then(|_| Ok(())));
+}
+
+/// Add the range from `start` to `end` to the ones received, merging it with the ranges it
+/// overlaps or adjoins.
+fn insert_range(ranges: &mut BTreeMap<u64, u64>, mut start: u64, mut end: u64) {
+    if let Some((&prev_start, &prev_end)) = ranges.range(..=start).next_back() {
+        if prev_end >= start {
+            start = prev_start;
+            end = cmp::max(end, prev_end);
+        }
+    }
+    let merged: Vec<u64> = ranges.range(start..=end).map(|(s, _)| *s).collect();
+    for merged_start in merged {
+        if let Some(merged_end) = ranges.remove(&merged_start) {
+            end = cmp::max(end, merged_end);
+        }
+    }
+    let _ = ranges.insert(start, end);
+}
+
+fn fire_progress(
+    event_tx: &EventTx,
+    peer_addr: SocketAddr,
+    hash: FileHash,
+    transferred: u64,
+    total: u64,
+) {
+    let event = Event::FileTransferProgress {
+        peer_addr,
+        hash,
+        transferred,
+        total,
+    };
+    if let Err(e) = event_tx.send(event) {
+        info!("Could not fire event: {:?}", e);
+    }
+}
+
+fn fire_failed(event_tx: &EventTx, peer_addr: SocketAddr, hash: FileHash, reason: &str) {
+    debug!(
+        "Transfer of file {} with peer {} failed: {}",
+        hex(&hash),
+        peer_addr,
+        reason
+    );
+    let event = Event::FileTransferFailed {
+        peer_addr,
+        hash,
+        reason: reason.to_string(),
+    };
+    if let Err(e) = event_tx.send(event) {
+        info!("Could not fire event: {:?}", e);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn received_ranges_are_merged() {
+        let mut ranges = BTreeMap::new();
+        insert_range(&mut ranges, 20, 30);
+        insert_range(&mut ranges, 50, 60);
+        insert_range(&mut ranges, 0, 10);
+        assert_eq!(ranges.len(), 3);
+
+        // Adjoining both its neighbours
+        insert_range(&mut ranges, 10, 20);
+        assert_eq!(ranges.get(&0), Some(&30));
+        // Overlapping and duplicated
+        insert_range(&mut ranges, 25, 55);
+        insert_range(&mut ranges, 0, 10);
+        assert_eq!(ranges.into_iter().collect::<Vec<_>>(), vec![(0, 60)]);
+    }
+
+    #[test]
+    fn incoming_files_are_never_opened_over_existing_paths() {
+        let dir = unwrap!(Transfers::new(0, 0)).dir.clone();
+        let path = dir.join("file.part");
+
+        let _ = unwrap!(create_file(&dir, &path));
+        match create_file(&dir, &path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        #[cfg(unix)]
+        {
+            let target = dir.join("target");
+            let link = dir.join("link.part");
+            unwrap!(std::os::unix::fs::symlink(&target, &link));
+            assert!(create_file(&dir, &link).is_err());
+            assert!(!target.exists());
+        }
+
+        unwrap!(fs::remove_dir_all(dir));
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Source of the socket our QUIC endpoint runs over. It's a plain UDP socket unless the user
+//! supplies their own `Transport` via `Builder::with_transport`, e.g. to hand over a socket set up
+//! by the host application or to route the packets through something else first, or asks for a
+//! `Config::upstream_proxy`.
+//!
+//! The endpoint, connections and streams on top of it are quinn's, and the quinn release we are on
+//! only runs over a UDP socket of the OS. Backends without one, e.g. WebTransport or WebRTC data
+//! channels for browsers, can't be slotted in here until those are abstracted as well.
+
+use crate::config::SocketOptions;
+use crate::socket;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+
+/// Binds the sockets our endpoint runs over, on start up and on `QuicP2p::restart_listener`.
+pub trait Transport: Send + Sync {
+    /// Socket bound to the given address (port 0 for any) with the requested options applied as
+    /// far as possible, along with the options actually in effect on it.
+    fn bind(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        requested: &SocketOptions,
+    ) -> io::Result<(UdpSocket, SocketOptions)>;
+
+    /// Address the endpoint is to send to in order to reach the peer at `peer_addr`. The same
+    /// unless the transport stands in for the peers itself, e.g. to relay the packets.
+    fn wire_addr(&self, peer_addr: SocketAddr) -> io::Result<SocketAddr> {
+        Ok(peer_addr)
+    }
+
+    /// Address of the peer the endpoint sees at `wire_addr`, the other way around.
+    fn peer_addr(&self, wire_addr: SocketAddr) -> SocketAddr {
+        wire_addr
+    }
+}
+
+/// Transport shared by the builder and the event loop.
+pub type SharedTransport = Arc<dyn Transport>;
+
+/// UDP sockets of the OS, the default.
+pub struct UdpTransport;
+
+impl Transport for UdpTransport {
+    fn bind(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        requested: &SocketOptions,
+    ) -> io::Result<(UdpSocket, SocketOptions)> {
+        socket::bind(ip, port, requested)
+    }
+}
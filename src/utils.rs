@@ -7,9 +7,11 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::ctx_mut;
+use crate::connect;
+use crate::context::Ctx;
 use crate::dirs::Dirs;
 use crate::error::Error;
+use crate::wire_msg::CloseReason;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fs::File;
@@ -35,6 +37,15 @@ pub fn project_dir() -> R<Dirs> {
     Ok(Dirs::Desktop(dirs))
 }
 
+/// Short hex digest of the certificate, stable for as long as our identity is.
+pub fn cert_fingerprint(cert_der: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert_der);
+    digest.as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 /// Convert binary data to a diplay-able format
 #[inline]
 pub fn bin_data_format(data: &[u8]) -> String {
@@ -57,13 +68,29 @@ pub fn bin_data_format(data: &[u8]) -> String {
 }
 
 /// Handle error in communication.
+///
+/// If auto-reconnect is enabled and this was a node we had connected to, it will be reconnected to.
 #[inline]
-pub fn handle_communication_err(peer_addr: SocketAddr, e: &Error, details: &str) {
+pub fn handle_communication_err(ctx: &Ctx, peer_addr: SocketAddr, e: &Error, details: &str) {
     debug!(
         "ERROR in communication with peer {}: {:?} - {}. Details: {}",
         peer_addr, e, e, details
     );
-    let _ = ctx_mut(|c| c.connections.remove(&peer_addr));
+    let reconnect_info = ctx.with_mut(|c| {
+        let mut conn = c.connections.remove(&peer_addr)?;
+        if let Some(reason) = CloseReason::from_peer_close(e) {
+            conn.set_close_reason(reason);
+        }
+        if c.auto_reconnect.is_some() {
+            conn.take_reconnect_info()
+        } else {
+            None
+        }
+    });
+
+    if let Some((node_info, msgs)) = reconnect_info {
+        connect::reconnect(ctx, node_info, msgs);
+    }
 }
 
 /// Try reading from the disk into the given structure.
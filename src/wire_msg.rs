@@ -7,40 +7,439 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::{utils, R};
+use crate::error::Error;
+use crate::handshake_auth::Nonce;
+use crate::transfer::FileHash;
+use crate::{utils, NodeInfo, DEFAULT_CHANNEL, R};
+use std::convert::TryFrom;
 use std::fmt;
 use std::net::SocketAddr;
 
-const MAX_MESSAGE_SIZE_FOR_SERIALISATION: usize = 1024; // 1 KiB
+/// Every frame starts with a header: the kind of the frame (one byte) followed by the length of
+/// the payload (big endian `u32`). The payload must take up exactly the rest of the frame.
+const FRAME_HEADER_LEN: usize = 5;
+/// Payload is the user message as is.
+const KIND_USER_MSG: u8 = 0;
+/// Payload is the envelope header followed by the user message as is.
+const KIND_USER_MSG_ENVELOPE: u8 = 1;
+/// Payload is any other message serialised with bincode.
+const KIND_SERIALISED: u8 = 2;
+/// Payload is a user message encoded by the sender's codec, as is.
+const KIND_TYPED_USER_MSG: u8 = 3;
+/// Header-only frame: this kind byte alone, followed by a small user message as is. The end of the
+/// stream delimits the message, so there's no length. Used for every user message of up to
+/// `SMALL_USER_MSG_MAX_LEN` bytes, e.g. application heartbeats and acknowledgements.
+const KIND_SMALL_USER_MSG: u8 = 4;
+/// Longest user message sent in a header-only frame. This saves just the four length bytes: such
+/// messages still take a stream of their own, as QUIC datagrams aren't supported by the quinn
+/// release we are on.
+const SMALL_USER_MSG_MAX_LEN: usize = 64;
+/// Payload is one or more user messages, each preceded by its length (big endian `u32`).
+const KIND_USER_MSG_BATCH: u8 = 5;
+/// Length prefix of every message in a batch.
+const BATCHED_MSG_HEADER_LEN: usize = 4;
+/// Flags (one byte, see below), channel (one byte), message id and in-reply-to id (both big endian
+/// `u64`, zero if the corresponding flag is unset).
+const ENVELOPE_HEADER_LEN: usize = 18;
+/// Envelope flag set if the message id is present.
+const ENVELOPE_HAS_MSG_ID: u8 = 0b01;
+/// Envelope flag set if the in-reply-to id is present.
+const ENVELOPE_HAS_IN_REPLY_TO: u8 = 0b10;
+/// Prefix of the frame of a user message sequenced for a peer which asked for ordered delivery:
+/// this kind byte followed by the sequence number (big endian `u64`), then the frame as is.
+const KIND_SEQUENCED: u8 = 6;
+/// Length of the prefix of sequenced frames.
+pub const SEQUENCED_PREFIX_LEN: usize = 9;
+/// Prefix of the frame of a user message tagged so that its replays can be told apart, see
+/// `Config::dedup`: this kind byte followed by the id of the message (big endian `u64`), then the
+/// frame as is, sequenced or not.
+const KIND_DEDUP: u8 = 7;
+/// Length of the prefix of tagged frames.
+pub const DEDUP_PREFIX_LEN: usize = 9;
+/// Messages internal to QuicP2p are all small, so anything bigger is rejected before it's
+/// deserialised. This also bounds the size of any collection they hold.
+const MAX_SERIALISED_MSG_SIZE: usize = 64 * 1024; // 64 KiB
+/// How much bigger than the user message it carries a frame can be.
+pub const MAX_FRAME_OVERHEAD: usize = FRAME_HEADER_LEN + ENVELOPE_HEADER_LEN;
 
 /// Final type serialised and sent on the wire by QuicP2p
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum WireMsg {
     Handshake(Handshake),
     EndpointEchoReq,
     EndpointEchoResp(SocketAddr),
     UserMsg(bytes::Bytes),
+    /// Ask the recipient to connect to the given node. Used when the node can't reach the
+    /// recipient itself, e.g. because it's behind a NAT.
+    ReverseConnect {
+        target_info: NodeInfo,
+    },
+    /// Outcome of an earlier `ReverseConnect` request
+    ReverseConnectResult {
+        target_addr: SocketAddr,
+        success: bool,
+    },
+    /// Ask the recipient for contacts from its bootstrap cache
+    GetContacts,
+    /// Contacts sent in response to `GetContacts`
+    Contacts(Vec<NodeInfo>),
+    /// Ask the recipient to confirm it's up and serving. Answered even before the handshake, so
+    /// it can be used to check our own endpoint end to end. `sent_at_msec` is our wall-clock time
+    /// in milliseconds since the UNIX epoch, for the clock skew estimate.
+    HealthCheckReq {
+        sent_at_msec: u64,
+    },
+    /// Response to `HealthCheckReq`, with the time the request was sent at as it came and the
+    /// wall-clock time of the responder
+    HealthCheckResp {
+        req_sent_at_msec: u64,
+        sent_at_msec: u64,
+    },
+    /// User message tagged with an id the recipient can refer to when replying and/or sent on a
+    /// channel other than the default one. Users which need neither keep sending the plain
+    /// `UserMsg`.
+    UserMsgEnvelope {
+        msg: bytes::Bytes,
+        msg_id: Option<u64>,
+        in_reply_to: Option<u64>,
+        channel: u8,
+    },
+    /// The sender has replaced its certificate with `new_cert`. The signature over it with the key
+    /// of the certificate it's replacing proves it's still the same peer.
+    CertRotation {
+        new_cert: Vec<u8>,
+        signature_by_old_key: Vec<u8>,
+    },
+    /// User message sent with `QuicP2p::send_typed`, i.e. encoded by the sender's codec. Peers
+    /// without a codec hand it over to their user as a plain message.
+    TypedUserMsg(bytes::Bytes),
+    /// User messages sent together with `QuicP2p::send_batch`, handed over to the recipient's user
+    /// one by one in the order they are in.
+    UserMsgBatch(Vec<bytes::Bytes>),
+    /// Offer of the file with the given hash sent with `QuicP2p::send_file`, see `transfer`
+    FileOffer {
+        hash: FileHash,
+        len: u64,
+    },
+    /// Acceptance of a `FileOffer`, asking for the file from `offset` on
+    FileAccept {
+        hash: FileHash,
+        offset: u64,
+    },
+    /// Part of an offered file starting at `offset`
+    FileChunk {
+        hash: FileHash,
+        offset: u64,
+        data: bytes::Bytes,
+    },
+    /// Refusal of a `FileOffer`, or of the rest of a file being sent
+    FileRefuse {
+        hash: FileHash,
+    },
 }
 
-impl Into<bytes::Bytes> for WireMsg {
-    fn into(self) -> bytes::Bytes {
-        if let WireMsg::UserMsg(ref m) = self {
-            if m.len() > MAX_MESSAGE_SIZE_FOR_SERIALISATION {
-                return m.clone();
+impl WireMsg {
+    /// Frame the message for the wire. Fails if it's longer than the length in its frame header,
+    /// or in the header of a message batched in it, can tell.
+    pub fn into_frame(self) -> R<bytes::Bytes> {
+        if let WireMsg::UserMsg(ref msg) = self {
+            if msg.len() <= SMALL_USER_MSG_MAX_LEN {
+                let mut frame = Vec::with_capacity(1 + msg.len());
+                frame.push(KIND_SMALL_USER_MSG);
+                frame.extend_from_slice(msg);
+                return Ok(From::from(frame));
             }
         }
 
-        From::from(unwrap!(bincode::serialize(&self)))
+        let mut frame = Vec::with_capacity(self.max_frame_len());
+        frame.extend_from_slice(&[0; FRAME_HEADER_LEN]);
+
+        let kind = match self {
+            WireMsg::UserMsg(ref msg) => {
+                frame.extend_from_slice(msg);
+                KIND_USER_MSG
+            }
+            WireMsg::TypedUserMsg(ref msg) => {
+                frame.extend_from_slice(msg);
+                KIND_TYPED_USER_MSG
+            }
+            WireMsg::UserMsgBatch(ref msgs) => {
+                for msg in msgs {
+                    frame.extend_from_slice(&wire_len(msg.len())?.to_be_bytes());
+                    frame.extend_from_slice(msg);
+                }
+                KIND_USER_MSG_BATCH
+            }
+            WireMsg::UserMsgEnvelope {
+                ref msg,
+                msg_id,
+                in_reply_to,
+                channel,
+            } => {
+                let mut flags = 0;
+                if msg_id.is_some() {
+                    flags |= ENVELOPE_HAS_MSG_ID;
+                }
+                if in_reply_to.is_some() {
+                    flags |= ENVELOPE_HAS_IN_REPLY_TO;
+                }
+                frame.push(flags);
+                frame.push(channel);
+                frame.extend_from_slice(&msg_id.unwrap_or(0).to_be_bytes());
+                frame.extend_from_slice(&in_reply_to.unwrap_or(0).to_be_bytes());
+                frame.extend_from_slice(msg);
+                KIND_USER_MSG_ENVELOPE
+            }
+            ref wire_msg => {
+                frame.extend_from_slice(&bincode::serialize(wire_msg)?);
+                KIND_SERIALISED
+            }
+        };
+
+        let payload_len = wire_len(frame.len() - FRAME_HEADER_LEN)?;
+        frame[0] = kind;
+        frame[1..FRAME_HEADER_LEN].copy_from_slice(&payload_len.to_be_bytes());
+
+        Ok(From::from(frame))
     }
-}
 
-impl WireMsg {
-    pub fn from_raw(raw: Vec<u8>) -> R<Self> {
-        if raw.len() > MAX_MESSAGE_SIZE_FOR_SERIALISATION {
-            return Ok(WireMsg::UserMsg(From::from(raw)));
+    /// Bytes of user data held by this message. Used to account for how much we are buffering on
+    /// behalf of peers.
+    pub fn user_data_len(&self) -> usize {
+        match *self {
+            WireMsg::UserMsg(ref m)
+            | WireMsg::UserMsgEnvelope { msg: ref m, .. }
+            | WireMsg::TypedUserMsg(ref m) => m.len(),
+            WireMsg::UserMsgBatch(ref msgs) => msgs.iter().map(bytes::Bytes::len).sum(),
+            WireMsg::Handshake(Handshake::Node {
+                ref cert_der,
+                ref user_data,
+                ..
+            })
+            | WireMsg::Handshake(Handshake::Client {
+                ref cert_der,
+                ref user_data,
+                ..
+            }) => cert_der.len() + user_data.as_ref().map_or(0, |d| d.len()),
+            WireMsg::CertRotation { ref new_cert, .. } => new_cert.len(),
+            WireMsg::FileChunk { ref data, .. } => data.len(),
+            WireMsg::EndpointEchoReq
+            | WireMsg::EndpointEchoResp(_)
+            | WireMsg::ReverseConnect { .. }
+            | WireMsg::ReverseConnectResult { .. }
+            | WireMsg::GetContacts
+            | WireMsg::Contacts(_)
+            | WireMsg::HealthCheckReq { .. }
+            | WireMsg::HealthCheckResp { .. }
+            | WireMsg::FileOffer { .. }
+            | WireMsg::FileAccept { .. }
+            | WireMsg::FileRefuse { .. } => 0,
+        }
+    }
+
+    /// Logical channel this is sent on. Everything but user messages sent on a particular channel
+    /// goes over the default one.
+    pub fn channel(&self) -> u8 {
+        match *self {
+            WireMsg::UserMsgEnvelope { channel, .. } => channel,
+            _ => DEFAULT_CHANNEL,
+        }
+    }
+
+    /// Id the sender tagged this user message with, if any.
+    pub fn msg_id(&self) -> Option<u64> {
+        match *self {
+            WireMsg::UserMsgEnvelope { msg_id, .. } => msg_id,
+            _ => None,
+        }
+    }
+
+    /// Id of the message this one replies to, if it's a reply.
+    pub fn in_reply_to(&self) -> Option<u64> {
+        match *self {
+            WireMsg::UserMsgEnvelope { in_reply_to, .. } => in_reply_to,
+            _ => None,
+        }
+    }
+
+    /// Whether this carries user data, as opposed to being a message internal to QuicP2p.
+    pub fn is_user_msg(&self) -> bool {
+        match *self {
+            WireMsg::UserMsg(_)
+            | WireMsg::UserMsgEnvelope { .. }
+            | WireMsg::TypedUserMsg(_)
+            | WireMsg::UserMsgBatch(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The user data carried by this message, if it's a single user message.
+    pub fn into_user_msg(self) -> Option<bytes::Bytes> {
+        match self {
+            WireMsg::UserMsg(msg)
+            | WireMsg::UserMsgEnvelope { msg, .. }
+            | WireMsg::TypedUserMsg(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Parse a frame received from a peer along with its dedup id and sequence number, if it's
+    /// tagged and sequenced respectively. Only user messages are.
+    pub fn from_tagged_bytes_safe(raw: Vec<u8>) -> R<(Option<u64>, Option<u64>, Self)> {
+        if raw.first() != Some(&KIND_DEDUP) {
+            let (seq, wire_msg) = Self::from_sequenced_bytes_safe(raw)?;
+            return Ok((None, seq, wire_msg));
+        }
+        if raw.len() < DEDUP_PREFIX_LEN {
+            return Err(Error::InvalidWireMsg(
+                "tagged frame is shorter than its prefix",
+            ));
+        }
+
+        let dedup_id = read_u64_be(&raw[1..DEDUP_PREFIX_LEN]);
+        let (seq, wire_msg) = Self::from_sequenced_bytes_safe(raw[DEDUP_PREFIX_LEN..].to_vec())?;
+        if !wire_msg.is_user_msg() {
+            return Err(Error::InvalidWireMsg("only user messages are tagged"));
         }
 
-        Ok(bincode::deserialize(&raw)?)
+        Ok((Some(dedup_id), seq, wire_msg))
+    }
+
+    /// Parse a frame received from a peer along with its sequence number, if it's sequenced. Only
+    /// user messages are.
+    pub fn from_sequenced_bytes_safe(raw: Vec<u8>) -> R<(Option<u64>, Self)> {
+        if raw.first() != Some(&KIND_SEQUENCED) {
+            return Ok((None, Self::from_bytes_safe(raw)?));
+        }
+        if raw.len() < SEQUENCED_PREFIX_LEN {
+            return Err(Error::InvalidWireMsg(
+                "sequenced frame is shorter than its prefix",
+            ));
+        }
+
+        let seq = read_u64_be(&raw[1..SEQUENCED_PREFIX_LEN]);
+        let wire_msg = Self::from_bytes_safe(raw[SEQUENCED_PREFIX_LEN..].to_vec())?;
+        if !wire_msg.is_user_msg() {
+            return Err(Error::InvalidWireMsg("only user messages are sequenced"));
+        }
+
+        Ok((Some(seq), wire_msg))
+    }
+
+    /// Parse a frame received from a peer.
+    ///
+    /// The peer is not trusted, so every length is validated before it's acted upon and frames
+    /// which are truncated, carry trailing bytes or are not in their canonical form are rejected.
+    pub fn from_bytes_safe(raw: Vec<u8>) -> R<Self> {
+        if raw.first() == Some(&KIND_SMALL_USER_MSG) {
+            if raw.len() - 1 > SMALL_USER_MSG_MAX_LEN {
+                return Err(Error::InvalidWireMsg("header-only frame is too long"));
+            }
+            return Ok(WireMsg::UserMsg(bytes::Bytes::from(raw).split_off(1)));
+        }
+
+        if raw.len() < FRAME_HEADER_LEN {
+            return Err(Error::InvalidWireMsg("frame is shorter than its header"));
+        }
+        let kind = raw[0];
+        let payload_len = read_u32_be(&raw[1..FRAME_HEADER_LEN]) as usize;
+        if payload_len != raw.len() - FRAME_HEADER_LEN {
+            return Err(Error::InvalidWireMsg(
+                "payload length doesn't match the frame length",
+            ));
+        }
+
+        let payload = bytes::Bytes::from(raw).split_off(FRAME_HEADER_LEN);
+
+        match kind {
+            KIND_USER_MSG if payload.len() <= SMALL_USER_MSG_MAX_LEN => Err(Error::InvalidWireMsg(
+                "small user message not in a header-only frame",
+            )),
+            KIND_USER_MSG => Ok(WireMsg::UserMsg(payload)),
+            KIND_USER_MSG_ENVELOPE => Self::envelope_from_payload(payload),
+            KIND_SERIALISED => Self::deserialise_payload(&payload),
+            KIND_TYPED_USER_MSG => Ok(WireMsg::TypedUserMsg(payload)),
+            KIND_USER_MSG_BATCH => Self::batch_from_payload(payload),
+            _ => Err(Error::InvalidWireMsg("unknown frame kind")),
+        }
+    }
+
+    fn envelope_from_payload(mut payload: bytes::Bytes) -> R<Self> {
+        if payload.len() < ENVELOPE_HEADER_LEN {
+            return Err(Error::InvalidWireMsg("envelope is shorter than its header"));
+        }
+        let msg = payload.split_off(ENVELOPE_HEADER_LEN);
+
+        let flags = payload[0];
+        if flags & !(ENVELOPE_HAS_MSG_ID | ENVELOPE_HAS_IN_REPLY_TO) != 0 {
+            return Err(Error::InvalidWireMsg("unknown flags in envelope"));
+        }
+        let channel = payload[1];
+        let msg_id = optional_id(flags & ENVELOPE_HAS_MSG_ID != 0, &payload[2..10])
+            .ok_or(Error::InvalidWireMsg("invalid message id in envelope"))?;
+        let in_reply_to = optional_id(
+            flags & ENVELOPE_HAS_IN_REPLY_TO != 0,
+            &payload[10..ENVELOPE_HEADER_LEN],
+        )
+        .ok_or(Error::InvalidWireMsg("invalid in-reply-to in envelope"))?;
+
+        Ok(WireMsg::UserMsgEnvelope {
+            msg,
+            msg_id,
+            in_reply_to,
+            channel,
+        })
+    }
+
+    fn batch_from_payload(mut payload: bytes::Bytes) -> R<Self> {
+        if payload.is_empty() {
+            return Err(Error::InvalidWireMsg("empty batch of user messages"));
+        }
+
+        let mut msgs = Vec::new();
+        while !payload.is_empty() {
+            if payload.len() < BATCHED_MSG_HEADER_LEN {
+                return Err(Error::InvalidWireMsg("truncated length of batched message"));
+            }
+            let len = read_u32_be(&payload.split_to(BATCHED_MSG_HEADER_LEN)) as usize;
+            if len > payload.len() {
+                return Err(Error::InvalidWireMsg("batched message is truncated"));
+            }
+            msgs.push(payload.split_to(len));
+        }
+
+        Ok(WireMsg::UserMsgBatch(msgs))
+    }
+
+    /// Upper bound of the length of the frame this is sent in.
+    fn max_frame_len(&self) -> usize {
+        let batch_headers_len = match *self {
+            WireMsg::UserMsgBatch(ref msgs) => msgs.len() * BATCHED_MSG_HEADER_LEN,
+            _ => 0,
+        };
+        FRAME_HEADER_LEN + ENVELOPE_HEADER_LEN + batch_headers_len + self.user_data_len()
+    }
+
+    fn deserialise_payload(payload: &[u8]) -> R<Self> {
+        if payload.len() > MAX_SERIALISED_MSG_SIZE {
+            return Err(Error::WireMsgTooLarge(payload.len()));
+        }
+
+        let wire_msg: WireMsg = bincode::config()
+            .limit(MAX_SERIALISED_MSG_SIZE as u64)
+            .deserialize(payload)?;
+        if wire_msg.is_user_msg() {
+            return Err(Error::InvalidWireMsg(
+                "user messages must not be serialised",
+            ));
+        }
+        if bincode::serialized_size(&wire_msg)? != payload.len() as u64 {
+            return Err(Error::InvalidWireMsg(
+                "trailing bytes after serialised message",
+            ));
+        }
+
+        Ok(wire_msg)
     }
 }
 
@@ -50,11 +449,74 @@ impl fmt::Display for WireMsg {
             WireMsg::UserMsg(ref m) => {
                 write!(f, "WireMsg::UserMsg({})", utils::bin_data_format(&*m))
             }
-            ref w => write!(f, "{}", w),
+            WireMsg::TypedUserMsg(ref m) => {
+                write!(f, "WireMsg::TypedUserMsg({})", utils::bin_data_format(&*m))
+            }
+            WireMsg::UserMsgEnvelope {
+                ref msg,
+                msg_id,
+                in_reply_to,
+                channel,
+            } => write!(
+                f,
+                "WireMsg::UserMsgEnvelope {{ msg: {}, msg_id: {:?}, in_reply_to: {:?}, channel: {} }}",
+                utils::bin_data_format(&*msg),
+                msg_id,
+                in_reply_to,
+                channel
+            ),
+            WireMsg::UserMsgBatch(ref msgs) => {
+                write!(f, "WireMsg::UserMsgBatch({} messages)", msgs.len())
+            }
+            ref w => write!(f, "{:?}", w),
         }
     }
 }
 
+/// Length as carried on the wire, if it fits.
+fn wire_len(len: usize) -> R<u32> {
+    u32::try_from(len).map_err(|_| Error::WireMsgTooLarge(len))
+}
+
+/// Prefix the frame of a user message with its sequence number.
+pub fn sequenced_frame(seq: u64, frame: &[u8]) -> bytes::Bytes {
+    let mut sequenced = Vec::with_capacity(SEQUENCED_PREFIX_LEN + frame.len());
+    sequenced.push(KIND_SEQUENCED);
+    sequenced.extend_from_slice(&seq.to_be_bytes());
+    sequenced.extend_from_slice(frame);
+    From::from(sequenced)
+}
+
+/// Prefix the frame of a user message, sequenced or not, with the id its replays share.
+pub fn dedup_frame(dedup_id: u64, frame: &[u8]) -> bytes::Bytes {
+    let mut tagged = Vec::with_capacity(DEDUP_PREFIX_LEN + frame.len());
+    tagged.push(KIND_DEDUP);
+    tagged.extend_from_slice(&dedup_id.to_be_bytes());
+    tagged.extend_from_slice(frame);
+    From::from(tagged)
+}
+
+/// Split the messages into as few batches as possible, keeping their order, so that each batch
+/// fits a frame of up to `max_frame_len` bytes. Messages too big to share a frame go on their own.
+pub fn split_into_batches(msgs: Vec<bytes::Bytes>, max_frame_len: usize) -> Vec<Vec<bytes::Bytes>> {
+    let mut batches: Vec<Vec<bytes::Bytes>> = Vec::new();
+    let mut frame_len = 0;
+    for msg in msgs {
+        let batched_len = BATCHED_MSG_HEADER_LEN + msg.len();
+        match batches.last_mut() {
+            Some(ref mut batch) if frame_len + batched_len <= max_frame_len => {
+                frame_len += batched_len;
+                batch.push(msg);
+            }
+            _ => {
+                frame_len = FRAME_HEADER_LEN + batched_len;
+                batches.push(vec![msg]);
+            }
+        }
+    }
+    batches
+}
+
 /// Type of Handshake.
 ///
 /// If the peer is a client then we allow a single connection between us. This can have multiple
@@ -64,24 +526,665 @@ impl fmt::Display for WireMsg {
 /// passive connection from a peer will allow only incoming uni-directional streams from it.
 ///
 /// Depending on the handshake we will categorise the peer and give this information to the user.
-#[derive(Serialize, Deserialize, Debug)]
+/// Either kind of peer can attach a small application defined blob (network name, version etc.)
+/// which is handed over to the user along with the connection event. The network id is checked
+/// against ours and the peer is rejected if they don't match. The address the peer reached us at
+/// is included too, which tells us how we are seen from the outside, as are the logical channels
+/// the peer accepts user messages on besides the default one and the wall-clock time of the peer
+/// in milliseconds since the UNIX epoch, for a first estimate of its clock skew. Finally the peer
+/// tells whether it wants the user messages sent to it sequenced, see
+/// `Config::ordered_delivery`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Handshake {
     /// The connecting peer is a node. Certificate is needed for allowing connection back to the
-    /// peer
-    Node { cert_der: Vec<u8> },
-    /// The connecting peer is a client. No need for a reverse connection.
-    Client,
+    /// peer. The signature over the nonce and the certificate proves the peer owns the
+    /// certificate, while the nonce prevents the handshake from being replayed.
+    Node {
+        cert_der: Vec<u8>,
+        network_id: String,
+        user_data: Option<bytes::Bytes>,
+        nonce: Nonce,
+        signature: Vec<u8>,
+        observed_addr: SocketAddr,
+        channels: Vec<u8>,
+        sent_at_msec: u64,
+        ordered_delivery: bool,
+    },
+    /// The connecting peer is a client. No need for a reverse connection. The certificate only
+    /// identifies the client across its connections and, as for nodes, the signature proves the
+    /// client owns it.
+    Client {
+        cert_der: Vec<u8>,
+        network_id: String,
+        user_data: Option<bytes::Bytes>,
+        nonce: Nonce,
+        signature: Vec<u8>,
+        observed_addr: SocketAddr,
+        channels: Vec<u8>,
+        sent_at_msec: u64,
+        ordered_delivery: bool,
+    },
+}
+
+impl Handshake {
+    /// Name of the network the peer claims to belong to
+    pub fn network_id(&self) -> &str {
+        match *self {
+            Handshake::Node { ref network_id, .. } | Handshake::Client { ref network_id, .. } => {
+                network_id
+            }
+        }
+    }
+
+    /// Channels the peer accepts user messages on besides the default one
+    pub fn channels(&self) -> &[u8] {
+        match *self {
+            Handshake::Node { ref channels, .. } | Handshake::Client { ref channels, .. } => {
+                channels
+            }
+        }
+    }
+
+    /// Address the peer reached us at
+    pub fn observed_addr(&self) -> SocketAddr {
+        match *self {
+            Handshake::Node { observed_addr, .. } | Handshake::Client { observed_addr, .. } => {
+                observed_addr
+            }
+        }
+    }
+
+    /// Wall-clock time of the peer when it sent the handshake
+    pub fn sent_at_msec(&self) -> u64 {
+        match *self {
+            Handshake::Node { sent_at_msec, .. } | Handshake::Client { sent_at_msec, .. } => {
+                sent_at_msec
+            }
+        }
+    }
+
+    /// Whether the peer wants the user messages we send it sequenced, to hand them over in order
+    pub fn ordered_delivery(&self) -> bool {
+        match *self {
+            Handshake::Node {
+                ordered_delivery, ..
+            }
+            | Handshake::Client {
+                ordered_delivery, ..
+            } => ordered_delivery,
+        }
+    }
 }
 
 impl fmt::Display for Handshake {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Handshake::Node { ref cert_der } => write!(
+            Handshake::Node {
+                ref cert_der,
+                ref network_id,
+                ref user_data,
+                ref nonce,
+                ref signature,
+                observed_addr,
+                ref channels,
+                sent_at_msec,
+                ordered_delivery,
+            } => write!(
+                f,
+                "Handshake::Node {{ cert_der: {}, network_id: {}, user_data: {}, nonce: {}, \
+                 signature: {}, observed_addr: {}, channels: {:?}, sent_at_msec: {}, \
+                 ordered_delivery: {} }}",
+                utils::bin_data_format(cert_der),
+                network_id,
+                user_data_format(user_data),
+                utils::bin_data_format(nonce),
+                utils::bin_data_format(signature),
+                observed_addr,
+                channels,
+                sent_at_msec,
+                ordered_delivery
+            ),
+            Handshake::Client {
+                ref cert_der,
+                ref network_id,
+                ref user_data,
+                ref nonce,
+                ref signature,
+                observed_addr,
+                ref channels,
+                sent_at_msec,
+                ordered_delivery,
+            } => write!(
                 f,
-                "Handshake::Node {{ cert_der: {} }}",
-                utils::bin_data_format(cert_der)
+                "Handshake::Client {{ cert_der: {}, network_id: {}, user_data: {}, nonce: {}, \
+                 signature: {}, observed_addr: {}, channels: {:?}, sent_at_msec: {}, \
+                 ordered_delivery: {} }}",
+                utils::bin_data_format(cert_der),
+                network_id,
+                user_data_format(user_data),
+                utils::bin_data_format(nonce),
+                utils::bin_data_format(signature),
+                observed_addr,
+                channels,
+                sent_at_msec,
+                ordered_delivery
             ),
-            Handshake::Client => write!(f, "Handshake::Client"),
+        }
+    }
+}
+
+/// Why a connection was closed. This is sent to the peer as the application error code when we
+/// close the connection, so it can tell policy decisions apart from network failures.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Nothing more specific is known, e.g. the connection was lost due to a network failure
+    Unspecified,
+    /// The connection was closed at the request of the user or because we are shutting down
+    Shutdown,
+    /// There already is a connection from the peer
+    Duplicate,
+    /// The peer broke the protocol
+    ProtocolViolation,
+    /// We were buffering more data on behalf of the peer than we allow
+    Overloaded,
+    /// The connection didn't complete in time
+    Evicted,
+    /// We don't accept the peer, e.g. it's blacklisted, from a different network or we are not
+    /// accepting new connections
+    Refused,
+    /// The peer speaks none of the QUIC versions we do (see `QuicP2p::supported_quic_versions`),
+    /// e.g. because it's built with another quinn release. quinn doesn't tell us which versions
+    /// the peer offered instead, compare with its own `supported_quic_versions` for those.
+    QuicVersionMismatch,
+    /// The peer connected to us but didn't introduce itself via its handshake in time (see
+    /// `Config::handshake_timeout_msec`)
+    HandshakeTimedOut,
+    /// We are at `Config::max_total_connections`, so either the connection is refused or it's
+    /// closed to make room for a new one
+    ConnectionLimit,
+    /// The connection got into a state it never should have, see `Config::strict`
+    IllegalState,
+    /// The certificate of the peer has expired, so TLS with it fails. It's told apart from other
+    /// failed connects by us, peers never close connections with it.
+    PeerCertExpired,
+}
+
+impl CloseReason {
+    /// Application error code the reason is sent as.
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Unspecified => 0,
+            CloseReason::Shutdown => 1,
+            CloseReason::Duplicate => 2,
+            CloseReason::ProtocolViolation => 3,
+            CloseReason::Overloaded => 4,
+            CloseReason::Evicted => 5,
+            CloseReason::Refused => 6,
+            CloseReason::QuicVersionMismatch => 7,
+            CloseReason::HandshakeTimedOut => 8,
+            CloseReason::ConnectionLimit => 9,
+            CloseReason::IllegalState => 10,
+            CloseReason::PeerCertExpired => 11,
+        }
+    }
+
+    /// Reason for the given application error code. Unknown codes are treated as unspecified.
+    pub fn from_code(code: u64) -> Self {
+        match code {
+            1 => CloseReason::Shutdown,
+            2 => CloseReason::Duplicate,
+            3 => CloseReason::ProtocolViolation,
+            4 => CloseReason::Overloaded,
+            5 => CloseReason::Evicted,
+            6 => CloseReason::Refused,
+            7 => CloseReason::QuicVersionMismatch,
+            8 => CloseReason::HandshakeTimedOut,
+            9 => CloseReason::ConnectionLimit,
+            10 => CloseReason::IllegalState,
+            11 => CloseReason::PeerCertExpired,
+            _ => CloseReason::Unspecified,
+        }
+    }
+
+    /// Reason the peer gave for closing the connection, or for refusing it outright during version
+    /// negotiation, if the error is due to that.
+    pub fn from_peer_close(e: &Error) -> Option<Self> {
+        match e {
+            Error::Connection(quinn::ConnectionError::ApplicationClosed { reason }) => {
+                Some(Self::from_code(reason.error_code.into()))
+            }
+            Error::Connection(quinn::ConnectionError::VersionMismatch) => {
+                Some(CloseReason::QuicVersionMismatch)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for CloseReason {
+    fn default() -> Self {
+        CloseReason::Unspecified
+    }
+}
+
+fn read_u32_be(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_be_bytes(buf)
+}
+
+fn read_u64_be(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+/// Id read off an envelope, `None` if it's malformed. Absent ids must be zeroed out.
+fn optional_id(is_set: bool, bytes: &[u8]) -> Option<Option<u64>> {
+    match read_u64_be(bytes) {
+        id if is_set => Some(Some(id)),
+        0 => Some(None),
+        _ => None,
+    }
+}
+
+fn user_data_format(user_data: &Option<bytes::Bytes>) -> String {
+    user_data
+        .as_ref()
+        .map_or_else(|| "None".to_string(), |d| utils::bin_data_format(d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn close_reasons_survive_the_round_trip_through_codes() {
+        for reason in &[
+            CloseReason::Unspecified,
+            CloseReason::Shutdown,
+            CloseReason::Duplicate,
+            CloseReason::ProtocolViolation,
+            CloseReason::Overloaded,
+            CloseReason::Evicted,
+            CloseReason::Refused,
+            CloseReason::QuicVersionMismatch,
+            CloseReason::HandshakeTimedOut,
+            CloseReason::ConnectionLimit,
+            CloseReason::IllegalState,
+            CloseReason::PeerCertExpired,
+        ] {
+            assert_eq!(CloseReason::from_code(u64::from(reason.code())), *reason);
+        }
+        assert_eq!(CloseReason::from_code(1000), CloseReason::Unspecified);
+    }
+
+    #[test]
+    fn version_negotiation_failures_are_told_apart() {
+        let e = Error::Connection(quinn::ConnectionError::VersionMismatch);
+        assert_eq!(
+            CloseReason::from_peer_close(&e),
+            Some(CloseReason::QuicVersionMismatch)
+        );
+        assert_eq!(
+            CloseReason::from_peer_close(&Error::ConnectionCancelled),
+            None
+        );
+    }
+
+    #[test]
+    fn only_envelopes_carry_ids() {
+        let reply = WireMsg::UserMsgEnvelope {
+            msg: bytes::Bytes::from(vec![1]),
+            msg_id: Some(7),
+            in_reply_to: Some(3),
+            channel: DEFAULT_CHANNEL,
+        };
+        assert_eq!(reply.msg_id(), Some(7));
+        assert_eq!(reply.in_reply_to(), Some(3));
+
+        let plain = WireMsg::UserMsg(bytes::Bytes::from(vec![1]));
+        assert_eq!(plain.msg_id(), None);
+        assert_eq!(plain.in_reply_to(), None);
+    }
+
+    fn to_frame(wire_msg: WireMsg) -> Vec<u8> {
+        unwrap!(wire_msg.into_frame()).to_vec()
+    }
+
+    #[test]
+    fn frames_are_parsed_back_into_the_original_messages() {
+        let msg = bytes::Bytes::from(vec![3; 2048]);
+
+        match unwrap!(WireMsg::from_bytes_safe(to_frame(WireMsg::UserMsg(
+            msg.clone()
+        )))) {
+            WireMsg::UserMsg(m) => assert_eq!(m, msg),
+            x => panic!("Unexpected message: {}", x),
+        }
+
+        let envelope = WireMsg::UserMsgEnvelope {
+            msg: msg.clone(),
+            msg_id: Some(7),
+            in_reply_to: Some(3),
+            channel: 2,
+        };
+        match unwrap!(WireMsg::from_bytes_safe(to_frame(envelope))) {
+            WireMsg::UserMsgEnvelope {
+                msg: m,
+                msg_id,
+                in_reply_to,
+                channel,
+            } => {
+                assert_eq!(m, msg);
+                assert_eq!(msg_id, Some(7));
+                assert_eq!(in_reply_to, Some(3));
+                assert_eq!(channel, 2);
+            }
+            x => panic!("Unexpected message: {}", x),
+        }
+
+        match unwrap!(WireMsg::from_bytes_safe(to_frame(WireMsg::TypedUserMsg(
+            msg.clone()
+        )))) {
+            WireMsg::TypedUserMsg(m) => assert_eq!(m, msg),
+            x => panic!("Unexpected message: {}", x),
+        }
+
+        let msgs = vec![
+            msg.clone(),
+            bytes::Bytes::new(),
+            bytes::Bytes::from(vec![4; 7]),
+        ];
+        match unwrap!(WireMsg::from_bytes_safe(to_frame(WireMsg::UserMsgBatch(
+            msgs.clone()
+        )))) {
+            WireMsg::UserMsgBatch(m) => assert_eq!(m, msgs),
+            x => panic!("Unexpected message: {}", x),
+        }
+
+        for len in &[0, SMALL_USER_MSG_MAX_LEN] {
+            let small_msg = bytes::Bytes::from(vec![5; *len]);
+            let frame = to_frame(WireMsg::UserMsg(small_msg.clone()));
+            assert_eq!(frame.len(), 1 + len);
+            match unwrap!(WireMsg::from_bytes_safe(frame)) {
+                WireMsg::UserMsg(m) => assert_eq!(m, small_msg),
+                x => panic!("Unexpected message: {}", x),
+            }
+        }
+
+        let our_addr: SocketAddr = unwrap!("127.0.0.1:8080".parse());
+        match unwrap!(WireMsg::from_bytes_safe(to_frame(
+            WireMsg::EndpointEchoResp(our_addr)
+        ))) {
+            WireMsg::EndpointEchoResp(addr) => assert_eq!(addr, our_addr),
+            x => panic!("Unexpected message: {}", x),
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let frame = to_frame(WireMsg::EndpointEchoReq);
+
+        // Truncated
+        assert!(WireMsg::from_bytes_safe(frame[..FRAME_HEADER_LEN - 1].to_vec()).is_err());
+        assert!(WireMsg::from_bytes_safe(frame[..frame.len() - 1].to_vec()).is_err());
+
+        // Trailing bytes, with and without the length accounting for them
+        let mut trailing = frame.clone();
+        trailing.push(0);
+        assert!(WireMsg::from_bytes_safe(trailing.clone()).is_err());
+        trailing[1..FRAME_HEADER_LEN].copy_from_slice(&(frame.len() as u32 - 4).to_be_bytes());
+        assert!(WireMsg::from_bytes_safe(trailing).is_err());
+
+        // Unknown kind
+        let mut unknown_kind = frame;
+        unknown_kind[0] = 0xff;
+        assert!(WireMsg::from_bytes_safe(unknown_kind).is_err());
+
+        // Small user messages in full frames and header-only frames too long for one
+        let mut small_in_full_frame = vec![KIND_USER_MSG, 0, 0, 0, 1];
+        small_in_full_frame.push(7);
+        assert!(WireMsg::from_bytes_safe(small_in_full_frame).is_err());
+        let mut too_long = vec![KIND_SMALL_USER_MSG];
+        too_long.extend_from_slice(&[7; SMALL_USER_MSG_MAX_LEN + 1]);
+        assert!(WireMsg::from_bytes_safe(too_long).is_err());
+
+        // User messages smuggled in as serialised ones
+        let mut serialised_user_msg = vec![KIND_SERIALISED, 0, 0, 0, 0];
+        serialised_user_msg.extend_from_slice(&unwrap!(bincode::serialize(&WireMsg::UserMsg(
+            bytes::Bytes::from(vec![1, 2, 3])
+        ))));
+        let payload_len = (serialised_user_msg.len() - FRAME_HEADER_LEN) as u32;
+        serialised_user_msg[1..FRAME_HEADER_LEN].copy_from_slice(&payload_len.to_be_bytes());
+        assert!(WireMsg::from_bytes_safe(serialised_user_msg).is_err());
+
+        // Oversized serialised messages
+        let mut oversized = vec![KIND_SERIALISED];
+        oversized.extend_from_slice(&(MAX_SERIALISED_MSG_SIZE as u32 + 1).to_be_bytes());
+        oversized.extend_from_slice(&vec![0; MAX_SERIALISED_MSG_SIZE + 1]);
+        assert!(WireMsg::from_bytes_safe(oversized).is_err());
+
+        // Envelopes with unknown flags or with ids present despite their flags being unset
+        let envelope = to_frame(WireMsg::UserMsgEnvelope {
+            msg: bytes::Bytes::from(vec![1, 2, 3]),
+            msg_id: None,
+            in_reply_to: None,
+            channel: 1,
+        });
+        assert!(WireMsg::from_bytes_safe(envelope.clone()).is_ok());
+        let mut unknown_flags = envelope.clone();
+        unknown_flags[FRAME_HEADER_LEN] = 0b100;
+        assert!(WireMsg::from_bytes_safe(unknown_flags).is_err());
+        let mut stray_id = envelope;
+        stray_id[FRAME_HEADER_LEN + 2] = 1;
+        assert!(WireMsg::from_bytes_safe(stray_id).is_err());
+
+        // Empty batches and batched messages cut short
+        assert!(WireMsg::from_bytes_safe(vec![KIND_USER_MSG_BATCH, 0, 0, 0, 0]).is_err());
+        assert!(WireMsg::from_bytes_safe(vec![KIND_USER_MSG_BATCH, 0, 0, 0, 2, 0, 0]).is_err());
+        assert!(
+            WireMsg::from_bytes_safe(vec![KIND_USER_MSG_BATCH, 0, 0, 0, 5, 0, 0, 0, 2, 7]).is_err()
+        );
+    }
+
+    #[test]
+    fn sequenced_frames_carry_user_messages_only() {
+        let frame = to_frame(WireMsg::UserMsg(bytes::Bytes::from(vec![1, 2, 3])));
+        let sequenced = sequenced_frame(7, &frame).to_vec();
+        match unwrap!(WireMsg::from_sequenced_bytes_safe(sequenced.clone())) {
+            (Some(7), WireMsg::UserMsg(ref msg)) if msg[..] == [1, 2, 3] => (),
+            parsed => panic!("Unexpected message: {:?}", parsed),
+        }
+        match unwrap!(WireMsg::from_sequenced_bytes_safe(frame)) {
+            (None, WireMsg::UserMsg(ref msg)) if msg[..] == [1, 2, 3] => (),
+            parsed => panic!("Unexpected message: {:?}", parsed),
+        }
+
+        // Truncated, nested and non-user messages
+        assert!(
+            WireMsg::from_sequenced_bytes_safe(sequenced[..SEQUENCED_PREFIX_LEN - 1].to_vec())
+                .is_err()
+        );
+        let nested = sequenced_frame(8, &sequenced).to_vec();
+        assert!(WireMsg::from_sequenced_bytes_safe(nested).is_err());
+        let echo_req = sequenced_frame(9, &to_frame(WireMsg::EndpointEchoReq)).to_vec();
+        assert!(WireMsg::from_sequenced_bytes_safe(echo_req).is_err());
+        assert!(WireMsg::from_bytes_safe(sequenced).is_err());
+    }
+
+    #[test]
+    fn tagged_frames_carry_user_messages_sequenced_or_not() {
+        let frame = to_frame(WireMsg::UserMsg(bytes::Bytes::from(vec![1, 2, 3])));
+        let tagged = dedup_frame(5, &frame).to_vec();
+        match unwrap!(WireMsg::from_tagged_bytes_safe(tagged.clone())) {
+            (Some(5), None, WireMsg::UserMsg(ref msg)) if msg[..] == [1, 2, 3] => (),
+            parsed => panic!("Unexpected message: {:?}", parsed),
+        }
+        let sequenced = sequenced_frame(7, &frame);
+        match unwrap!(WireMsg::from_tagged_bytes_safe(
+            dedup_frame(6, &sequenced).to_vec()
+        )) {
+            (Some(6), Some(7), WireMsg::UserMsg(ref msg)) if msg[..] == [1, 2, 3] => (),
+            parsed => panic!("Unexpected message: {:?}", parsed),
+        }
+        match unwrap!(WireMsg::from_tagged_bytes_safe(frame)) {
+            (None, None, WireMsg::UserMsg(ref msg)) if msg[..] == [1, 2, 3] => (),
+            parsed => panic!("Unexpected message: {:?}", parsed),
+        }
+
+        // Truncated, nested, tagged inside sequenced and non-user messages
+        assert!(WireMsg::from_tagged_bytes_safe(tagged[..DEDUP_PREFIX_LEN - 1].to_vec()).is_err());
+        let nested = dedup_frame(8, &tagged).to_vec();
+        assert!(WireMsg::from_tagged_bytes_safe(nested).is_err());
+        let inside_out = sequenced_frame(7, &tagged).to_vec();
+        assert!(WireMsg::from_tagged_bytes_safe(inside_out).is_err());
+        let echo_req = dedup_frame(9, &to_frame(WireMsg::EndpointEchoReq)).to_vec();
+        assert!(WireMsg::from_tagged_bytes_safe(echo_req).is_err());
+        assert!(WireMsg::from_sequenced_bytes_safe(tagged).is_err());
+    }
+
+    #[test]
+    fn batches_fill_frames_in_order() {
+        let msg = |len| bytes::Bytes::from(vec![1; len]);
+        let per_msg = |len| BATCHED_MSG_HEADER_LEN + len;
+        let max_frame_len = FRAME_HEADER_LEN + per_msg(10) + per_msg(20);
+
+        let batches = split_into_batches(
+            vec![msg(10), msg(20), msg(1), msg(100), msg(2)],
+            max_frame_len,
+        );
+        let lens: Vec<Vec<usize>> = batches
+            .iter()
+            .map(|batch| batch.iter().map(bytes::Bytes::len).collect())
+            .collect();
+        assert_eq!(lens, vec![vec![10, 20], vec![1], vec![100], vec![2]]);
+
+        for batch in batches.into_iter().filter(|batch| batch.len() > 1) {
+            assert!(to_frame(WireMsg::UserMsgBatch(batch)).len() <= max_frame_len);
+        }
+        assert!(split_into_batches(Vec::new(), max_frame_len).is_empty());
+    }
+
+    fn any_socket_addr() -> impl Strategy<Value = SocketAddr> {
+        (any::<[u8; 4]>(), any::<u16>())
+            .prop_map(|(ip, port)| SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+    }
+
+    fn any_node_info() -> impl Strategy<Value = NodeInfo> {
+        (any_socket_addr(), vec(any::<u8>(), 0..512)).prop_map(|(peer_addr, peer_cert_der)| {
+            NodeInfo {
+                peer_addr,
+                peer_cert_der,
+            }
+        })
+    }
+
+    fn any_wire_msg() -> impl Strategy<Value = WireMsg> {
+        let user_msg = || vec(any::<u8>(), 0..4096).prop_map(bytes::Bytes::from);
+        prop_oneof![
+            user_msg().prop_map(WireMsg::UserMsg),
+            user_msg().prop_map(WireMsg::TypedUserMsg),
+            vec(user_msg(), 1..8).prop_map(WireMsg::UserMsgBatch),
+            (
+                user_msg(),
+                any::<Option<u64>>(),
+                any::<Option<u64>>(),
+                any::<u8>()
+            )
+                .prop_map(|(msg, msg_id, in_reply_to, channel)| {
+                    WireMsg::UserMsgEnvelope {
+                        msg,
+                        msg_id,
+                        in_reply_to,
+                        channel,
+                    }
+                }),
+            Just(WireMsg::EndpointEchoReq),
+            any_socket_addr().prop_map(WireMsg::EndpointEchoResp),
+            (
+                vec(any::<u8>(), 0..512),
+                any::<String>(),
+                any::<[u8; 32]>(),
+                vec(any::<u8>(), 0..72),
+                any_socket_addr(),
+                vec(any::<u8>(), 0..8),
+                any::<u64>(),
+                any::<bool>()
+            )
+                .prop_map(
+                    |(
+                        cert_der,
+                        network_id,
+                        nonce,
+                        signature,
+                        observed_addr,
+                        channels,
+                        sent_at_msec,
+                        ordered_delivery,
+                    )| {
+                        WireMsg::Handshake(Handshake::Client {
+                            cert_der,
+                            network_id,
+                            user_data: None,
+                            nonce,
+                            signature,
+                            observed_addr,
+                            channels,
+                            sent_at_msec,
+                            ordered_delivery,
+                        })
+                    }
+                ),
+            any_node_info().prop_map(|target_info| WireMsg::ReverseConnect { target_info }),
+            (any_socket_addr(), any::<bool>()).prop_map(|(target_addr, success)| {
+                WireMsg::ReverseConnectResult {
+                    target_addr,
+                    success,
+                }
+            }),
+            Just(WireMsg::GetContacts),
+            vec(any_node_info(), 0..8).prop_map(WireMsg::Contacts),
+            any::<u64>().prop_map(|sent_at_msec| WireMsg::HealthCheckReq { sent_at_msec }),
+            (any::<u64>(), any::<u64>()).prop_map(|(req_sent_at_msec, sent_at_msec)| {
+                WireMsg::HealthCheckResp {
+                    req_sent_at_msec,
+                    sent_at_msec,
+                }
+            }),
+            (vec(any::<u8>(), 0..512), vec(any::<u8>(), 0..72)).prop_map(
+                |(new_cert, signature_by_old_key)| WireMsg::CertRotation {
+                    new_cert,
+                    signature_by_old_key,
+                }
+            ),
+            (any::<[u8; 32]>(), any::<u64>())
+                .prop_map(|(hash, len)| WireMsg::FileOffer { hash, len }),
+            (any::<[u8; 32]>(), any::<u64>())
+                .prop_map(|(hash, offset)| WireMsg::FileAccept { hash, offset }),
+            (any::<[u8; 32]>(), any::<u64>(), user_msg())
+                .prop_map(|(hash, offset, data)| { WireMsg::FileChunk { hash, offset, data } }),
+            any::<[u8; 32]>().prop_map(|hash| WireMsg::FileRefuse { hash }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn any_message_survives_the_round_trip_through_its_frame(wire_msg in any_wire_msg()) {
+            let frame = to_frame(wire_msg);
+            let parsed = unwrap!(WireMsg::from_bytes_safe(frame.clone()));
+            prop_assert_eq!(to_frame(parsed), frame);
+        }
+
+        #[test]
+        fn any_node_info_survives_the_round_trip_through_serialisation(
+            node_info in any_node_info()
+        ) {
+            let serialised = unwrap!(bincode::serialize(&node_info));
+            prop_assert_eq!(unwrap!(bincode::deserialize::<NodeInfo>(&serialised)), node_info);
         }
     }
 }
@@ -0,0 +1,42 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Observer hook for the raw wire traffic. Only compiled in with the `wire-tap` feature so that
+//! there is no cost to it otherwise.
+
+use crate::context::Ctx;
+use std::net::SocketAddr;
+
+/// Direction of the observed frame relative to us.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    /// Frame received from the peer
+    Incoming,
+    /// Frame sent to the peer
+    Outgoing,
+}
+
+/// Observer of every serialised wire message exchanged with peers.
+///
+/// The callback is invoked from within the event loop so it should return quickly and must not
+/// block.
+pub trait WireTap: Send {
+    /// Called with the frame exactly as it is (or was) on the wire.
+    fn on_frame(&mut self, direction: Direction, peer_addr: SocketAddr, frame: &[u8]);
+}
+
+/// Hand the frame over to the registered tap, if any. This must not be called while the `Context`
+/// is already borrowed.
+pub fn tap(ctx: &Ctx, direction: Direction, peer_addr: SocketAddr, frame: &[u8]) {
+    ctx.with_mut(|c| {
+        if let Some(wire_tap) = c.wire_tap.as_mut() {
+            wire_tap.on_frame(direction, peer_addr, frame);
+        }
+    })
+}
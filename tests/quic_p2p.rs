@@ -1,12 +1,20 @@
-use quic_p2p::{Builder, Config, Event, Peer, QuicP2p};
-use std::net::{IpAddr, Ipv4Addr};
-use std::sync::mpsc;
+use quic_p2p::{
+    AdmissionPolicy, Builder, CertParams, CloseReason, Config, Error, Event, EventVerbosity,
+    FromPeerState, MergeStrategy, NodeInfo, OurType, Peer, PeerKind, ProxyConfig, QuicP2p,
+    SerialisableCertificate, SocketOptions, StaleConnReaperConfig, ToPeerState, TrafficProfile,
+    Transport, UdpTransport, UnsentReason, BACKGROUND_MAX_SEND_RATE, ECHO_MARKER,
+};
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{env, fs, io, process, thread};
 use unwrap::unwrap;
 
 /// Waits for `Event::ConnectedTo`.
 fn wait_till_connected(ev_rx: mpsc::Receiver<Event>) -> Peer {
     for event in ev_rx.iter() {
-        if let Event::ConnectedTo { peer } = event {
+        if let Event::ConnectedTo { peer, .. } = event {
             return peer;
         }
     }
@@ -19,7 +27,7 @@ fn test_peer() -> (QuicP2p, mpsc::Receiver<Event>) {
     let builder = Builder::new(ev_tx)
         .with_config(Config {
             port: Some(0),
-            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
             ..Default::default()
         })
         // Make sure we start with an empty cache. Otherwise, we might get into unexpected state.
@@ -29,10 +37,10 @@ fn test_peer() -> (QuicP2p, mpsc::Receiver<Event>) {
 
 #[test]
 fn successfull_connection_stores_peer_in_bootstrap_cache() {
-    let (mut peer1, _) = test_peer();
+    let (peer1, _) = test_peer();
     let peer1_conn_info = unwrap!(peer1.our_connection_info());
 
-    let (mut peer2, ev_rx) = test_peer();
+    let (peer2, ev_rx) = test_peer();
     peer2.connect_to(peer1_conn_info.clone());
 
     let connected_to = wait_till_connected(ev_rx);
@@ -44,10 +52,10 @@ fn successfull_connection_stores_peer_in_bootstrap_cache() {
 
 #[test]
 fn incoming_connections_yield_connected_to_event() {
-    let (mut peer1, ev_rx) = test_peer();
+    let (peer1, ev_rx) = test_peer();
     let peer1_conn_info = unwrap!(peer1.our_connection_info());
 
-    let (mut peer2, _) = test_peer();
+    let (peer2, _) = test_peer();
     peer2.connect_to(peer1_conn_info.clone());
     let peer2_conn_info = unwrap!(peer2.our_connection_info());
 
@@ -60,10 +68,10 @@ fn incoming_connections_yield_connected_to_event() {
 
 #[test]
 fn incoming_connections_are_not_put_into_bootstrap_cache_upon_connected_to_event() {
-    let (mut peer1, ev_rx) = test_peer();
+    let (peer1, ev_rx) = test_peer();
     let peer1_conn_info = unwrap!(peer1.our_connection_info());
 
-    let (mut peer2, _) = test_peer();
+    let (peer2, _) = test_peer();
     peer2.connect_to(peer1_conn_info.clone());
 
     let _ = wait_till_connected(ev_rx);
@@ -71,3 +79,2448 @@ fn incoming_connections_are_not_put_into_bootstrap_cache_upon_connected_to_event
     let cache = unwrap!(peer1.bootstrap_cache());
     assert!(cache.is_empty());
 }
+
+#[test]
+fn restarting_listener_moves_us_to_a_new_port() {
+    let (peer, _) = test_peer();
+    let old_conn_info = unwrap!(peer.our_connection_info());
+
+    unwrap!(peer.restart_listener(None));
+
+    let new_conn_info = unwrap!(peer.our_connection_info());
+    assert_ne!(old_conn_info.peer_addr, new_conn_info.peer_addr);
+    assert_eq!(old_conn_info.peer_cert_der, new_conn_info.peer_cert_der);
+}
+
+#[test]
+fn sockets_are_bound_via_the_transport_supplied() {
+    struct RecordingTransport(Arc<Mutex<Vec<SocketAddr>>>);
+
+    impl Transport for RecordingTransport {
+        fn bind(
+            &self,
+            ip: IpAddr,
+            port: u16,
+            requested: &SocketOptions,
+        ) -> io::Result<(UdpSocket, SocketOptions)> {
+            let bound = UdpTransport.bind(ip, port, requested)?;
+            unwrap!(self.0.lock()).push(bound.0.local_addr()?);
+            Ok(bound)
+        }
+    }
+
+    let bound_addrs = Arc::new(Mutex::new(Vec::new()));
+    let (ev_tx, _ev_rx) = mpsc::channel();
+    let peer = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .with_transport(RecordingTransport(bound_addrs.clone()))
+        .build());
+    let old_conn_info = unwrap!(peer.our_connection_info());
+
+    unwrap!(peer.restart_listener(None));
+    let new_conn_info = unwrap!(peer.our_connection_info());
+
+    assert_eq!(
+        *unwrap!(bound_addrs.lock()),
+        vec![old_conn_info.peer_addr, new_conn_info.peer_addr]
+    );
+}
+
+#[test]
+fn reverse_connect_request_makes_node_connect_to_target() {
+    let (relay, _) = test_peer();
+    let relay_conn_info = unwrap!(relay.our_connection_info());
+
+    let (target, target_ev_rx) = test_peer();
+    let target_conn_info = unwrap!(target.our_connection_info());
+    let _ =
+        unwrap!(relay
+            .import_bootstrap_cache(vec![target_conn_info.clone()], MergeStrategy::KeepExisting));
+
+    let (requester, requester_ev_rx) = test_peer();
+    requester.request_reverse_connect(relay_conn_info.clone(), target_conn_info.clone());
+
+    let connected_to = wait_till_connected(target_ev_rx);
+    assert_eq!(
+        unwrap!(connected_to.peer_cert_der()),
+        &relay_conn_info.peer_cert_der[..]
+    );
+
+    for event in requester_ev_rx.iter() {
+        if let Event::ReverseConnectResult {
+            via,
+            target_addr,
+            success,
+        } = event
+        {
+            assert_eq!(via, relay_conn_info.peer_addr);
+            assert_eq!(target_addr, target_conn_info.peer_addr);
+            assert!(success);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected ReverseConnectResult event");
+}
+
+#[test]
+fn reverse_connect_request_to_unknown_target_is_refused() {
+    let (relay, _) = test_peer();
+    let relay_conn_info = unwrap!(relay.our_connection_info());
+
+    let (target, _) = test_peer();
+    let target_conn_info = unwrap!(target.our_connection_info());
+
+    let (requester, requester_ev_rx) = test_peer();
+    requester.request_reverse_connect(relay_conn_info.clone(), target_conn_info.clone());
+
+    for event in requester_ev_rx.iter() {
+        if let Event::ReverseConnectResult {
+            target_addr,
+            success,
+            ..
+        } = event
+        {
+            assert_eq!(target_addr, target_conn_info.peer_addr);
+            assert!(!success);
+            assert!(unwrap!(relay.connected_nodes())
+                .iter()
+                .all(|node| node.peer_addr != target_conn_info.peer_addr));
+            return;
+        }
+    }
+    panic!("Didn't receive the expected ReverseConnectResult event");
+}
+
+#[test]
+fn message_ids_are_delivered_along_with_messages() {
+    let (peer1, ev_rx1) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx2) = test_peer();
+    let peer2_conn_info = unwrap!(peer2.our_connection_info());
+    let request = bytes::Bytes::from(vec![7; 4 * 1024]);
+    peer2.send_with_id(peer1_conn_info.into(), request.clone(), 42);
+
+    let msg_id = ev_rx1
+        .iter()
+        .filter_map(|event| match event {
+            Event::NewMessage {
+                peer,
+                msg,
+                msg_id,
+                in_reply_to,
+                ..
+            } => {
+                assert_eq!(peer, peer2_conn_info.clone().into());
+                assert_eq!(msg, request);
+                assert_eq!(in_reply_to, None);
+                msg_id
+            }
+            _ => None,
+        })
+        .next()
+        .expect("Didn't receive the expected NewMessage event");
+    assert_eq!(msg_id, 42);
+
+    let reply = bytes::Bytes::from(vec![1, 2, 3]);
+    peer1.send_reply(peer2_conn_info.into(), reply.clone(), 1, msg_id);
+
+    for event in ev_rx2.iter() {
+        if let Event::NewMessage {
+            msg,
+            msg_id,
+            in_reply_to,
+            ..
+        } = event
+        {
+            assert_eq!(msg, reply);
+            assert_eq!(msg_id, Some(1));
+            assert_eq!(in_reply_to, Some(42));
+            return;
+        }
+    }
+    panic!("Didn't receive the expected reply");
+}
+
+#[test]
+fn paused_listener_refuses_new_connections_until_resumed() {
+    let (peer1, ev_rx1) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    peer1.pause_accepting();
+    assert!(!unwrap!(peer1.stats()).is_accepting_incoming);
+
+    let (peer2, _) = test_peer();
+    peer2.connect_to(peer1_conn_info.clone());
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert_eq!(unwrap!(peer1.stats()).connections, 0);
+
+    peer1.resume_accepting();
+    assert!(unwrap!(peer1.stats()).is_accepting_incoming);
+
+    let (peer3, _) = test_peer();
+    let peer3_conn_info = unwrap!(peer3.our_connection_info());
+    peer3.connect_to(peer1_conn_info);
+
+    let connected_to = wait_till_connected(ev_rx1);
+    assert_eq!(
+        unwrap!(connected_to.peer_cert_der()),
+        &peer3_conn_info.peer_cert_der[..]
+    );
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn injected_fault_drops_inbound_messages() {
+    use quic_p2p::FaultSpec;
+
+    let (peer1, ev_rx1) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    peer1.inject_fault(FaultSpec::DropInbound { count: 1 });
+
+    let (peer2, _) = test_peer();
+    let dropped_msg = bytes::Bytes::from(vec![1, 2, 3]);
+    let delivered_msg = bytes::Bytes::from(vec![4, 5, 6]);
+    peer2.send(peer1_conn_info.clone().into(), dropped_msg);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    peer2.send(peer1_conn_info.into(), delivered_msg.clone());
+
+    for event in ev_rx1.iter() {
+        if let Event::NewMessage { msg, .. } = event {
+            assert_eq!(msg, delivered_msg);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected NewMessage event");
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_utils_spawn_peers_which_can_reach_each_other() {
+    use quic_p2p::test_utils::{spawn_peers, wait_for_event};
+    use std::time::Duration;
+
+    let mut peers = spawn_peers(2);
+    let (peer2, _) = unwrap!(peers.pop());
+    let (peer1, ev_rx1) = unwrap!(peers.pop());
+
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    peer2.send(unwrap!(peer1.our_connection_info()).into(), msg.clone());
+
+    let event = wait_for_event(
+        &ev_rx1,
+        |event| match *event {
+            Event::NewMessage { .. } => true,
+            _ => false,
+        },
+        Duration::from_secs(10),
+    );
+    match event {
+        Some(Event::NewMessage { msg: m, .. }) => assert_eq!(m, msg),
+        x => panic!("Unexpected outcome: {:?}", x),
+    }
+}
+
+#[test]
+fn peers_share_contacts_from_their_bootstrap_cache() {
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx2) = test_peer();
+    let peer2_conn_info = unwrap!(peer2.our_connection_info());
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx2);
+
+    let (peer3, ev_rx3) = test_peer();
+    peer3.connect_to(peer2_conn_info.clone());
+    peer3.request_contacts(peer2_conn_info.peer_addr);
+
+    for event in ev_rx3.iter() {
+        if let Event::ContactsReceived {
+            peer_addr,
+            contacts,
+        } = event
+        {
+            assert_eq!(peer_addr, peer2_conn_info.peer_addr);
+            assert_eq!(contacts, vec![peer1_conn_info]);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected ContactsReceived event");
+}
+
+#[test]
+fn client_built_with_typed_builder_can_message_node() {
+    let config = || Config {
+        port: Some(0),
+        bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        ..Default::default()
+    };
+
+    let (node_ev_tx, node_ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(node_ev_tx)
+        .with_config(config())
+        .with_proxies(Default::default(), true)
+        .build_node());
+    let node_info = unwrap!(node.our_connection_info());
+
+    let (client_ev_tx, _client_ev_rx) = mpsc::channel();
+    let client = unwrap!(Builder::new(client_ev_tx)
+        .with_config(config())
+        .with_proxies(Default::default(), true)
+        .build_client());
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    client.send(node_info.into(), msg.clone());
+
+    for event in node_ev_rx.iter() {
+        if let Event::NewMessage { msg: received, .. } = event {
+            assert_eq!(received, msg);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected NewMessage event");
+}
+
+#[test]
+fn clients_are_identified_by_their_certificate_and_replied_to() {
+    let config = || Config {
+        port: Some(0),
+        bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        ..Default::default()
+    };
+
+    let (node_ev_tx, node_ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(node_ev_tx)
+        .with_config(config())
+        .with_proxies(Default::default(), true)
+        .build_node());
+    let node_info = unwrap!(node.our_connection_info());
+
+    let (client_ev_tx, client_ev_rx) = mpsc::channel();
+    let client = unwrap!(Builder::new(client_ev_tx)
+        .with_config(Config {
+            our_type: OurType::Client,
+            ..config()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    match client.our_connection_info() {
+        Err(Error::OperationNotAllowed) => (),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    client.connect_to(node_info);
+
+    let peer = wait_till_connected(node_ev_rx);
+    match peer {
+        Peer::Client {
+            ref client_info, ..
+        } => assert_eq!(*client_info, client.our_client_info()),
+        ref x => panic!("Unexpected peer: {:?}", x),
+    }
+
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    node.send(peer, msg.clone());
+    for event in client_ev_rx.iter() {
+        if let Event::NewMessage { msg: received, .. } = event {
+            assert_eq!(received, msg);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected NewMessage event");
+}
+
+#[test]
+fn messages_to_a_client_are_held_until_it_reconnects_or_the_grace_period_expires() {
+    let (node_ev_tx, node_ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(node_ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            client_send_grace_msec: Some(2_000),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build_node());
+    let node_info = unwrap!(node.our_connection_info());
+
+    let client_cert = SerialisableCertificate::default();
+    let new_client = || {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let client = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_complete_cert: Some(client_cert.clone()),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .build_client());
+        client.connect_to(node_info.clone());
+        (client, ev_rx)
+    };
+
+    let wait_till_disconnected = || {
+        for event in node_ev_rx.iter() {
+            if let Event::ConnectionFailure { .. } = event {
+                return;
+            }
+        }
+    };
+
+    let (client, _) = new_client();
+    let peer = unwrap!(node_ev_rx.iter().find_map(|event| match event {
+        Event::ConnectedTo { peer, .. } => Some(peer),
+        _ => None,
+    }));
+    drop(client);
+    wait_till_disconnected();
+
+    // Delivered once the client is back, even though it's now at another address
+    let held_msg = bytes::Bytes::from(vec![1, 2, 3]);
+    node.send(peer.clone(), held_msg.clone());
+    let (client, client_ev_rx) = new_client();
+    for event in client_ev_rx.iter() {
+        if let Event::NewMessage { msg, .. } = event {
+            assert_eq!(msg, held_msg);
+            break;
+        }
+    }
+    drop(client);
+    wait_till_disconnected();
+
+    // Given up on if it doesn't come back in time
+    let unsent_msg = bytes::Bytes::from(vec![4, 5, 6]);
+    node.send(peer.clone(), unsent_msg.clone());
+    for event in node_ev_rx.iter() {
+        if let Event::UnsentUserMessage {
+            peer: p,
+            msg,
+            reason,
+        } = event
+        {
+            assert_eq!(p, peer);
+            assert_eq!(msg, unsent_msg);
+            assert_eq!(reason, UnsentReason::ClientDisconnected);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected UnsentUserMessage event");
+}
+
+#[test]
+fn connection_phases_are_reported_with_verbose_events() {
+    let verbose_peer = || {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                event_verbosity: EventVerbosity::Verbose,
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .build());
+        (peer, ev_rx)
+    };
+
+    let (peer1, ev_rx1) = verbose_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    let (peer2, ev_rx2) = verbose_peer();
+    peer2.send(
+        peer1_conn_info.clone().into(),
+        bytes::Bytes::from(vec![1, 2, 3]),
+    );
+
+    let mut phases = Vec::new();
+    for event in ev_rx2.iter() {
+        match event {
+            Event::ConnectingTo { peer_addr } => {
+                assert_eq!(peer_addr, peer1_conn_info.peer_addr);
+                phases.push("connecting");
+            }
+            // Ignoring the completion of the connection peer1 makes back to us
+            Event::HandshakeCompleted {
+                elapsed: Some(_), ..
+            } => phases.push("handshake"),
+            Event::ConnectedTo { .. } => {
+                phases.push("connected");
+                break;
+            }
+            _ => (),
+        }
+    }
+    assert_eq!(phases, vec!["connecting", "handshake", "connected"]);
+
+    let mut is_incoming_handshake_completed = false;
+    for event in ev_rx1.iter() {
+        match event {
+            Event::HandshakeCompleted { elapsed: None, .. } => {
+                is_incoming_handshake_completed = true
+            }
+            Event::StreamOpened { .. } => {
+                assert!(is_incoming_handshake_completed);
+                return;
+            }
+            _ => (),
+        }
+    }
+    panic!("Didn't receive the expected StreamOpened event");
+}
+
+#[test]
+fn connects_beyond_the_limit_are_queued() {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            max_concurrent_connects: Some(1),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    let (peer2, _) = test_peer();
+    let peer2_conn_info = unwrap!(peer2.our_connection_info());
+
+    peer.connect_to(peer1_conn_info);
+    peer.connect_to(peer2_conn_info.clone());
+    assert_eq!(
+        unwrap!(peer.pending_connects()),
+        vec![peer2_conn_info.peer_addr]
+    );
+
+    // Once the first connect completes the queued one is started
+    let _ = wait_till_connected(ev_rx);
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(unwrap!(peer.pending_connects()).is_empty());
+}
+
+#[test]
+fn connection_details_are_available_once_connected() {
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx) = test_peer();
+    match peer2.connection_details(peer1_conn_info.peer_addr) {
+        Err(Error::PeerNotConnected(_)) => (),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx);
+
+    let details = unwrap!(peer2.connection_details(peer1_conn_info.peer_addr));
+    assert!(details.is_outgoing);
+    assert_eq!(details.tls_version, "TLSv1.3");
+    assert!(!details.used_0rtt);
+}
+
+/// Bare bones SOCKS5 proxy for a single UDP association without authentication, relaying from
+/// any peer. Returns its address and the number of datagrams it relayed from its client so far.
+fn start_socks5_proxy() -> (SocketAddr, Arc<AtomicUsize>) {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = unwrap!(TcpListener::bind((Ipv4Addr::LOCALHOST, 0)));
+    let proxy_addr = unwrap!(listener.local_addr());
+    let relayed = Arc::new(AtomicUsize::new(0));
+
+    let relayed_from_client = relayed.clone();
+    let _ = thread::spawn(move || {
+        let (mut control, _) = unwrap!(listener.accept());
+        let mut greeting = [0; 3];
+        unwrap!(control.read_exact(&mut greeting));
+        assert_eq!(greeting, [5, 1, 0]);
+        unwrap!(control.write_all(&[5, 0]));
+
+        let mut associate = [0; 10];
+        unwrap!(control.read_exact(&mut associate));
+        assert_eq!(associate[..4], [5, 3, 0, 1]);
+        let udp = unwrap!(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)));
+        let port = unwrap!(udp.local_addr()).port().to_be_bytes();
+        // The unspecified address stands for the proxy's own
+        unwrap!(control.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, port[0], port[1]]));
+
+        let mut client_addr = None;
+        let mut buf = vec![0; 65_535];
+        while let Ok((len, from)) = udp.recv_from(&mut buf) {
+            if client_addr.is_none() || client_addr == Some(from) {
+                client_addr = Some(from);
+                assert_eq!(buf[..4], [0, 0, 0, 1]);
+                let dest = SocketAddr::new(
+                    Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]).into(),
+                    u16::from_be_bytes([buf[8], buf[9]]),
+                );
+                let _ = udp.send_to(&buf[10..len], dest);
+                let _ = relayed_from_client.fetch_add(1, Ordering::SeqCst);
+            } else if let (Some(client_addr), SocketAddr::V4(from)) = (client_addr, from) {
+                let mut datagram = vec![0, 0, 0, 1];
+                datagram.extend_from_slice(&from.ip().octets());
+                datagram.extend_from_slice(&from.port().to_be_bytes());
+                datagram.extend_from_slice(&buf[..len]);
+                let _ = udp.send_to(&datagram, client_addr);
+            }
+        }
+    });
+
+    (proxy_addr, relayed)
+}
+
+#[test]
+fn traffic_is_relayed_through_the_upstream_proxy() {
+    let (proxy_addr, relayed) = start_socks5_proxy();
+    let (peer1, _) = test_peer();
+    let peer1_info = unwrap!(peer1.our_connection_info());
+
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer2 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            upstream_proxy: Some(ProxyConfig {
+                addr: proxy_addr,
+                credentials: None,
+            }),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+
+    peer2.connect_to(peer1_info.clone());
+    // Known by its own address, not the one we relay it at
+    let peer = wait_till_connected(ev_rx);
+    assert_eq!(peer.peer_addr(), peer1_info.peer_addr);
+    assert!(relayed.load(Ordering::SeqCst) > 0);
+}
+
+#[test]
+fn upstream_proxy_cant_be_combined_with_a_transport_of_our_own() {
+    let (ev_tx, _ev_rx) = mpsc::channel();
+    let res = Builder::new(ev_tx)
+        .with_config(Config {
+            upstream_proxy: Some(ProxyConfig {
+                addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1080),
+                credentials: None,
+            }),
+            ..Default::default()
+        })
+        .with_transport(UdpTransport)
+        .build();
+    match res {
+        Err(Error::InvalidConfig(_)) => (),
+        x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn node_can_send_over_incoming_connection() {
+    // No connects allowed so that the node never gets to connect back to the peer
+    let (node_ev_tx, _node_ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(node_ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            max_concurrent_connects: Some(0),
+            send_over_incoming_connections: true,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let node_info = unwrap!(node.our_connection_info());
+
+    let (peer, peer_ev_rx) = test_peer();
+    let peer_info = unwrap!(peer.our_connection_info());
+    peer.connect_to(node_info);
+
+    // Give the node time to receive the peer's handshake
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    node.send(peer_info.into(), msg.clone());
+
+    for event in peer_ev_rx.iter() {
+        if let Event::NewMessage { msg: received, .. } = event {
+            assert_eq!(received, msg);
+            assert_eq!(unwrap!(node.pending_connects()).len(), 1);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected NewMessage event");
+}
+
+#[test]
+fn restarted_peer_reconnects_to_peers_from_exported_state() {
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx) = test_peer();
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx);
+
+    let state = unwrap!(peer2.export_state());
+    assert_eq!(state.peers, vec![peer1_conn_info.clone()]);
+    drop(peer2);
+
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let _restarted_peer2 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .with_state(state)
+        .build());
+
+    let connected_to = wait_till_connected(ev_rx);
+    assert_eq!(connected_to, peer1_conn_info.into());
+}
+
+#[test]
+fn peer_is_told_why_we_closed_the_connection() {
+    let (peer1, ev_rx1) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx2) = test_peer();
+    let peer2_conn_info = unwrap!(peer2.our_connection_info());
+    peer2.connect_to(peer1_conn_info);
+    let _ = wait_till_connected(ev_rx1);
+
+    peer1.disconnect_from(peer2_conn_info.peer_addr);
+
+    for event in ev_rx2.iter() {
+        if let Event::ConnectionFailure { reason, .. } = event {
+            assert_eq!(reason, CloseReason::Shutdown);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected ConnectionFailure event");
+}
+
+#[test]
+fn peers_report_the_address_they_reached_us_at() {
+    let (peer1, ev_rx) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    assert!(unwrap!(peer1.observed_addresses()).is_empty());
+
+    let (peer2, _) = test_peer();
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx);
+
+    assert_eq!(
+        unwrap!(peer1.observed_addresses()),
+        vec![(peer1_conn_info.peer_addr, 1)]
+    );
+}
+
+#[test]
+fn messages_are_delivered_on_accepted_channels_only() {
+    let (ev_tx1, ev_rx1) = mpsc::channel();
+    let peer1 = unwrap!(Builder::new(ev_tx1)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            channels: vec![5],
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, _) = test_peer();
+    let unaccepted = bytes::Bytes::from(vec![6; 16]);
+    let accepted = bytes::Bytes::from(vec![5; 16]);
+    peer2.send_on_channel(peer1_conn_info.clone().into(), unaccepted, 6);
+    peer2.send_on_channel(peer1_conn_info.into(), accepted.clone(), 5);
+
+    for event in ev_rx1.iter() {
+        if let Event::NewMessage { msg, channel, .. } = event {
+            assert_eq!(msg, accepted);
+            assert_eq!(channel, 5);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected NewMessage event");
+}
+
+#[test]
+fn self_test_succeeds_on_a_healthy_endpoint() {
+    let (peer, ev_rx) = test_peer();
+
+    let report = unwrap!(peer.self_test());
+    assert!(report.is_ok(), "Self test failed: {:?}", report);
+    assert!(report.failure.is_none());
+
+    // Our own loopback connection is not reported as a peer
+    assert!(ev_rx.try_recv().is_err());
+}
+
+#[test]
+fn connected_peers_are_listed_by_kind() {
+    let config = || Config {
+        port: Some(0),
+        bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        ..Default::default()
+    };
+
+    let (node_ev_tx, node_ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(node_ev_tx)
+        .with_config(config())
+        .with_proxies(Default::default(), true)
+        .build_node());
+    let node_info = unwrap!(node.our_connection_info());
+
+    let (client_ev_tx, client_ev_rx) = mpsc::channel();
+    let client = unwrap!(Builder::new(client_ev_tx)
+        .with_config(config())
+        .with_proxies(Default::default(), true)
+        .build_client());
+    client.connect_to(node_info.clone());
+
+    let client_peer = wait_till_connected(node_ev_rx);
+    assert_eq!(client_peer.kind(), PeerKind::Client);
+    assert_eq!(
+        unwrap!(node.connected_clients()),
+        vec![client_peer.peer_addr()]
+    );
+    assert!(unwrap!(node.connected_nodes()).is_empty());
+
+    let node_peer = wait_till_connected(client_ev_rx);
+    assert_eq!(node_peer.kind(), PeerKind::Node);
+    assert_eq!(unwrap!(client.connected_nodes()), vec![node_info]);
+    assert!(unwrap!(client.connected_clients()).is_empty());
+}
+
+#[test]
+fn peers_only_connect_over_a_common_alpn_protocol() {
+    let peer_with_alpn = |protocol: &[u8], proxies: VecDeque<NodeInfo>| {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                alpn_protocols: vec![protocol.to_vec()],
+                ..Default::default()
+            })
+            .with_proxies(proxies, true)
+            .build());
+        (peer, ev_rx)
+    };
+
+    let (peer1, _) = peer_with_alpn(b"qp2p/1", Default::default());
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx2) = peer_with_alpn(b"qp2p/1", Default::default());
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx2);
+    let details = unwrap!(peer2.connection_details(peer1_conn_info.peer_addr));
+    assert_eq!(details.alpn_protocol, Some(b"qp2p/1".to_vec()));
+
+    let (peer3, ev_rx3) = peer_with_alpn(b"qp2p/2", vec![peer1_conn_info].into_iter().collect());
+    peer3.bootstrap();
+    for event in ev_rx3.iter() {
+        match event {
+            Event::BootstrapFailure => return,
+            Event::BootstrappedTo { .. } => panic!("Bootstrapped despite the ALPN mismatch"),
+            _ => (),
+        }
+    }
+    panic!("Didn't receive the expected BootstrapFailure event");
+}
+
+#[test]
+fn peers_only_connect_under_a_common_server_name() {
+    let peer_with_server_name = |server_name: &str, proxies: VecDeque<NodeInfo>| {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                server_name: Some(server_name.to_string()),
+                ..Default::default()
+            })
+            .with_proxies(proxies, true)
+            .build());
+        (peer, ev_rx)
+    };
+
+    let (peer1, _) = peer_with_server_name("net-a.example", Default::default());
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx2) = peer_with_server_name("NET-A.example", Default::default());
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx2);
+
+    let (peer3, ev_rx3) =
+        peer_with_server_name("net-b.example", vec![peer1_conn_info].into_iter().collect());
+    peer3.bootstrap();
+    for event in ev_rx3.iter() {
+        match event {
+            Event::BootstrapFailure => return,
+            Event::BootstrappedTo { .. } => panic!("Bootstrapped under another server name"),
+            _ => (),
+        }
+    }
+    panic!("Didn't receive the expected BootstrapFailure event");
+}
+
+#[test]
+fn outbound_only_clients_connect_out_but_nodes_must_listen() {
+    let config = |listen| Config {
+        port: Some(0),
+        bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        listen,
+        ..Default::default()
+    };
+
+    let (ev_tx, _ev_rx) = mpsc::channel();
+    match Builder::new(ev_tx)
+        .with_config(config(false))
+        .with_proxies(Default::default(), true)
+        .build_node()
+    {
+        Err(Error::InvalidConfig(_)) => (),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Non-listening node was built"),
+    }
+
+    let (node_ev_tx, node_ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(node_ev_tx)
+        .with_config(config(true))
+        .with_proxies(Default::default(), true)
+        .build_node());
+    let node_info = unwrap!(node.our_connection_info());
+
+    let (client_ev_tx, _client_ev_rx) = mpsc::channel();
+    let client = unwrap!(Builder::new(client_ev_tx)
+        .with_config(config(false))
+        .with_proxies(Default::default(), true)
+        .build_client());
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    client.send(node_info.into(), msg.clone());
+
+    for event in node_ev_rx.iter() {
+        if let Event::NewMessage { msg: received, .. } = event {
+            assert_eq!(received, msg);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected NewMessage event");
+}
+
+#[test]
+fn rotated_certificate_is_announced_and_used_for_new_connections() {
+    let (peer1, peer1_ev_rx) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, peer2_ev_rx) = test_peer();
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(peer1_ev_rx);
+
+    let new_cert = unwrap!(peer1.rotate_certificate());
+    let rotated_conn_info = NodeInfo {
+        peer_addr: peer1_conn_info.peer_addr,
+        peer_cert_der: new_cert.cert_der,
+    };
+    assert_eq!(unwrap!(peer1.our_connection_info()), rotated_conn_info);
+
+    let (old, new) = unwrap!(peer2_ev_rx.iter().find_map(|event| match event {
+        Event::PeerCertificateRotated { old, new } => Some((old, new)),
+        _ => None,
+    }));
+    assert_eq!(old, peer1_conn_info);
+    assert_eq!(new, rotated_conn_info);
+    assert_eq!(
+        unwrap!(peer2.bootstrap_cache()),
+        vec![rotated_conn_info.clone()]
+    );
+
+    let (peer3, peer3_ev_rx) = test_peer();
+    peer3.connect_to(rotated_conn_info.clone());
+    assert_eq!(wait_till_connected(peer3_ev_rx), rotated_conn_info.into());
+}
+
+#[test]
+fn node_certificate_about_to_expire_is_reported_and_rotated() {
+    use std::time::Duration;
+
+    let cert = SerialisableCertificate::generate(&CertParams {
+        validity_days: Some(1),
+        ..Default::default()
+    });
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            our_complete_cert: Some(cert.clone()),
+            cert_expiry_warning_sec: Some(2 * 24 * 3600),
+            auto_rotate_cert: true,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build_node());
+
+    let expires_in = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::CertificateExpiring { expires_in } => Some(expires_in),
+        _ => None,
+    }));
+    assert!(expires_in <= Duration::from_secs(24 * 3600));
+
+    let node_info = unwrap!(node.our_connection_info());
+    assert_ne!(node_info.peer_cert_der, cert.cert_der);
+}
+
+#[test]
+fn node_showing_up_at_another_address_is_rebound_by_certificate() {
+    let (peer1, peer1_ev_rx) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let cert = SerialisableCertificate::default();
+    let peer_with_cert = || {
+        let (ev_tx, _) = mpsc::channel();
+        unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_complete_cert: Some(cert.clone()),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .build())
+    };
+
+    let peer2 = peer_with_cert();
+    let old_conn_info = unwrap!(peer2.our_connection_info());
+    peer1.connect_to(old_conn_info.clone());
+    unwrap!(peer1_ev_rx.iter().find(|event| match event {
+        Event::ConnectedTo { .. } => true,
+        _ => false,
+    }));
+    drop(peer2);
+
+    // Same node, different address
+    let peer2 = peer_with_cert();
+    let new_conn_info = unwrap!(peer2.our_connection_info());
+    assert_ne!(new_conn_info.peer_addr, old_conn_info.peer_addr);
+    peer2.connect_to(peer1_conn_info);
+
+    let (old, new) = unwrap!(peer1_ev_rx.iter().find_map(|event| match event {
+        Event::PeerAddressChanged { old, new } => Some((old, new)),
+        _ => None,
+    }));
+    assert_eq!(old, old_conn_info);
+    assert_eq!(new, new_conn_info);
+    assert!(unwrap!(peer1.bootstrap_cache()).contains(&new_conn_info));
+    assert!(!unwrap!(peer1.bootstrap_cache()).contains(&old_conn_info));
+}
+
+#[test]
+fn debug_dump_shows_connection_stages_and_cache() {
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx) = test_peer();
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx);
+
+    let dump = unwrap!(peer2.debug_dump());
+    assert_eq!(dump.connections.len(), 1);
+    let conn = &dump.connections[0];
+    assert_eq!(conn.peer_addr, peer1_conn_info.peer_addr);
+    assert_eq!(conn.to_peer, ToPeerState::Established);
+    assert_eq!(conn.from_peer, FromPeerState::Established);
+    assert_eq!(conn.pending_sends, 0);
+    assert!(conn.we_contacted_peer);
+    assert!(dump.connects_in_flight.is_empty());
+    assert_eq!(dump.bootstrap_cache, vec![peer1_conn_info]);
+}
+
+#[test]
+fn node_bound_to_all_interfaces_advertises_its_external_address() {
+    let (ev_tx, _ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            external_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build_node());
+
+    let node_info = unwrap!(node.our_connection_info());
+    assert_eq!(node_info.peer_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    assert_ne!(node_info.peer_addr.port(), 0);
+
+    let candidates = unwrap!(node.candidate_addresses());
+    assert_eq!(candidates.first(), Some(&node_info.peer_addr));
+
+    let (peer, ev_rx) = test_peer();
+    peer.connect_to(node_info.clone());
+    assert_eq!(wait_till_connected(ev_rx), node_info.into());
+}
+
+#[test]
+fn node_behind_port_forwarding_advertises_its_external_port() {
+    let (echo_server, _) = test_peer();
+    let echo_server_info = unwrap!(echo_server.our_connection_info());
+
+    let external_port = 45_678;
+    let (ev_tx, _ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            hard_coded_contacts: vec![echo_server_info.into()].into_iter().collect(),
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            external_port: Some(external_port),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build_node());
+
+    // The echo service sees the port we are bound to, which isn't the one we are reached at
+    let node_info = unwrap!(node.our_connection_info());
+    assert_eq!(node_info.peer_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    assert_eq!(node_info.peer_addr.port(), external_port);
+}
+
+#[test]
+fn messages_the_peer_does_not_take_in_time_are_reported_unsent() {
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer2 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            write_timeout_msec: Some(20),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    peer2.connect_to(peer1_conn_info.clone());
+
+    let mut events = ev_rx.iter();
+    assert!(events.any(|event| match event {
+        Event::ConnectedTo { .. } => true,
+        _ => false,
+    }));
+
+    // Far too big to be taken within the timeout
+    let big_msg = bytes::Bytes::from(vec![0; 64 * 1024 * 1024]);
+    peer2.send(peer1_conn_info.clone().into(), big_msg.clone());
+
+    for event in events {
+        if let Event::UnsentUserMessage { peer, msg, reason } = event {
+            assert_eq!(peer, peer1_conn_info.into());
+            assert_eq!(msg, big_msg);
+            assert_eq!(reason, UnsentReason::WriteTimedOut);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected UnsentUserMessage event");
+}
+
+#[test]
+fn instances_seeded_alike_have_the_same_identity() {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    let seeded_peer = |seed| {
+        let (ev_tx, _ev_rx) = mpsc::channel();
+        let peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .with_rng(StdRng::seed_from_u64(seed))
+            .build());
+        unwrap!(peer.our_connection_info()).peer_cert_der
+    };
+
+    assert_eq!(seeded_peer(7), seeded_peer(7));
+    assert_ne!(seeded_peer(7), seeded_peer(8));
+}
+
+#[test]
+fn batch_connect_reports_every_outcome_once() {
+    let (node1, _) = test_peer();
+    let node1_info = unwrap!(node1.our_connection_info());
+    let (node2, _) = test_peer();
+    let node2_info = unwrap!(node2.our_connection_info());
+    // Presenting another certificate than the one we expect fails the connect
+    let (node3, _) = test_peer();
+    let node3_info = NodeInfo {
+        peer_cert_der: SerialisableCertificate::default().cert_der,
+        ..unwrap!(node3.our_connection_info())
+    };
+
+    let (peer, ev_rx) = test_peer();
+    peer.connect_to_many(vec![
+        node1_info.clone(),
+        node2_info.clone(),
+        node1_info.clone(),
+        node3_info.clone(),
+    ]);
+
+    let (mut connected, failed) = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::BatchConnectComplete { connected, failed } => Some((connected, failed)),
+        _ => None,
+    }));
+    connected.sort_by_key(|node_info| node_info.peer_addr);
+    let mut expected = vec![node1_info, node2_info];
+    expected.sort_by_key(|node_info| node_info.peer_addr);
+    assert_eq!(connected, expected);
+    assert_eq!(failed, vec![node3_info]);
+}
+
+#[cfg(feature = "codec")]
+#[test]
+fn typed_messages_are_decoded_by_the_registered_codec() {
+    use quic_p2p::BincodeCodec;
+
+    let typed_peer = || {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let builder = Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .with_codec(BincodeCodec::<(u32, String)>::new());
+        (unwrap!(builder.build()), ev_rx)
+    };
+
+    let (peer1, ev_rx1) = typed_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    let (peer2, _) = typed_peer();
+
+    match peer2.send_typed(peer1_conn_info.clone().into(), &7u64) {
+        Err(Error::CodecTypeMismatch) => (),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    let typed_msg = (7u32, "hello".to_string());
+    unwrap!(peer2.send_typed(peer1_conn_info.clone().into(), &typed_msg));
+    peer2.send(peer1_conn_info.into(), bytes::Bytes::from(vec![1, 2, 3]));
+
+    let mut got_typed = false;
+    for event in ev_rx1.iter() {
+        match event {
+            Event::NewTypedMessage { msg, .. } => {
+                assert_eq!(*unwrap!(msg.downcast::<(u32, String)>()), typed_msg);
+                got_typed = true;
+            }
+            Event::NewMessage { msg, .. } => {
+                // Plain messages are left alone
+                assert_eq!(&msg[..], &[1, 2, 3]);
+                assert!(got_typed);
+                return;
+            }
+            _ => (),
+        }
+    }
+    panic!("Didn't receive the expected messages");
+}
+
+#[test]
+fn taken_port_is_fallen_back_from_only_if_asked_to() {
+    let (peer1, _) = test_peer();
+    let taken_port = unwrap!(peer1.our_connection_info()).peer_addr.port();
+
+    let builder = |port_fallback| {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let builder = Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(taken_port),
+                port_fallback,
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true);
+        (builder, ev_rx)
+    };
+
+    let (strict, _) = builder(false);
+    match strict.build() {
+        Err(Error::Io(_)) => (),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Bound to a taken port"),
+    }
+
+    let (falling_back, ev_rx) = builder(true);
+    let peer2 = unwrap!(falling_back.build());
+    let listening_addr = ev_rx
+        .iter()
+        .filter_map(|event| match event {
+            Event::ListenerStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .next()
+        .expect("Didn't receive the expected ListenerStarted event");
+    assert_ne!(listening_addr.port(), taken_port);
+    assert_eq!(
+        unwrap!(peer2.our_connection_info()).peer_addr,
+        listening_addr
+    );
+}
+
+#[test]
+fn peer_context_lives_as_long_as_the_connection() {
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx) = test_peer();
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx);
+    let peer_addr = peer1_conn_info.peer_addr;
+
+    let no_context = unwrap!(peer2.with_peer_context(peer_addr, |context| context.is_none()));
+    assert!(no_context);
+
+    unwrap!(peer2.set_peer_context(peer_addr, Box::new(5u32)));
+    let incremented = unwrap!(peer2.with_peer_context(peer_addr, |context| {
+        let count = unwrap!(unwrap!(context).downcast_mut::<u32>());
+        *count += 1;
+        *count
+    }));
+    assert_eq!(incremented, 6);
+
+    peer2.disconnect_from(peer_addr);
+    match peer2.with_peer_context(peer_addr, |_| ()) {
+        Err(Error::PeerNotConnected(addr)) => assert_eq!(addr, peer_addr),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    match peer2.set_peer_context(peer_addr, Box::new(())) {
+        Err(Error::PeerNotConnected(addr)) => assert_eq!(addr, peer_addr),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn peers_not_sending_their_handshake_in_time_are_disconnected() {
+    use quic_p2p::FaultSpec;
+
+    let (ev_tx, ev_rx1) = mpsc::channel();
+    let peer1 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            handshake_timeout_msec: Some(200),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, _) = test_peer();
+    let peer2_conn_info = unwrap!(peer2.our_connection_info());
+    peer2.inject_fault(FaultSpec::DelayOutbound { delay_msec: 2000 });
+    peer2.connect_to(peer1_conn_info);
+
+    for event in ev_rx1.iter() {
+        match event {
+            Event::ConnectionFailure { peer_addr, reason } => {
+                assert_eq!(peer_addr, peer2_conn_info.peer_addr);
+                assert_eq!(reason, CloseReason::HandshakeTimedOut);
+                return;
+            }
+            Event::ConnectedTo { .. } => panic!("Peer connected without a timely handshake"),
+            _ => (),
+        }
+    }
+    panic!("Didn't receive the expected ConnectionFailure event");
+}
+
+#[test]
+fn nodes_authenticating_each_other_in_tls_connect_and_exchange_messages() {
+    let mutual_tls_node = || {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let node = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                mutual_tls: true,
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .build_node());
+        (node, ev_rx)
+    };
+
+    let (node1, ev_rx1) = mutual_tls_node();
+    let node1_conn_info = unwrap!(node1.our_connection_info());
+    let (node2, _) = mutual_tls_node();
+    let node2_conn_info = unwrap!(node2.our_connection_info());
+
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    node2.send(node1_conn_info.into(), msg.clone());
+
+    let mut is_connected = false;
+    for event in ev_rx1.iter() {
+        match event {
+            Event::ConnectedTo { peer, .. } => {
+                assert_eq!(peer, node2_conn_info.clone().into());
+                is_connected = true;
+            }
+            Event::NewMessage { msg: received, .. } => {
+                assert!(is_connected);
+                assert_eq!(received, msg);
+                return;
+            }
+            Event::ProtocolViolation { reason, .. } => panic!("Rejected node: {}", reason),
+            _ => (),
+        }
+    }
+    panic!("Didn't receive the expected message");
+}
+
+#[test]
+fn send_to_many_reports_every_write_in_one_event() {
+    let (node1, ev_rx1) = test_peer();
+    let node1_info = unwrap!(node1.our_connection_info());
+    let (node2, _) = test_peer();
+    let node2_info = unwrap!(node2.our_connection_info());
+    let (node3, _) = test_peer();
+    let node3_info = unwrap!(node3.our_connection_info());
+
+    let (peer, ev_rx) = test_peer();
+    peer.connect_to_many(vec![node1_info.clone(), node2_info.clone()]);
+    let _ = unwrap!(ev_rx.iter().find(|event| match event {
+        Event::BatchConnectComplete { .. } => true,
+        _ => false,
+    }));
+
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    let peers: Vec<Peer> = vec![
+        node1_info.clone().into(),
+        node2_info.clone().into(),
+        node1_info.clone().into(),
+        node3_info.clone().into(),
+    ];
+    peer.send_to_many(&peers, msg.clone(), 7);
+
+    let (token, mut sent, failed) = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::SendToManyComplete {
+            token,
+            sent,
+            failed,
+        } => Some((token, sent, failed)),
+        _ => None,
+    }));
+    assert_eq!(token, 7);
+    sent.sort();
+    let mut expected = vec![node1_info.peer_addr, node2_info.peer_addr];
+    expected.sort();
+    assert_eq!(sent, expected);
+    assert_eq!(failed, vec![node3_info.peer_addr]);
+
+    let received = unwrap!(ev_rx1.iter().find_map(|event| match event {
+        Event::NewMessage { msg, .. } => Some(msg),
+        _ => None,
+    }));
+    assert_eq!(received, msg);
+}
+
+#[test]
+fn send_batch_reports_the_whole_batch_in_one_event() {
+    let (node, ev_rx_node) = test_peer();
+    let node_info = unwrap!(node.our_connection_info());
+    let (stranger, _) = test_peer();
+    let stranger_info = unwrap!(stranger.our_connection_info());
+
+    let (peer, ev_rx) = test_peer();
+    peer.connect_to(node_info.clone());
+    let _ = unwrap!(ev_rx.iter().find(|event| match event {
+        Event::ConnectedTo { .. } => true,
+        _ => false,
+    }));
+
+    let msgs: Vec<_> = (0..3u8)
+        .map(|i| bytes::Bytes::from(vec![i; 100 * usize::from(i)]))
+        .collect();
+    peer.send_batch(node_info.clone().into(), msgs.clone(), 5);
+    peer.send_batch(stranger_info.clone().into(), msgs.clone(), 6);
+
+    let mut is_sent = false;
+    let mut is_unsent = false;
+    for event in ev_rx.iter() {
+        match event {
+            Event::SentUserMessageBatch { peer, token } => {
+                assert_eq!(peer, node_info.clone().into());
+                assert_eq!(token, 5);
+                is_sent = true;
+            }
+            Event::UnsentUserMessageBatch {
+                peer,
+                msgs: unsent,
+                token,
+                reason,
+            } => {
+                assert_eq!(peer, stranger_info.clone().into());
+                assert_eq!(unsent, msgs);
+                assert_eq!(token, 6);
+                assert_eq!(reason, UnsentReason::NotConnected);
+                is_unsent = true;
+            }
+            _ => (),
+        }
+        if is_sent && is_unsent {
+            break;
+        }
+    }
+
+    let received: Vec<_> = ev_rx_node
+        .iter()
+        .filter_map(|event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        })
+        .take(msgs.len())
+        .collect();
+    assert_eq!(received, msgs);
+}
+
+#[test]
+fn echo_service_sends_messages_back_marked() {
+    let (ev_tx, _ev_rx) = mpsc::channel();
+    let echo_node = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            echo_service: true,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let echo_node_info = unwrap!(echo_node.our_connection_info());
+
+    let (peer, ev_rx) = test_peer();
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    peer.send_with_id(echo_node_info.into(), msg.clone(), 11);
+
+    let (echo, in_reply_to) = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::NewMessage {
+            msg, in_reply_to, ..
+        } => Some((msg, in_reply_to)),
+        _ => None,
+    }));
+    assert_eq!(&echo[..ECHO_MARKER.len()], ECHO_MARKER);
+    assert_eq!(&echo[ECHO_MARKER.len()..], &msg[..]);
+    assert_eq!(in_reply_to, Some(11));
+}
+
+#[test]
+fn connection_limit_evicts_idle_clients_only() {
+    let (ev_tx, node_ev_rx) = mpsc::channel();
+    let node = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            max_total_connections: Some(1),
+            admission_policy: AdmissionPolicy::EvictIdleClient,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build_node());
+    let node_info = unwrap!(node.our_connection_info());
+
+    let (ev_tx, _client_ev_rx) = mpsc::channel();
+    let client = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build_client());
+    client.connect_to(node_info.clone());
+    let client_addr = unwrap!(node_ev_rx.iter().find_map(|event| match event {
+        Event::ConnectedTo { peer, .. } => Some(peer.peer_addr()),
+        _ => None,
+    }));
+
+    // The client makes room for the node
+    let (peer1, _) = test_peer();
+    let peer1_addr = unwrap!(peer1.our_connection_info()).peer_addr;
+    peer1.connect_to(node_info.clone());
+    let (peer_addr, evicted) = unwrap!(node_ev_rx.iter().find_map(|event| match event {
+        Event::ConnectionLimitReached { peer_addr, evicted } => Some((peer_addr, evicted)),
+        _ => None,
+    }));
+    assert_eq!(peer_addr, peer1_addr);
+    assert_eq!(evicted, Some(client_addr));
+
+    // But nodes are never evicted
+    let (peer2, _) = test_peer();
+    let peer2_addr = unwrap!(peer2.our_connection_info()).peer_addr;
+    peer2.connect_to(node_info);
+    let (peer_addr, evicted) = unwrap!(node_ev_rx.iter().find_map(|event| match event {
+        Event::ConnectionLimitReached { peer_addr, evicted } => Some((peer_addr, evicted)),
+        _ => None,
+    }));
+    assert_eq!(peer_addr, peer2_addr);
+    assert_eq!(evicted, None);
+}
+
+#[test]
+fn lifetime_stats_carry_on_after_a_restart() {
+    let cache_dir = env::temp_dir().join(format!("quic_p2p_lifetime_stats_{}", process::id()));
+    let stats_peer = || {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let builder = Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                bootstrap_cache_dir: Some(cache_dir.clone()),
+                lifetime_stats_snapshot_sec: Some(60),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true);
+        (unwrap!(builder.build()), ev_rx)
+    };
+
+    let (peer1, ev_rx1) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, _) = stats_peer();
+    peer2.send(peer1_conn_info.into(), bytes::Bytes::from(vec![1, 2, 3]));
+    let _ = unwrap!(ev_rx1.iter().find(|event| match event {
+        Event::NewMessage { .. } => true,
+        _ => false,
+    }));
+
+    let before_restart = unwrap!(peer2.lifetime_stats());
+    assert!(before_restart.bytes_sent > 0);
+    assert_eq!(before_restart.peers_seen, 1);
+    drop(peer2);
+
+    let (restarted_peer2, _) = stats_peer();
+    let after_restart = unwrap!(restarted_peer2.lifetime_stats());
+    assert_eq!(after_restart.bytes_sent, before_restart.bytes_sent);
+    assert_eq!(after_restart.bytes_received, before_restart.bytes_received);
+    assert_eq!(after_restart.peers_seen, before_restart.peers_seen);
+    assert!(after_restart.uptime_sec >= before_restart.uptime_sec);
+
+    drop(restarted_peer2);
+    let _ = fs::remove_dir_all(cache_dir);
+}
+
+#[test]
+fn cancelling_reads_keeps_the_connection() {
+    let (peer1, ev_rx1) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    let (peer2, _) = test_peer();
+    let peer2_conn_info = unwrap!(peer2.our_connection_info());
+
+    match peer1.cancel_reads_from(peer2_conn_info.peer_addr) {
+        Err(Error::PeerNotConnected(addr)) => assert_eq!(addr, peer2_conn_info.peer_addr),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+
+    peer2.send(peer1_conn_info.clone().into(), bytes::Bytes::from(vec![1]));
+    let _ = unwrap!(ev_rx1.iter().find(|event| match event {
+        Event::NewMessage { .. } => true,
+        _ => false,
+    }));
+    let _ = unwrap!(peer1.cancel_reads_from(peer2_conn_info.peer_addr));
+
+    let msg = bytes::Bytes::from(vec![2]);
+    peer2.send(peer1_conn_info.into(), msg.clone());
+    let received = unwrap!(ev_rx1.iter().find_map(|event| match event {
+        Event::NewMessage { msg, .. } => Some(msg),
+        _ => None,
+    }));
+    assert_eq!(received, msg);
+}
+
+#[test]
+fn connections_stuck_half_way_are_reaped() {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            idle_timeout_msec: Some(60_000),
+            stale_conn_reaper: Some(StaleConnReaperConfig {
+                sweep_interval_sec: 1,
+                max_incomplete_sec: 1,
+            }),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+
+    // Takes the QUIC handshake packets and never answers them
+    let silent_socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    let silent_node = NodeInfo {
+        peer_addr: unwrap!(silent_socket.local_addr()),
+        peer_cert_der: SerialisableCertificate::default().cert_der,
+    };
+    peer.connect_to(silent_node.clone());
+
+    let reason = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::ConnectionFailure { peer_addr, reason } if peer_addr == silent_node.peer_addr => {
+            Some(reason)
+        }
+        _ => None,
+    }));
+    assert_eq!(reason, CloseReason::Evicted);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn manual_clock_moves_timers_on_instantly() {
+    use quic_p2p::ManualClock;
+    use std::time::Duration;
+
+    let clock = ManualClock::new();
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            stale_conn_reaper: Some(StaleConnReaperConfig {
+                sweep_interval_sec: 10,
+                max_incomplete_sec: 30,
+            }),
+            event_verbosity: EventVerbosity::Verbose,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .with_clock(clock.clone())
+        .build());
+
+    // Takes the QUIC handshake packets and never answers them
+    let silent_socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    let silent_node = NodeInfo {
+        peer_addr: unwrap!(silent_socket.local_addr()),
+        peer_cert_der: SerialisableCertificate::default().cert_der,
+    };
+    peer.connect_to(silent_node.clone());
+    unwrap!(ev_rx.iter().find(|event| match event {
+        Event::ConnectingTo { peer_addr } => *peer_addr == silent_node.peer_addr,
+        _ => false,
+    }));
+
+    // Overdue for the reaper without waiting for it
+    clock.advance(Duration::from_secs(40));
+
+    let reason = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::ConnectionFailure { peer_addr, reason } if peer_addr == silent_node.peer_addr => {
+            Some(reason)
+        }
+        _ => None,
+    }));
+    assert_eq!(reason, CloseReason::Evicted);
+}
+
+#[test]
+fn exported_bootstrap_cache_seeds_another_instance() {
+    let (peer1, _) = test_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, ev_rx) = test_peer();
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx);
+
+    let exported = unwrap!(peer2.export_bootstrap_cache());
+    assert_eq!(exported, vec![peer1_conn_info.clone()]);
+
+    let cache_dir = env::temp_dir().join(format!("quic_p2p_cache_import_{}", process::id()));
+    let (ev_tx, _ev_rx) = mpsc::channel();
+    let peer3 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            bootstrap_cache_dir: Some(cache_dir.clone()),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    assert_eq!(
+        unwrap!(peer3.import_bootstrap_cache(exported.clone(), MergeStrategy::PreferImported)),
+        1
+    );
+    // Importing the same contacts again doesn't duplicate them
+    assert_eq!(
+        unwrap!(peer3.import_bootstrap_cache(exported, MergeStrategy::KeepExisting)),
+        0
+    );
+    assert_eq!(unwrap!(peer3.bootstrap_cache()), vec![peer1_conn_info]);
+
+    drop(peer3);
+    let _ = fs::remove_dir_all(cache_dir);
+}
+
+#[test]
+fn proxies_not_connecting_within_the_budget_are_given_up_on() {
+    use std::time::Duration;
+
+    let (live_node, _) = test_peer();
+    let live_node_info = unwrap!(live_node.our_connection_info());
+
+    // Take the QUIC handshake packets and never answer them
+    let silent_sockets: Vec<_> = (0..2)
+        .map(|_| unwrap!(UdpSocket::bind("127.0.0.1:0")))
+        .collect();
+    let silent_nodes: VecDeque<_> = silent_sockets
+        .iter()
+        .map(|socket| NodeInfo {
+            peer_addr: unwrap!(socket.local_addr()),
+            peer_cert_der: SerialisableCertificate::default().cert_der,
+        })
+        .collect();
+
+    let bootstrapper = |proxies: VecDeque<NodeInfo>| {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                bootstrap_member_budget_msec: Some(200),
+                bootstrap_grace_msec: Some(50),
+                ..Default::default()
+            })
+            .with_proxies(proxies, true)
+            .build());
+        (peer, ev_rx)
+    };
+
+    // Only silent proxies: we fail well within the idle timeout
+    let (peer, ev_rx) = bootstrapper(silent_nodes.clone());
+    peer.bootstrap();
+    let event = unwrap!(ev_rx.recv_timeout(Duration::from_secs(5)));
+    match event {
+        Event::BootstrapFailure => (),
+        event => panic!("Unexpected event: {:?}", event),
+    }
+
+    // With a live one among them we bootstrap to it and are told how long it took
+    let mut proxies = silent_nodes;
+    proxies.push_back(live_node_info.clone());
+    let (peer, ev_rx) = bootstrapper(proxies);
+    peer.bootstrap();
+    for event in ev_rx.iter() {
+        match event {
+            Event::BootstrappedTo {
+                node,
+                elapsed,
+                timings,
+                ..
+            } => {
+                assert_eq!(node, live_node_info);
+                assert!(elapsed < Duration::from_secs(5));
+                // We dialled it, so the QUIC handshake is timed too
+                let quic_handshake = unwrap!(timings.quic_handshake);
+                assert!(quic_handshake < Duration::from_secs(5));
+                assert!(timings.total() >= quic_handshake);
+                return;
+            }
+            Event::BootstrapFailure => panic!("Failed to bootstrap to the live proxy"),
+            _ => (),
+        }
+    }
+    panic!("Didn't receive the expected BootstrappedTo event");
+}
+
+#[test]
+fn background_profile_limits_the_send_rate_until_reconfigured() {
+    use std::time::{Duration, Instant};
+
+    let (node, ev_rx_node) = test_peer();
+    let node_info = unwrap!(node.our_connection_info());
+
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            traffic_profile: TrafficProfile::Background,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    peer.connect_to(node_info.clone());
+    let _ = wait_till_connected(ev_rx);
+
+    let time_to_deliver = |msg: bytes::Bytes| {
+        let sent_at = Instant::now();
+        peer.send(node_info.clone().into(), msg.clone());
+        let received = unwrap!(ev_rx_node.iter().find_map(|event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        }));
+        assert_eq!(received, msg);
+        sent_at.elapsed()
+    };
+
+    // A second's worth goes out in one burst, the rest at the limited rate
+    let msg = bytes::Bytes::from(vec![1; 2 * BACKGROUND_MAX_SEND_RATE as usize]);
+    assert!(time_to_deliver(msg.clone()) >= Duration::from_millis(800));
+
+    peer.reconfigure(TrafficProfile::Interactive);
+    assert!(time_to_deliver(msg) < Duration::from_millis(800));
+}
+
+#[test]
+fn bootstrap_cache_updates_are_reported_in_one_event() {
+    let (peer1, _) = test_peer();
+    let peer1_info = unwrap!(peer1.our_connection_info());
+    let (peer2, _) = test_peer();
+    let peer2_info = unwrap!(peer2.our_connection_info());
+
+    let (peer, ev_rx) = test_peer();
+    peer.connect_to_many(vec![peer1_info.clone(), peer2_info.clone()]);
+
+    let (mut added, removed) = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::BootstrapCacheUpdated { added, removed } => Some((added, removed)),
+        _ => None,
+    }));
+    added.sort_by_key(|node_info| node_info.peer_addr);
+    let mut expected = vec![peer1_info, peer2_info];
+    expected.sort_by_key(|node_info| node_info.peer_addr);
+    assert_eq!(added, expected);
+    assert!(removed.is_empty());
+}
+
+#[cfg(feature = "net-sim")]
+#[test]
+fn peers_connect_and_message_through_an_impaired_network() {
+    use quic_p2p::{NetSim, NetSimConfig};
+    use std::time::{Duration, Instant};
+
+    let (node, node_ev_rx) = test_peer();
+    let node_info = unwrap!(node.our_connection_info());
+    let net_sim = unwrap!(NetSim::start(
+        node_info.peer_addr,
+        NetSimConfig {
+            delay_msec: 50,
+            jitter_msec: 10,
+            loss: 0.05,
+            reorder: 0.1,
+            seed: 1,
+        }
+    ));
+
+    let (client_ev_tx, client_ev_rx) = mpsc::channel();
+    let client = unwrap!(Builder::new(client_ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            our_type: OurType::Client,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+
+    // The handshake takes at least a round trip of the relay
+    let started_at = Instant::now();
+    client.connect_to(NodeInfo {
+        peer_addr: net_sim.addr(),
+        peer_cert_der: node_info.peer_cert_der,
+    });
+    let peer = wait_till_connected(node_ev_rx);
+    assert!(started_at.elapsed() >= Duration::from_millis(100));
+
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    node.send(peer, msg.clone());
+    let received = unwrap!(client_ev_rx.iter().find_map(|event| match event {
+        Event::NewMessage { msg, .. } => Some(msg),
+        _ => None,
+    }));
+    assert_eq!(received, msg);
+}
+
+#[test]
+fn messages_waiting_for_a_failed_connect_are_reported_unsent() {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            idle_timeout_msec: Some(500),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+
+    // Takes the QUIC handshake packets and never answers them
+    let silent_socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    let silent_node = NodeInfo {
+        peer_addr: unwrap!(silent_socket.local_addr()),
+        peer_cert_der: SerialisableCertificate::default().cert_der,
+    };
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    peer.send(silent_node.clone().into(), msg.clone());
+
+    let (unsent_to, unsent_msg) = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::UnsentUserMessage {
+            peer,
+            msg,
+            reason: UnsentReason::ConnectFailed,
+        } => Some((peer, msg)),
+        _ => None,
+    }));
+    assert_eq!(unsent_to, silent_node.into());
+    assert_eq!(unsent_msg, msg);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn peers_gone_silent_are_reported_unresponsive_until_heard_from() {
+    use quic_p2p::FaultSpec;
+
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer1 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            unresponsive_peer_msec: Some(400),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let peer1_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, _) = test_peer();
+    peer2.connect_to(peer1_info);
+    let peer2_addr = wait_till_connected(ev_rx).peer_addr();
+
+    // Answers to the health checks come too late from now on
+    peer2.inject_fault(FaultSpec::DelayOutbound { delay_msec: 1_500 });
+    let since = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::PeerUnresponsive { peer_addr, since } if peer_addr == peer2_addr => Some(since),
+        Event::PeerResponsive { .. } => panic!("Unexpected event: {:?}", event),
+        _ => None,
+    }));
+    assert!(since.elapsed() >= std::time::Duration::from_millis(400));
+
+    peer2.inject_fault(FaultSpec::DelayOutbound { delay_msec: 0 });
+    unwrap!(ev_rx.iter().find(|event| match event {
+        Event::PeerResponsive { peer_addr } => *peer_addr == peer2_addr,
+        _ => false,
+    }));
+}
+
+#[test]
+fn every_subscriber_receives_the_events() {
+    let (peer1, ev_rx) = test_peer();
+    let peer1_info = unwrap!(peer1.our_connection_info());
+    let subscriber1 = unwrap!(peer1.subscribe());
+    let subscriber2 = unwrap!(peer1.subscribe());
+
+    let (peer2, _) = test_peer();
+    peer2.connect_to(peer1_info);
+
+    let peer = wait_till_connected(ev_rx);
+    assert_eq!(wait_till_connected(subscriber1), peer);
+    assert_eq!(wait_till_connected(subscriber2), peer);
+}
+
+#[test]
+fn clock_skew_of_peers_is_estimated_from_their_handshake() {
+    let (peer1, ev_rx) = test_peer();
+    let peer1_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, _) = test_peer();
+    peer2.connect_to(peer1_info);
+    let peer2_addr = wait_till_connected(ev_rx).peer_addr();
+
+    // Same clock on both ends
+    let skew = unwrap!(unwrap!(peer1.peer_clock_skew(peer2_addr)));
+    assert!(skew.abs() < 1_000, "skew {}", skew);
+
+    let stranger: SocketAddr = unwrap!("127.0.0.1:1".parse());
+    match peer1.peer_clock_skew(stranger) {
+        Err(Error::PeerNotConnected(addr)) => assert_eq!(addr, stranger),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+}
+
+#[test]
+fn files_are_transferred_in_chunks_and_verified() {
+    let (peer1, ev_rx) = test_peer();
+    let peer1_info = unwrap!(peer1.our_connection_info());
+    let (peer2, _) = test_peer();
+
+    // Several chunks and a partial one
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let path = env::temp_dir().join(format!("quic_p2p_file_transfer_{}", process::id()));
+    unwrap!(fs::write(&path, &content));
+
+    let hash = unwrap!(peer2.send_file(peer1_info.into(), path.clone()));
+    let mut last_progress = 0;
+    let (received_hash, temp_path) = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::FileOffered { peer, hash, len } => {
+            assert_eq!(len, content.len() as u64);
+            peer1.accept_file(peer.peer_addr(), hash);
+            None
+        }
+        Event::FileTransferProgress {
+            transferred, total, ..
+        } => {
+            assert!(transferred > last_progress);
+            assert_eq!(total, content.len() as u64);
+            last_progress = transferred;
+            None
+        }
+        Event::FileReceived {
+            hash, temp_path, ..
+        } => Some((hash, temp_path)),
+        Event::FileTransferFailed { reason, .. } => panic!("Transfer failed: {}", reason),
+        _ => None,
+    }));
+    assert_eq!(received_hash, hash);
+    assert_eq!(last_progress, content.len() as u64);
+    assert_eq!(unwrap!(fs::read(&temp_path)), content);
+
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(temp_path);
+}
+
+#[test]
+fn files_refused_by_the_user_or_too_long_are_not_transferred() {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer1 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            max_file_len: Some(10),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let peer1_info = unwrap!(peer1.our_connection_info());
+    let (peer2, peer2_ev_rx) = test_peer();
+
+    let path = env::temp_dir().join(format!("quic_p2p_file_refused_{}", process::id()));
+    let assert_refused = |hash| {
+        let failed_hash = unwrap!(peer2_ev_rx.iter().find_map(|event| match event {
+            Event::FileTransferFailed { hash, .. } => Some(hash),
+            Event::FileTransferProgress { .. } => panic!("Refused file transferred"),
+            _ => None,
+        }));
+        assert_eq!(failed_hash, hash);
+    };
+
+    // Refused by the user
+    unwrap!(fs::write(&path, b"short"));
+    let hash = unwrap!(peer2.send_file(peer1_info.clone().into(), path.clone()));
+    let (peer_addr, offered_hash) = unwrap!(ev_rx.iter().find_map(|event| match event {
+        Event::FileOffered { peer, hash, .. } => Some((peer.peer_addr(), hash)),
+        _ => None,
+    }));
+    assert_eq!(offered_hash, hash);
+    peer1.refuse_file(peer_addr, hash);
+    assert_refused(hash);
+
+    // Longer than the recipient takes, without it being offered to its user
+    unwrap!(fs::write(&path, b"much too long"));
+    let hash = unwrap!(peer2.send_file(peer1_info.into(), path.clone()));
+    assert_refused(hash);
+    assert!(ev_rx.try_iter().all(|event| match event {
+        Event::FileOffered { .. } => false,
+        _ => true,
+    }));
+
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn hard_coded_contacts_given_by_hostname_are_resolved_again_once_dials_fail() {
+    use quic_p2p::{Contact, Resolver};
+    use std::time::Duration;
+
+    // Resolves to the given addresses in turn, sticking to the last ones
+    struct ScriptedResolver {
+        answers: Mutex<VecDeque<Vec<SocketAddr>>>,
+        lookups: Arc<Mutex<Vec<(String, u16)>>>,
+    }
+
+    impl Resolver for ScriptedResolver {
+        fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            unwrap!(self.lookups.lock()).push((host.to_string(), port));
+            let mut answers = unwrap!(self.answers.lock());
+            if answers.len() > 1 {
+                Ok(unwrap!(answers.pop_front()))
+            } else {
+                Ok(answers[0].clone())
+            }
+        }
+    }
+
+    let (live_node, _) = test_peer();
+    let live_node_info = unwrap!(live_node.our_connection_info());
+    // Takes the QUIC handshake packets and never answers them
+    let silent_socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+    let silent_addr = unwrap!(silent_socket.local_addr());
+
+    let lookups = Arc::new(Mutex::new(Vec::new()));
+    let resolver = ScriptedResolver {
+        answers: Mutex::new(
+            vec![
+                vec![silent_addr],
+                vec![silent_addr, live_node_info.peer_addr],
+            ]
+            .into(),
+        ),
+        lookups: lookups.clone(),
+    };
+    let contact = Contact {
+        peer_addr: unwrap!("seed.test:5483".parse()),
+        peer_cert_der: live_node_info.peer_cert_der.clone(),
+    };
+
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            idle_timeout_msec: Some(500),
+            hard_coded_contacts: vec![contact].into_iter().collect(),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .with_resolver(resolver)
+        .build());
+
+    // The host only resolves to the silent address at first
+    peer.bootstrap();
+    match unwrap!(ev_rx.recv_timeout(Duration::from_secs(5))) {
+        Event::BootstrapFailure => (),
+        event => panic!("Unexpected event: {:?}", event),
+    }
+
+    // The failed dial had it resolved again, to the live node among others
+    peer.bootstrap();
+    for event in ev_rx.iter() {
+        match event {
+            Event::BootstrappedTo { node, .. } => {
+                assert_eq!(node, live_node_info);
+                break;
+            }
+            Event::BootstrapFailure => panic!("Failed to bootstrap to the live node"),
+            _ => (),
+        }
+    }
+    let lookups = unwrap!(lookups.lock());
+    assert!(lookups.len() >= 2);
+    assert!(lookups
+        .iter()
+        .all(|lookup| *lookup == ("seed.test".to_string(), 5483)));
+}
+
+#[test]
+fn messages_are_handed_over_in_send_order_with_ordered_delivery() {
+    let ordered_peer = || {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ordered_delivery: Some(Default::default()),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .build());
+        (peer, ev_rx)
+    };
+    let (peer1, ev_rx1) = ordered_peer();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    let (peer2, _) = ordered_peer();
+
+    // The big one takes longest on the way, so the others would overtake it
+    let msgs: Vec<_> = (0..32u8)
+        .map(|n| {
+            let len = if n == 0 { 2 * 1024 * 1024 } else { 16 };
+            bytes::Bytes::from(vec![n; len])
+        })
+        .collect();
+    for msg in &msgs {
+        peer2.send(peer1_conn_info.clone().into(), msg.clone());
+    }
+
+    let rxd: Vec<_> = ev_rx1
+        .iter()
+        .filter_map(|event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        })
+        .take(msgs.len())
+        .collect();
+    assert_eq!(rxd, msgs);
+}
+
+#[test]
+fn backpressure_is_reported_while_the_application_leaves_events_untaken() {
+    use quic_p2p::{event_channel, BackpressureConfig, BackpressureLevel};
+
+    let (ev_tx, ev_rx) = event_channel();
+    let peer1 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            backpressure: Some(BackpressureConfig {
+                max_event_depth: 8,
+                check_interval_msec: 10,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, _) = test_peer();
+    for n in 0..16 {
+        peer2.send(peer1_conn_info.clone().into(), bytes::Bytes::from(vec![n]));
+    }
+
+    let wait_for = |level| {
+        while unwrap!(peer1.stats()).backpressure != level {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    };
+    let backpressure_events = |ev_rx: &quic_p2p::EventReceiver| -> Vec<BackpressureLevel> {
+        ev_rx
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::Backpressure { level } => Some(level),
+                _ => None,
+            })
+            .collect()
+    };
+
+    wait_for(BackpressureLevel::Critical);
+    assert!(unwrap!(peer1.stats()).event_depth >= Some(8));
+    assert!(backpressure_events(&ev_rx).contains(&BackpressureLevel::Critical));
+
+    // Messages still arriving might keep it elevated for a bit
+    wait_for(BackpressureLevel::Normal);
+    assert_eq!(
+        backpressure_events(&ev_rx).last(),
+        Some(&BackpressureLevel::Normal)
+    );
+}
+
+#[test]
+fn node_shuts_down_once_its_events_are_no_longer_taken() {
+    use quic_p2p::EventChannelClosedPolicy;
+
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer1 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            on_event_channel_closed: EventChannelClosedPolicy::Shutdown,
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    drop(ev_rx);
+
+    let (peer2, ev_rx2) = test_peer();
+    peer2.connect_to(peer1_conn_info);
+
+    for event in ev_rx2.iter() {
+        if let Event::ConnectionFailure { reason, .. } = event {
+            assert_eq!(reason, CloseReason::Shutdown);
+            assert!(peer1.stats().is_err());
+            return;
+        }
+    }
+    panic!("Didn't receive the expected ConnectionFailure event");
+}
+
+#[test]
+fn instances_sharing_an_event_loop_are_isolated() {
+    use quic_p2p::SharedEventLoop;
+
+    let event_loop = SharedEventLoop::new();
+    let peer_on_shared_loop = || {
+        let (ev_tx, ev_rx) = mpsc::channel();
+        let peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_proxies(Default::default(), true)
+            .with_event_loop(&event_loop)
+            .build());
+        (peer, ev_rx)
+    };
+
+    let (peer1, ev_rx1) = peer_on_shared_loop();
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+    let (peer2, ev_rx2) = peer_on_shared_loop();
+    let peer2_conn_info = unwrap!(peer2.our_connection_info());
+    assert_ne!(peer1_conn_info, peer2_conn_info);
+
+    let msg = bytes::Bytes::from(vec![1, 2, 3]);
+    peer2.send(peer1_conn_info.clone().into(), msg.clone());
+    let received = ev_rx1.iter().find_map(|event| match event {
+        Event::NewMessage { peer, msg, .. } => Some((peer, msg)),
+        _ => None,
+    });
+    assert_eq!(received, Some((peer2_conn_info.clone().into(), msg)));
+
+    // Dropping one instance shuts it down alone
+    let _ = ev_rx2.iter().find(|event| match event {
+        Event::ConnectedTo { .. } => true,
+        _ => false,
+    });
+    drop(peer1);
+    for event in ev_rx2.iter() {
+        if let Event::ConnectionFailure { reason, .. } = event {
+            assert_eq!(reason, CloseReason::Shutdown);
+            assert_eq!(unwrap!(peer2.our_connection_info()), peer2_conn_info);
+            return;
+        }
+    }
+    panic!("Didn't receive the expected ConnectionFailure event");
+}
+
+#[test]
+fn messages_longer_than_we_allow_are_scored_as_oversized() {
+    let (ev_tx, ev_rx) = mpsc::channel();
+    let peer1 = unwrap!(Builder::new(ev_tx)
+        .with_config(Config {
+            port: Some(0),
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            max_msg_size_allowed: Some(1024),
+            ..Default::default()
+        })
+        .with_proxies(Default::default(), true)
+        .build());
+    let peer1_conn_info = unwrap!(peer1.our_connection_info());
+
+    let (peer2, _) = test_peer();
+    let peer2_addr = unwrap!(peer2.our_connection_info()).peer_addr;
+    peer2.connect_to(peer1_conn_info.clone());
+    let _ = wait_till_connected(ev_rx);
+
+    peer2.send(
+        peer1_conn_info.into(),
+        bytes::Bytes::from(vec![1; 64 * 1024]),
+    );
+    while unwrap!(peer1.peer_score(peer2_addr)) == 0 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    // The penalty for an oversized message, not the one for a generic protocol violation
+    assert_eq!(unwrap!(peer1.peer_score(peer2_addr)), 20);
+}